@@ -0,0 +1,11 @@
+#!/bin/sh
+# Rebuilds runtime.jar from the sources in src/.
+#
+# The classes replace the JDK's own java.base classes, so they are compiled
+# as a patch to that module.
+set -e
+cd "$(dirname "$0")"
+out=$(mktemp -d)
+javac --release 17 --patch-module java.base=src -d "$out" $(find src -name '*.java')
+jar --create --file runtime.jar --date=2000-01-01T00:00:00Z -C "$out" .
+rm -rf "$out"
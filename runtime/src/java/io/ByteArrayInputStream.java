@@ -0,0 +1,37 @@
+package java.io;
+
+public class ByteArrayInputStream extends InputStream {
+    protected byte[] buf;
+    protected int pos;
+    protected int count;
+
+    public ByteArrayInputStream(byte[] buf) {
+        this.buf = buf;
+        this.pos = 0;
+        this.count = buf.length;
+    }
+
+    public int read() {
+        return pos < count ? buf[pos++] & 0xFF : -1;
+    }
+
+    public int read(byte[] b, int off, int len) {
+        if (pos >= count) {
+            return -1;
+        }
+        int available = count - pos;
+        if (len > available) {
+            len = available;
+        }
+        if (len <= 0) {
+            return 0;
+        }
+        System.arraycopy(buf, pos, b, off, len);
+        pos += len;
+        return len;
+    }
+
+    public int available() {
+        return count - pos;
+    }
+}
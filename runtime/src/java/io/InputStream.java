@@ -0,0 +1,39 @@
+package java.io;
+
+public abstract class InputStream {
+    public InputStream() {
+    }
+
+    public abstract int read();
+
+    public int read(byte[] b) {
+        return read(b, 0, b.length);
+    }
+
+    public int read(byte[] b, int off, int len) {
+        if (len == 0) {
+            return 0;
+        }
+        int c = read();
+        if (c == -1) {
+            return -1;
+        }
+        b[off] = (byte) c;
+        int i = 1;
+        for (; i < len; i++) {
+            c = read();
+            if (c == -1) {
+                break;
+            }
+            b[off + i] = (byte) c;
+        }
+        return i;
+    }
+
+    public int available() {
+        return 0;
+    }
+
+    public void close() {
+    }
+}
@@ -0,0 +1,49 @@
+package java.io;
+
+public class PrintStream {
+    public native void print(String s);
+
+    public native void print(int i);
+
+    public native void print(long l);
+
+    public native void print(char c);
+
+    public native void print(double d);
+
+    public native void println();
+
+    public void print(boolean b) {
+        print(b ? "true" : "false");
+    }
+
+    public void println(boolean b) {
+        print(b);
+        println();
+    }
+
+    public void println(String s) {
+        print(s);
+        println();
+    }
+
+    public void println(int i) {
+        print(i);
+        println();
+    }
+
+    public void println(long l) {
+        print(l);
+        println();
+    }
+
+    public void println(char c) {
+        print(c);
+        println();
+    }
+
+    public void println(double d) {
+        print(d);
+        println();
+    }
+}
@@ -0,0 +1,53 @@
+package java.lang;
+
+import java.io.InputStream;
+import java.net.URL;
+
+/**
+ * The translator lays out an instance per class and array class, like
+ * string literals, see `src/classes.rs`. Arrays of references all share the
+ * class of `Object[]`.
+ */
+public final class Class<T> {
+    private final String name;
+
+    private Class() {
+        name = null;
+    }
+
+    public String getName() {
+        return name;
+    }
+
+    public String toString() {
+        return "class " + name;
+    }
+
+    public InputStream getResourceAsStream(String name) {
+        return ClassLoader.getSystemResourceAsStream(resolveName(name));
+    }
+
+    public URL getResource(String name) {
+        return ClassLoader.getSystemResource(resolveName(name));
+    }
+
+    /** Absolute names start with a slash, relative ones are in the package of the class. */
+    private String resolveName(String name) {
+        String resolved = "";
+        if (name.length() > 0 && name.charAt(0) == '/') {
+            for (int i = 1; i < name.length(); i++) {
+                resolved += name.charAt(i);
+            }
+            return resolved;
+        }
+        int end = this.name.length();
+        while (end > 0 && this.name.charAt(end - 1) != '.') {
+            end--;
+        }
+        for (int i = 0; i < end; i++) {
+            char c = this.name.charAt(i);
+            resolved += c == '.' ? '/' : c;
+        }
+        return resolved + name;
+    }
+}
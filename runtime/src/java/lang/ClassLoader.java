@@ -0,0 +1,33 @@
+package java.lang;
+
+import java.io.ByteArrayInputStream;
+import java.io.InputStream;
+import java.net.URL;
+
+/**
+ * Resources are the files of the input JARs the translator embedded, read
+ * through the natives implemented in `src/resources.rs`.
+ */
+public abstract class ClassLoader {
+    protected ClassLoader() {
+    }
+
+    public static InputStream getSystemResourceAsStream(String name) {
+        int index = index(name);
+        return index < 0 ? null : new ByteArrayInputStream(resourceData(index));
+    }
+
+    public static URL getSystemResource(String name) {
+        return index(name) < 0 ? null : new URL("resource", "", name);
+    }
+
+    /** Finds nothing for `null`, like a name no resource has. */
+    private static int index(String name) {
+        return name == null ? -1 : findResource(name);
+    }
+
+    /** Index of the resource with the name, -1 if there is none. */
+    private static native int findResource(String name);
+
+    private static native byte[] resourceData(int index);
+}
@@ -0,0 +1,18 @@
+package java.lang;
+
+public class Object {
+    public Object() {
+    }
+
+    public final native Class<?> getClass();
+
+    public native int hashCode();
+
+    public boolean equals(Object other) {
+        return this == other;
+    }
+
+    public String toString() {
+        return "Object";
+    }
+}
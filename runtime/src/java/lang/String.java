@@ -0,0 +1,60 @@
+package java.lang;
+
+/**
+ * Field order and types have to match the layout in `src/layout.rs`,
+ * string literals are laid out by the translator.
+ */
+public final class String implements java.io.Serializable {
+    private final byte[] value;
+    private final byte coder;
+    private int hash;
+
+    private String() {
+        value = null;
+        coder = 0;
+    }
+
+    public int hashCode() {
+        return hash;
+    }
+
+    public int length() {
+        return value.length >> coder;
+    }
+
+    /** UTF-16 strings are stored little-endian. */
+    public char charAt(int index) {
+        if (coder == 0) {
+            return (char) (value[index] & 0xFF);
+        }
+        return (char) ((value[2 * index] & 0xFF) | (value[2 * index + 1] & 0xFF) << 8);
+    }
+
+    public boolean equals(Object other) {
+        if (this == other) {
+            return true;
+        }
+        if (!(other instanceof String)) {
+            return false;
+        }
+        String s = (String) other;
+        int length = length();
+        if (s.length() != length) {
+            return false;
+        }
+        for (int i = 0; i < length; i++) {
+            if (charAt(i) != s.charAt(i)) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    public String toString() {
+        return this;
+    }
+
+    public static String valueOf(Object o) {
+        return o == null ? "null" : o.toString();
+    }
+}
@@ -0,0 +1,14 @@
+package java.lang;
+
+import java.io.PrintStream;
+
+public final class System {
+    public static final PrintStream out = new PrintStream();
+
+    private System() {
+    }
+
+    public static native void arraycopy(Object src, int srcPos, Object dest, int destPos, int length);
+
+    public static native long currentTimeMillis();
+}
@@ -0,0 +1,71 @@
+package java.lang;
+
+/**
+ * The stack trace is kept as the frame words of the translated code's shadow
+ * stack, which the natives implemented in `src/trace.rs` decode.
+ */
+public class Throwable {
+    private final String message;
+    private final int[] backtrace;
+
+    public Throwable() {
+        message = null;
+        backtrace = backtrace();
+    }
+
+    public Throwable(String message) {
+        this.message = message;
+        backtrace = backtrace();
+    }
+
+    public String getMessage() {
+        return message;
+    }
+
+    public String toString() {
+        String name = className();
+        return message == null ? name : name + ": " + message;
+    }
+
+    public StackTraceElement[] getStackTrace() {
+        StackTraceElement[] trace = new StackTraceElement[backtrace.length];
+        for (int i = 0; i < trace.length; i++) {
+            int frame = backtrace[i];
+            trace[i] = new StackTraceElement(frameClass(frame), frameMethod(frame), frameFile(frame), frameLine(frame));
+        }
+        return trace;
+    }
+
+    public void printStackTrace() {
+        print(describe());
+    }
+
+    private String describe() {
+        String text = toString() + "\n";
+        for (StackTraceElement element : getStackTrace()) {
+            text = text + "\tat " + element + "\n";
+        }
+        return text;
+    }
+
+    /** Called with exceptions that escape an exported method. */
+    static void uncaught(Throwable throwable) {
+        print("Exception in thread \"main\" " + throwable.describe());
+    }
+
+    /** Frames of the methods that are running, innermost first, without the constructors of this throwable. */
+    private native int[] backtrace();
+
+    private native String className();
+
+    private static native String frameClass(int frame);
+
+    private static native String frameMethod(int frame);
+
+    private static native String frameFile(int frame);
+
+    private static native int frameLine(int frame);
+
+    /** Writes the text to the host's standard error. */
+    private static native void print(String text);
+}
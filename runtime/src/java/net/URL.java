@@ -0,0 +1,43 @@
+package java.net;
+
+import java.io.InputStream;
+
+/**
+ * Only the resources embedded by the translator can be opened, their URLs
+ * are `resource:` followed by the name. Other URLs open no stream.
+ */
+public final class URL {
+    private final String protocol;
+    private final String host;
+    private final String file;
+
+    public URL(String protocol, String host, String file) {
+        this.protocol = protocol;
+        this.host = host;
+        this.file = file;
+    }
+
+    public String getProtocol() {
+        return protocol;
+    }
+
+    public String getHost() {
+        return host;
+    }
+
+    public String getFile() {
+        return file;
+    }
+
+    public String getPath() {
+        return file;
+    }
+
+    public InputStream openStream() {
+        return protocol.equals("resource") ? ClassLoader.getSystemResourceAsStream(file) : null;
+    }
+
+    public String toString() {
+        return host.length() == 0 ? protocol + ":" + file : protocol + "://" + host + file;
+    }
+}
@@ -0,0 +1,170 @@
+//! Splitting method bodies into basic blocks.
+
+use std::ops::Range;
+
+use anyhow::bail;
+use noak::reader::attributes::{Code, Index, RawInstruction};
+use noak::reader::cpool::ConstantPool;
+
+/// An entry of the exception table.
+#[derive(Clone, Debug)]
+pub struct Handler{
+    pub start: u32,
+    pub end: u32,
+    pub handler: u32,
+    /// `None` catches everything.
+    pub catch_type: Option<Box<[u8]>>,
+}
+
+impl Handler{
+    pub fn read_all(code: &Code, cp: &ConstantPool) -> anyhow::Result<Vec<Self>>{
+        let mut handlers = Vec::new();
+        for handler in code.exception_handlers(){
+            let catch_type = match handler.catch_type() {
+                Some(index) => Some(cp.get(cp.get(index)?.name)?.content.as_bytes().into()),
+                None => None
+            };
+            handlers.push(Self{
+                start: handler.start().as_u32(),
+                end: handler.end().as_u32(),
+                handler: handler.handler().as_u32(),
+                catch_type
+            });
+        }
+        Ok(handlers)
+    }
+
+    pub fn covers(&self, offset: u32) -> bool{
+        (self.start..self.end).contains(&offset)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Block{
+    /// Bytecode offset of the first instruction.
+    pub start: u32,
+    /// Range into the instruction list.
+    pub instructions: Range<usize>,
+    /// Offsets of the blocks control can continue to, not counting exception handlers.
+    pub successors: Vec<u32>,
+}
+
+/// Offset the jump at `at` targets.
+pub fn target(at: Index, offset: i32) -> u32{
+    at.as_u32().wrapping_add_signed(offset)
+}
+
+/// Offsets an instruction can jump to, `None` if it just continues with the next one.
+pub fn jump_targets(at: Index, instruction: &RawInstruction) -> anyhow::Result<Option<Vec<u32>>>{
+    Ok(Some(match instruction {
+        RawInstruction::AReturn |
+        RawInstruction::DReturn |
+        RawInstruction::FReturn |
+        RawInstruction::IReturn |
+        RawInstruction::LReturn |
+        RawInstruction::Return |
+        RawInstruction::AThrow => vec![],
+        RawInstruction::Goto { offset } => vec![target(at, *offset as i32)],
+        RawInstruction::GotoW { offset } => vec![target(at, *offset)],
+        RawInstruction::IfACmpEq { offset } |
+        RawInstruction::IfACmpNe { offset } |
+        RawInstruction::IfICmpEq { offset } |
+        RawInstruction::IfICmpNe { offset } |
+        RawInstruction::IfICmpLt { offset } |
+        RawInstruction::IfICmpGe { offset } |
+        RawInstruction::IfICmpGt { offset } |
+        RawInstruction::IfICmpLe { offset } |
+        RawInstruction::IfEq { offset } |
+        RawInstruction::IfNe { offset } |
+        RawInstruction::IfLt { offset } |
+        RawInstruction::IfGe { offset } |
+        RawInstruction::IfGt { offset } |
+        RawInstruction::IfLe { offset } |
+        RawInstruction::IfNonNull { offset } |
+        RawInstruction::IfNull { offset } => vec![target(at, *offset as i32)],
+        RawInstruction::LookupSwitch(lookup) => {
+            let mut targets = vec![target(at, lookup.default_offset())];
+            targets.extend(lookup.pairs().take(lookup.pairs().count()).map(|p| target(at, p.offset())));
+            targets
+        }
+        RawInstruction::TableSwitch(table) => {
+            let mut targets = vec![target(at, table.default_offset())];
+            targets.extend(table.pairs().map(|p| target(at, p.offset())));
+            targets
+        }
+        RawInstruction::JSr { .. } |
+        RawInstruction::JSrW { .. } |
+        RawInstruction::Ret { .. } |
+        RawInstruction::RetW { .. } => bail!("Subroutines are not supported"),
+        _ => return Ok(None)
+    }))
+}
+
+/// Whether execution can continue with the next instruction.
+pub fn falls_through(instruction: &RawInstruction) -> bool{
+    !matches!(
+        instruction,
+        RawInstruction::AReturn |
+        RawInstruction::DReturn |
+        RawInstruction::FReturn |
+        RawInstruction::IReturn |
+        RawInstruction::LReturn |
+        RawInstruction::Return |
+        RawInstruction::AThrow |
+        RawInstruction::Goto { .. } |
+        RawInstruction::GotoW { .. } |
+        RawInstruction::LookupSwitch(_) |
+        RawInstruction::TableSwitch(_)
+    )
+}
+
+/// Splits the instructions into basic blocks, ordered by offset.
+pub fn split(instructions: &[(Index, RawInstruction)], handlers: &[Handler]) -> anyhow::Result<Vec<Block>>{
+    let offset_of = |i: usize| instructions[i].0.as_u32();
+    let position = |offset: u32| instructions.binary_search_by_key(&offset, |(i, _)| i.as_u32());
+
+    let mut leaders = vec![0];
+    leaders.extend(handlers.iter().map(|h| h.handler));
+    for (i, (at, instruction)) in instructions.iter().enumerate(){
+        if let Some(targets) = jump_targets(*at, instruction)?{
+            leaders.extend(targets);
+            if i + 1 < instructions.len(){
+                leaders.push(offset_of(i + 1));
+            }
+        }
+    }
+    leaders.sort();
+    leaders.dedup();
+    for leader in leaders.iter(){
+        if position(*leader).is_err(){
+            bail!("Jump to {} is not at an instruction", leader);
+        }
+    }
+
+    let mut blocks = Vec::with_capacity(leaders.len());
+    for (n, leader) in leaders.iter().enumerate(){
+        let start = position(*leader).unwrap();
+        let end = match leaders.get(n + 1) {
+            Some(next) => position(*next).unwrap(),
+            None => instructions.len()
+        };
+
+        let (at, last) = &instructions[end - 1];
+        let mut successors = jump_targets(*at, last)?.unwrap_or_default();
+        if falls_through(last){
+            match instructions.get(end) {
+                Some((next, _)) => successors.push(next.as_u32()),
+                None => bail!("Execution falls off the end of the method")
+            }
+        }
+        successors.dedup();
+
+        blocks.push(Block{
+            start: *leader,
+            instructions: start..end,
+            successors
+        });
+    }
+
+    Ok(blocks)
+}
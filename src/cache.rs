@@ -0,0 +1,501 @@
+//! On-disk cache of per-class results, reused across runs.
+//!
+//! Entries are files named by a [`Fingerprint`] of everything their content
+//! depends on, so they are never updated in place: a changed input leads to
+//! another key. Every key covers the bytes of the class and the translator
+//! build, so a rebuilt translator starts over.
+//!
+//! What only depends on the class itself, like its dependencies and the
+//! operand stacks at the start of each block, is keyed by just that. Lowered
+//! bodies embed function indices, field offsets, vtable slots and addresses
+//! of the rest of the program, so their keys also cover the
+//! [environment] every body is lowered in and the [references] of the body:
+//! what the program, the layouts and the index spaces say about the classes,
+//! fields, methods and strings its instructions name. An edit to a class
+//! invalidates its own methods and those naming something it changed, but
+//! adding or removing a method still shifts the indices of every function
+//! declared after it, including the runtime.
+//!
+//! Entries that can't be read or decoded are misses.
+
+use std::{collections::{BTreeSet, HashMap, HashSet}, hash::{Hash, Hasher}, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, OnceLock}};
+
+use anyhow::bail;
+use noak::reader::{attributes::RawInstruction, cpool};
+
+use crate::{blocks::Handler, code::Value, data::{ParsedClass, ParsedMethod}, deps::{Dependency, Kind}, indy::{CallSite, Piece}, lower::{emit_barrier, Env}, module::signature, optimize::{Action, CheckReport, Decision}, program::MethodKey, runtime::STRINGS, translate::Options};
+
+/// 128 bit FNV-1a, which unlike the std hashers is the same in every build.
+#[derive(Clone, Copy, Debug)]
+pub struct Fingerprint(u128);
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013B;
+
+impl Default for Fingerprint{
+    fn default() -> Self{
+        Self(FNV_OFFSET)
+    }
+}
+
+impl Fingerprint{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn digest(&self) -> u128{
+        self.0
+    }
+}
+
+impl Hasher for Fingerprint{
+    fn write(&mut self, bytes: &[u8]){
+        for byte in bytes{
+            self.0 ^= *byte as u128;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64{
+        self.0 as u64
+    }
+}
+
+/// Hashes the entries of a map in the order of their keys.
+pub fn hash_map<K: Hash + Ord, V: Hash>(map: &HashMap<K, V>, fp: &mut Fingerprint){
+    let mut entries = map.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries.hash(fp);
+}
+
+/// Digest of the running translator, which changes whenever it's rebuilt.
+pub fn translator_version() -> u128{
+    static VERSION: OnceLock<u128> = OnceLock::new();
+    *VERSION.get_or_init(||{
+        let mut fp = Fingerprint::new();
+        env!("CARGO_PKG_VERSION").hash(&mut fp);
+        if let Some(bytes) = std::env::current_exe().ok().and_then(|exe| std::fs::read(exe).ok()){
+            fp.write(&bytes);
+        }
+        fp.digest()
+    })
+}
+
+/// Digest of what the lowering of every method reads: the options, the
+/// runtime and the strings it uses.
+pub fn environment(env: &Env, options: &Options) -> u128{
+    let mut fp = Fingerprint::new();
+    translator_version().hash(&mut fp);
+    options.monitors.hash(&mut fp);
+    format!("{:?}", options.optimizations).hash(&mut fp);
+    options.split_longs.hash(&mut fp);
+    env.runtime.fingerprint(&mut fp);
+    env.layouts.class_table().hash(&mut fp);
+    for s in STRINGS{
+        env.strings.address(&s.encode_utf16().collect::<Vec<_>>()).hash(&mut fp);
+    }
+    fp.digest()
+}
+
+/// Hashes what lowering reads about a class or array type: its declaration
+/// and superclasses, its layout and type tag, and the barrier initializing it.
+fn hash_class(env: &Env, name: &[u8], fp: &mut Fingerprint){
+    name.hash(fp);
+    env.layouts.type_tag(name).ok().hash(fp);
+    env.layouts.class_object(name).hash(fp);
+    let element = &name[name.iter().take_while(|c| **c == b'[').count()..];
+    let element = element.strip_prefix(b"L").and_then(|e| e.strip_suffix(b";")).unwrap_or(element);
+    env.program.fingerprint_class(element, fp);
+    env.layouts.fingerprint_class(element, fp);
+    let mut barrier = Vec::new();
+    emit_barrier(env, &mut HashSet::new(), &mut barrier, element);
+    format!("{:?}", barrier).hash(fp);
+}
+
+/// Digest of what lowering the method reads from the rest of the program,
+/// following the classes, fields, methods and strings its instructions and
+/// exception handlers name, seeded with the [environment].
+pub fn references(env: &Env, environment: u128, class: &ParsedClass, method: &ParsedMethod) -> anyhow::Result<u128>{
+    let mut fp = Fingerprint::new();
+    environment.hash(&mut fp);
+    env.trace.id(&MethodKey::new(&class.name, &method.name, &method.descriptor)).hash(&mut fp);
+    let mut classes = BTreeSet::from([class.name.clone()]);
+    let mut fields = BTreeSet::new();
+    let mut methods = BTreeSet::new();
+    class.with_code(&method.name, &method.descriptor, |cp, code|{
+        for handler in Handler::read_all(code, cp)?{
+            classes.extend(handler.catch_type);
+        }
+        for instruction in code.raw_instructions(){
+            match instruction?.1 {
+                RawInstruction::New { index } |
+                RawInstruction::ANewArray { index } |
+                RawInstruction::MultiANewArray { index, .. } |
+                RawInstruction::CheckCast { index } |
+                RawInstruction::InstanceOf { index } => {
+                    classes.insert(cp.get(cp.get(index)?.name)?.content.as_bytes().into());
+                }
+                RawInstruction::LdC { index } |
+                RawInstruction::LdCW { index } => match cp.get(index)? {
+                    cpool::Item::String(s) => env.strings.address_of(cp.get(s.string)?.content.as_bytes()).ok().hash(&mut fp),
+                    cpool::Item::Class(c) => {
+                        classes.insert(cp.get(c.name)?.content.as_bytes().into());
+                    }
+                    _ => ()
+                },
+                RawInstruction::GetField { index } |
+                RawInstruction::PutField { index } |
+                RawInstruction::GetStatic { index } |
+                RawInstruction::PutStatic { index } => {
+                    let field = cp.get(index)?;
+                    let owner = cp.get(cp.get(field.class)?.name)?.content.as_bytes();
+                    let name = cp.get(cp.get(field.name_and_type)?.name)?.content.as_bytes();
+                    fields.insert((Box::<[u8]>::from(owner), Box::<[u8]>::from(name)));
+                }
+                RawInstruction::InvokeStatic { index } => {
+                    methods.insert(MethodKey::read(cp, index)?);
+                }
+                RawInstruction::InvokeSpecial { index } => {
+                    methods.insert(MethodKey::read(cp, index)?);
+                }
+                RawInstruction::InvokeVirtual { index } => {
+                    methods.insert(MethodKey::read(cp, index)?);
+                }
+                RawInstruction::InvokeInterface { index, .. } => {
+                    methods.insert(MethodKey::read(cp, index)?);
+                }
+                RawInstruction::InvokeDynamic { index } => match CallSite::read(class, cp, index) {
+                    Ok(CallSite::Lambda(lambda)) => {
+                        classes.insert(lambda.class);
+                    }
+                    Ok(CallSite::Concat(concat)) => for piece in concat.pieces{
+                        if let Piece::Literal(units) = piece{
+                            env.strings.address(&units).hash(&mut fp);
+                        }
+                    },
+                    // Lowering fails the same way again.
+                    Err(_) => ()
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    })?;
+
+    for (owner, name) in fields{
+        (&owner, &name).hash(&mut fp);
+        if let Some((declaring, field)) = env.program.resolve_field(&owner, &name){
+            (&declaring.name, &field.descriptor, field.flags.bits()).hash(&mut fp);
+            env.layouts.field(&declaring.name, &name).hash(&mut fp);
+            env.statics.field(&declaring.name, &name).hash(&mut fp);
+            classes.insert(declaring.name.clone());
+        }
+        classes.insert(owner);
+    }
+    for target in methods{
+        target.hash(&mut fp);
+        env.module.function(&target).hash(&mut fp);
+        for is_static in [false, true]{
+            let (params, results) = signature(&target.descriptor, is_static)?;
+            env.module.find_type(&params, &results).hash(&mut fp);
+        }
+        env.layouts.interface_selector(&target.name, &target.descriptor).hash(&mut fp);
+        env.layouts.vtable_slot(&target.class, &target.name, &target.descriptor).hash(&mut fp);
+        if let Some((declaring, resolved)) = env.program.resolve_method(&target.class, &target.name, &target.descriptor){
+            let key = MethodKey::new(&declaring.name, &target.name, &target.descriptor);
+            (&key, resolved.flags.bits()).hash(&mut fp);
+            env.module.function(&key).hash(&mut fp);
+            env.layouts.vtable_slot(&key.class, &key.name, &key.descriptor).hash(&mut fp);
+            format!("{:?}", env.optimizer.inline_body(&key)).hash(&mut fp);
+            classes.insert(key.class);
+        }
+        if env.optimizer.options().devirtualize{
+            for only in env.optimizer.targets(env.program, &target).into_iter().flatten(){
+                (&only, env.module.function(&only)).hash(&mut fp);
+                format!("{:?}", env.optimizer.inline_body(&only)).hash(&mut fp);
+            }
+        }
+        classes.insert(target.class);
+    }
+    for name in classes{
+        hash_class(env, &name, &mut fp);
+    }
+    Ok(fp.digest())
+}
+
+/// Appends values to an entry.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer{
+    fn u32(&mut self, value: u32){
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]){
+        self.u32(bytes.len() as u32);
+        self.0.extend(bytes);
+    }
+
+    fn key(&mut self, key: &MethodKey){
+        self.bytes(&key.class);
+        self.bytes(&key.name);
+        self.bytes(&key.descriptor);
+    }
+}
+
+/// Reads values from an entry, failing at its end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a>{
+    fn u32(&mut self) -> anyhow::Result<u32>{
+        let Some((value, rest)) = self.0.split_first_chunk::<4>() else {
+            bail!("Truncated cache entry");
+        };
+        self.0 = rest;
+        Ok(u32::from_le_bytes(*value))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]>{
+        let len = self.u32()? as usize;
+        if self.0.len() < len{
+            bail!("Truncated cache entry");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String>{
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    fn key(&mut self) -> anyhow::Result<MethodKey>{
+        Ok(MethodKey::new(self.bytes()?, self.bytes()?, self.bytes()?))
+    }
+}
+
+const KINDS: [Kind; 5] = [Kind::Superclass, Kind::FieldType, Kind::MethodSignature, Kind::Instantiation, Kind::Reference];
+const VALUES: [Value; 5] = [Value::I32, Value::F32, Value::I64, Value::F64, Value::Ref];
+const INLINED: [&str; 4] = ["empty method", "constant", "getter", "setter"];
+/// Marks a block that is never reached in the encoded entry stacks.
+const UNREACHED: u32 = u32::MAX;
+
+fn index_of<T: PartialEq>(all: &[T], value: &T) -> u32{
+    all.iter().position(|v| v == value).expect("Every variant is listed") as u32
+}
+
+fn variant<T: Copy>(all: &[T], index: u32) -> anyhow::Result<T>{
+    match all.get(index as usize) {
+        Some(value) => Ok(*value),
+        None => bail!("Invalid variant {} in cache entry", index)
+    }
+}
+
+/// A method lowered in an earlier run, with what the optimizer logged for it.
+pub struct CachedMethod{
+    /// The encoded body, without its size.
+    pub body: Vec<u8>,
+    pub lines: Vec<(u32, u32)>,
+    pub locals: Vec<(u32, String)>,
+    pub decisions: Vec<Decision>,
+    pub checks: Option<CheckReport>,
+}
+
+impl CachedMethod{
+    fn encode(&self) -> Vec<u8>{
+        let mut w = Writer::default();
+        w.bytes(&self.body);
+        w.u32(self.lines.len() as u32);
+        for (offset, line) in self.lines.iter(){
+            w.u32(*offset);
+            w.u32(*line);
+        }
+        w.u32(self.locals.len() as u32);
+        for (local, name) in self.locals.iter(){
+            w.u32(*local);
+            w.bytes(name.as_bytes());
+        }
+        w.u32(self.decisions.len() as u32);
+        for decision in self.decisions.iter(){
+            w.key(&decision.caller);
+            w.u32(decision.offset);
+            w.key(&decision.target);
+            match &decision.action {
+                Action::Devirtualized(method) => {
+                    w.u32(0);
+                    w.key(method);
+                }
+                Action::Virtual(count) => {
+                    w.u32(1);
+                    w.u32(count.map_or(u32::MAX, |c| c as u32));
+                }
+                Action::Inlined(kind) => {
+                    w.u32(2);
+                    w.u32(index_of(&INLINED, kind));
+                }
+            }
+        }
+        match &self.checks {
+            Some(checks) => {
+                w.u32(1);
+                w.key(&checks.method);
+                for count in [checks.null_checks, checks.null_checks_removed, checks.bounds_checks, checks.bounds_checks_removed]{
+                    w.u32(count as u32);
+                }
+            }
+            None => w.u32(0)
+        }
+        w.0
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self>{
+        let mut r = Reader(bytes);
+        let body = r.bytes()?.to_vec();
+        let lines = (0..r.u32()?).map(|_| Ok((r.u32()?, r.u32()?))).collect::<anyhow::Result<_>>()?;
+        let locals = (0..r.u32()?).map(|_| Ok((r.u32()?, r.string()?))).collect::<anyhow::Result<_>>()?;
+        let mut decisions = Vec::new();
+        for _ in 0..r.u32()?{
+            let (caller, offset, target) = (r.key()?, r.u32()?, r.key()?);
+            let action = match r.u32()? {
+                0 => Action::Devirtualized(r.key()?),
+                1 => Action::Virtual(Some(r.u32()?).filter(|c| *c != u32::MAX).map(|c| c as usize)),
+                2 => Action::Inlined(variant(&INLINED, r.u32()?)?),
+                other => bail!("Invalid action {} in cache entry", other)
+            };
+            decisions.push(Decision{caller, offset, target, action});
+        }
+        let checks = match r.u32()? {
+            0 => None,
+            _ => Some(CheckReport{
+                method: r.key()?,
+                null_checks: r.u32()? as usize,
+                null_checks_removed: r.u32()? as usize,
+                bounds_checks: r.u32()? as usize,
+                bounds_checks_removed: r.u32()? as usize
+            })
+        };
+        Ok(Self{body, lines, locals, decisions, checks})
+    }
+}
+
+/// A directory of cache entries.
+#[derive(Debug)]
+pub struct Cache{
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Cache{
+    /// Opens the cache in `dir`, which is created if it's missing.
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self>{
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self{
+            dir,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0)
+        })
+    }
+
+    /// Lookups that found an entry and those that didn't, since the cache was opened.
+    pub fn stats(&self) -> (usize, usize){
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    fn path(&self, key: u128) -> PathBuf{
+        self.dir.join(format!("{:032x}", key))
+    }
+
+    fn get<T>(&self, key: u128, decode: impl FnOnce(&[u8]) -> anyhow::Result<T>) -> Option<T>{
+        let found = std::fs::read(self.path(key)).ok().and_then(|bytes| decode(&bytes).ok());
+        let counter = if found.is_some() {&self.hits} else {&self.misses};
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Writes the entry under a temporary name first, so that concurrent
+    /// runs never see half of it.
+    fn put(&self, key: u128, bytes: &[u8]) -> anyhow::Result<()>{
+        let path = self.path(key);
+        let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&temporary, bytes)?;
+        std::fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+
+    fn key(kind: &str, class: &ParsedClass, method: Option<&ParsedMethod>, context: u128) -> u128{
+        let mut fp = Fingerprint::new();
+        kind.hash(&mut fp);
+        context.hash(&mut fp);
+        class.bytes.hash(&mut fp);
+        if let Some(method) = method{
+            method.name.hash(&mut fp);
+            method.descriptor.hash(&mut fp);
+        }
+        fp.digest()
+    }
+
+    /// The classes the class depends on, computed by `compute` unless cached.
+    pub fn dependencies(&self, class: &ParsedClass, compute: impl FnOnce() -> anyhow::Result<Vec<Dependency>>) -> anyhow::Result<Vec<Dependency>>{
+        let key = Self::key("dependencies", class, None, translator_version());
+        let decode = |bytes: &[u8]|{
+            let mut r = Reader(bytes);
+            (0..r.u32()?).map(|_| Ok((r.bytes()?.into(), variant(&KINDS, r.u32()?)?))).collect::<anyhow::Result<Vec<_>>>()
+        };
+        if let Some(found) = self.get(key, decode){
+            return Ok(found);
+        }
+        let dependencies = compute()?;
+        let mut w = Writer::default();
+        w.u32(dependencies.len() as u32);
+        for (name, kind) in dependencies.iter(){
+            w.bytes(name);
+            w.u32(index_of(&KINDS, kind));
+        }
+        self.put(key, &w.0)?;
+        Ok(dependencies)
+    }
+
+    /// Operand stack types at the start of every block of the method,
+    /// computed by `compute` unless cached.
+    pub fn entry_stacks(&self, class: &ParsedClass, method: &ParsedMethod, compute: impl FnOnce() -> anyhow::Result<Vec<Option<Vec<Value>>>>) -> anyhow::Result<Vec<Option<Vec<Value>>>>{
+        let key = Self::key("entry stacks", class, Some(method), translator_version());
+        let decode = |bytes: &[u8]|{
+            let mut r = Reader(bytes);
+            (0..r.u32()?).map(|_| match r.u32()? {
+                UNREACHED => Ok(None),
+                len => (0..len).map(|_| variant(&VALUES, r.u32()?)).collect::<anyhow::Result<_>>().map(Some)
+            }).collect::<anyhow::Result<Vec<_>>>()
+        };
+        if let Some(found) = self.get(key, decode){
+            return Ok(found);
+        }
+        let entries = compute()?;
+        let mut w = Writer::default();
+        w.u32(entries.len() as u32);
+        for entry in entries.iter(){
+            match entry {
+                Some(values) => {
+                    w.u32(values.len() as u32);
+                    for value in values{
+                        w.u32(index_of(&VALUES, value));
+                    }
+                }
+                None => w.u32(UNREACHED)
+            }
+        }
+        self.put(key, &w.0)?;
+        Ok(entries)
+    }
+
+    /// The method as lowered with the digest of its [references].
+    pub fn lowered(&self, class: &ParsedClass, method: &ParsedMethod, references: u128) -> Option<CachedMethod>{
+        self.get(Self::key("lowered", class, Some(method), references), CachedMethod::decode)
+    }
+
+    pub fn store_lowered(&self, class: &ParsedClass, method: &ParsedMethod, references: u128, lowered: &CachedMethod) -> anyhow::Result<()>{
+        self.put(Self::key("lowered", class, Some(method), references), &lowered.encode())
+    }
+}
@@ -0,0 +1,410 @@
+//! Class ids, instance layouts, dispatch tables and `Class` objects.
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, hash::Hash};
+
+use anyhow::bail;
+use noak::AccessFlags;
+use wasm_encoder::{Function, Instruction, MemArg, ValType};
+
+use crate::{cache::{self, Fingerprint}, data::ParsedClass, layout::{self, class_id, DataSegment}, module::ModuleBuilder, program::{MethodKey, Program}, runtime, strings::{StringPool, StringTable}, trace::dotted};
+
+pub const CLASS: &[u8] = b"java/lang/Class";
+
+/// Names of the array classes by id. Arrays of references have the class of
+/// `Object[]` whatever their component.
+const ARRAY_NAMES: &[(u32, &str)] = &[
+    (class_id::BYTE_ARRAY, "[B"),
+    (class_id::BOOLEAN_ARRAY, "[Z"),
+    (class_id::CHAR_ARRAY, "[C"),
+    (class_id::SHORT_ARRAY, "[S"),
+    (class_id::INT_ARRAY, "[I"),
+    (class_id::FLOAT_ARRAY, "[F"),
+    (class_id::LONG_ARRAY, "[J"),
+    (class_id::DOUBLE_ARRAY, "[D"),
+    (class_id::OBJECT_ARRAY, "[Ljava.lang.Object;"),
+];
+
+/// `Object.getClass`, defined here rather than imported.
+pub fn get_class() -> MethodKey{
+    MethodKey::new(b"java/lang/Object", b"getClass", b"()Ljava/lang/Class;")
+}
+
+/// Whether the native is the one this module implements.
+pub fn implements(key: &MethodKey) -> bool{
+    *key == get_class()
+}
+
+/// Name and descriptor of a virtual method.
+pub type Selector = (Box<[u8]>, Box<[u8]>);
+
+pub struct ClassLayout{
+    pub id: u32,
+    /// Instance size including the header.
+    pub size: u32,
+    /// Address of the class info record, valid after [`Layouts::write`].
+    pub info: u32,
+    /// Number of superclasses, counting `Object` even if the chain is missing
+    /// classes, which is the index of the class in its display.
+    pub depth: u32,
+    /// Offsets of the instance fields declared by the class itself.
+    fields: HashMap<Box<[u8]>, u32>,
+    vtable: Vec<Selector>,
+}
+
+pub struct Layouts{
+    classes: HashMap<Box<[u8]>, ClassLayout>,
+    interface_selectors: BTreeMap<Selector, u32>,
+    /// Bit of each interface in the interface sets.
+    interface_bits: BTreeMap<Box<[u8]>, u32>,
+    class_table: u32,
+    /// Addresses of the `Class` objects by class id, valid after [`Layouts::write`].
+    class_objects: BTreeMap<u32, u32>,
+    get_class: Option<u32>,
+}
+
+fn is_virtual(flags: AccessFlags, name: &[u8]) -> bool{
+    !flags.intersects(AccessFlags::STATIC | AccessFlags::PRIVATE) && !name.starts_with(b"<")
+}
+
+/// Every interface the class implements, directly or through superclasses and superinterfaces.
+pub fn all_interfaces<'a>(program: &'a Program, class: &[u8]) -> BTreeSet<&'a [u8]>{
+    let mut found = BTreeSet::new();
+    let mut pending = program.superclasses(class).flat_map(|c| c.interfaces.iter().map(|i| &**i)).collect::<Vec<_>>();
+    while let Some(interface) = pending.pop(){
+        if found.insert(interface){
+            if let Some(c) = program.class(interface){
+                pending.extend(c.interfaces.iter().map(|i| &**i));
+            }
+        }
+    }
+    found
+}
+
+impl Layouts{
+    /// Hashes the layout and vtable of the class and its interface bit.
+    pub fn fingerprint_class(&self, name: &[u8], fp: &mut Fingerprint){
+        if let Some(class) = self.class(name){
+            (class.id, class.size, class.info, class.depth, &class.vtable).hash(fp);
+            cache::hash_map(&class.fields, fp);
+        }
+        self.interface_bit(name).hash(fp);
+    }
+
+    /// Interns the names of the `Class` objects, before the strings are laid out.
+    pub fn intern_names(program: &Program, strings: &StringPool) -> anyhow::Result<()>{
+        if program.class(CLASS).is_none(){
+            return Ok(());
+        }
+        for class in program.classes(){
+            strings.intern(&dotted(&class.name))?;
+        }
+        for (_, name) in ARRAY_NAMES{
+            strings.intern(name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn compute(program: &Program) -> anyhow::Result<Self>{
+        let mut layouts = Self{
+            classes: HashMap::new(),
+            interface_selectors: BTreeMap::new(),
+            interface_bits: BTreeMap::new(),
+            class_table: layout::NULL,
+            class_objects: BTreeMap::new(),
+            get_class: None
+        };
+
+        let mut ids = HashMap::new();
+        let mut next_id = class_id::FIRST_FREE;
+        for class in program.classes(){
+            let id = match &*class.name {
+                b"java/lang/Object" => class_id::OBJECT,
+                b"java/lang/String" => class_id::STRING,
+                _ => {
+                    next_id += 1;
+                    next_id - 1
+                }
+            };
+            ids.insert(&*class.name, id);
+        }
+        for class in program.classes(){
+            layouts.compute_class(program, class, &ids);
+        }
+
+        for class in program.classes().into_iter().filter(|c| c.flags.contains(AccessFlags::INTERFACE)){
+            let bit = layouts.interface_bits.len() as u32;
+            layouts.interface_bits.insert(class.name.clone(), bit);
+            for method in class.methods.iter().filter(|m| is_virtual(m.flags, &m.name)){
+                let next = layouts.interface_selectors.len() as u32;
+                layouts.interface_selectors.entry((method.name.clone(), method.descriptor.clone())).or_insert(next);
+            }
+        }
+
+        // String literals are laid out before any class is translated.
+        if program.class(b"java/lang/String").is_some(){
+            for (name, offset) in [(&b"value"[..], layout::STRING_VALUE_OFFSET), (b"coder", layout::STRING_CODER_OFFSET), (b"hash", layout::STRING_HASH_OFFSET)]{
+                if layouts.field(b"java/lang/String", name) != Some(offset){
+                    bail!("java/lang/String.{} has to be at offset {}", String::from_utf8_lossy(name), offset);
+                }
+            }
+        }
+
+        Ok(layouts)
+    }
+
+    fn compute_class(&mut self, program: &Program, class: &ParsedClass, ids: &HashMap<&[u8], u32>){
+        if self.classes.contains_key(&class.name){
+            return;
+        }
+
+        let (mut size, depth, mut vtable) = match class.inherited.as_deref().and_then(|s| program.class(s)) {
+            Some(super_class) => {
+                self.compute_class(program, super_class, ids);
+                let parent = &self.classes[&super_class.name];
+                (parent.size, parent.depth + 1, parent.vtable.clone())
+            }
+            None => (layout::OBJECT_HEADER_SIZE, (&*class.name != b"java/lang/Object") as u32, Vec::new())
+        };
+
+        let mut fields = HashMap::new();
+        for field in class.fields.iter().filter(|f| !f.flags.contains(AccessFlags::STATIC)){
+            let field_size = layout::field_size(&field.descriptor);
+            let offset = (size + field_size - 1) & !(field_size - 1);
+            fields.insert(field.name.clone(), offset);
+            size = offset + field_size;
+        }
+
+        let defaults = all_interfaces(program, &class.name).into_iter()
+            .filter_map(|i| program.class(i))
+            .flat_map(|i| i.methods.iter().filter(|m| !m.flags.contains(AccessFlags::ABSTRACT)));
+        for method in class.methods.iter().chain(defaults){
+            if is_virtual(method.flags, &method.name) && !vtable.iter().any(|(n, d)| *n == method.name && *d == method.descriptor){
+                vtable.push((method.name.clone(), method.descriptor.clone()));
+            }
+        }
+
+        self.classes.insert(class.name.clone(), ClassLayout{
+            id: ids[&*class.name],
+            size,
+            info: layout::NULL,
+            depth,
+            fields,
+            vtable
+        });
+    }
+
+    pub fn class(&self, name: &[u8]) -> Option<&ClassLayout>{
+        self.classes.get(name)
+    }
+
+    pub fn id(&self, name: &[u8]) -> Option<u32>{
+        self.class(name).map(|c| c.id)
+    }
+
+    /// [Type tag](layout::type_tag) of a class constant, which names a class
+    /// or an array type. Classes missing from the program have id 0.
+    pub fn type_tag(&self, name: &[u8]) -> anyhow::Result<u32>{
+        let dimensions = name.iter().take_while(|c| **c == b'[').count() as u32;
+        let element = &name[dimensions as usize..];
+        if dimensions == 0{
+            return Ok(self.id(name).unwrap_or(0));
+        }
+        match element {
+            [primitive] => match layout::primitive_array(*primitive) {
+                Some(id) => Ok(layout::type_tag(id, dimensions - 1)),
+                None => bail!("Invalid array type {}", String::from_utf8_lossy(name))
+            },
+            [b'L', class @ .., b';'] => Ok(layout::type_tag(self.id(class).unwrap_or(0), dimensions)),
+            _ => bail!("Invalid array type {}", String::from_utf8_lossy(name))
+        }
+    }
+
+    /// Offset of an instance field, `class` has to be the declaring class.
+    pub fn field(&self, class: &[u8], name: &[u8]) -> Option<u32>{
+        self.class(class)?.fields.get(name).copied()
+    }
+
+    /// Index of the method in the vtable of `class`.
+    pub fn vtable_slot(&self, class: &[u8], name: &[u8], descriptor: &[u8]) -> Option<u32>{
+        self.class(class)?.vtable.iter().position(|(n, d)| &**n == name && &**d == descriptor).map(|i| i as u32)
+    }
+
+    /// Id of an interface method, used to look it up in itables.
+    pub fn interface_selector(&self, name: &[u8], descriptor: &[u8]) -> Option<u32>{
+        self.interface_selectors.get(&(name.into(), descriptor.into())).copied()
+    }
+
+    /// Bit of the interface in the interface sets of the class info records.
+    pub fn interface_bit(&self, name: &[u8]) -> Option<u32>{
+        self.interface_bits.get(name).copied()
+    }
+
+    /// Address of the class table, valid after [`Self::write`].
+    pub fn class_table(&self) -> u32{
+        self.class_table
+    }
+
+    /// Address of the `Class` object of a class constant, which names a class
+    /// or an array type, valid after [`Self::write`].
+    pub fn class_object(&self, name: &[u8]) -> Option<u32>{
+        let id = match name {
+            [b'[', primitive] => layout::primitive_array(*primitive)?,
+            [b'[', ..] => class_id::OBJECT_ARRAY,
+            _ => self.id(name)?
+        };
+        self.class_objects.get(&id).copied()
+    }
+
+    /// Writes the `Class` objects if the program has `Class` and its name,
+    /// laid out like string literals.
+    fn write_class_objects(&mut self, program: &Program, strings: &StringTable, segment: &mut DataSegment) -> anyhow::Result<()>{
+        let (Some(class), Some(name_offset)) = (self.class(CLASS), self.field(CLASS, b"name")) else {
+            return Ok(());
+        };
+        let (id, size) = (class.id, class.size);
+        let names = program.classes().into_iter()
+            .map(|c| (self.classes[&c.name].id, dotted(&c.name)))
+            .chain(ARRAY_NAMES.iter().map(|(id, name)| (*id, name.as_bytes().to_vec())));
+        for (class, name) in names{
+            let address = segment.alloc(size, 8);
+            segment.write_header(address, id);
+            segment.write_u32(address + name_offset, strings.address_of(&name)?);
+            self.class_objects.insert(class, address);
+        }
+        Ok(())
+    }
+
+    /// Writes the `Class` objects, the class info records and the class table
+    /// to the data segment. Methods have to be declared in the module by now.
+    pub fn write(&mut self, program: &Program, module: &ModuleBuilder, strings: &StringTable, segment: &mut DataSegment) -> anyhow::Result<()>{
+        self.write_class_objects(program, strings, segment)?;
+        let table_entry = |class: &[u8], name: &[u8], descriptor: &[u8]|{
+            program.resolve_method(class, name, descriptor)
+                .and_then(|(declaring, _)| module.function(&MethodKey::new(&declaring.name, name, descriptor)))
+                .map_or(0, |f| f + 1)
+        };
+        let words = (self.interface_bits.len() as u32).div_ceil(32);
+        let write_interfaces = |segment: &mut DataSegment, interfaces: &BTreeSet<&[u8]>|{
+            let address = segment.alloc(4 * words, 4);
+            let mut set = vec![0u32; words as usize];
+            for bit in interfaces.iter().filter_map(|i| self.interface_bits.get(*i)){
+                set[(bit / 32) as usize] |= 1 << (bit % 32);
+            }
+            for (i, word) in set.into_iter().enumerate(){
+                segment.write_u32(address + 4 * i as u32, word);
+            }
+            address
+        };
+        let write_display = |segment: &mut DataSegment, display: &[u32]|{
+            let address = segment.alloc(4 + 4 * display.len() as u32, 4);
+            segment.write_u32(address, display.len() as u32);
+            for (i, id) in display.iter().enumerate(){
+                segment.write_u32(address + 4 + 4 * i as u32, *id);
+            }
+            address
+        };
+
+        let mut records = Vec::with_capacity(self.classes.len());
+        for class in program.classes(){
+            let layout = &self.classes[&class.name];
+            let interfaces = all_interfaces(program, &class.name);
+
+            let mut itable = Vec::new();
+            for interface in interfaces.iter().filter_map(|i| program.class(i)){
+                for method in interface.methods.iter().filter(|m| is_virtual(m.flags, &m.name)){
+                    let selector = self.interface_selectors[&(method.name.clone(), method.descriptor.clone())];
+                    let entry = table_entry(&class.name, &method.name, &method.descriptor);
+                    if entry != 0 && !itable.iter().any(|(s, _)| *s == selector){
+                        itable.push((selector, entry));
+                    }
+                }
+            }
+            let itable_address = segment.alloc(4 + 8 * itable.len() as u32, 4);
+            segment.write_u32(itable_address, itable.len() as u32);
+            for (i, (selector, entry)) in itable.iter().enumerate(){
+                segment.write_u32(itable_address + 4 + 8 * i as u32, *selector);
+                segment.write_u32(itable_address + 8 + 8 * i as u32, *entry);
+            }
+
+            let mut display = program.superclasses(&class.name)
+                .filter(|c| &*c.name != b"java/lang/Object")
+                .map(|c| self.classes[&c.name].id)
+                .collect::<Vec<_>>();
+            display.push(class_id::OBJECT);
+            display.reverse();
+            let display = write_display(segment, &display);
+            let interface_set = write_interfaces(segment, &interfaces);
+
+            let address = segment.alloc(layout::INFO_VTABLE_OFFSET + 4 * layout.vtable.len() as u32, 4);
+            let super_id = class.inherited.as_deref().and_then(|s| self.id(s)).unwrap_or(0);
+            segment.write_u32(address + layout::INFO_SUPER_OFFSET, super_id);
+            segment.write_u32(address + layout::INFO_SIZE_OFFSET, layout.size);
+            segment.write_u32(address + layout::INFO_ITABLE_OFFSET, itable_address);
+            segment.write_u32(address + layout::INFO_DISPLAY_OFFSET, display);
+            segment.write_u32(address + layout::INFO_INTERFACES_OFFSET, interface_set);
+            segment.write_u32(address + layout::INFO_INTERFACE_BIT_OFFSET, self.interface_bit(&class.name).unwrap_or(u32::MAX));
+            segment.write_u32(address + layout::INFO_CLASS_OFFSET, self.class_objects.get(&layout.id).copied().unwrap_or(layout::NULL));
+            for (slot, (name, descriptor)) in layout.vtable.iter().enumerate(){
+                segment.write_u32(address + layout::INFO_VTABLE_OFFSET + 4 * slot as u32, table_entry(&class.name, name, descriptor));
+            }
+            records.push((layout.id, address));
+            self.classes.get_mut(&class.name).unwrap().info = address;
+        }
+
+        // Arrays extend `Object` and implement `Cloneable` and `Serializable`,
+        // their records share an empty itable.
+        let object = b"java/lang/Object".as_slice();
+        let vtable = self.class(object).map(|c| c.vtable.clone()).unwrap_or_default();
+        let interfaces = BTreeSet::from([b"java/lang/Cloneable".as_slice(), b"java/io/Serializable"]);
+        let interface_set = write_interfaces(segment, &interfaces);
+        let itable_address = segment.alloc(4, 4);
+        for id in class_id::BYTE_ARRAY..=class_id::OBJECT_ARRAY{
+            let display = write_display(segment, &[class_id::OBJECT, id]);
+            let address = segment.alloc(layout::INFO_VTABLE_OFFSET + 4 * vtable.len() as u32, 4);
+            segment.write_u32(address + layout::INFO_SUPER_OFFSET, class_id::OBJECT);
+            segment.write_u32(address + layout::INFO_ITABLE_OFFSET, itable_address);
+            segment.write_u32(address + layout::INFO_DISPLAY_OFFSET, display);
+            segment.write_u32(address + layout::INFO_INTERFACES_OFFSET, interface_set);
+            segment.write_u32(address + layout::INFO_INTERFACE_BIT_OFFSET, u32::MAX);
+            segment.write_u32(address + layout::INFO_CLASS_OFFSET, self.class_objects.get(&id).copied().unwrap_or(layout::NULL));
+            for (slot, (name, descriptor)) in vtable.iter().enumerate(){
+                segment.write_u32(address + layout::INFO_VTABLE_OFFSET + 4 * slot as u32, table_entry(object, name, descriptor));
+            }
+            records.push((id, address));
+        }
+
+        // Covers the reserved ids even if there are few classes, so that
+        // looking up the info of a missing class reads zeroes.
+        let entries = self.classes.values().map(|c| c.id + 1).max().unwrap_or(0).max(class_id::FIRST_FREE);
+        self.class_table = segment.alloc(4 * entries, 4);
+        for (id, address) in records{
+            segment.write_u32(self.class_table + 4 * id, address);
+        }
+        Ok(())
+    }
+
+    /// Declares `Object.getClass` if the program kept it.
+    pub fn declare_natives(&mut self, module: &mut ModuleBuilder, program: &Program){
+        let key = get_class();
+        if program.class(&key.class).and_then(|c| c.method(&key.name, &key.descriptor)).is_some(){
+            self.get_class = Some(module.declare_function(key, &[ValType::I32], &[ValType::I32]));
+        }
+    }
+
+    /// Defines `Object.getClass`, which reads the `Class` object from the
+    /// info record of the receiver's class, after [`Self::write`].
+    pub fn define_natives(&self, module: &mut ModuleBuilder){
+        let Some(function) = self.get_class else {
+            return;
+        };
+        let mut body = Function::new(Vec::new());
+        body.instruction(&Instruction::LocalGet(0));
+        body.instruction(&Instruction::I32Load(MemArg{offset: layout::HEADER_CLASS_OFFSET as u64, align: 2, memory_index: 0}));
+        for instruction in runtime::load_class_info(self.class_table){
+            body.instruction(&instruction);
+        }
+        body.instruction(&Instruction::I32Load(MemArg{offset: layout::INFO_CLASS_OFFSET as u64, align: 2, memory_index: 0}));
+        body.instruction(&Instruction::End);
+        module.define(function, body);
+    }
+}
@@ -0,0 +1,81 @@
+//! Reading classes from disk.
+
+use std::{collections::{hash_map::Entry, HashMap, HashSet}, fs::File, io::Read, path::{Path, PathBuf}};
+
+use anyhow::bail;
+use zip::ZipArchive;
+
+use crate::{data::ParsedClass, manifest::Manifest};
+
+/// Parses every class in the JAR.
+pub fn read_jar(path: impl AsRef<Path>) -> anyhow::Result<Vec<ParsedClass>>{
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut classes = Vec::new();
+    for i in 0..archive.len(){
+        let mut file = archive.by_index(i)?;
+        if file.name().ends_with(".class"){
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            classes.push(ParsedClass::parse(&bytes)?);
+        }
+    }
+    Ok(classes)
+}
+
+/// The JAR followed by the JARs the `Class-Path` of its manifest lists, and
+/// then theirs, each resolved against the directory of the JAR listing it.
+/// Missing JARs are skipped like the JVM does, and every JAR is listed once.
+pub fn class_path(jar: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>>{
+    let mut jars = vec![jar.as_ref().to_path_buf()];
+    let mut seen = HashSet::from([jar.as_ref().canonicalize()?]);
+    let mut next = 0;
+    while let Some(jar) = jars.get(next).cloned(){
+        next += 1;
+        let Some(manifest) = Manifest::read(&jar)? else {
+            continue;
+        };
+        let directory = jar.parent().unwrap_or(Path::new(""));
+        for entry in manifest.class_path(){
+            let path = directory.join(entry);
+            if path.is_file() && seen.insert(path.canonicalize()?){
+                jars.push(path);
+            }
+        }
+    }
+    Ok(jars)
+}
+
+/// Which JAR the classes of each package come from, to hold packages sealed
+/// by a manifest to a single JAR like the JVM does.
+#[derive(Debug, Default)]
+pub struct Seals{
+    /// The first JAR with a class in the package, and whether it seals it.
+    packages: HashMap<String, (PathBuf, bool)>,
+}
+
+impl Seals{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Adds a class loaded from the JAR, like `com/example/Main`, failing if
+    /// its package is sealed by another JAR or if the JAR seals a package
+    /// another one already has classes in.
+    pub fn add(&mut self, jar: &Path, manifest: &Manifest, class: &str) -> anyhow::Result<()>{
+        let package = class.rsplit_once('/').map_or("", |(package, _)| package);
+        let sealed = manifest.is_sealed(package);
+        match self.packages.entry(package.to_string()) {
+            Entry::Vacant(v) => {
+                v.insert((jar.to_path_buf(), sealed));
+            }
+            Entry::Occupied(o) => {
+                let (first, first_sealed) = o.get();
+                if first != jar && (*first_sealed || sealed){
+                    let sealer = if *first_sealed {first} else {jar};
+                    bail!("Package {} is sealed by {}, but {} comes from {}", package, sealer.display(), class, jar.display());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
-use noak::reader::{attributes::{Index, RawInstruction}, cpool::ConstantPool};
+use anyhow::bail;
+use noak::reader::{attributes::{Index, RawInstruction}, cpool::{self, ConstantPool, Item}};
+use wasm_encoder::ValType;
+
+use crate::descriptor::{descriptor_parser, JavaType};
 
 const BYTE: char = 'B';
 const CHAR: char = 'C';
@@ -26,7 +30,7 @@ impl Stack{
             values: Vec::new()
         };
 
-        if inputs.len() > 0{
+        if !inputs.is_empty(){
             for value in inputs[0].values.iter(){
                 s.inputs.push(*value);
                 s.values.push(*value);
@@ -43,6 +47,14 @@ impl Stack{
         s
     }
 
+    /// A stack whose values are known on entry.
+    pub fn with_values(values: &[Value]) -> Self{
+        Self{
+            inputs: values.to_vec(),
+            values: values.to_vec()
+        }
+    }
+
 
     pub fn pop_known(&mut self, expected: Value) -> Value{
         if let Some(value) = self.values.pop(){
@@ -85,9 +97,35 @@ impl Stack{
         self.pop_known(in_);
         self.push(out);
     }
+
+    /// Pops the arguments, and the receiver if there is one, then pushes the result.
+    pub fn invoke(&mut self, descriptor: &[u8], receiver: bool) -> anyhow::Result<()>{
+        let Ok((params, result)) = descriptor_parser::method(descriptor) else {
+            bail!("Invalid method descriptor {}", String::from_utf8_lossy(descriptor));
+        };
+        for param in params.iter().rev(){
+            self.pop_known(Value::of_type(param));
+        }
+        if receiver{
+            self.pop_known(Value::Ref);
+        }
+        if let Some(result) = result{
+            self.push(Value::of_type(&result));
+        }
+        Ok(())
+    }
+
+    pub fn values(&self) -> &[Value]{
+        &self.values
+    }
+
+    /// Values popped from below the values the stack started with, in the order they were popped.
+    pub fn inputs(&self) -> &[Value]{
+        &self.inputs
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Value{
     I32,
     F32,
@@ -104,125 +142,296 @@ impl Value{
     pub fn is_small(self) -> bool{
         !self.is_big()
     }
+
+    /// The stack type of a value with the given field descriptor.
+    pub fn of_descriptor(descriptor: &[u8]) -> Self{
+        match descriptor.first().map(|c| *c as char) {
+            Some(BYTE | BOOL | SHORT | CHAR | INT) => Self::I32,
+            Some(FLOAT) => Self::F32,
+            Some(DOUBLE) => Self::F64,
+            Some(LONG) => Self::I64,
+            Some(REF | ARR) => Self::Ref,
+
+            x => panic!("Invalid descriptor char {:?}", x)
+        }
+    }
+
+    pub fn of_type(type_: &JavaType) -> Self{
+        match type_ {
+            JavaType::Reference(..) |
+            JavaType::Bool(1..) |
+            JavaType::Byte(1..) |
+            JavaType::Char(1..) |
+            JavaType::Short(1..) |
+            JavaType::Int(1..) |
+            JavaType::Float(1..) |
+            JavaType::Double(1..) |
+            JavaType::Long(1..) => Self::Ref,
+            JavaType::Bool(0) |
+            JavaType::Byte(0) |
+            JavaType::Char(0) |
+            JavaType::Short(0) |
+            JavaType::Int(0) => Self::I32,
+            JavaType::Float(0) => Self::F32,
+            JavaType::Double(0) => Self::F64,
+            JavaType::Long(0) => Self::I64,
+        }
+    }
+
+    /// References are addresses into linear memory.
+    pub fn val_type(self) -> ValType{
+        match self {
+            Self::I32 | Self::Ref => ValType::I32,
+            Self::F32 => ValType::F32,
+            Self::I64 => ValType::I64,
+            Self::F64 => ValType::F64,
+        }
+    }
+}
+
+/// The local variable a load or store accesses.
+pub fn local(instruction: &RawInstruction) -> Option<(u16, Value)>{
+    Some(match instruction {
+        RawInstruction::ILoad { index } | RawInstruction::IStore { index } => (*index as u16, Value::I32),
+        RawInstruction::LLoad { index } | RawInstruction::LStore { index } => (*index as u16, Value::I64),
+        RawInstruction::FLoad { index } | RawInstruction::FStore { index } => (*index as u16, Value::F32),
+        RawInstruction::DLoad { index } | RawInstruction::DStore { index } => (*index as u16, Value::F64),
+        RawInstruction::ALoad { index } | RawInstruction::AStore { index } => (*index as u16, Value::Ref),
+        RawInstruction::ILoadW { index } | RawInstruction::IStoreW { index } => (*index, Value::I32),
+        RawInstruction::LLoadW { index } | RawInstruction::LStoreW { index } => (*index, Value::I64),
+        RawInstruction::FLoadW { index } | RawInstruction::FStoreW { index } => (*index, Value::F32),
+        RawInstruction::DLoadW { index } | RawInstruction::DStoreW { index } => (*index, Value::F64),
+        RawInstruction::ALoadW { index } | RawInstruction::AStoreW { index } => (*index, Value::Ref),
+        RawInstruction::ILoad0 | RawInstruction::IStore0 => (0, Value::I32),
+        RawInstruction::ILoad1 | RawInstruction::IStore1 => (1, Value::I32),
+        RawInstruction::ILoad2 | RawInstruction::IStore2 => (2, Value::I32),
+        RawInstruction::ILoad3 | RawInstruction::IStore3 => (3, Value::I32),
+        RawInstruction::LLoad0 | RawInstruction::LStore0 => (0, Value::I64),
+        RawInstruction::LLoad1 | RawInstruction::LStore1 => (1, Value::I64),
+        RawInstruction::LLoad2 | RawInstruction::LStore2 => (2, Value::I64),
+        RawInstruction::LLoad3 | RawInstruction::LStore3 => (3, Value::I64),
+        RawInstruction::FLoad0 | RawInstruction::FStore0 => (0, Value::F32),
+        RawInstruction::FLoad1 | RawInstruction::FStore1 => (1, Value::F32),
+        RawInstruction::FLoad2 | RawInstruction::FStore2 => (2, Value::F32),
+        RawInstruction::FLoad3 | RawInstruction::FStore3 => (3, Value::F32),
+        RawInstruction::DLoad0 | RawInstruction::DStore0 => (0, Value::F64),
+        RawInstruction::DLoad1 | RawInstruction::DStore1 => (1, Value::F64),
+        RawInstruction::DLoad2 | RawInstruction::DStore2 => (2, Value::F64),
+        RawInstruction::DLoad3 | RawInstruction::DStore3 => (3, Value::F64),
+        RawInstruction::ALoad0 | RawInstruction::AStore0 => (0, Value::Ref),
+        RawInstruction::ALoad1 | RawInstruction::AStore1 => (1, Value::Ref),
+        RawInstruction::ALoad2 | RawInstruction::AStore2 => (2, Value::Ref),
+        RawInstruction::ALoad3 | RawInstruction::AStore3 => (3, Value::Ref),
+        _ => return None
+    })
 }
 
-pub fn generate_stack(block: &[(Index, RawInstruction)], cp: &ConstantPool, inputs: &[Stack]) -> Stack{
+/// Whether the instruction stores to a local, `iinc` aside.
+pub fn is_store(instruction: &RawInstruction) -> bool{
+    matches!(
+        instruction,
+        RawInstruction::IStore { .. } | RawInstruction::LStore { .. } | RawInstruction::FStore { .. } |
+        RawInstruction::DStore { .. } | RawInstruction::AStore { .. } |
+        RawInstruction::IStoreW { .. } | RawInstruction::LStoreW { .. } | RawInstruction::FStoreW { .. } |
+        RawInstruction::DStoreW { .. } | RawInstruction::AStoreW { .. } |
+        RawInstruction::IStore0 | RawInstruction::IStore1 | RawInstruction::IStore2 | RawInstruction::IStore3 |
+        RawInstruction::LStore0 | RawInstruction::LStore1 | RawInstruction::LStore2 | RawInstruction::LStore3 |
+        RawInstruction::FStore0 | RawInstruction::FStore1 | RawInstruction::FStore2 | RawInstruction::FStore3 |
+        RawInstruction::DStore0 | RawInstruction::DStore1 | RawInstruction::DStore2 | RawInstruction::DStore3 |
+        RawInstruction::AStore0 | RawInstruction::AStore1 | RawInstruction::AStore2 | RawInstruction::AStore3
+    )
+}
+
+/// How the `dup` instructions and `swap` rearrange the top of the stack, by
+/// the size of the window they take and the values they push, by their
+/// position in the window from the bottom. `before` is the stack before the
+/// instruction.
+pub fn shuffle(instruction: &RawInstruction, before: &[Value]) -> Option<(usize, &'static [usize])>{
+    let n = before.len();
+    let small = |depth: usize| n >= depth && before[n - depth].is_small();
+    Some(match instruction {
+        RawInstruction::Dup => (1, &[0, 0]),
+        RawInstruction::DupX1 => (2, &[1, 0, 1]),
+        RawInstruction::DupX2 if small(2) => (3, &[2, 0, 1, 2]),
+        RawInstruction::DupX2 => (2, &[1, 0, 1]),
+        RawInstruction::Dup2 if small(1) => (2, &[0, 1, 0, 1]),
+        RawInstruction::Dup2 => (1, &[0, 0]),
+        RawInstruction::Dup2X1 if small(1) => (3, &[1, 2, 0, 1, 2]),
+        RawInstruction::Dup2X1 => (2, &[1, 0, 1]),
+        RawInstruction::Dup2X2 => match (small(1), small(2), small(3)) {
+            (false, false, _) => (2, &[1, 0, 1]),
+            (false, true, _) => (3, &[2, 0, 1, 2]),
+            (true, _, false) => (3, &[1, 2, 0, 1, 2]),
+            (true, _, true) => (4, &[2, 3, 0, 1, 2, 3]),
+        },
+        RawInstruction::Swap => (2, &[1, 0]),
+        _ => return None
+    })
+}
+
+pub fn generate_stack(block: &[(Index, RawInstruction)], cp: &ConstantPool, inputs: &[Stack]) -> anyhow::Result<Stack>{
     let mut stack = Stack::new(inputs);
-    
+
     for (_, instruction) in block.iter(){
-        match instruction {
-            RawInstruction::AALoad => stack.array_load(Value::Ref),
-            RawInstruction::DALoad => stack.array_load(Value::F64),
-            RawInstruction::IALoad |
-            RawInstruction::CALoad |
-            RawInstruction::BALoad => stack.array_load(Value::I32),
-            RawInstruction::FALoad => stack.array_load(Value::F32),
-            RawInstruction::LALoad => stack.array_load(Value::I64),
-            RawInstruction::AAStore => stack.array_store(Value::Ref),
-            RawInstruction::IAStore |
-            RawInstruction::CAStore |
-            RawInstruction::BAStore => stack.array_store(Value::I32),
-            RawInstruction::DAStore => stack.array_store(Value::F64),
-            RawInstruction::FAStore => stack.array_store(Value::F32),
-            RawInstruction::LAStore => stack.array_store(Value::I64),
-            RawInstruction::ALoad { .. } |
-            RawInstruction::ALoadW { .. } |
-            RawInstruction::ALoad0 |
-            RawInstruction::ALoad1 |
-            RawInstruction::ALoad2 |
-            RawInstruction::ALoad3 |
-            RawInstruction::AConstNull => stack.push(Value::Ref),
-            RawInstruction::AStore { .. } |
-            RawInstruction::AStoreW { .. } |
-            RawInstruction::AStore0 |
-            RawInstruction::AStore1 |
-            RawInstruction::AStore2 |
-            RawInstruction::AStore3 |
-            RawInstruction::AThrow |
-            RawInstruction::AReturn => {stack.pop_known(Value::Ref);}
-            RawInstruction::ANewArray { .. } => stack.convert(Value::I32, Value::Ref),
-            RawInstruction::ArrayLength => stack.convert(Value::Ref, Value::I32),
-            RawInstruction::BIPush { .. } => stack.push(Value::I32),
-            RawInstruction::CheckCast { .. } => stack.convert(Value::Ref, Value::Ref),
-            RawInstruction::D2F => stack.convert(Value::F64, Value::F32),
-            RawInstruction::D2I => stack.convert(Value::F64, Value::I32),
-            RawInstruction::D2L => stack.convert(Value::F64, Value::I64),
-            RawInstruction::DDiv |
-            RawInstruction::DMul |
-            RawInstruction::DRem |
-            RawInstruction::DSub |
-            RawInstruction::DAdd => stack.bin_op(Value::F64),
-            RawInstruction::DCmpG |
-            RawInstruction::DCmpL => {stack.pop_known(Value::F64); stack.pop_known(Value::F64); stack.push(Value::I32)},
-            RawInstruction::DConst0 |
-            RawInstruction::DConst1 |
-            RawInstruction::DLoad { .. } |
-            RawInstruction::DLoadW { .. } |
-            RawInstruction::DLoad0 |
-            RawInstruction::DLoad1 |
-            RawInstruction::DLoad2 |
-            RawInstruction::DLoad3 => stack.push(Value::F64),
-            RawInstruction::DNeg => todo!(),
-            RawInstruction::DStore {..} |
-            RawInstruction::DStoreW {..} |
-            RawInstruction::DStore0 |
-            RawInstruction::DStore1 |
-            RawInstruction::DStore2 |
-            RawInstruction::DStore3 |
-            RawInstruction::DReturn => {stack.pop_known(Value::F64);}
-            RawInstruction::Dup => {
-                let v = stack.pop_unknown(); 
-                assert!(v.is_small(), "Dup only works with short values!");
-                stack.push(v);
-                stack.push(v);
-            },
-            RawInstruction::DupX1 => {
-                let v1 = stack.pop_unknown();
-                assert!(v1.is_small(), "DupX1 only works with short values!");
-                let v2 = stack.pop_unknown();
-                assert!(v2.is_small(), "DupX1 only works with short values!");
+        step(&mut stack, instruction, cp)?;
+    }
+
+    Ok(stack)
+}
+
+/// The type an `x2y` instruction converts from and the one it converts to.
+///
+/// `i2b`, `i2c` and `i2s` stay ints but only keep the bits of the narrower type.
+pub fn conversion(instruction: &RawInstruction) -> Option<(Value, Value)>{
+    Some(match instruction {
+        RawInstruction::I2L => (Value::I32, Value::I64),
+        RawInstruction::I2F => (Value::I32, Value::F32),
+        RawInstruction::I2D => (Value::I32, Value::F64),
+        RawInstruction::I2B |
+        RawInstruction::I2C |
+        RawInstruction::I2S => (Value::I32, Value::I32),
+        RawInstruction::L2I => (Value::I64, Value::I32),
+        RawInstruction::L2F => (Value::I64, Value::F32),
+        RawInstruction::L2D => (Value::I64, Value::F64),
+        RawInstruction::F2I => (Value::F32, Value::I32),
+        RawInstruction::F2L => (Value::F32, Value::I64),
+        RawInstruction::F2D => (Value::F32, Value::F64),
+        RawInstruction::D2I => (Value::F64, Value::I32),
+        RawInstruction::D2L => (Value::F64, Value::I64),
+        RawInstruction::D2F => (Value::F64, Value::F32),
+        _ => return None
+    })
+}
+
+/// Applies the stack effect of a single instruction.
+pub fn step(stack: &mut Stack, instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<()>{
+    match instruction {
+        RawInstruction::AALoad => stack.array_load(Value::Ref),
+        RawInstruction::DALoad => stack.array_load(Value::F64),
+        RawInstruction::IALoad |
+        RawInstruction::CALoad |
+        RawInstruction::BALoad => stack.array_load(Value::I32),
+        RawInstruction::FALoad => stack.array_load(Value::F32),
+        RawInstruction::LALoad => stack.array_load(Value::I64),
+        RawInstruction::AAStore => stack.array_store(Value::Ref),
+        RawInstruction::IAStore |
+        RawInstruction::CAStore |
+        RawInstruction::BAStore => stack.array_store(Value::I32),
+        RawInstruction::DAStore => stack.array_store(Value::F64),
+        RawInstruction::FAStore => stack.array_store(Value::F32),
+        RawInstruction::LAStore => stack.array_store(Value::I64),
+        RawInstruction::ALoad { .. } |
+        RawInstruction::ALoadW { .. } |
+        RawInstruction::ALoad0 |
+        RawInstruction::ALoad1 |
+        RawInstruction::ALoad2 |
+        RawInstruction::ALoad3 |
+        RawInstruction::AConstNull => stack.push(Value::Ref),
+        RawInstruction::AStore { .. } |
+        RawInstruction::AStoreW { .. } |
+        RawInstruction::AStore0 |
+        RawInstruction::AStore1 |
+        RawInstruction::AStore2 |
+        RawInstruction::AStore3 |
+        RawInstruction::AThrow |
+        RawInstruction::AReturn => {stack.pop_known(Value::Ref);}
+        RawInstruction::ANewArray { .. } => stack.convert(Value::I32, Value::Ref),
+        RawInstruction::ArrayLength => stack.convert(Value::Ref, Value::I32),
+        RawInstruction::BIPush { .. } => stack.push(Value::I32),
+        RawInstruction::CheckCast { .. } => stack.convert(Value::Ref, Value::Ref),
+        RawInstruction::DDiv |
+        RawInstruction::DMul |
+        RawInstruction::DRem |
+        RawInstruction::DSub |
+        RawInstruction::DAdd => stack.bin_op(Value::F64),
+        RawInstruction::DCmpG |
+        RawInstruction::DCmpL => {stack.pop_known(Value::F64); stack.pop_known(Value::F64); stack.push(Value::I32)},
+        RawInstruction::DConst0 |
+        RawInstruction::DConst1 |
+        RawInstruction::DLoad { .. } |
+        RawInstruction::DLoadW { .. } |
+        RawInstruction::DLoad0 |
+        RawInstruction::DLoad1 |
+        RawInstruction::DLoad2 |
+        RawInstruction::DLoad3 => stack.push(Value::F64),
+        RawInstruction::DNeg => stack.convert(Value::F64, Value::F64),
+        RawInstruction::DStore {..} |
+        RawInstruction::DStoreW {..} |
+        RawInstruction::DStore0 |
+        RawInstruction::DStore1 |
+        RawInstruction::DStore2 |
+        RawInstruction::DStore3 |
+        RawInstruction::DReturn => {stack.pop_known(Value::F64);}
+        RawInstruction::Dup => {
+            let v = stack.pop_unknown(); 
+            assert!(v.is_small(), "Dup only works with short values!");
+            stack.push(v);
+            stack.push(v);
+        },
+        RawInstruction::DupX1 => {
+            let v1 = stack.pop_unknown();
+            assert!(v1.is_small(), "DupX1 only works with short values!");
+            let v2 = stack.pop_unknown();
+            assert!(v2.is_small(), "DupX1 only works with short values!");
+            stack.push(v1);
+            stack.push(v2);
+            stack.push(v1);
+        }
+        RawInstruction::DupX2 => {
+            let v1 = stack.pop_unknown();
+            assert!(v1.is_small(), "DupX2 expects top value to be small!");
+            let v2 = stack.pop_unknown();
+            if v2.is_small(){
+                let v3 = stack.pop_unknown();
+                assert!(v3.is_small(), "DupX2 expects the third value to be small!");
                 stack.push(v1);
-                stack.push(v2);
+                stack.push(v3)
+            }
+            else{
                 stack.push(v1);
             }
-            RawInstruction::DupX2 => {
-                let v1 = stack.pop_unknown();
-                assert!(v1.is_small(), "DupX2 expects top value to be small!");
+            stack.push(v2);
+            stack.push(v1);
+        }
+        RawInstruction::Dup2 => {
+            let v1 = stack.pop_unknown();
+            if v1.is_small(){
                 let v2 = stack.pop_unknown();
-                if v2.is_small(){
-                    let v3 = stack.pop_unknown();
-                    assert!(v3.is_small(), "DupX2 expects the third value to be small!");
-                    stack.push(v1);
-                    stack.push(v3)
-                }
-                else{
-                    stack.push(v1);
-                }
+                assert!(v2.is_small(), "Dup2 expects second value to be small!");
                 stack.push(v2);
                 stack.push(v1);
+                stack.push(v2)
             }
-            RawInstruction::Dup2 => {
-                let v1 = stack.pop_unknown();
-                if v1.is_small(){
-                    let v2 = stack.pop_unknown();
-                    assert!(v2.is_small(), "Dup2 expects second value to be small!");
-                    stack.push(v2);
-                    stack.push(v1);
-                    stack.push(v2)
-                }
-                else{
-                    stack.push(v1);
-                }
+            else{
                 stack.push(v1);
-            },
-            RawInstruction::Dup2X1 => {
-                let v1 = stack.pop_unknown();
-                let v2 = stack.pop_unknown();
-                assert!(v2.is_small(), "Dup2X1 expects second value to be small");
-                if v1.is_small(){
+            }
+            stack.push(v1);
+        },
+        RawInstruction::Dup2X1 => {
+            let v1 = stack.pop_unknown();
+            let v2 = stack.pop_unknown();
+            assert!(v2.is_small(), "Dup2X1 expects second value to be small");
+            if v1.is_small(){
+                let v3 = stack.pop_unknown();
+                assert!(v3.is_small(), "Dup2X1 expects third value to be small");
+                stack.push(v2);
+                stack.push(v1);
+                stack.push(v3);
+            }
+            else {
+                stack.push(v1);
+            }
+            stack.push(v2);
+            stack.push(v1);
+        }
+        RawInstruction::Dup2X2 => {
+            let v1 = stack.pop_unknown();
+            let v2 = stack.pop_unknown();
+            if v1.is_big(){
+                if v2.is_small(){
                     let v3 = stack.pop_unknown();
-                    assert!(v3.is_small(), "Dup2X1 expects third value to be small");
-                    stack.push(v2);
+                    assert!(v3.is_small());
+
                     stack.push(v1);
                     stack.push(v3);
                 }
@@ -232,234 +441,248 @@ pub fn generate_stack(block: &[(Index, RawInstruction)], cp: &ConstantPool, inpu
                 stack.push(v2);
                 stack.push(v1);
             }
-            RawInstruction::Dup2X2 => {
-                let v1 = stack.pop_unknown();
-                let v2 = stack.pop_unknown();
-                if v1.is_big(){
-                    if v2.is_small(){
-                        let v3 = stack.pop_unknown();
-                        assert!(v3.is_small());
-
-                        stack.push(v1);
-                        stack.push(v3);
-                    }
-                    else {
-                        stack.push(v1);
-                    }
+            else{
+                assert!(v2.is_small());
+                let v3 = stack.pop_unknown();
+                if v3.is_small(){
+                    let v4 = stack.pop_unknown();
+                    assert!(v4.is_small());
                     stack.push(v2);
                     stack.push(v1);
+                    stack.push(v4);
+                    
                 }
                 else{
-                    assert!(v2.is_small());
-                    let v3 = stack.pop_unknown();
-                    if v3.is_small(){
-                        let v4 = stack.pop_unknown();
-                        assert!(v4.is_small());
-                        stack.push(v2);
-                        stack.push(v1);
-                        stack.push(v4);
-                        
-                    }
-                    else{
-                        stack.push(v2);
-                        stack.push(v1);
-                    }
-                    stack.push(v3);
                     stack.push(v2);
                     stack.push(v1);
                 }
+                stack.push(v3);
+                stack.push(v2);
+                stack.push(v1);
             }
-            RawInstruction::F2D => stack.convert(Value::F32, Value::F64),
-            RawInstruction::F2I => stack.convert(Value::F32, Value::I32),
-            RawInstruction::F2L => stack.convert(Value::F32, Value::I64),
-            RawInstruction::FAdd |
-            RawInstruction::FDiv |
-            RawInstruction::FMul |
-            RawInstruction::FSub |
-            RawInstruction::FRem => stack.bin_op(Value::F32),
-            RawInstruction::FCmpG |
-            RawInstruction::FCmpL => {stack.pop_known(Value::F32); stack.pop_known(Value::F32); stack.push(Value::I32); }
-            RawInstruction::FConst0 |
-            RawInstruction::FConst1 |
-            RawInstruction::FConst2 |
-            RawInstruction::FLoad { .. } |
-            RawInstruction::FLoadW { .. } |
-            RawInstruction::FLoad0 |
-            RawInstruction::FLoad1 |
-            RawInstruction::FLoad2 |
-            RawInstruction::FReturn |
-            RawInstruction::FLoad3 => stack.push(Value::F32),
-            RawInstruction::FNeg => stack.convert(Value::F32, Value::F32),
-            RawInstruction::FStore { .. } |
-            RawInstruction::FStoreW { .. } |
-            RawInstruction::FStore0 |
-            RawInstruction::FStore1 |
-            RawInstruction::FStore2 |
-            RawInstruction::FStore3 => {stack.pop_known(Value::F32);},
-            RawInstruction::GetField { index } =>{
-                stack.pop_known(Value::Ref);
-                let fr = cp.get(*index).unwrap();
-                let nt = cp.get(fr.name_and_type).unwrap();
-                let utf8 = cp.get(nt.descriptor).unwrap();
-                let descriptor = utf8.content.to_str().unwrap();
-                match descriptor.chars().next() {
-                    Some(BYTE | BOOL | SHORT | CHAR | INT) => stack.push(Value::I32),
-                    Some(FLOAT) => stack.push(Value::F32),
-                    Some(DOUBLE) => stack.push(Value::F64),
-                    Some(LONG) => stack.push(Value::I64),
-                    Some(REF | ARR) => stack.push(Value::Ref),
-
-                    x => panic!("Invalid descriptor char {:?}", x)
-                }
+        }
+        RawInstruction::FAdd |
+        RawInstruction::FDiv |
+        RawInstruction::FMul |
+        RawInstruction::FSub |
+        RawInstruction::FRem => stack.bin_op(Value::F32),
+        RawInstruction::FCmpG |
+        RawInstruction::FCmpL => {stack.pop_known(Value::F32); stack.pop_known(Value::F32); stack.push(Value::I32); }
+        RawInstruction::FConst0 |
+        RawInstruction::FConst1 |
+        RawInstruction::FConst2 |
+        RawInstruction::FLoad { .. } |
+        RawInstruction::FLoadW { .. } |
+        RawInstruction::FLoad0 |
+        RawInstruction::FLoad1 |
+        RawInstruction::FLoad2 |
+        RawInstruction::FLoad3 => stack.push(Value::F32),
+        RawInstruction::FNeg => stack.convert(Value::F32, Value::F32),
+        RawInstruction::FStore { .. } |
+        RawInstruction::FStoreW { .. } |
+        RawInstruction::FStore0 |
+        RawInstruction::FStore1 |
+        RawInstruction::FStore2 |
+        RawInstruction::FStore3 |
+        RawInstruction::FReturn => {stack.pop_known(Value::F32);},
+        RawInstruction::GetField { index } =>{
+            stack.pop_known(Value::Ref);
+            let fr = cp.get(*index).unwrap();
+            let nt = cp.get(fr.name_and_type).unwrap();
+            let utf8 = cp.get(nt.descriptor).unwrap();
+            stack.push(Value::of_descriptor(utf8.content.as_bytes()));
+        }
+        RawInstruction::GetStatic { index } => {
+            let fr = cp.get(*index).unwrap();
+            let nt = cp.get(fr.name_and_type).unwrap();
+            let utf8 = cp.get(nt.descriptor).unwrap();
+            stack.push(Value::of_descriptor(utf8.content.as_bytes()));
+        },
+        RawInstruction::Goto { .. } => (),
+        RawInstruction::GotoW { .. } => (),
+        RawInstruction::IAnd |
+        RawInstruction::IDiv |
+        RawInstruction::IMul |
+        RawInstruction::IOr |
+        RawInstruction::IRem |
+        RawInstruction::IAdd => stack.bin_op(Value::I32),
+        RawInstruction::IConstM1 |
+        RawInstruction::IConst0 |
+        RawInstruction::IConst1 |
+        RawInstruction::IConst2 |
+        RawInstruction::IConst3 |
+        RawInstruction::IConst4 |
+        RawInstruction::ILoadW { .. } |
+        RawInstruction::ILoad { .. } |
+        RawInstruction::ILoad0 |
+        RawInstruction::ILoad1 |
+        RawInstruction::ILoad2 |
+        RawInstruction::ILoad3 |
+        RawInstruction::IConst5 => stack.push(Value::I32),
+        RawInstruction::IfACmpNe { .. } |
+        RawInstruction::IfACmpEq { .. } => {stack.pop_known(Value::Ref); stack.pop_known(Value::Ref);},
+        RawInstruction::IfICmpEq { .. } |
+        RawInstruction::IfICmpNe { .. } |
+        RawInstruction::IfICmpLt { .. } |
+        RawInstruction::IfICmpGe { .. } |
+        RawInstruction::IfICmpGt { .. } |
+        RawInstruction::IfICmpLe { .. } => {stack.pop_known(Value::I32); stack.pop_known(Value::I32);},
+        RawInstruction::IfEq { .. } |
+        RawInstruction::IfNe { .. } |
+        RawInstruction::IfLt { .. } |
+        RawInstruction::IfGe { .. } |
+        RawInstruction::IfGt { .. } |
+        RawInstruction::IfLe { .. } => {stack.pop_known(Value::I32);},
+        RawInstruction::IfNull { .. } => {stack.pop_known(Value::Ref);}
+        RawInstruction::IfNonNull { .. } => {stack.pop_known(Value::Ref);},
+        RawInstruction::IInc { .. } => (),
+        RawInstruction::IIncW { .. } => (),
+        RawInstruction::INeg => stack.convert(Value::I32, Value::I32),
+        RawInstruction::InstanceOf { .. } => stack.convert(Value::Ref, Value::I32),
+        RawInstruction::InvokeDynamic { index } => {
+            let id = cp.get(*index)?;
+            let nt = cp.get(id.name_and_type)?;
+            stack.invoke(cp.get(nt.descriptor)?.content.as_bytes(), false)?;
+        }
+        RawInstruction::InvokeInterface { index, .. } => stack.invoke(method_descriptor(cp, *index)?, true)?,
+        RawInstruction::InvokeSpecial { index } => stack.invoke(method_descriptor(cp, *index)?, true)?,
+        RawInstruction::InvokeStatic { index } => stack.invoke(method_descriptor(cp, *index)?, false)?,
+        RawInstruction::InvokeVirtual { index } => stack.invoke(method_descriptor(cp, *index)?, true)?,
+        RawInstruction::IReturn => {stack.pop_known(Value::I32);}
+        RawInstruction::IStore { .. } |
+        RawInstruction::IStoreW { .. } |
+        RawInstruction::IStore0 |
+        RawInstruction::IStore1 |
+        RawInstruction::IStore2 |
+        RawInstruction::IStore3 => {stack.pop_known(Value::I32);}
+        RawInstruction::IShL |
+        RawInstruction::IShR |
+        RawInstruction::ISub |
+        RawInstruction::IUShR |
+        RawInstruction::IXor => stack.bin_op(Value::I32),
+        RawInstruction::JSr { .. } => bail!("{:?} is not supported yet", instruction),
+        RawInstruction::JSrW { .. } => bail!("{:?} is not supported yet", instruction),
+        RawInstruction::LAdd |
+        RawInstruction::LAnd => stack.bin_op(Value::I64),
+        RawInstruction::LCmp => {stack.pop_known(Value::I64); stack.pop_known(Value::I64); stack.push(Value::I32)},
+        RawInstruction::LConst0 |
+        RawInstruction::LConst1 => stack.push(Value::I64),
+        RawInstruction::LdC { index } |
+        RawInstruction::LdCW { index } |
+        RawInstruction::LdC2W { index } => {
+            match cp.get(*index).unwrap() {
+                Item::Integer(_) => stack.push(Value::I32),
+                Item::Float(_) => stack.push(Value::F32),
+                Item::Long(_) => stack.push(Value::I64),
+                Item::Double(_) => stack.push(Value::F64),
+                Item::String(_) |
+                Item::Class(_) |
+                Item::MethodType(_) |
+                Item::MethodHandle(_) => stack.push(Value::Ref),
+                x => panic!("Invalid constant {:?}", x)
             }
-            RawInstruction::GetStatic { index } => {
-                let fr = cp.get(*index).unwrap();
-                let nt = cp.get(fr.name_and_type).unwrap();
-                let utf8 = cp.get(nt.descriptor).unwrap();
-                let descriptor = utf8.content.to_str().unwrap();
-                match descriptor.chars().next() {
-                    Some(BYTE | BOOL | SHORT | CHAR | INT) => stack.push(Value::I32),
-                    Some(FLOAT) => stack.push(Value::F32),
-                    Some(DOUBLE) => stack.push(Value::F64),
-                    Some(LONG) => stack.push(Value::I64),
-                    Some(REF | ARR) => stack.push(Value::Ref),
-
-                    x => panic!("Invalid descriptor char {:?}", x)
-                }
-            },
-            RawInstruction::Goto { offset } => (),
-            RawInstruction::GotoW { offset } => (),
-            RawInstruction::I2S |
-            RawInstruction::I2C |
-            RawInstruction::I2B => stack.convert(Value::I32, Value::I32),
-            RawInstruction::I2D => stack.convert(Value::I32, Value::F32),
-            RawInstruction::I2F => stack.convert(Value::I32, Value::F32),
-            RawInstruction::I2L => stack.convert(Value::I32, Value::I64),
-            RawInstruction::IAnd |
-            RawInstruction::IDiv |
-            RawInstruction::IMul |
-            RawInstruction::IOr |
-            RawInstruction::IRem |
-            RawInstruction::IAdd => stack.bin_op(Value::I32),
-            RawInstruction::IConstM1 |
-            RawInstruction::IConst0 |
-            RawInstruction::IConst1 |
-            RawInstruction::IConst2 |
-            RawInstruction::IConst3 |
-            RawInstruction::IConst4 |
-            RawInstruction::ILoadW { .. } |
-            RawInstruction::ILoad { .. } |
-            RawInstruction::ILoad0 |
-            RawInstruction::ILoad1 |
-            RawInstruction::ILoad2 |
-            RawInstruction::ILoad3 |
-            RawInstruction::IConst5 => stack.push(Value::I32),
-            RawInstruction::IfACmpNe { offset } |
-            RawInstruction::IfACmpEq { offset } => {stack.pop_known(Value::Ref); stack.pop_known(Value::Ref);},
-            RawInstruction::IfICmpEq { offset } |
-            RawInstruction::IfICmpNe { offset } |
-            RawInstruction::IfICmpLt { offset } |
-            RawInstruction::IfICmpGe { offset } |
-            RawInstruction::IfICmpGt { offset } |
-            RawInstruction::IfICmpLe { offset } => {stack.pop_known(Value::I32); stack.pop_known(Value::I32);},
-            RawInstruction::IfEq { offset } |
-            RawInstruction::IfNe { offset } |
-            RawInstruction::IfLt { offset } |
-            RawInstruction::IfGe { offset } |
-            RawInstruction::IfGt { offset } |
-            RawInstruction::IfLe { offset } => {stack.pop_known(Value::I32);},
-            RawInstruction::IfNull { offset } => {stack.pop_known(Value::Ref);}
-            RawInstruction::IfNonNull { offset } => {stack.pop_known(Value::Ref);},
-            RawInstruction::IInc { index, value } => (),
-            RawInstruction::IIncW { index, value } => (),
-            RawInstruction::INeg => stack.convert(Value::I32, Value::I32),
-            RawInstruction::InstanceOf { index } => stack.convert(Value::Ref, Value::I32),
-            RawInstruction::InvokeDynamic { index } => {
-                let id = cp.get(*index).unwrap();
-                let nt = cp.get(id.name_and_type).unwrap();
-                let d = cp.get(nt.descriptor);
+        }
+        RawInstruction::LDiv |
+        RawInstruction::LMul |
+        RawInstruction::LOr |
+        RawInstruction::LRem |
+        RawInstruction::LSub |
+        RawInstruction::LXor => stack.bin_op(Value::I64),
+        RawInstruction::LLoad { .. } |
+        RawInstruction::LLoadW { .. } |
+        RawInstruction::LLoad0 |
+        RawInstruction::LLoad1 |
+        RawInstruction::LLoad2 |
+        RawInstruction::LLoad3 => stack.push(Value::I64),
+        RawInstruction::LNeg => stack.convert(Value::I64, Value::I64),
+        RawInstruction::LookupSwitch(_) => {stack.pop_known(Value::I32);}
+        RawInstruction::LStore { .. } |
+        RawInstruction::LStoreW { .. } |
+        RawInstruction::LStore0 |
+        RawInstruction::LStore1 |
+        RawInstruction::LStore2 |
+        RawInstruction::LStore3 |
+        RawInstruction::LReturn => {stack.pop_known(Value::I64);}
+        // The shift count is an int.
+        RawInstruction::LShL |
+        RawInstruction::LShR |
+        RawInstruction::LUShR => {stack.pop_known(Value::I32); stack.convert(Value::I64, Value::I64);}
+        RawInstruction::MonitorEnter |
+        RawInstruction::MonitorExit => {stack.pop_known(Value::Ref);},
+        RawInstruction::MultiANewArray { dimensions, .. } => {
+            for _ in 0..*dimensions{
+                stack.pop_known(Value::I32);
+            }
+            stack.push(Value::Ref);
+        }
+        RawInstruction::New { .. } => stack.push(Value::Ref),
+        RawInstruction::NewArray { .. } => stack.convert(Value::I32, Value::Ref),
+        RawInstruction::Nop => (),
+        RawInstruction::Pop => {
+            let v = stack.pop_unknown();
+            assert!(v.is_small(), "Pop only works with short values!");
+        }
+        RawInstruction::Pop2 => {
+            let v = stack.pop_unknown();
+            if v.is_small(){
+                let v2 = stack.pop_unknown();
+                assert!(v2.is_small(), "Pop2 expects second value to be small!");
             }
-            RawInstruction::InvokeInterface { index, count } => todo!(),
-            RawInstruction::InvokeSpecial { index } => todo!(),
-            RawInstruction::InvokeStatic { index } => todo!(),
-            RawInstruction::InvokeVirtual { index } => todo!(),
-            RawInstruction::IReturn => todo!(),
-            RawInstruction::IShL => todo!(),
-            RawInstruction::IShR => todo!(),
-            RawInstruction::IStore { index } => todo!(),
-            RawInstruction::IStoreW { index } => todo!(),
-            RawInstruction::IStore0 => todo!(),
-            RawInstruction::IStore1 => todo!(),
-            RawInstruction::IStore2 => todo!(),
-            RawInstruction::IStore3 => todo!(),
-            RawInstruction::ISub => todo!(),
-            RawInstruction::IUShR => todo!(),
-            RawInstruction::IXor => todo!(),
-            RawInstruction::JSr { offset } => todo!(),
-            RawInstruction::JSrW { offset } => todo!(),
-            RawInstruction::L2D => todo!(),
-            RawInstruction::L2F => todo!(),
-            RawInstruction::L2I => todo!(),
-            RawInstruction::LAdd => todo!(),
-            RawInstruction::LAnd => todo!(),
-            RawInstruction::LCmp => todo!(),
-            RawInstruction::LConst0 => todo!(),
-            RawInstruction::LConst1 => todo!(),
-            RawInstruction::LdC { index } => todo!(),
-            RawInstruction::LdCW { index } => todo!(),
-            RawInstruction::LdC2W { index } => todo!(),
-            RawInstruction::LDiv => todo!(),
-            RawInstruction::LLoad { index } => todo!(),
-            RawInstruction::LLoadW { index } => todo!(),
-            RawInstruction::LLoad0 => todo!(),
-            RawInstruction::LLoad1 => todo!(),
-            RawInstruction::LLoad2 => todo!(),
-            RawInstruction::LLoad3 => todo!(),
-            RawInstruction::LMul => todo!(),
-            RawInstruction::LNeg => todo!(),
-            RawInstruction::LookupSwitch(_) => todo!(),
-            RawInstruction::LOr => todo!(),
-            RawInstruction::LRem => todo!(),
-            RawInstruction::LReturn => todo!(),
-            RawInstruction::LShL => todo!(),
-            RawInstruction::LShR => todo!(),
-            RawInstruction::LStore { index } => todo!(),
-            RawInstruction::LStoreW { index } => todo!(),
-            RawInstruction::LStore0 => todo!(),
-            RawInstruction::LStore1 => todo!(),
-            RawInstruction::LStore2 => todo!(),
-            RawInstruction::LStore3 => todo!(),
-            RawInstruction::LSub => todo!(),
-            RawInstruction::LUShR => todo!(),
-            RawInstruction::LXor => todo!(),
-            RawInstruction::MonitorEnter => todo!(),
-            RawInstruction::MonitorExit => todo!(),
-            RawInstruction::MultiANewArray { index, dimensions } => todo!(),
-            RawInstruction::New { index } => todo!(),
-            RawInstruction::NewArray { atype } => todo!(),
-            RawInstruction::Nop => todo!(),
-            RawInstruction::Pop => todo!(),
-            RawInstruction::Pop2 => todo!(),
-            RawInstruction::PutField { index } => todo!(),
-            RawInstruction::PutStatic { index } => todo!(),
-            RawInstruction::Ret { index } => todo!(),
-            RawInstruction::RetW { index } => todo!(),
-            RawInstruction::Return => todo!(),
-            RawInstruction::SALoad => todo!(),
-            RawInstruction::SAStore => todo!(),
-            RawInstruction::SIPush { value } => todo!(),
-            RawInstruction::Swap => todo!(),
-            RawInstruction::TableSwitch(_) => todo!(),
+        }
+        RawInstruction::PutField { index } => {
+            let fr = cp.get(*index).unwrap();
+            let nt = cp.get(fr.name_and_type).unwrap();
+            let utf8 = cp.get(nt.descriptor).unwrap();
+            stack.pop_known(Value::of_descriptor(utf8.content.as_bytes()));
+            stack.pop_known(Value::Ref);
+        }
+        RawInstruction::PutStatic { index } => {
+            let fr = cp.get(*index).unwrap();
+            let nt = cp.get(fr.name_and_type).unwrap();
+            let utf8 = cp.get(nt.descriptor).unwrap();
+            stack.pop_known(Value::of_descriptor(utf8.content.as_bytes()));
+        }
+        RawInstruction::Ret { .. } => bail!("{:?} is not supported yet", instruction),
+        RawInstruction::RetW { .. } => bail!("{:?} is not supported yet", instruction),
+        RawInstruction::Return => (),
+        RawInstruction::SALoad => stack.array_load(Value::I32),
+        RawInstruction::SAStore => stack.array_store(Value::I32),
+        RawInstruction::SIPush { .. } => stack.push(Value::I32),
+        RawInstruction::Swap => {
+            let v1 = stack.pop_unknown();
+            let v2 = stack.pop_unknown();
+            assert!(v1.is_small() && v2.is_small(), "Swap only works with short values!");
+            stack.push(v1);
+            stack.push(v2);
+        }
+        RawInstruction::TableSwitch(_) => {stack.pop_known(Value::I32);}
+        RawInstruction::I2L |
+        RawInstruction::I2F |
+        RawInstruction::I2D |
+        RawInstruction::I2B |
+        RawInstruction::I2C |
+        RawInstruction::I2S |
+        RawInstruction::L2I |
+        RawInstruction::L2F |
+        RawInstruction::L2D |
+        RawInstruction::F2I |
+        RawInstruction::F2L |
+        RawInstruction::F2D |
+        RawInstruction::D2I |
+        RawInstruction::D2L |
+        RawInstruction::D2F => {
+            let (from, to) = conversion(instruction).expect("Conversions are in the table");
+            stack.convert(from, to);
         }
     }
 
+    Ok(())
+}
 
-    stack
-} 
-
-fn parse_method_descriptor(descriptor: &str) -> (Vec<char>, Option<char>){
-    let mut iterator = descriptor.chars().peekable();
-    assert_eq!(iterator.next(), Some('('));
-
-    todo!()
-}
\ No newline at end of file
+fn method_descriptor<'a, I>(cp: &ConstantPool<'a>, index: cpool::Index<I>) -> anyhow::Result<&'a [u8]>{
+    let (cpool::Item::MethodRef(cpool::MethodRef { name_and_type, .. }) |
+        cpool::Item::InterfaceMethodRef(cpool::InterfaceMethodRef { name_and_type, .. })) = cp.get(cpool::Index::<Item>::new(index.as_u16())?)? else {
+        bail!("Expected a method reference");
+    };
+    Ok(cp.get(cp.get(*name_and_type)?.descriptor)?.content.as_bytes())
+}
@@ -1,5 +1,195 @@
+use noak::{AccessFlags, reader::{attributes::Code, cpool::{self, ConstantPool, Item}, AttributeContent, AttributeIter, Class}};
+
+use crate::strings::decode_mutf8;
+
+#[derive(Clone)]
 pub struct ParsedClass{
-    name: Box<[u8]>,
-    inherited: Option<Box<[u8]>>,
-    interfaces: Vec<Box<[u8]>>,   
-}
\ No newline at end of file
+    pub name: Box<[u8]>,
+    pub flags: AccessFlags,
+    pub inherited: Option<Box<[u8]>>,
+    pub interfaces: Vec<Box<[u8]>>,
+    pub fields: Vec<ParsedField>,
+    pub methods: Vec<ParsedMethod>,
+    /// Entries of the `BootstrapMethods` attribute, indexed by `invokedynamic` constants.
+    pub bootstrap_methods: Vec<BootstrapMethod>,
+    /// Name of the file the class was compiled from, from the `SourceFile` attribute.
+    pub source_file: Option<Box<[u8]>>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct ParsedField{
+    pub name: Box<[u8]>,
+    pub descriptor: Box<[u8]>,
+    pub flags: AccessFlags,
+    /// Value of the `ConstantValue` attribute, only used for static fields.
+    pub constant: Option<Constant>,
+}
+
+#[derive(Clone)]
+pub struct ParsedMethod{
+    pub name: Box<[u8]>,
+    pub descriptor: Box<[u8]>,
+    pub flags: AccessFlags,
+}
+
+/// A bootstrap method and its static arguments, as constant pool indices.
+#[derive(Clone)]
+pub struct BootstrapMethod{
+    pub method: u16,
+    pub arguments: Vec<u16>,
+}
+
+/// A loadable constant that is known at translation time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant{
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(Box<[u16]>),
+}
+
+impl Constant{
+    /// Reads a constant from the pool, `None` if it isn't a plain value.
+    pub fn read(pool: &ConstantPool, index: cpool::Index<Item>) -> anyhow::Result<Option<Self>>{
+        Ok(match pool.get(index)? {
+            Item::Integer(i) => Some(Self::Int(i.value)),
+            Item::Long(l) => Some(Self::Long(l.value)),
+            Item::Float(f) => Some(Self::Float(f.value)),
+            Item::Double(d) => Some(Self::Double(d.value)),
+            Item::String(s) => Some(Self::String(decode_mutf8(pool.get(s.string)?.content.as_bytes())?.into_boxed_slice())),
+            _ => None
+        })
+    }
+}
+
+impl ParsedClass{
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self>{
+        Self::read(&mut Class::new(bytes)?, bytes)
+    }
+
+    pub fn read(class: &mut Class, bytes: &[u8]) -> anyhow::Result<Self>{
+        let flags = class.access_flags()?;
+        let this = class.this_class_index()?;
+        let super_class = class.super_class_index()?;
+        let interface_indices = class.interfaces()?.collect::<Result<Vec<_>, _>>()?;
+        let field_items = class.fields()?.collect::<Result<Vec<_>, _>>()?;
+        let method_items = class.methods()?.collect::<Result<Vec<_>, _>>()?;
+        let attributes = class.attributes()?;
+        let pool = class.pool()?;
+
+        let class_name = |index: cpool::Index<cpool::Class>| -> anyhow::Result<Box<[u8]>>{
+            let c = pool.get(index)?;
+            Ok(pool.get(c.name)?.content.as_bytes().into())
+        };
+        let utf8 = |index: cpool::Index<cpool::Utf8>| -> anyhow::Result<Box<[u8]>>{
+            Ok(pool.get(index)?.content.as_bytes().into())
+        };
+
+        let mut fields = Vec::with_capacity(field_items.len());
+        for field in field_items{
+            fields.push(ParsedField{
+                name: utf8(field.name())?,
+                descriptor: utf8(field.descriptor())?,
+                flags: field.access_flags(),
+                constant: constant_value(pool, field.attributes())?
+            });
+        }
+
+        let mut methods = Vec::with_capacity(method_items.len());
+        for method in method_items{
+            methods.push(ParsedMethod{
+                name: utf8(method.name())?,
+                descriptor: utf8(method.descriptor())?,
+                flags: method.access_flags()
+            });
+        }
+
+        Ok(Self{
+            name: class_name(this)?,
+            flags,
+            inherited: super_class.map(class_name).transpose()?,
+            interfaces: interface_indices.into_iter().map(class_name).collect::<Result<_, _>>()?,
+            fields,
+            methods,
+            bootstrap_methods: bootstrap_methods(pool, attributes.clone())?,
+            source_file: source_file(pool, attributes)?,
+            bytes: bytes.to_vec()
+        })
+    }
+
+    pub fn field(&self, name: &[u8]) -> Option<&ParsedField>{
+        self.fields.iter().find(|f| &*f.name == name)
+    }
+
+    pub fn method(&self, name: &[u8], descriptor: &[u8]) -> Option<&ParsedMethod>{
+        self.methods.iter().find(|m| &*m.name == name && &*m.descriptor == descriptor)
+    }
+
+    /// Runs `f` on the `Code` attribute of the method, `None` if the method has no code.
+    pub fn with_code<R>(&self, name: &[u8], descriptor: &[u8], f: impl FnOnce(&ConstantPool, &Code) -> anyhow::Result<R>) -> anyhow::Result<Option<R>>{
+        let mut class = Class::new(&self.bytes)?;
+        let methods = class.methods()?.collect::<Result<Vec<_>, _>>()?;
+        let pool = class.pool()?;
+
+        for method in methods{
+            if pool.get(method.name())?.content.as_bytes() != name || pool.get(method.descriptor())?.content.as_bytes() != descriptor{
+                continue;
+            }
+            for attribute in method.attributes(){
+                let attribute = attribute?;
+                if pool.get(attribute.name())?.content.as_bytes() == b"Code"{
+                    if let AttributeContent::Code(code) = attribute.read_content(pool)?{
+                        return f(pool, &code).map(Some);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn constant_value(pool: &ConstantPool, attributes: AttributeIter) -> anyhow::Result<Option<Constant>>{
+    for attribute in attributes{
+        let attribute = attribute?;
+        if pool.get(attribute.name())?.content.as_bytes() == b"ConstantValue"{
+            if let AttributeContent::ConstantValue(value) = attribute.read_content(pool)?{
+                return Constant::read(pool, value.value());
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn source_file(pool: &ConstantPool, attributes: AttributeIter) -> anyhow::Result<Option<Box<[u8]>>>{
+    for attribute in attributes{
+        let attribute = attribute?;
+        if pool.get(attribute.name())?.content.as_bytes() == b"SourceFile"{
+            if let AttributeContent::SourceFile(file) = attribute.read_content(pool)?{
+                return Ok(Some(pool.get(file.source_file())?.content.as_bytes().into()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn bootstrap_methods(pool: &ConstantPool, attributes: AttributeIter) -> anyhow::Result<Vec<BootstrapMethod>>{
+    for attribute in attributes{
+        let attribute = attribute?;
+        if pool.get(attribute.name())?.content.as_bytes() == b"BootstrapMethods"{
+            if let AttributeContent::BootstrapMethods(methods) = attribute.read_content(pool)?{
+                let mut read = Vec::new();
+                for method in methods.iter(){
+                    let method = method?;
+                    read.push(BootstrapMethod{
+                        method: method.method_ref().as_u16(),
+                        arguments: method.arguments().iter().map(|a| a.map(|a| a.as_u16())).collect::<Result<_, _>>()?
+                    });
+                }
+                return Ok(read);
+            }
+        }
+    }
+    Ok(Vec::new())
+}
@@ -0,0 +1,175 @@
+//! Debug information carried from the class files into the module.
+//!
+//! Functions and their Java locals are named in the `name` section, from the
+//! method keys and the `LocalVariableTable`s. The lowering remembers where the
+//! code of each bytecode instruction starts, which the `LineNumberTable`s turn
+//! into lines of the file named by the class' `SourceFile`; a source map
+//! relates them to offsets in the module, which is how browsers map code in
+//! WebAssembly back to its source.
+
+use noak::reader::{attributes::Code, cpool::ConstantPool, AttributeContent};
+use anyhow::bail;
+use serde_json::json;
+
+use crate::{code::Value, data::ParsedClass};
+
+/// An entry of the `LocalVariableTable`.
+pub struct Variable{
+    /// Bytecode range the variable is live in.
+    pub start: u32,
+    pub end: u32,
+    pub slot: u16,
+    pub name: String,
+    pub value: Value,
+}
+
+/// The debugging attributes of a method's code.
+#[derive(Default)]
+pub struct MethodDebug{
+    /// Bytecode offsets at which lines start, sorted by offset.
+    pub lines: Vec<(u32, u32)>,
+    pub variables: Vec<Variable>,
+}
+
+impl MethodDebug{
+    pub fn read(code: &Code, cp: &ConstantPool) -> anyhow::Result<Self>{
+        let mut debug = Self::default();
+        for attribute in code.attributes(){
+            let attribute = attribute?;
+            // Only these are decoded, noak can't read every other attribute.
+            if !matches!(cp.get(attribute.name())?.content.as_bytes(), b"LineNumberTable" | b"LocalVariableTable"){
+                continue;
+            }
+            match attribute.read_content(cp)? {
+                // noak decodes the start of each line as a `u32`, so the
+                // table is read from the attribute's bytes instead.
+                AttributeContent::LineNumberTable(_) => {
+                    let content = attribute.content();
+                    let u16_at = |at: usize| content.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+                    let Some(count) = u16_at(0) else {
+                        bail!("Truncated LineNumberTable");
+                    };
+                    for i in 0..count as usize{
+                        let (Some(start), Some(line)) = (u16_at(2 + i * 4), u16_at(4 + i * 4)) else {
+                            bail!("Truncated LineNumberTable");
+                        };
+                        debug.lines.push((start, line));
+                    }
+                }
+                AttributeContent::LocalVariableTable(table) => for variable in table.iter(){
+                    let variable = variable?;
+                    let range = variable.range();
+                    debug.variables.push(Variable{
+                        start: range.start.as_u32(),
+                        end: range.end.as_u32(),
+                        slot: variable.index(),
+                        name: String::from_utf8_lossy(cp.get(variable.name())?.content.as_bytes()).into_owned(),
+                        value: Value::of_descriptor(cp.get(variable.descriptor())?.content.as_bytes())
+                    });
+                }
+                _ => ()
+            }
+        }
+        debug.lines.sort();
+        Ok(debug)
+    }
+
+    /// The line the instruction at `offset` is on.
+    pub fn line(&self, offset: u32) -> Option<u32>{
+        let index = self.lines.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|i| self.lines[i].1)
+    }
+
+    /// Names of the variables in the slot with values of the type, joined
+    /// with `|` if the slot is reused.
+    pub fn variable_name(&self, slot: u16, value: Value) -> Option<String>{
+        let mut names = Vec::new();
+        for variable in self.variables.iter().filter(|v| v.slot == slot && v.value == value){
+            if !names.contains(&variable.name.as_str()){
+                names.push(variable.name.as_str());
+            }
+        }
+        (!names.is_empty()).then(|| names.join("|"))
+    }
+}
+
+/// Path of the class' source file relative to the source root, like
+/// `java/lang/String.java`.
+pub fn source_path(class: &ParsedClass) -> Option<String>{
+    let file = String::from_utf8_lossy(class.source_file.as_deref()?).into_owned();
+    let name = String::from_utf8_lossy(&class.name);
+    Some(match name.rsplit_once('/') {
+        Some((package, _)) => format!("{}/{}", package, file),
+        None => file
+    })
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Appends a number in the base 64 VLQ encoding of source maps.
+fn vlq(value: i64, out: &mut String){
+    let mut rest = if value < 0 {((-value) << 1) | 1} else {value << 1};
+    loop {
+        let mut digit = (rest & 0x1F) as usize;
+        rest >>= 5;
+        if rest != 0{
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if rest == 0{
+            break;
+        }
+    }
+}
+
+/// A source map of a module. The whole module is the first and only
+/// generated line, columns are offsets from the start of the module.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap{
+    pub sources: Vec<String>,
+    /// Module offset, index into `sources` and line, sorted by offset.
+    pub mappings: Vec<(u32, u32, u32)>,
+}
+
+impl SourceMap{
+    /// Index of the source, added if it isn't there yet.
+    pub fn source(&mut self, path: &str) -> u32{
+        match self.sources.iter().position(|s| s == path) {
+            Some(index) => index as u32,
+            None => {
+                self.sources.push(path.into());
+                self.sources.len() as u32 - 1
+            }
+        }
+    }
+
+    /// The source and line of the code at the module offset.
+    pub fn lookup(&self, offset: u32) -> Option<(&str, u32)>{
+        let index = self.mappings.partition_point(|(start, _, _)| *start <= offset).checked_sub(1)?;
+        let (_, source, line) = self.mappings[index];
+        Some((&self.sources[source as usize], line))
+    }
+
+    /// The source map in its JSON format, version 3.
+    pub fn json(&self) -> serde_json::Value{
+        let mut mappings = String::new();
+        let (mut column, mut source, mut line) = (0, 0, 0);
+        for (i, (offset, s, l)) in self.mappings.iter().enumerate(){
+            if i > 0{
+                mappings.push(',');
+            }
+            // Fields are relative to the previous segment, lines are 0-based.
+            vlq(*offset as i64 - column, &mut mappings);
+            vlq(*s as i64 - source, &mut mappings);
+            vlq(*l as i64 - 1 - line, &mut mappings);
+            vlq(0, &mut mappings);
+            (column, source, line) = (*offset as i64, *s as i64, *l as i64 - 1);
+        }
+        json!({
+            "version": 3,
+            "sources": self.sources,
+            "names": [],
+            "mappings": mappings,
+        })
+    }
+}
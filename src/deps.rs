@@ -0,0 +1,332 @@
+//! Class-to-class dependencies.
+//!
+//! The crawler follows every class a class refers to; the references are kept
+//! here with their kind, so the graph can be exported and its strongly
+//! connected components listed. A component of more than one class is a
+//! cycle, and if more than one of its classes has a `<clinit>`, the order
+//! they are initialized in depends on which one is touched first.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use noak::reader::{attributes::RawInstruction, cpool::Item, Class};
+use serde_json::json;
+
+use crate::{data::ParsedClass, descriptor::{descriptor_parser, JavaType}, init::{CLINIT, CLINIT_DESCRIPTOR}};
+
+/// How a class refers to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind{
+    /// `extends` or `implements`.
+    Superclass,
+    /// The type of a field that is declared or accessed.
+    FieldType,
+    /// A parameter or return type of a method that is declared, invoked or
+    /// bootstrapped.
+    MethodSignature,
+    /// `new`.
+    Instantiation,
+    /// Any other class constant: owners of accessed members, casts, class
+    /// literals and array creation.
+    Reference,
+}
+
+impl Kind{
+    pub fn name(self) -> &'static str{
+        match self {
+            Kind::Superclass => "superclass",
+            Kind::FieldType => "field type",
+            Kind::MethodSignature => "method signature",
+            Kind::Instantiation => "instantiation",
+            Kind::Reference => "reference",
+        }
+    }
+}
+
+/// A referenced class and how it's referenced.
+pub type Dependency = (Box<[u8]>, Kind);
+
+/// Kinds of references to each class.
+type Edges = BTreeMap<Box<[u8]>, BTreeSet<Kind>>;
+
+/// The class named by a class constant, which is a descriptor for arrays.
+/// `None` for arrays of primitives.
+fn element_class(name: &[u8]) -> anyhow::Result<Option<Box<[u8]>>>{
+    if !name.starts_with(b"["){
+        return Ok(Some(name.into()));
+    }
+    Ok(match descriptor_parser::field(name)? {
+        JavaType::Reference(_, n) => Some(n),
+        _ => None
+    })
+}
+
+fn descriptor_classes(descriptor: &[u8], kind: Kind, out: &mut Vec<Dependency>) -> anyhow::Result<()>{
+    for type_ in descriptor_parser::class_names(descriptor)?{
+        if let JavaType::Reference(_, n) = type_ {
+            out.push((n, kind));
+        }
+    }
+    Ok(())
+}
+
+fn instantiations(class: &ParsedClass, out: &mut Vec<Dependency>) -> anyhow::Result<()>{
+    for method in class.methods.iter(){
+        class.with_code(&method.name, &method.descriptor, |pool, code|{
+            for instruction in code.raw_instructions(){
+                if let (_, RawInstruction::New { index }) = instruction?{
+                    let name = pool.get(pool.get(index)?.name)?.content.as_bytes();
+                    out.push((name.into(), Kind::Instantiation));
+                }
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Every class the class refers to, in the order they are found. A class can
+/// be listed more than once, with different or the same kinds.
+pub fn dependencies(class: &ParsedClass) -> anyhow::Result<Vec<Dependency>>{
+    let mut out = Vec::new();
+    for superclass in class.inherited.iter().chain(class.interfaces.iter()){
+        out.push((superclass.clone(), Kind::Superclass));
+    }
+    for field in class.fields.iter(){
+        descriptor_classes(&field.descriptor, Kind::FieldType, &mut out)?;
+    }
+    for method in class.methods.iter(){
+        descriptor_classes(&method.descriptor, Kind::MethodSignature, &mut out)?;
+    }
+    instantiations(class, &mut out)?;
+
+    let mut reader = Class::new(&class.bytes)?;
+    let pool = reader.pool()?;
+    for item in pool.iter(){
+        match item {
+            Item::Class(c) => {
+                if let Some(name) = element_class(pool.get(c.name)?.content.as_bytes())?{
+                    out.push((name, Kind::Reference));
+                }
+            }
+            Item::FieldRef(f) => {
+                let descriptor = pool.get(pool.get(f.name_and_type)?.descriptor)?;
+                descriptor_classes(descriptor.content.as_bytes(), Kind::FieldType, &mut out)?;
+            }
+            Item::MethodRef(m) => {
+                let descriptor = pool.get(pool.get(m.name_and_type)?.descriptor)?;
+                descriptor_classes(descriptor.content.as_bytes(), Kind::MethodSignature, &mut out)?;
+            }
+            Item::InterfaceMethodRef(m) => {
+                let descriptor = pool.get(pool.get(m.name_and_type)?.descriptor)?;
+                descriptor_classes(descriptor.content.as_bytes(), Kind::MethodSignature, &mut out)?;
+            }
+            Item::InvokeDynamic(d) => {
+                let descriptor = pool.get(pool.get(d.name_and_type)?.descriptor)?;
+                descriptor_classes(descriptor.content.as_bytes(), Kind::MethodSignature, &mut out)?;
+            }
+            Item::Dynamic(d) => {
+                let descriptor = pool.get(pool.get(d.name_and_type)?.descriptor)?;
+                descriptor_classes(descriptor.content.as_bytes(), Kind::FieldType, &mut out)?;
+            }
+            Item::MethodType(t) => {
+                descriptor_classes(pool.get(t.descriptor)?.content.as_bytes(), Kind::MethodSignature, &mut out)?;
+            }
+            // The referenced member is listed through its own entry, lambda
+            // implementations can be in other classes though.
+            Item::MethodHandle(h) => {
+                let owner = match pool.get(h.reference)? {
+                    Item::MethodRef(r) => r.class,
+                    Item::InterfaceMethodRef(r) => r.class,
+                    Item::FieldRef(r) => r.class,
+                    _ => continue
+                };
+                if let Some(name) = element_class(pool.get(pool.get(owner)?.name)?.content.as_bytes())?{
+                    out.push((name, Kind::Reference));
+                }
+            }
+            Item::String(_) |
+            Item::Integer(_) |
+            Item::Long(_) |
+            Item::Float(_) |
+            Item::Double(_) |
+            Item::NameAndType(_) |
+            Item::Utf8(_) |
+            Item::Module(_) |
+            Item::Package(_) => (),
+        }
+    }
+    Ok(out)
+}
+
+/// Dependencies between classes. References of a class to itself are left
+/// out, and so is [`Kind::Reference`] between classes with a more specific
+/// kind of edge.
+#[derive(Clone, Debug, Default)]
+pub struct DependencyGraph{
+    /// Every class that was added or is referred to.
+    classes: BTreeSet<Box<[u8]>>,
+    /// Added classes with a `<clinit>`.
+    initializers: BTreeSet<Box<[u8]>>,
+    edges: BTreeMap<Box<[u8]>, Edges>,
+}
+
+impl DependencyGraph{
+    pub fn build<'a>(classes: impl IntoIterator<Item = &'a ParsedClass>) -> anyhow::Result<Self>{
+        let mut graph = Self::default();
+        for class in classes{
+            graph.add(class, &dependencies(class)?);
+        }
+        Ok(graph)
+    }
+
+    /// Adds the class with the dependencies found by [`dependencies`].
+    pub fn add(&mut self, class: &ParsedClass, dependencies: &[Dependency]){
+        self.classes.insert(class.name.clone());
+        if class.method(CLINIT, CLINIT_DESCRIPTOR).is_some(){
+            self.initializers.insert(class.name.clone());
+        }
+        let edges = self.edges.entry(class.name.clone()).or_default();
+        for (target, kind) in dependencies.iter().filter(|(target, _)| *target != class.name){
+            self.classes.insert(target.clone());
+            edges.entry(target.clone()).or_default().insert(*kind);
+        }
+        for kinds in edges.values_mut(){
+            if kinds.len() > 1{
+                kinds.remove(&Kind::Reference);
+            }
+        }
+    }
+
+    /// The kinds of references from `from` to `to`, empty if there are none.
+    pub fn kinds(&self, from: &[u8], to: &[u8]) -> Vec<Kind>{
+        self.edges.get(from).and_then(|e| e.get(to)).map_or(Vec::new(), |k| k.iter().copied().collect())
+    }
+
+    fn successors(&self, class: &[u8]) -> impl Iterator<Item = &Box<[u8]>>{
+        self.edges.get(class).into_iter().flat_map(|e| e.keys())
+    }
+
+    /// Strongly connected components with more than one class, the classes
+    /// of each sorted by name, components in reverse topological order:
+    /// a component only depends on components before it.
+    pub fn cycles(&self) -> Vec<Vec<Box<[u8]>>>{
+        // Tarjan's algorithm with an explicit stack, class graphs can be deep.
+        let indices = self.classes.iter().enumerate().map(|(i, c)| (&**c, i)).collect::<BTreeMap<&[u8], usize>>();
+        let names = self.classes.iter().collect::<Vec<_>>();
+        let successors = names.iter().map(|n| self.successors(n).map(|s| indices[&**s]).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        let mut order = vec![usize::MAX; names.len()];
+        let mut low = vec![0; names.len()];
+        let mut on_stack = vec![false; names.len()];
+        let mut stack = Vec::new();
+        let mut next = 0;
+        let mut components = Vec::new();
+
+        for root in 0..names.len(){
+            if order[root] != usize::MAX{
+                continue;
+            }
+            let mut calls = vec![(root, 0)];
+            order[root] = next;
+            low[root] = next;
+            next += 1;
+            stack.push(root);
+            on_stack[root] = true;
+
+            while let Some((node, edge)) = calls.last_mut(){
+                let node = *node;
+                if let Some(&successor) = successors[node].get(*edge){
+                    *edge += 1;
+                    if order[successor] == usize::MAX{
+                        order[successor] = next;
+                        low[successor] = next;
+                        next += 1;
+                        stack.push(successor);
+                        on_stack[successor] = true;
+                        calls.push((successor, 0));
+                    }
+                    else if on_stack[successor]{
+                        low[node] = low[node].min(order[successor]);
+                    }
+                    continue;
+                }
+                calls.pop();
+                if let Some((parent, _)) = calls.last(){
+                    low[*parent] = low[*parent].min(low[node]);
+                }
+                if low[node] == order[node]{
+                    let mut component = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack[member] = false;
+                        component.push(names[member].clone());
+                        if member == node{
+                            break;
+                        }
+                    }
+                    if component.len() > 1{
+                        component.sort();
+                        components.push(component);
+                    }
+                }
+            }
+        }
+        components
+    }
+
+    /// One line per cycle, classes with a `<clinit>` are marked with `*`.
+    pub fn report(&self) -> String{
+        let mut report = String::new();
+        for cycle in self.cycles(){
+            let initializers = cycle.iter().filter(|c| self.initializers.contains(*c)).count();
+            let members = cycle.iter().map(|c|{
+                let mark = if self.initializers.contains(c) {"*"} else {""};
+                format!("{}{}", String::from_utf8_lossy(c), mark)
+            }).collect::<Vec<_>>();
+            report.push_str(&format!("cycle of {} classes, {} with <clinit>: {}\n", cycle.len(), initializers, members.join(", ")));
+        }
+        report
+    }
+
+    /// The graph in Graphviz's DOT language, edges labelled with their kinds.
+    pub fn dot(&self) -> String{
+        let escape = |name: &[u8]| String::from_utf8_lossy(name).replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::new();
+        out.push_str("digraph dependencies {\n");
+        out.push_str("  node [shape=box];\n");
+        for class in self.classes.iter(){
+            let style = if self.initializers.contains(class) {", style=bold"} else {""};
+            let _ = writeln!(out, "  \"{}\" [label=\"{}\"{}];", escape(class), escape(class), style);
+        }
+        for (from, edges) in self.edges.iter(){
+            for (to, kinds) in edges.iter(){
+                let label = kinds.iter().map(|k| k.name()).collect::<Vec<_>>().join(", ");
+                let style = if kinds.contains(&Kind::Superclass) {", style=bold"} else {""};
+                let _ = writeln!(out, "  \"{}\" -> \"{}\" [label=\"{}\"{}];", escape(from), escape(to), label, style);
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// JSON with the classes, the edges and the cycles.
+    pub fn json(&self) -> serde_json::Value{
+        let name = |c: &[u8]| String::from_utf8_lossy(c).into_owned();
+        let classes = self.classes.iter().map(|c| json!({
+            "name": name(c),
+            "clinit": self.initializers.contains(c),
+        })).collect::<Vec<_>>();
+        let edges = self.edges.iter().flat_map(|(from, edges)| edges.iter().map(move |(to, kinds)| json!({
+            "from": name(from),
+            "to": name(to),
+            "kinds": kinds.iter().map(|k| k.name()).collect::<Vec<_>>(),
+        }))).collect::<Vec<_>>();
+        let cycles = self.cycles().iter().map(|c| c.iter().map(|c| name(c)).collect::<Vec<_>>()).collect::<Vec<_>>();
+        json!({
+            "classes": classes,
+            "edges": edges,
+            "cycles": cycles,
+        })
+    }
+}
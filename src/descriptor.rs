@@ -0,0 +1,71 @@
+use peg::parser;
+
+parser!(
+    pub grammar descriptor_parser() for [u8]{
+        rule primitive(c: usize) -> JavaType
+        = "B" {JavaType::Byte(c as u8)}
+        / "C" {JavaType::Char(c as u8)}
+        / "D" {JavaType::Double(c as u8)}
+        / "F" {JavaType::Float(c as u8)}
+        / "I" {JavaType::Int(c as u8)}
+        / "J" {JavaType::Long(c as u8)}
+        / "L" n:([^ 59]+) ";" {JavaType::Reference(c as u8, n.into_boxed_slice())}
+        / "S" {JavaType::Short(c as u8)}
+        / "Z" {JavaType::Bool(c as u8)}
+
+        pub rule field() -> JavaType
+        = n:$("["*) p:primitive({n.len()}) {p}
+
+        pub rule return_type() -> Option<JavaType>
+        = "V" {None}
+        / f:field() {Some(f)}
+
+        pub rule method() -> (Vec<JavaType>, Option<JavaType>)
+        = "(" x:field()* ")" r:return_type() {(x, r)}
+
+        pub rule class_names() -> Vec<JavaType>
+         = &"(" x: method() {let mut y = x.0; y.extend(x.1); y}
+         / x:field() {vec![x]}
+    }
+);
+
+
+/// A field type, the number is the amount of array dimensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JavaType{
+    Bool(u8),
+    Byte(u8),
+    Char(u8),
+    Short(u8),
+    Int(u8),
+    Float(u8),
+    Double(u8),
+    Long(u8), 
+    Reference(u8, Box<[u8]>)
+}
+
+impl JavaType{
+    /// The field descriptor of the type.
+    pub fn descriptor(&self) -> Vec<u8>{
+        let (dimensions, base) = match self {
+            JavaType::Bool(d) => (*d, &b"Z"[..]),
+            JavaType::Byte(d) => (*d, &b"B"[..]),
+            JavaType::Char(d) => (*d, &b"C"[..]),
+            JavaType::Short(d) => (*d, &b"S"[..]),
+            JavaType::Int(d) => (*d, &b"I"[..]),
+            JavaType::Float(d) => (*d, &b"F"[..]),
+            JavaType::Double(d) => (*d, &b"D"[..]),
+            JavaType::Long(d) => (*d, &b"J"[..]),
+            JavaType::Reference(d, name) => {
+                let mut descriptor = vec![b'['; *d as usize];
+                descriptor.push(b'L');
+                descriptor.extend_from_slice(name);
+                descriptor.push(b';');
+                return descriptor;
+            }
+        };
+        let mut descriptor = vec![b'['; dimensions as usize];
+        descriptor.extend_from_slice(base);
+        descriptor
+    }
+}
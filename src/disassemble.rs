@@ -0,0 +1,595 @@
+//! A `javap`-like listing of method bodies, for debugging the lowering.
+//!
+//! Every instruction is shown with its offset, its constant pool operands
+//! resolved, and the operand stack and local types inferred before it runs.
+//! Blocks start with their successors, exception ranges are marked where
+//! they begin and end. Inference errors, including the panics of
+//! [`code::step`] on mismatched stacks, are reported at the instruction they
+//! happen at rather than aborting the listing.
+
+use std::{fmt::Write, panic::{self, AssertUnwindSafe}};
+
+use anyhow::bail;
+use noak::{AccessFlags, reader::{attributes::{Code, Index as Offset, RawInstruction}, cpool::{self, ConstantPool, Item}}};
+
+use crate::{blocks::{self, Block, Handler}, code::{self, Stack, Value}, data::{ParsedClass, ParsedMethod}, descriptor::descriptor_parser};
+
+/// Types of the operand stack and the locals before an instruction. Locals
+/// that are unset, hold the second half of a `long` or `double` or differ
+/// between predecessors are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Frame{
+    pub stack: Vec<Value>,
+    pub locals: Vec<Option<Value>>,
+}
+
+impl Frame{
+    /// The frame on entry, holding `this` and the parameters.
+    pub fn entry(method: &ParsedMethod, max_locals: u16) -> anyhow::Result<Self>{
+        let Ok((params, _)) = descriptor_parser::method(&method.descriptor) else {
+            bail!("Invalid method descriptor {}", String::from_utf8_lossy(&method.descriptor));
+        };
+        let mut frame = Self{
+            stack: Vec::new(),
+            locals: vec![None; max_locals as usize]
+        };
+        let mut slot = 0;
+        if !method.flags.contains(AccessFlags::STATIC){
+            frame.store(0, Value::Ref)?;
+            slot += 1;
+        }
+        for param in params.iter(){
+            let value = Value::of_type(param);
+            frame.store(slot, value)?;
+            slot += if value.is_big() {2} else {1};
+        }
+        Ok(frame)
+    }
+
+    fn store(&mut self, slot: u16, value: Value) -> anyhow::Result<()>{
+        let slot = slot as usize;
+        let size = if value.is_big() {2} else {1};
+        if slot + size > self.locals.len(){
+            bail!("Local {} is out of the {} locals", slot, self.locals.len());
+        }
+        // Overwriting the second half of a big value invalidates it.
+        if slot > 0 && self.locals[slot - 1].is_some_and(Value::is_big){
+            self.locals[slot - 1] = None;
+        }
+        self.locals[slot] = Some(value);
+        if size == 2{
+            self.locals[slot + 1] = None;
+        }
+        Ok(())
+    }
+
+    /// Joins the frame of another predecessor, returns whether this one changed.
+    fn merge(&mut self, other: &Frame) -> anyhow::Result<bool>{
+        if self.stack != other.stack{
+            bail!("Stack mismatch: {} and {}", types(&self.stack), types(&other.stack));
+        }
+        let mut changed = false;
+        for (local, other) in self.locals.iter_mut().zip(other.locals.iter()){
+            if local.is_some() && local != other{
+                *local = None;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Runs the instruction on the frame.
+    pub fn step(&mut self, instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<()>{
+        // `code::step` checks the operands of stores but knows nothing of locals.
+        if let (Some((slot, value)), false) = (code::local(instruction), code::is_store(instruction)){
+            let loaded = self.locals.get(slot as usize).copied().flatten();
+            if loaded != Some(value){
+                bail!("Loads {:?} from local {} holding {}", value, slot, loaded.map_or("nothing".into(), |l| format!("{:?}", l)));
+            }
+        }
+
+        let mut stack = Stack::with_values(&self.stack);
+        // Mismatched operands are assertions in `code::step`.
+        match panic::catch_unwind(AssertUnwindSafe(|| code::step(&mut stack, instruction, cp))) {
+            Ok(result) => result?,
+            Err(payload) => {
+                let message = payload.downcast_ref::<String>().cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "code::step panicked".into());
+                bail!("{}", message.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+        if stack.inputs().len() > self.stack.len(){
+            bail!("Pops {} values from a stack of {}", stack.inputs().len(), self.stack.len());
+        }
+        if let (Some((slot, value)), true) = (code::local(instruction), code::is_store(instruction)){
+            self.store(slot, value)?;
+        }
+        self.stack = stack.values().to_vec();
+        Ok(())
+    }
+}
+
+fn letter(value: Value) -> char{
+    match value {
+        Value::I32 => 'I',
+        Value::I64 => 'J',
+        Value::F32 => 'F',
+        Value::F64 => 'D',
+        Value::Ref => 'A'
+    }
+}
+
+pub(crate) fn types(values: &[Value]) -> String{
+    format!("[{}]", values.iter().map(|v| letter(*v)).collect::<String>())
+}
+
+fn locals(locals: &[Option<Value>]) -> String{
+    format!("[{}]", locals.iter().map(|l| l.map_or('-', letter)).collect::<String>())
+}
+
+/// The mnemonic `javap` shows for the instruction.
+pub(crate) fn mnemonic(instruction: &RawInstruction) -> &'static str{
+    match instruction {
+        RawInstruction::AALoad => "aaload",
+        RawInstruction::AAStore => "aastore",
+        RawInstruction::AConstNull => "aconst_null",
+        RawInstruction::ALoad { .. } => "aload",
+        RawInstruction::ALoadW { .. } => "wide aload",
+        RawInstruction::ALoad0 => "aload_0",
+        RawInstruction::ALoad1 => "aload_1",
+        RawInstruction::ALoad2 => "aload_2",
+        RawInstruction::ALoad3 => "aload_3",
+        RawInstruction::ANewArray { .. } => "anewarray",
+        RawInstruction::AReturn => "areturn",
+        RawInstruction::ArrayLength => "arraylength",
+        RawInstruction::AStore { .. } => "astore",
+        RawInstruction::AStoreW { .. } => "wide astore",
+        RawInstruction::AStore0 => "astore_0",
+        RawInstruction::AStore1 => "astore_1",
+        RawInstruction::AStore2 => "astore_2",
+        RawInstruction::AStore3 => "astore_3",
+        RawInstruction::AThrow => "athrow",
+        RawInstruction::BALoad => "baload",
+        RawInstruction::BAStore => "bastore",
+        RawInstruction::BIPush { .. } => "bipush",
+        RawInstruction::CALoad => "caload",
+        RawInstruction::CAStore => "castore",
+        RawInstruction::CheckCast { .. } => "checkcast",
+        RawInstruction::D2F => "d2f",
+        RawInstruction::D2I => "d2i",
+        RawInstruction::D2L => "d2l",
+        RawInstruction::DAdd => "dadd",
+        RawInstruction::DALoad => "daload",
+        RawInstruction::DAStore => "dastore",
+        RawInstruction::DCmpG => "dcmpg",
+        RawInstruction::DCmpL => "dcmpl",
+        RawInstruction::DConst0 => "dconst_0",
+        RawInstruction::DConst1 => "dconst_1",
+        RawInstruction::DDiv => "ddiv",
+        RawInstruction::DLoad { .. } => "dload",
+        RawInstruction::DLoadW { .. } => "wide dload",
+        RawInstruction::DLoad0 => "dload_0",
+        RawInstruction::DLoad1 => "dload_1",
+        RawInstruction::DLoad2 => "dload_2",
+        RawInstruction::DLoad3 => "dload_3",
+        RawInstruction::DMul => "dmul",
+        RawInstruction::DNeg => "dneg",
+        RawInstruction::DRem => "drem",
+        RawInstruction::DReturn => "dreturn",
+        RawInstruction::DStore { .. } => "dstore",
+        RawInstruction::DStoreW { .. } => "wide dstore",
+        RawInstruction::DStore0 => "dstore_0",
+        RawInstruction::DStore1 => "dstore_1",
+        RawInstruction::DStore2 => "dstore_2",
+        RawInstruction::DStore3 => "dstore_3",
+        RawInstruction::DSub => "dsub",
+        RawInstruction::Dup => "dup",
+        RawInstruction::DupX1 => "dup_x1",
+        RawInstruction::DupX2 => "dup_x2",
+        RawInstruction::Dup2 => "dup2",
+        RawInstruction::Dup2X1 => "dup2_x1",
+        RawInstruction::Dup2X2 => "dup2_x2",
+        RawInstruction::F2D => "f2d",
+        RawInstruction::F2I => "f2i",
+        RawInstruction::F2L => "f2l",
+        RawInstruction::FAdd => "fadd",
+        RawInstruction::FALoad => "faload",
+        RawInstruction::FAStore => "fastore",
+        RawInstruction::FCmpG => "fcmpg",
+        RawInstruction::FCmpL => "fcmpl",
+        RawInstruction::FConst0 => "fconst_0",
+        RawInstruction::FConst1 => "fconst_1",
+        RawInstruction::FConst2 => "fconst_2",
+        RawInstruction::FDiv => "fdiv",
+        RawInstruction::FLoad { .. } => "fload",
+        RawInstruction::FLoadW { .. } => "wide fload",
+        RawInstruction::FLoad0 => "fload_0",
+        RawInstruction::FLoad1 => "fload_1",
+        RawInstruction::FLoad2 => "fload_2",
+        RawInstruction::FLoad3 => "fload_3",
+        RawInstruction::FMul => "fmul",
+        RawInstruction::FNeg => "fneg",
+        RawInstruction::FRem => "frem",
+        RawInstruction::FReturn => "freturn",
+        RawInstruction::FStore { .. } => "fstore",
+        RawInstruction::FStoreW { .. } => "wide fstore",
+        RawInstruction::FStore0 => "fstore_0",
+        RawInstruction::FStore1 => "fstore_1",
+        RawInstruction::FStore2 => "fstore_2",
+        RawInstruction::FStore3 => "fstore_3",
+        RawInstruction::FSub => "fsub",
+        RawInstruction::GetField { .. } => "getfield",
+        RawInstruction::GetStatic { .. } => "getstatic",
+        RawInstruction::Goto { .. } => "goto",
+        RawInstruction::GotoW { .. } => "goto_w",
+        RawInstruction::I2B => "i2b",
+        RawInstruction::I2C => "i2c",
+        RawInstruction::I2D => "i2d",
+        RawInstruction::I2F => "i2f",
+        RawInstruction::I2L => "i2l",
+        RawInstruction::I2S => "i2s",
+        RawInstruction::IAdd => "iadd",
+        RawInstruction::IALoad => "iaload",
+        RawInstruction::IAnd => "iand",
+        RawInstruction::IAStore => "iastore",
+        RawInstruction::IConstM1 => "iconst_m1",
+        RawInstruction::IConst0 => "iconst_0",
+        RawInstruction::IConst1 => "iconst_1",
+        RawInstruction::IConst2 => "iconst_2",
+        RawInstruction::IConst3 => "iconst_3",
+        RawInstruction::IConst4 => "iconst_4",
+        RawInstruction::IConst5 => "iconst_5",
+        RawInstruction::IDiv => "idiv",
+        RawInstruction::IfACmpEq { .. } => "if_acmpeq",
+        RawInstruction::IfACmpNe { .. } => "if_acmpne",
+        RawInstruction::IfICmpEq { .. } => "if_icmpeq",
+        RawInstruction::IfICmpNe { .. } => "if_icmpne",
+        RawInstruction::IfICmpLt { .. } => "if_icmplt",
+        RawInstruction::IfICmpGe { .. } => "if_icmpge",
+        RawInstruction::IfICmpGt { .. } => "if_icmpgt",
+        RawInstruction::IfICmpLe { .. } => "if_icmple",
+        RawInstruction::IfEq { .. } => "ifeq",
+        RawInstruction::IfNe { .. } => "ifne",
+        RawInstruction::IfLt { .. } => "iflt",
+        RawInstruction::IfGe { .. } => "ifge",
+        RawInstruction::IfGt { .. } => "ifgt",
+        RawInstruction::IfLe { .. } => "ifle",
+        RawInstruction::IfNonNull { .. } => "ifnonnull",
+        RawInstruction::IfNull { .. } => "ifnull",
+        RawInstruction::IInc { .. } => "iinc",
+        RawInstruction::IIncW { .. } => "wide iinc",
+        RawInstruction::ILoad { .. } => "iload",
+        RawInstruction::ILoadW { .. } => "wide iload",
+        RawInstruction::ILoad0 => "iload_0",
+        RawInstruction::ILoad1 => "iload_1",
+        RawInstruction::ILoad2 => "iload_2",
+        RawInstruction::ILoad3 => "iload_3",
+        RawInstruction::IMul => "imul",
+        RawInstruction::INeg => "ineg",
+        RawInstruction::InstanceOf { .. } => "instanceof",
+        RawInstruction::InvokeDynamic { .. } => "invokedynamic",
+        RawInstruction::InvokeInterface { .. } => "invokeinterface",
+        RawInstruction::InvokeSpecial { .. } => "invokespecial",
+        RawInstruction::InvokeStatic { .. } => "invokestatic",
+        RawInstruction::InvokeVirtual { .. } => "invokevirtual",
+        RawInstruction::IOr => "ior",
+        RawInstruction::IRem => "irem",
+        RawInstruction::IReturn => "ireturn",
+        RawInstruction::IShL => "ishl",
+        RawInstruction::IShR => "ishr",
+        RawInstruction::IStore { .. } => "istore",
+        RawInstruction::IStoreW { .. } => "wide istore",
+        RawInstruction::IStore0 => "istore_0",
+        RawInstruction::IStore1 => "istore_1",
+        RawInstruction::IStore2 => "istore_2",
+        RawInstruction::IStore3 => "istore_3",
+        RawInstruction::ISub => "isub",
+        RawInstruction::IUShR => "iushr",
+        RawInstruction::IXor => "ixor",
+        RawInstruction::JSr { .. } => "jsr",
+        RawInstruction::JSrW { .. } => "jsr_w",
+        RawInstruction::L2D => "l2d",
+        RawInstruction::L2F => "l2f",
+        RawInstruction::L2I => "l2i",
+        RawInstruction::LAdd => "ladd",
+        RawInstruction::LALoad => "laload",
+        RawInstruction::LAnd => "land",
+        RawInstruction::LAStore => "lastore",
+        RawInstruction::LCmp => "lcmp",
+        RawInstruction::LConst0 => "lconst_0",
+        RawInstruction::LConst1 => "lconst_1",
+        RawInstruction::LdC { .. } => "ldc",
+        RawInstruction::LdCW { .. } => "ldc_w",
+        RawInstruction::LdC2W { .. } => "ldc2_w",
+        RawInstruction::LDiv => "ldiv",
+        RawInstruction::LLoad { .. } => "lload",
+        RawInstruction::LLoadW { .. } => "wide lload",
+        RawInstruction::LLoad0 => "lload_0",
+        RawInstruction::LLoad1 => "lload_1",
+        RawInstruction::LLoad2 => "lload_2",
+        RawInstruction::LLoad3 => "lload_3",
+        RawInstruction::LMul => "lmul",
+        RawInstruction::LNeg => "lneg",
+        RawInstruction::LookupSwitch(_) => "lookupswitch",
+        RawInstruction::LOr => "lor",
+        RawInstruction::LRem => "lrem",
+        RawInstruction::LReturn => "lreturn",
+        RawInstruction::LShL => "lshl",
+        RawInstruction::LShR => "lshr",
+        RawInstruction::LStore { .. } => "lstore",
+        RawInstruction::LStoreW { .. } => "wide lstore",
+        RawInstruction::LStore0 => "lstore_0",
+        RawInstruction::LStore1 => "lstore_1",
+        RawInstruction::LStore2 => "lstore_2",
+        RawInstruction::LStore3 => "lstore_3",
+        RawInstruction::LSub => "lsub",
+        RawInstruction::LUShR => "lushr",
+        RawInstruction::LXor => "lxor",
+        RawInstruction::MonitorEnter => "monitorenter",
+        RawInstruction::MonitorExit => "monitorexit",
+        RawInstruction::MultiANewArray { .. } => "multianewarray",
+        RawInstruction::New { .. } => "new",
+        RawInstruction::NewArray { .. } => "newarray",
+        RawInstruction::Nop => "nop",
+        RawInstruction::Pop => "pop",
+        RawInstruction::Pop2 => "pop2",
+        RawInstruction::PutField { .. } => "putfield",
+        RawInstruction::PutStatic { .. } => "putstatic",
+        RawInstruction::Ret { .. } => "ret",
+        RawInstruction::RetW { .. } => "wide ret",
+        RawInstruction::Return => "return",
+        RawInstruction::SALoad => "saload",
+        RawInstruction::SAStore => "sastore",
+        RawInstruction::SIPush { .. } => "sipush",
+        RawInstruction::Swap => "swap",
+        RawInstruction::TableSwitch(_) => "tableswitch",
+    }
+}
+
+fn utf8(cp: &ConstantPool, index: cpool::Index<cpool::Utf8>) -> anyhow::Result<String>{
+    Ok(cp.get(index)?.content.display().to_string())
+}
+
+fn member(cp: &ConstantPool, class: cpool::Index<cpool::Class>, name_and_type: cpool::Index<cpool::NameAndType>) -> anyhow::Result<String>{
+    let name_and_type = cp.get(name_and_type)?;
+    Ok(format!(
+        "{}.{}:{}",
+        utf8(cp, cp.get(class)?.name)?,
+        utf8(cp, name_and_type.name)?,
+        utf8(cp, name_and_type.descriptor)?
+    ))
+}
+
+/// The constant pool entry, resolved to text.
+fn constant(cp: &ConstantPool, index: u16) -> anyhow::Result<String>{
+    Ok(match cp.get(cpool::Index::<Item>::new(index)?)? {
+        Item::Class(c) => utf8(cp, c.name)?,
+        Item::FieldRef(r) => member(cp, r.class, r.name_and_type)?,
+        Item::MethodRef(r) => member(cp, r.class, r.name_and_type)?,
+        Item::InterfaceMethodRef(r) => member(cp, r.class, r.name_and_type)?,
+        Item::String(s) => format!("{:?}", utf8(cp, s.string)?),
+        Item::Integer(i) => i.value.to_string(),
+        Item::Long(l) => format!("{}L", l.value),
+        Item::Float(f) => format!("{:?}f", f.value),
+        Item::Double(d) => format!("{:?}d", d.value),
+        Item::NameAndType(n) => format!("{}:{}", utf8(cp, n.name)?, utf8(cp, n.descriptor)?),
+        Item::Utf8(u) => u.content.display().to_string(),
+        Item::MethodHandle(h) => format!("{:?} {}", h.kind, constant(cp, h.reference.as_u16())?),
+        Item::MethodType(t) => utf8(cp, t.descriptor)?,
+        Item::Dynamic(d) => format!("#{} {}", d.bootstrap_method_attr, constant(cp, d.name_and_type.as_u16())?),
+        Item::InvokeDynamic(d) => format!("#{} {}", d.bootstrap_method_attr, constant(cp, d.name_and_type.as_u16())?),
+        Item::Module(m) => utf8(cp, m.name)?,
+        Item::Package(p) => utf8(cp, p.name)?,
+    })
+}
+
+/// Operands of the instruction, with constants resolved and jumps as the offsets they go to.
+pub(crate) fn operands(at: Offset, instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<String>{
+    Ok(match instruction {
+        RawInstruction::LookupSwitch(lookup) => {
+            let mut cases = lookup.pairs().map(|p| format!("{}: {}", p.key(), blocks::target(at, p.offset()))).collect::<Vec<_>>();
+            cases.push(format!("default: {}", blocks::target(at, lookup.default_offset())));
+            format!("{{ {} }}", cases.join(", "))
+        }
+        RawInstruction::TableSwitch(table) => {
+            let mut cases = table.pairs().map(|p| format!("{}: {}", p.key(), blocks::target(at, p.offset()))).collect::<Vec<_>>();
+            cases.push(format!("default: {}", blocks::target(at, table.default_offset())));
+            format!("{{ {} }}", cases.join(", "))
+        }
+        RawInstruction::BIPush { value } => value.to_string(),
+        RawInstruction::SIPush { value } => value.to_string(),
+        RawInstruction::IInc { index, value } => format!("{}, {}", index, value),
+        RawInstruction::IIncW { index, value } => format!("{}, {}", index, value),
+        RawInstruction::Ret { index } => index.to_string(),
+        RawInstruction::RetW { index } => index.to_string(),
+        RawInstruction::NewArray { atype } => format!("{:?}", atype).to_ascii_lowercase(),
+        RawInstruction::ANewArray { index } |
+        RawInstruction::CheckCast { index } |
+        RawInstruction::InstanceOf { index } |
+        RawInstruction::New { index } => constant(cp, index.as_u16())?,
+        RawInstruction::MultiANewArray { index, dimensions } => format!("{}, {}", constant(cp, index.as_u16())?, dimensions),
+        RawInstruction::GetField { index } |
+        RawInstruction::GetStatic { index } |
+        RawInstruction::PutField { index } |
+        RawInstruction::PutStatic { index } => constant(cp, index.as_u16())?,
+        RawInstruction::InvokeVirtual { index } => constant(cp, index.as_u16())?,
+        RawInstruction::InvokeInterface { index, count } => format!("{}, {}", constant(cp, index.as_u16())?, count),
+        RawInstruction::InvokeDynamic { index } => constant(cp, index.as_u16())?,
+        RawInstruction::InvokeSpecial { index } |
+        RawInstruction::InvokeStatic { index } |
+        RawInstruction::LdC { index } |
+        RawInstruction::LdCW { index } |
+        RawInstruction::LdC2W { index } => constant(cp, index.as_u16())?,
+        // Only the explicit forms name their local, `iload_0` doesn't.
+        RawInstruction::ILoad { index } | RawInstruction::IStore { index } |
+        RawInstruction::LLoad { index } | RawInstruction::LStore { index } |
+        RawInstruction::FLoad { index } | RawInstruction::FStore { index } |
+        RawInstruction::DLoad { index } | RawInstruction::DStore { index } |
+        RawInstruction::ALoad { index } | RawInstruction::AStore { index } => index.to_string(),
+        RawInstruction::ILoadW { index } | RawInstruction::IStoreW { index } |
+        RawInstruction::LLoadW { index } | RawInstruction::LStoreW { index } |
+        RawInstruction::FLoadW { index } | RawInstruction::FStoreW { index } |
+        RawInstruction::DLoadW { index } | RawInstruction::DStoreW { index } |
+        RawInstruction::ALoadW { index } | RawInstruction::AStoreW { index } => index.to_string(),
+        _ => match blocks::jump_targets(at, instruction) {
+            Ok(Some(targets)) => targets.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", "),
+            _ => String::new()
+        }
+    })
+}
+
+/// Infers the frame at the start of every reachable block, along with the
+/// first error found in each block.
+pub(crate) fn entry_frames(blocks: &[Block], instructions: &[(Offset, RawInstruction)], handlers: &[Handler], cp: &ConstantPool, entry: Frame) -> (Vec<Option<Frame>>, Vec<Option<String>>){
+    fn reach(blocks: &[Block], frames: &mut [Option<Frame>], errors: &mut [Option<String>], pending: &mut Vec<usize>, offset: u32, frame: &Frame){
+        let Ok(index) = blocks.binary_search_by_key(&offset, |b| b.start) else {
+            return;
+        };
+        let changed = match &mut frames[index] {
+            None => {
+                frames[index] = Some(frame.clone());
+                true
+            }
+            Some(existing) => existing.merge(frame).unwrap_or_else(|e|{
+                errors[index].get_or_insert_with(|| format!("{:#}", e));
+                false
+            })
+        };
+        if changed{
+            pending.push(index);
+        }
+    }
+
+    let mut frames = vec![None; blocks.len()];
+    let mut errors = vec![None; blocks.len()];
+    let mut pending = Vec::new();
+    reach(blocks, &mut frames, &mut errors, &mut pending, 0, &entry);
+
+    while let Some(index) = pending.pop(){
+        let block = &blocks[index];
+        let mut frame = frames[index].clone().unwrap();
+        let mut failed = false;
+        for (at, instruction) in instructions[block.instructions.clone()].iter(){
+            for handler in handlers.iter().filter(|h| h.covers(at.as_u32())){
+                let caught = Frame{stack: vec![Value::Ref], locals: frame.locals.clone()};
+                reach(blocks, &mut frames, &mut errors, &mut pending, handler.handler, &caught);
+            }
+            if let Err(e) = frame.step(instruction, cp){
+                errors[index].get_or_insert_with(|| format!("{:#}", e));
+                failed = true;
+                break;
+            }
+        }
+        if !failed{
+            for successor in block.successors.iter(){
+                reach(blocks, &mut frames, &mut errors, &mut pending, *successor, &frame);
+            }
+        }
+    }
+    (frames, errors)
+}
+
+fn listing(method: &ParsedMethod, cp: &ConstantPool, code: &Code, out: &mut String) -> anyhow::Result<()>{
+    let instructions = code.raw_instructions().collect::<Result<Vec<_>, _>>()?;
+    let handlers = Handler::read_all(code, cp)?;
+    writeln!(out, "    stack {}, locals {}", code.max_stack(), code.max_locals())?;
+    for (i, handler) in handlers.iter().enumerate(){
+        let catch_type = handler.catch_type.as_deref().map_or("any".into(), String::from_utf8_lossy);
+        writeln!(out, "    try #{} [{}, {}) -> {} catch {}", i, handler.start, handler.end, handler.handler, catch_type)?;
+    }
+    let blocks = match blocks::split(&instructions, &handlers) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            writeln!(out, "    !! {:#}", e)?;
+            for (at, instruction) in instructions.iter(){
+                writeln!(out, "    {:>5}  {} {}", at.as_u32(), mnemonic(instruction), operands(*at, instruction, cp)?)?;
+            }
+            return Ok(());
+        }
+    };
+    let (frames, errors) = entry_frames(&blocks, &instructions, &handlers, cp, Frame::entry(method, code.max_locals())?);
+
+    for (index, block) in blocks.iter().enumerate(){
+        let caught = handlers.iter().enumerate().filter(|(_, h)| h.handler == block.start).map(|(i, _)| format!(" handler #{}", i)).collect::<String>();
+        let successors = block.successors.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" ");
+        let reached = if frames[index].is_some() {""} else {" unreachable"};
+        writeln!(out, "    block {} -> [{}]{}{}", block.start, successors, caught, reached)?;
+
+        let mut frame = frames[index].clone();
+        for (at, instruction) in instructions[block.instructions.clone()].iter(){
+            for (i, handler) in handlers.iter().enumerate(){
+                if handler.end == at.as_u32(){
+                    writeln!(out, "           end try #{}", i)?;
+                }
+                if handler.start == at.as_u32(){
+                    writeln!(out, "           try #{}", i)?;
+                }
+            }
+            let text = format!("{} {}", mnemonic(instruction), operands(*at, instruction, cp)?);
+            match &frame {
+                Some(f) => writeln!(out, "    {:>5}  {:<48} stack {} locals {}", at.as_u32(), text.trim_end(), types(&f.stack), locals(&f.locals))?,
+                None => writeln!(out, "    {:>5}  {}", at.as_u32(), text.trim_end())?
+            }
+            if let Some(f) = &mut frame{
+                if let Err(e) = f.step(instruction, cp){
+                    writeln!(out, "           !! {:#}", e)?;
+                    frame = None;
+                }
+            }
+        }
+        // Errors found merging the frames of predecessors.
+        if let (Some(error), Some(_)) = (&errors[index], &frame){
+            writeln!(out, "           !! {}", error)?;
+        }
+    }
+    if let Some((end, _)) = instructions.last(){
+        for (i, handler) in handlers.iter().enumerate().filter(|(_, h)| h.end > end.as_u32()){
+            writeln!(out, "           end try #{} at {}", i, handler.end)?;
+        }
+    }
+    Ok(())
+}
+
+fn modifiers(flags: AccessFlags) -> String{
+    [
+        (AccessFlags::PUBLIC, "public "),
+        (AccessFlags::PRIVATE, "private "),
+        (AccessFlags::PROTECTED, "protected "),
+        (AccessFlags::STATIC, "static "),
+        (AccessFlags::FINAL, "final "),
+        (AccessFlags::SYNCHRONIZED, "synchronized "),
+        (AccessFlags::NATIVE, "native "),
+        (AccessFlags::ABSTRACT, "abstract "),
+    ].into_iter().filter(|(flag, _)| flags.contains(*flag)).map(|(_, name)| name).collect()
+}
+
+/// Lists every method of the class.
+pub fn disassemble(class: &ParsedClass) -> anyhow::Result<String>{
+    let mut out = String::new();
+    let kind = if class.flags.contains(AccessFlags::INTERFACE) {"interface"} else {"class"};
+    write!(out, "{} {}", kind, String::from_utf8_lossy(&class.name))?;
+    if let Some(inherited) = &class.inherited{
+        write!(out, " extends {}", String::from_utf8_lossy(inherited))?;
+    }
+    if !class.interfaces.is_empty(){
+        let interfaces = class.interfaces.iter().map(|i| String::from_utf8_lossy(i)).collect::<Vec<_>>();
+        write!(out, " implements {}", interfaces.join(", "))?;
+    }
+    writeln!(out)?;
+
+    for method in class.methods.iter(){
+        writeln!(out)?;
+        writeln!(out, "  {}{}{}", modifiers(method.flags), String::from_utf8_lossy(&method.name), String::from_utf8_lossy(&method.descriptor))?;
+        let mut body = String::new();
+        match class.with_code(&method.name, &method.descriptor, |cp, code| listing(method, cp, code, &mut body)) {
+            Ok(Some(())) => out.push_str(&body),
+            Ok(None) => writeln!(out, "    no code")?,
+            Err(e) => {
+                out.push_str(&body);
+                writeln!(out, "    !! {:#}", e)?;
+            }
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,93 @@
+//! Control flow graphs of methods in Graphviz's DOT language.
+//!
+//! Each method is a cluster of its basic blocks, labelled with their
+//! instructions. Normal edges are solid, edges to exception handlers are
+//! dashed and labelled with the class they catch. Blocks can also show the
+//! operand stack types they are entered and left with.
+
+use std::fmt::Write;
+
+use anyhow::bail;
+use noak::reader::{attributes::Code, cpool::ConstantPool};
+
+use crate::{blocks::{self, Handler}, data::{ParsedClass, ParsedMethod}, disassemble::{self, Frame}};
+
+/// Escapes text for a quoted DOT string.
+fn escape(text: &str) -> String{
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn cluster(method: &ParsedMethod, id: usize, cp: &ConstantPool, code: &Code, stacks: bool, out: &mut String) -> anyhow::Result<()>{
+    let instructions = code.raw_instructions().collect::<Result<Vec<_>, _>>()?;
+    let handlers = Handler::read_all(code, cp)?;
+    let blocks = blocks::split(&instructions, &handlers)?;
+    let frames = if stacks {
+        disassemble::entry_frames(&blocks, &instructions, &handlers, cp, Frame::entry(method, code.max_locals())?).0
+    }
+    else{
+        vec![None; blocks.len()]
+    };
+
+    let name = format!("{}{}", String::from_utf8_lossy(&method.name), String::from_utf8_lossy(&method.descriptor));
+    writeln!(out, "  subgraph cluster_{} {{", id)?;
+    writeln!(out, "    label=\"{}\";", escape(&name))?;
+    for (block, frame) in blocks.iter().zip(frames.iter()){
+        let mut label = format!("block {}\\l", block.start);
+        let mut frame = frame.clone();
+        if let Some(f) = &frame{
+            write!(label, "in {}\\l", disassemble::types(&f.stack))?;
+        }
+        for (at, instruction) in instructions[block.instructions.clone()].iter(){
+            let text = format!("{} {}", disassemble::mnemonic(instruction), disassemble::operands(*at, instruction, cp)?);
+            write!(label, "{:>5}  {}\\l", at.as_u32(), escape(text.trim_end()))?;
+            if let Some(f) = &mut frame{
+                if let Err(e) = f.step(instruction, cp){
+                    write!(label, "!! {}\\l", escape(&format!("{:#}", e)))?;
+                    frame = None;
+                }
+            }
+        }
+        if let Some(f) = &frame{
+            write!(label, "out {}\\l", disassemble::types(&f.stack))?;
+        }
+        writeln!(out, "    m{}_{} [label=\"{}\"];", id, block.start, label)?;
+    }
+
+    for block in blocks.iter(){
+        for successor in block.successors.iter(){
+            writeln!(out, "    m{}_{} -> m{}_{};", id, block.start, id, successor)?;
+        }
+        let offsets = instructions[block.instructions.clone()].iter().map(|(at, _)| at.as_u32()).collect::<Vec<_>>();
+        for handler in handlers.iter().filter(|h| offsets.iter().any(|o| h.covers(*o))){
+            let catch_type = handler.catch_type.as_deref().map_or("any".into(), String::from_utf8_lossy);
+            writeln!(
+                out,
+                "    m{}_{} -> m{}_{} [style=dashed, label=\"{}\"];",
+                id, block.start, id, handler.handler, escape(&catch_type)
+            )?;
+        }
+    }
+    writeln!(out, "  }}")?;
+    Ok(())
+}
+
+/// The graph of every method of the class with code whose name, or name and
+/// descriptor, is `method`, or of every method if it's `None`.
+pub fn class_graph(class: &ParsedClass, method: Option<&str>, stacks: bool) -> anyhow::Result<String>{
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", escape(&String::from_utf8_lossy(&class.name)))?;
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];")?;
+    let selected = class.methods.iter().filter(|m|{
+        let name = String::from_utf8_lossy(&m.name);
+        method.is_none_or(|filter| filter == name || filter == format!("{}{}", name, String::from_utf8_lossy(&m.descriptor)))
+    });
+    let mut found = false;
+    for (id, m) in selected.enumerate(){
+        found |= class.with_code(&m.name, &m.descriptor, |cp, code| cluster(m, id, cp, code, stacks, &mut out))?.is_some();
+    }
+    if let (Some(method), false) = (method, found){
+        bail!("No method {} with code in {}", method, String::from_utf8_lossy(&class.name));
+    }
+    writeln!(out, "}}")?;
+    Ok(out)
+}
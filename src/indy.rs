@@ -0,0 +1,476 @@
+//! Desugaring of `invokedynamic` call sites.
+//!
+//! Only the bootstrap methods javac uses for lambdas and string concatenation
+//! are supported. A lambda becomes a synthetic class implementing the
+//! functional interface, with one field per captured value, and the call site
+//! allocates an instance of it. String concatenation is lowered inline.
+
+use anyhow::{bail, Context};
+use noak::{AccessFlags, Version, error::EncodeError, reader::{Class, cpool::{self, ConstantPool, Index, Item, MethodKind}}, writer::{self, ClassWriter}};
+
+use crate::{data::{Constant, ParsedClass}, descriptor::{descriptor_parser, JavaType}, program::MethodKey, strings::decode_mutf8};
+
+const LAMBDA_METAFACTORY: (&[u8], &[u8]) = (b"java/lang/invoke/LambdaMetafactory", b"metafactory");
+const STRING_CONCAT_FACTORY: (&[u8], &[u8]) = (b"java/lang/invoke/StringConcatFactory", b"makeConcatWithConstants");
+
+/// Marks where `makeConcatWithConstants` inserts an argument or a constant into its recipe.
+const TAG_ARGUMENT: u16 = 1;
+const TAG_CONSTANT: u16 = 2;
+
+pub enum CallSite{
+    Lambda(Lambda),
+    Concat(Concat),
+}
+
+/// A call site of `LambdaMetafactory.metafactory`.
+pub struct Lambda{
+    /// The synthetic class implementing the lambda.
+    pub class: Box<[u8]>,
+    pub interface: Box<[u8]>,
+    /// Name and erased descriptor of the method the lambda implements.
+    pub method: Box<[u8]>,
+    pub erased: Box<[u8]>,
+    pub kind: MethodKind,
+    pub implementation: MethodKey,
+    /// Whether the implementation is a method of an interface.
+    pub on_interface: bool,
+    /// Types of the values captured at the call site, which come first in the
+    /// arguments of the implementation.
+    pub captured: Vec<JavaType>,
+}
+
+/// A call site of `StringConcatFactory.makeConcatWithConstants`.
+pub struct Concat{
+    pub pieces: Vec<Piece>,
+    pub arguments: Vec<JavaType>,
+}
+
+pub enum Piece{
+    Literal(Box<[u16]>),
+    Argument(usize),
+}
+
+/// Name of the class implementing the lambda of `invokedynamic` constant `index` in `class`.
+pub fn lambda_class(class: &[u8], index: u16) -> Box<[u8]>{
+    let mut name = class.to_vec();
+    name.extend_from_slice(format!("$$Lambda${}", index).as_bytes());
+    name.into_boxed_slice()
+}
+
+/// Field of a lambda class holding the captured value at `index`.
+pub fn captured_field(index: usize) -> String{
+    format!("arg${}", index + 1)
+}
+
+impl CallSite{
+    pub fn read(class: &ParsedClass, cp: &ConstantPool, index: Index<cpool::InvokeDynamic>) -> anyhow::Result<Self>{
+        let site = cp.get(index)?;
+        let nt = cp.get(site.name_and_type)?;
+        let name = cp.get(nt.name)?.content.as_bytes();
+        let descriptor = cp.get(nt.descriptor)?.content.as_bytes();
+        let Ok((params, result)) = descriptor_parser::method(descriptor) else {
+            bail!("Invalid method descriptor {}", String::from_utf8_lossy(descriptor));
+        };
+
+        let Some(bootstrap) = class.bootstrap_methods.get(site.bootstrap_method_attr as usize) else {
+            bail!("{} has no bootstrap method {}", String::from_utf8_lossy(&class.name), site.bootstrap_method_attr);
+        };
+        let Item::MethodHandle(handle) = cp.get(Index::<Item>::new(bootstrap.method)?)? else {
+            bail!("Bootstrap method {} of {} is not a method handle", site.bootstrap_method_attr, String::from_utf8_lossy(&class.name));
+        };
+        let target = MethodKey::read(cp, handle.reference)?;
+        let arguments = bootstrap.arguments.iter().map(|a| Index::<Item>::new(*a)).collect::<Result<Vec<_>, _>>()?;
+
+        match (&*target.class, &*target.name) {
+            LAMBDA_METAFACTORY => {
+                let [erased, implementation, _instantiated] = arguments[..] else {
+                    bail!("{} takes 3 static arguments, got {}", target, arguments.len());
+                };
+                let Some(JavaType::Reference(0, interface)) = result else {
+                    bail!("Lambda call site {} doesn't return an interface", String::from_utf8_lossy(descriptor));
+                };
+                let Item::MethodType(erased) = cp.get(erased)? else {
+                    bail!("Expected the erased method type of the lambda");
+                };
+                let Item::MethodHandle(implementation) = cp.get(implementation)? else {
+                    bail!("Expected the implementation method handle of the lambda");
+                };
+                if !matches!(implementation.kind, MethodKind::InvokeStatic | MethodKind::InvokeVirtual | MethodKind::InvokeInterface | MethodKind::InvokeSpecial | MethodKind::NewInvokeSpecial){
+                    bail!("Lambdas implemented by {:?} method handles are not supported", implementation.kind);
+                }
+                Ok(Self::Lambda(Lambda{
+                    class: lambda_class(&class.name, index.as_u16()),
+                    interface,
+                    method: name.into(),
+                    erased: cp.get(erased.descriptor)?.content.as_bytes().into(),
+                    kind: implementation.kind,
+                    implementation: MethodKey::read(cp, implementation.reference)?,
+                    on_interface: matches!(cp.get(implementation.reference)?, Item::InterfaceMethodRef(_)),
+                    captured: params
+                }))
+            }
+            STRING_CONCAT_FACTORY => {
+                let Some((recipe, constants)) = arguments.split_first() else {
+                    bail!("{} needs a recipe", target);
+                };
+                let Item::String(recipe) = cp.get(*recipe)? else {
+                    bail!("The recipe of {} has to be a string", target);
+                };
+                let recipe = decode_mutf8(cp.get(recipe.string)?.content.as_bytes())?;
+
+                let mut pieces = Vec::new();
+                let mut literal = Vec::new();
+                let (mut argument, mut constant) = (0, 0);
+                for unit in recipe{
+                    match unit {
+                        TAG_ARGUMENT => {
+                            if !literal.is_empty(){
+                                pieces.push(Piece::Literal(std::mem::take(&mut literal).into_boxed_slice()));
+                            }
+                            pieces.push(Piece::Argument(argument));
+                            argument += 1;
+                        }
+                        TAG_CONSTANT => {
+                            let Some(index) = constants.get(constant) else {
+                                bail!("Concatenation recipe refers to missing constant {}", constant);
+                            };
+                            literal.extend(constant_text(cp, *index)?);
+                            constant += 1;
+                        }
+                        unit => literal.push(unit)
+                    }
+                }
+                if !literal.is_empty(){
+                    pieces.push(Piece::Literal(literal.into_boxed_slice()));
+                }
+                if argument != params.len(){
+                    bail!("Concatenation recipe uses {} of {} arguments", argument, params.len());
+                }
+                Ok(Self::Concat(Concat{
+                    pieces,
+                    arguments: params
+                }))
+            }
+            _ => bail!(
+                "Unsupported bootstrap method {} for invokedynamic {}{} in {}",
+                target,
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(descriptor),
+                String::from_utf8_lossy(&class.name)
+            )
+        }
+    }
+}
+
+/// Text a constant of a concatenation recipe is inserted as.
+fn constant_text(cp: &ConstantPool, index: Index<Item>) -> anyhow::Result<Vec<u16>>{
+    let text = match Constant::read(cp, index)? {
+        Some(Constant::String(units)) => return Ok(units.into_vec()),
+        Some(Constant::Int(i)) => i.to_string(),
+        Some(Constant::Long(l)) => l.to_string(),
+        Some(Constant::Float(_) | Constant::Double(_)) => bail!("Floating point constants in concatenation recipes are not supported"),
+        None => bail!("Unsupported concatenation constant {:?}", cp.get(index)?)
+    };
+    Ok(text.encode_utf16().collect())
+}
+
+/// Every call site of the class, by the index of its `InvokeDynamic` constant.
+/// Call sites that can't be desugared are reported as errors.
+pub fn call_sites(class: &ParsedClass) -> anyhow::Result<Vec<(u16, anyhow::Result<CallSite>)>>{
+    let mut parsed = Class::new(&class.bytes)?;
+    let cp = parsed.pool()?;
+    let mut sites = Vec::new();
+    for (index, item) in cp.iter_indices(){
+        if let Item::InvokeDynamic(_) = item{
+            let index = Index::new(index.as_u16())?;
+            sites.push((index.as_u16(), CallSite::read(class, cp, index)));
+        }
+    }
+    Ok(sites)
+}
+
+/// Synthesizes the classes implementing the lambdas created in `classes`.
+pub fn lambda_classes(classes: &[ParsedClass]) -> anyhow::Result<Vec<ParsedClass>>{
+    let mut synthesized = Vec::new();
+    for class in classes{
+        for (_, site) in call_sites(class)?{
+            if let Ok(CallSite::Lambda(lambda)) = site{
+                let bytes = lambda.synthesize().with_context(|| format!("Synthesizing {}", String::from_utf8_lossy(&lambda.class)))?;
+                synthesized.push(ParsedClass::parse(&bytes)?);
+            }
+        }
+    }
+    Ok(synthesized)
+}
+
+/// Bytecode of the method implementing a lambda, planned up front because
+/// the class writer can't report our errors.
+enum Op{
+    Load(JavaType, u16),
+    GetField(String, String),
+    /// Calls a static `valueOf` or virtual `xValue` method of a wrapper class.
+    Box(&'static str, String),
+    Unbox(&'static str, String, String),
+    Widen(JavaType, JavaType),
+    New(String),
+    Dup,
+    Invoke(MethodKind, bool, String, String, String, u8),
+    Pop(bool),
+    Return(Option<JavaType>),
+}
+
+fn text(bytes: &[u8]) -> anyhow::Result<String>{
+    Ok(std::str::from_utf8(bytes).with_context(|| format!("{:?} is not valid UTF-8", bytes))?.to_owned())
+}
+
+fn is_primitive(t: &JavaType) -> bool{
+    matches!(t, JavaType::Bool(0) | JavaType::Byte(0) | JavaType::Char(0) | JavaType::Short(0) | JavaType::Int(0) | JavaType::Float(0) | JavaType::Double(0) | JavaType::Long(0))
+}
+
+fn slots(t: &JavaType) -> u16{
+    match t {
+        JavaType::Long(0) | JavaType::Double(0) => 2,
+        _ => 1
+    }
+}
+
+/// Wrapper class and unboxing method of a primitive type.
+fn wrapper(t: &JavaType) -> (&'static str, &'static str){
+    match t {
+        JavaType::Bool(_) => ("java/lang/Boolean", "booleanValue"),
+        JavaType::Byte(_) => ("java/lang/Byte", "byteValue"),
+        JavaType::Char(_) => ("java/lang/Character", "charValue"),
+        JavaType::Short(_) => ("java/lang/Short", "shortValue"),
+        JavaType::Int(_) => ("java/lang/Integer", "intValue"),
+        JavaType::Float(_) => ("java/lang/Float", "floatValue"),
+        JavaType::Double(_) => ("java/lang/Double", "doubleValue"),
+        JavaType::Long(_) => ("java/lang/Long", "longValue"),
+        JavaType::Reference(..) => unreachable!("References have no wrapper")
+    }
+}
+
+/// Converts a value of type `from` to `to` the way method handles adapt arguments and results.
+fn adapt(ops: &mut Vec<Op>, from: &JavaType, to: &JavaType) -> anyhow::Result<()>{
+    if from == to{
+        return Ok(());
+    }
+    let (from_primitive, to_primitive) = (is_primitive(from), is_primitive(to));
+    match (from_primitive, to_primitive) {
+        // References are passed on without a `checkcast`, noak 0.5 encodes it
+        // with the opcode of `anewarray`. The metafactory has already checked
+        // the types, only a heap polluted by unchecked generics slips through.
+        (false, false) => (),
+        (true, false) => {
+            let (class, _) = wrapper(from);
+            let descriptor = format!("({})L{};", text(&from.descriptor())?, class);
+            ops.push(Op::Box(class, descriptor));
+        }
+        (false, true) => {
+            let (class, method) = wrapper(to);
+            ops.push(Op::Unbox(class, method.into(), format!("(){}", text(&to.descriptor())?)));
+        }
+        (true, true) => {
+            let widening = matches!(
+                (from, to),
+                (JavaType::Byte(_) | JavaType::Short(_) | JavaType::Char(_) | JavaType::Int(_), JavaType::Int(_) | JavaType::Long(_) | JavaType::Float(_) | JavaType::Double(_)) |
+                (JavaType::Byte(_), JavaType::Short(_)) |
+                (JavaType::Long(_), JavaType::Float(_) | JavaType::Double(_)) |
+                (JavaType::Float(_), JavaType::Double(_))
+            );
+            if !widening{
+                bail!("Can't adapt {} to {}", String::from_utf8_lossy(&from.descriptor()), String::from_utf8_lossy(&to.descriptor()));
+            }
+            ops.push(Op::Widen(from.clone(), to.clone()));
+        }
+    }
+    Ok(())
+}
+
+impl Lambda{
+    /// Plans the body of the implemented method.
+    fn body(&self) -> anyhow::Result<(Vec<Op>, u16, u16)>{
+        let Ok((erased_params, erased_result)) = descriptor_parser::method(&self.erased) else {
+            bail!("Invalid method descriptor {}", String::from_utf8_lossy(&self.erased));
+        };
+        let Ok((mut params, result)) = descriptor_parser::method(&self.implementation.descriptor) else {
+            bail!("Invalid method descriptor {}", self.implementation);
+        };
+        let class = text(&self.implementation.class)?;
+        let receiver = JavaType::Reference(0, self.implementation.class.clone());
+        if matches!(self.kind, MethodKind::InvokeVirtual | MethodKind::InvokeInterface | MethodKind::InvokeSpecial){
+            params.insert(0, receiver.clone());
+        }
+        if params.len() != self.captured.len() + erased_params.len(){
+            bail!("{} takes {} arguments, the lambda provides {}", self.implementation, params.len(), self.captured.len() + erased_params.len());
+        }
+
+        let mut ops = Vec::new();
+        let mut stack = 0;
+        if self.kind == MethodKind::NewInvokeSpecial{
+            ops.push(Op::New(class.clone()));
+            ops.push(Op::Dup);
+            stack += 2;
+        }
+        let this = text(&self.class)?;
+        for (i, captured) in self.captured.iter().enumerate(){
+            ops.push(Op::Load(JavaType::Reference(0, self.class.clone()), 0));
+            ops.push(Op::GetField(captured_field(i), text(&captured.descriptor())?));
+            adapt(&mut ops, captured, &params[i])?;
+        }
+        let mut slot = 1;
+        for (param, target) in erased_params.iter().zip(params[self.captured.len()..].iter()){
+            ops.push(Op::Load(param.clone(), slot));
+            adapt(&mut ops, param, target)?;
+            slot += slots(param);
+        }
+        stack += params.iter().map(slots).sum::<u16>();
+        let argument_slots = params.iter().skip(usize::from(self.kind != MethodKind::InvokeStatic && self.kind != MethodKind::NewInvokeSpecial)).map(slots).sum::<u16>();
+
+        ops.push(Op::Invoke(
+            self.kind,
+            self.on_interface,
+            class,
+            text(&self.implementation.name)?,
+            text(&self.implementation.descriptor)?,
+            (argument_slots + 1).min(255) as u8
+        ));
+        let produced = if self.kind == MethodKind::NewInvokeSpecial {Some(receiver)} else {result};
+        match (&produced, &erased_result) {
+            (None, None) => (),
+            (Some(produced), None) => ops.push(Op::Pop(slots(produced) == 2)),
+            (Some(produced), Some(expected)) => adapt(&mut ops, produced, expected)?,
+            (None, Some(_)) => bail!("{} returns nothing, {} expects a value", self.implementation, this)
+        }
+        ops.push(Op::Return(erased_result));
+        // Boxing and unboxing take at most one more slot than the arguments.
+        Ok((ops, stack + 2, slot))
+    }
+
+    /// Writes the class file of the lambda class.
+    fn synthesize(&self) -> anyhow::Result<Vec<u8>>{
+        let (ops, max_stack, max_locals) = self.body()?;
+        let this = text(&self.class)?;
+        let interface = text(&self.interface)?;
+        let method = text(&self.method)?;
+        let erased = text(&self.erased)?;
+        let fields = self.captured.iter().enumerate()
+            .map(|(i, t)| Ok((captured_field(i), text(&t.descriptor())?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let write = || -> Result<Vec<u8>, EncodeError> {ClassWriter::new()
+            .version(Version::V6)?
+            .access_flags(AccessFlags::FINAL | AccessFlags::SUPER | AccessFlags::SYNTHETIC)?
+            .this_class(&*this)?
+            .super_class("java/lang/Object")?
+            .interfaces(|interfaces|{
+                interfaces.begin(|i| i.interface(&*interface))?;
+                Ok(())
+            })?
+            .fields(|writer|{
+                for (field, descriptor) in fields.iter(){
+                    writer.begin(|f|{
+                        f.access_flags(AccessFlags::PRIVATE | AccessFlags::FINAL)?
+                            .name(&**field)?
+                            .descriptor(&**descriptor)?
+                            .attributes(|_| Ok(()))
+                    })?;
+                }
+                Ok(())
+            })?
+            .methods(|methods|{
+                methods.begin(|m|{
+                    m.access_flags(AccessFlags::PUBLIC)?
+                        .name(&*method)?
+                        .descriptor(&*erased)?
+                        .attributes(|attributes|{
+                            attributes.begin(|a| a.code(|code|{
+                                code.max_stack(max_stack)?
+                                    .max_locals(max_locals)?
+                                    .instructions(|out|{
+                                        for op in ops.iter(){
+                                            match op {
+                                                Op::Load(t, slot) => {
+                                                    let wide = *slot > u8::MAX as u16;
+                                                    let slot8 = *slot as u8;
+                                                    match (t, wide) {
+                                                        (JavaType::Long(0), false) => out.lload(slot8)?,
+                                                        (JavaType::Long(0), true) => out.lload_wide(*slot)?,
+                                                        (JavaType::Float(0), false) => out.fload(slot8)?,
+                                                        (JavaType::Float(0), true) => out.fload_wide(*slot)?,
+                                                        (JavaType::Double(0), false) => out.dload(slot8)?,
+                                                        (JavaType::Double(0), true) => out.dload_wide(*slot)?,
+                                                        (t, false) if is_primitive(t) => out.iload(slot8)?,
+                                                        (t, true) if is_primitive(t) => out.iload_wide(*slot)?,
+                                                        (_, false) => out.aload(slot8)?,
+                                                        (_, true) => out.aload_wide(*slot)?,
+                                                    };
+                                                }
+                                                Op::GetField(name, descriptor) => {
+                                                    out.getfield(writer::cpool::FieldRef::by(&*this, (&**name, &**descriptor)))?;
+                                                }
+                                                Op::Box(class, descriptor) => {
+                                                    out.invokestatic(writer::cpool::MethodRef::by(*class, ("valueOf", &**descriptor)))?;
+                                                }
+                                                Op::Unbox(class, method, descriptor) => {
+                                                    out.invokevirtual(writer::cpool::MethodRef::by(*class, (&**method, &**descriptor)))?;
+                                                }
+                                                Op::Widen(from, to) => {
+                                                    match (from, to) {
+                                                        (JavaType::Long(_), JavaType::Float(_)) => out.l2f()?,
+                                                        (JavaType::Long(_), JavaType::Double(_)) => out.l2d()?,
+                                                        (JavaType::Float(_), JavaType::Double(_)) => out.f2d()?,
+                                                        (_, JavaType::Long(_)) => out.i2l()?,
+                                                        (_, JavaType::Float(_)) => out.i2f()?,
+                                                        (_, JavaType::Double(_)) => out.i2d()?,
+                                                        // Small integers are all ints on the operand stack.
+                                                        _ => out
+                                                    };
+                                                }
+                                                Op::New(class) => {
+                                                    out.new(&**class)?;
+                                                }
+                                                Op::Dup => {
+                                                    out.dup()?;
+                                                }
+                                                Op::Invoke(kind, on_interface, class, name, descriptor, count) => {
+                                                    let (class, nt) = (&**class, (&**name, &**descriptor));
+                                                    match (kind, on_interface) {
+                                                        (MethodKind::InvokeInterface, _) => out.invokeinterface(writer::cpool::InterfaceMethodRef::by(class, nt), *count)?,
+                                                        (MethodKind::InvokeVirtual, _) => out.invokevirtual(writer::cpool::MethodRef::by(class, nt))?,
+                                                        (MethodKind::InvokeStatic, true) => out.invokestatic(writer::cpool::InterfaceMethodRef::by(class, nt))?,
+                                                        (MethodKind::InvokeStatic, false) => out.invokestatic(writer::cpool::MethodRef::by(class, nt))?,
+                                                        (_, true) => out.invokespecial(writer::cpool::InterfaceMethodRef::by(class, nt))?,
+                                                        (_, false) => out.invokespecial(writer::cpool::MethodRef::by(class, nt))?,
+                                                    };
+                                                }
+                                                Op::Pop(wide) => {
+                                                    if *wide {out.pop2()?} else {out.pop()?};
+                                                }
+                                                Op::Return(t) => {
+                                                    match t {
+                                                        None => out.return_()?,
+                                                        Some(JavaType::Long(0)) => out.lreturn()?,
+                                                        Some(JavaType::Float(0)) => out.freturn()?,
+                                                        Some(JavaType::Double(0)) => out.dreturn()?,
+                                                        Some(t) if is_primitive(t) => out.ireturn()?,
+                                                        Some(_) => out.areturn()?,
+                                                    };
+                                                }
+                                            }
+                                        }
+                                        Ok(())
+                                    })?
+                                    .exceptions(|_| Ok(()))?
+                                    .attributes(|_| Ok(()))
+                            }))?;
+                            Ok(())
+                        })
+                })?;
+                Ok(())
+            })?
+            .attributes(|_| Ok(()))?
+            .finish()};
+        // Encoding errors aren't `Send`, so they can't be wrapped directly.
+        write().map_err(|e| anyhow::anyhow!("Writing the class file failed: {}", e))
+    }
+}
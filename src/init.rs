@@ -0,0 +1,259 @@
+//! Class initialization.
+//!
+//! A class' `<clinit>` has to run the first time the class is touched by
+//! `getstatic`, `putstatic`, `invokestatic` or `new`. Classes whose
+//! initializer only stores constants into their own static fields have those
+//! stores folded into the initial values of the fields instead, the rest get
+//! an init-state global that is checked by a barrier at each of those sites.
+//!
+//! Modules whose memory is shared between threads keep static fields and
+//! init states in the data segment instead of globals, so that every
+//! instance sees the same ones.
+
+use std::{collections::{HashMap, HashSet}, hash::Hash};
+
+use anyhow::bail;
+use noak::{AccessFlags, reader::attributes::RawInstruction};
+use wasm_encoder::{ConstExpr, ValType};
+
+use crate::{code::Value, data::{Constant, ParsedClass}, layout::DataSegment, module::ModuleBuilder, program::{MethodKey, Program}, strings::StringTable};
+
+pub const CLINIT: &[u8] = b"<clinit>";
+pub const CLINIT_DESCRIPTOR: &[u8] = b"()V";
+
+/// Values of an init-state global.
+pub const UNINITIALIZED: i32 = 0;
+/// Set before `<clinit>` runs, so a recursive use of the class from its own
+/// initializer doesn't run it again. In shared memory it's set once
+/// `<clinit>` returned, see [`INITIALIZING`].
+pub const INITIALIZED: i32 = 1;
+/// `<clinit>` or a superclass' threw, every later use of the class throws
+/// `NoClassDefFoundError`.
+pub const ERRONEOUS: i32 = 2;
+/// With [`crate::runtime::Monitors::Atomic`], `<clinit>` is running on the
+/// thread whose id is above this, and the other threads wait for it.
+pub const INITIALIZING: i32 = 3;
+
+pub enum Initializer{
+    /// The class has no `<clinit>`.
+    Absent,
+    /// `<clinit>` only stores constants into the class' own static fields.
+    /// `None` stores `null`.
+    Constant(Vec<(Box<[u8]>, Option<Constant>)>),
+    /// `<clinit>` has to run at runtime.
+    Code,
+}
+
+impl Initializer{
+    pub fn analyze(class: &ParsedClass) -> anyhow::Result<Self>{
+        let stores = class.with_code(CLINIT, CLINIT_DESCRIPTOR, |cp, code|{
+            let mut pending = None;
+            let mut stores = Vec::new();
+
+            for instruction in code.raw_instructions(){
+                let (_, instruction) = instruction?;
+                let constant = match instruction {
+                    RawInstruction::AConstNull => None,
+                    RawInstruction::IConstM1 => Some(Constant::Int(-1)),
+                    RawInstruction::IConst0 => Some(Constant::Int(0)),
+                    RawInstruction::IConst1 => Some(Constant::Int(1)),
+                    RawInstruction::IConst2 => Some(Constant::Int(2)),
+                    RawInstruction::IConst3 => Some(Constant::Int(3)),
+                    RawInstruction::IConst4 => Some(Constant::Int(4)),
+                    RawInstruction::IConst5 => Some(Constant::Int(5)),
+                    RawInstruction::BIPush { value } => Some(Constant::Int(value as i32)),
+                    RawInstruction::SIPush { value } => Some(Constant::Int(value as i32)),
+                    RawInstruction::LConst0 => Some(Constant::Long(0)),
+                    RawInstruction::LConst1 => Some(Constant::Long(1)),
+                    RawInstruction::FConst0 => Some(Constant::Float(0.0)),
+                    RawInstruction::FConst1 => Some(Constant::Float(1.0)),
+                    RawInstruction::FConst2 => Some(Constant::Float(2.0)),
+                    RawInstruction::DConst0 => Some(Constant::Double(0.0)),
+                    RawInstruction::DConst1 => Some(Constant::Double(1.0)),
+                    RawInstruction::LdC { index } |
+                    RawInstruction::LdCW { index } |
+                    RawInstruction::LdC2W { index } => match Constant::read(cp, index)? {
+                        Some(c) => Some(c),
+                        None => return Ok(None)
+                    },
+                    RawInstruction::PutStatic { index } => {
+                        let fr = cp.get(index)?;
+                        let owner = cp.get(cp.get(fr.class)?.name)?.content.as_bytes();
+                        let name = cp.get(cp.get(fr.name_and_type)?.name)?.content.as_bytes();
+                        match pending.take() {
+                            Some(value) if owner == &*class.name && class.field(name).is_some() => {
+                                stores.push((name.into(), value));
+                                continue;
+                            }
+                            _ => return Ok(None)
+                        }
+                    }
+                    RawInstruction::Return => break,
+                    _ => return Ok(None)
+                };
+                if pending.replace(constant).is_some(){
+                    return Ok(None);
+                }
+            }
+
+            Ok(Some(stores))
+        })?;
+
+        Ok(match stores {
+            None => Self::Absent,
+            Some(Some(stores)) => Self::Constant(stores),
+            Some(None) => Self::Code
+        })
+    }
+}
+
+/// Which classes need initialization barriers.
+pub struct InitPlan{
+    initializers: HashMap<Box<[u8]>, Initializer>,
+    barriers: HashSet<Box<[u8]>>,
+}
+
+impl InitPlan{
+    pub fn analyze(program: &Program) -> anyhow::Result<Self>{
+        let mut initializers = HashMap::new();
+        for class in program.classes(){
+            initializers.insert(class.name.clone(), Initializer::analyze(class)?);
+        }
+
+        // Initializing a class initializes its superclasses first, so a
+        // barrier is needed if any of them has code to run.
+        let barriers = program.classes().into_iter()
+            .filter(|c| program.superclasses(&c.name).any(|s| matches!(initializers.get(&s.name), Some(Initializer::Code))))
+            .map(|c| c.name.clone())
+            .collect();
+
+        Ok(Self{
+            initializers,
+            barriers
+        })
+    }
+
+    pub fn initializer(&self, class: &[u8]) -> Option<&Initializer>{
+        self.initializers.get(class)
+    }
+
+    pub fn needs_barrier(&self, class: &[u8]) -> bool{
+        self.barriers.contains(class)
+    }
+
+    /// Classes that need barriers, sorted by name.
+    pub fn barriers(&self) -> Vec<&[u8]>{
+        let mut barriers = self.barriers.iter().map(|c| &**c).collect::<Vec<_>>();
+        barriers.sort();
+        barriers
+    }
+}
+
+/// Declaring class and name of a field.
+type FieldKey = (Box<[u8]>, Box<[u8]>);
+
+/// Where a static field or an init state is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Slot{
+    Global(u32),
+    /// Address in the data segment, aligned to the size of the value.
+    Memory(u32),
+}
+
+/// Globals or memory holding static fields and init states.
+pub struct Statics{
+    fields: HashMap<FieldKey, Slot>,
+    states: HashMap<Box<[u8]>, Slot>,
+}
+
+impl Statics{
+    /// Adds a global for every static field and for the init state of every
+    /// class that needs a barrier, or places them in `shared` if given. The
+    /// classes needing barriers also get their `<clinit>` declared.
+    pub fn declare(program: &Program, plan: &InitPlan, strings: &StringTable, module: &mut ModuleBuilder, mut shared: Option<&mut DataSegment>) -> anyhow::Result<Self>{
+        let mut fields = HashMap::new();
+        let mut states = HashMap::new();
+
+        for class in program.classes(){
+            let folded = match plan.initializer(&class.name) {
+                Some(Initializer::Constant(stores)) => stores.as_slice(),
+                _ => &[]
+            };
+
+            for field in class.fields.iter().filter(|f| f.flags.contains(AccessFlags::STATIC)){
+                let value = Value::of_descriptor(&field.descriptor);
+                let constant = match folded.iter().rev().find(|(name, _)| *name == field.name) {
+                    Some((_, constant)) => constant.as_ref(),
+                    None => field.constant.as_ref()
+                };
+                // The initial value as a constant expression and as it's stored in memory.
+                let (init, bytes) = match constant {
+                    None => (zero(value), vec![0; value_size(value)]),
+                    Some(Constant::Int(i)) => (ConstExpr::i32_const(*i), i.to_le_bytes().to_vec()),
+                    Some(Constant::Long(l)) => (ConstExpr::i64_const(*l), l.to_le_bytes().to_vec()),
+                    Some(Constant::Float(f)) => (ConstExpr::f32_const(*f), f.to_le_bytes().to_vec()),
+                    Some(Constant::Double(d)) => (ConstExpr::f64_const(*d), d.to_le_bytes().to_vec()),
+                    Some(Constant::String(s)) => match strings.address(s) {
+                        Some(address) => (ConstExpr::i32_const(address as i32), address.to_le_bytes().to_vec()),
+                        None => bail!("String constant of {}.{} was never interned", String::from_utf8_lossy(&class.name), String::from_utf8_lossy(&field.name))
+                    }
+                };
+                let slot = match shared.as_deref_mut() {
+                    Some(segment) => Slot::Memory(store(segment, &bytes)),
+                    None => Slot::Global(module.add_global(value.val_type(), true, init))
+                };
+                fields.insert((class.name.clone(), field.name.clone()), slot);
+            }
+
+            if plan.needs_barrier(&class.name){
+                let state = match shared.as_deref_mut() {
+                    Some(segment) => Slot::Memory(store(segment, &UNINITIALIZED.to_le_bytes())),
+                    None => Slot::Global(module.add_global(ValType::I32, true, ConstExpr::i32_const(UNINITIALIZED)))
+                };
+                states.insert(class.name.clone(), state);
+                if class.method(CLINIT, CLINIT_DESCRIPTOR).is_some(){
+                    module.declare_function(MethodKey::new(&class.name, CLINIT, CLINIT_DESCRIPTOR), &[], &[]);
+                }
+            }
+        }
+
+        Ok(Self{
+            fields,
+            states
+        })
+    }
+
+    /// Slot of a static field, `class` has to be the declaring class.
+    pub fn field(&self, class: &[u8], name: &[u8]) -> Option<Slot>{
+        self.fields.get(&(class.into(), name.into())).copied()
+    }
+
+    pub fn init_state(&self, class: &[u8]) -> Option<Slot>{
+        self.states.get(class).copied()
+    }
+}
+
+/// Bytes a value takes in memory.
+fn value_size(value: Value) -> usize{
+    match value.val_type() {
+        ValType::I64 | ValType::F64 => 8,
+        _ => 4
+    }
+}
+
+/// Places the value in the segment, aligned to its size.
+fn store(segment: &mut DataSegment, bytes: &[u8]) -> u32{
+    let address = segment.alloc(bytes.len() as u32, bytes.len() as u32);
+    segment.write_bytes(address, bytes);
+    address
+}
+
+fn zero(value: Value) -> ConstExpr{
+    match value.val_type() {
+        ValType::I64 => ConstExpr::i64_const(0),
+        ValType::F32 => ConstExpr::f32_const(0.0),
+        ValType::F64 => ConstExpr::f64_const(0.0),
+        _ => ConstExpr::i32_const(0),
+    }
+}
+
@@ -0,0 +1,517 @@
+//! Construction of the SSA form from bytecode.
+//!
+//! Locals and operand stack slots are variables that are read and written
+//! while walking the blocks, following "Simple and Efficient Construction of
+//! Static Single Assignment Form" by Braun et al.: reading a variable a block
+//! doesn't define looks it up in the predecessors, placing a phi where they
+//! join. Blocks are sealed once all their predecessors have been walked,
+//! until then phis are left incomplete. Trivial phis are left for
+//! [`propagate_copies`](super::propagate_copies) to remove.
+
+use std::{collections::{BTreeMap, HashMap}, ops::Range};
+
+use anyhow::bail;
+use noak::{AccessFlags, reader::{attributes::{Index as Offset, RawInstruction}, cpool::{ConstantPool, Item}}};
+
+use crate::{blocks::{self, Block, Handler}, code::{self, local, Stack, Value}, data::ParsedMethod, descriptor::descriptor_parser};
+
+use super::{BasicBlock, BlockId, Constant, Function, Inst, Op, Phi, Terminator, ValueId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Variable{
+    /// A local variable slot while it holds the type.
+    Local(u16, Value),
+    /// The operand stack slot at a depth, between blocks.
+    Stack(usize, Value),
+}
+
+impl Variable{
+    fn value(self) -> Value{
+        match self {
+            Self::Local(_, value) | Self::Stack(_, value) => value
+        }
+    }
+}
+
+/// The constant an instruction pushes, if it's a plain number or `null`.
+fn constant(instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<Option<Constant>>{
+    Ok(Some(match instruction {
+        RawInstruction::AConstNull => Constant::Null,
+        RawInstruction::IConstM1 => Constant::Int(-1),
+        RawInstruction::IConst0 => Constant::Int(0),
+        RawInstruction::IConst1 => Constant::Int(1),
+        RawInstruction::IConst2 => Constant::Int(2),
+        RawInstruction::IConst3 => Constant::Int(3),
+        RawInstruction::IConst4 => Constant::Int(4),
+        RawInstruction::IConst5 => Constant::Int(5),
+        RawInstruction::LConst0 => Constant::Long(0),
+        RawInstruction::LConst1 => Constant::Long(1),
+        RawInstruction::FConst0 => Constant::Float(0.0),
+        RawInstruction::FConst1 => Constant::Float(1.0),
+        RawInstruction::FConst2 => Constant::Float(2.0),
+        RawInstruction::DConst0 => Constant::Double(0.0),
+        RawInstruction::DConst1 => Constant::Double(1.0),
+        RawInstruction::BIPush { value } => Constant::Int(*value as i32),
+        RawInstruction::SIPush { value } => Constant::Int(*value as i32),
+        RawInstruction::LdC { index } |
+        RawInstruction::LdCW { index } |
+        RawInstruction::LdC2W { index } => match cp.get(*index)? {
+            Item::Integer(i) => Constant::Int(i.value),
+            Item::Float(f) => Constant::Float(f.value),
+            Item::Long(l) => Constant::Long(l.value),
+            Item::Double(d) => Constant::Double(d.value),
+            _ => return Ok(None)
+        },
+        _ => return Ok(None)
+    }))
+}
+
+struct Builder<'a>{
+    function: Function<'a>,
+    /// The value of each variable at the end of a block, or where the walk is.
+    definitions: HashMap<(BlockId, Variable), ValueId>,
+    /// Phis of unsealed blocks that still need their operands.
+    incomplete: HashMap<BlockId, Vec<(Variable, ValueId)>>,
+    sealed: Vec<bool>,
+}
+
+impl<'a> Builder<'a>{
+    fn block(&mut self, id: BlockId) -> &mut BasicBlock<'a>{
+        self.function.block_mut(id).expect("Only reachable blocks are walked")
+    }
+
+    fn write(&mut self, block: BlockId, variable: Variable, value: ValueId){
+        self.definitions.insert((block, variable), value);
+    }
+
+    fn read(&mut self, block: BlockId, variable: Variable) -> ValueId{
+        if let Some(value) = self.definitions.get(&(block, variable)){
+            return *value;
+        }
+        let value = if !self.sealed[block.0 as usize] {
+            let phi = self.phi(block, variable.value());
+            self.incomplete.entry(block).or_default().push((variable, phi));
+            phi
+        }
+        else if block == Function::ENTRY {
+            // Locals start out as zero, like wasm locals.
+            let value = self.function.new_value(variable.value());
+            self.block(block).instructions.push(Inst{
+                result: Some(value),
+                op: Op::Const(Constant::zero(variable.value())),
+                args: vec![]
+            });
+            value
+        }
+        else if let [predecessor] = self.block(block).predecessors[..] {
+            self.read(predecessor, variable)
+        }
+        else {
+            // Written first, so that loops find the phi instead of placing another.
+            let phi = self.phi(block, variable.value());
+            self.write(block, variable, phi);
+            self.complete(block, variable, phi);
+            phi
+        };
+        self.write(block, variable, value);
+        value
+    }
+
+    fn phi(&mut self, block: BlockId, value: Value) -> ValueId{
+        let result = self.function.new_value(value);
+        self.block(block).phis.push(Phi{
+            result,
+            incoming: vec![]
+        });
+        result
+    }
+
+    /// Reads the variable in every predecessor for the operands of the phi.
+    fn complete(&mut self, block: BlockId, variable: Variable, phi: ValueId){
+        for predecessor in self.block(block).predecessors.clone(){
+            let value = self.read(predecessor, variable);
+            let phi = self.block(block).phis.iter_mut().find(|p| p.result == phi).expect("The phi is in its block");
+            phi.incoming.push((predecessor, value));
+        }
+    }
+
+    fn seal(&mut self, block: BlockId){
+        self.sealed[block.0 as usize] = true;
+        for (variable, phi) in self.incomplete.remove(&block).unwrap_or_default(){
+            self.complete(block, variable, phi);
+        }
+    }
+
+    /// `iinc`, as an addition of a constant.
+    fn increment(&mut self, block: BlockId, at: Offset, slot: u16, amount: i32){
+        let variable = Variable::Local(slot, Value::I32);
+        let old = self.read(block, variable);
+        let amount = self.instruction(block, Op::Const(Constant::Int(amount)), vec![], Some(Value::I32)).unwrap();
+        let new = self.instruction(block, Op::Bytecode(at, &RawInstruction::IAdd), vec![old, amount], Some(Value::I32)).unwrap();
+        self.write(block, variable, new);
+    }
+
+    fn instruction(&mut self, block: BlockId, op: Op<'a>, args: Vec<ValueId>, result: Option<Value>) -> Option<ValueId>{
+        let result = result.map(|r| self.function.new_value(r));
+        self.block(block).instructions.push(Inst{result, op, args});
+        result
+    }
+}
+
+/// Whether the lowering of the instruction can throw.
+fn may_throw(instruction: &RawInstruction) -> bool{
+    local(instruction).is_none() && !matches!(
+        instruction,
+        RawInstruction::Nop | RawInstruction::AConstNull | RawInstruction::IConstM1 |
+        RawInstruction::IConst0 | RawInstruction::IConst1 | RawInstruction::IConst2 |
+        RawInstruction::IConst3 | RawInstruction::IConst4 | RawInstruction::IConst5 |
+        RawInstruction::LConst0 | RawInstruction::LConst1 | RawInstruction::FConst0 |
+        RawInstruction::FConst1 | RawInstruction::FConst2 | RawInstruction::DConst0 |
+        RawInstruction::DConst1 | RawInstruction::BIPush { .. } | RawInstruction::SIPush { .. } |
+        RawInstruction::LdC { .. } | RawInstruction::LdCW { .. } | RawInstruction::LdC2W { .. } |
+        RawInstruction::Pop | RawInstruction::Pop2 | RawInstruction::Dup | RawInstruction::DupX1 |
+        RawInstruction::DupX2 | RawInstruction::Dup2 | RawInstruction::Dup2X1 | RawInstruction::Dup2X2 |
+        RawInstruction::Swap |
+        RawInstruction::IAdd | RawInstruction::ISub | RawInstruction::IMul | RawInstruction::IAnd |
+        RawInstruction::IOr | RawInstruction::IXor | RawInstruction::IShL | RawInstruction::IShR |
+        RawInstruction::IUShR | RawInstruction::INeg |
+        RawInstruction::LAdd | RawInstruction::LSub | RawInstruction::LMul | RawInstruction::LAnd |
+        RawInstruction::LOr | RawInstruction::LXor | RawInstruction::LShL | RawInstruction::LShR |
+        RawInstruction::LUShR | RawInstruction::LNeg | RawInstruction::LCmp |
+        RawInstruction::FAdd | RawInstruction::FSub | RawInstruction::FMul | RawInstruction::FDiv |
+        RawInstruction::FRem | RawInstruction::FNeg | RawInstruction::FCmpL | RawInstruction::FCmpG |
+        RawInstruction::DAdd | RawInstruction::DSub | RawInstruction::DMul | RawInstruction::DDiv |
+        RawInstruction::DRem | RawInstruction::DNeg | RawInstruction::DCmpL | RawInstruction::DCmpG |
+        RawInstruction::I2L | RawInstruction::I2F | RawInstruction::I2D | RawInstruction::I2B |
+        RawInstruction::I2C | RawInstruction::I2S | RawInstruction::L2I | RawInstruction::L2F |
+        RawInstruction::L2D | RawInstruction::F2I | RawInstruction::F2L | RawInstruction::F2D |
+        RawInstruction::D2I | RawInstruction::D2L | RawInstruction::D2F |
+        RawInstruction::IInc { .. } | RawInstruction::IIncW { .. } |
+        RawInstruction::Goto { .. } | RawInstruction::GotoW { .. } |
+        RawInstruction::IfEq { .. } | RawInstruction::IfNe { .. } | RawInstruction::IfLt { .. } |
+        RawInstruction::IfGe { .. } | RawInstruction::IfGt { .. } | RawInstruction::IfLe { .. } |
+        RawInstruction::IfICmpEq { .. } | RawInstruction::IfICmpNe { .. } | RawInstruction::IfICmpLt { .. } |
+        RawInstruction::IfICmpGe { .. } | RawInstruction::IfICmpGt { .. } | RawInstruction::IfICmpLe { .. } |
+        RawInstruction::IfACmpEq { .. } | RawInstruction::IfACmpNe { .. } |
+        RawInstruction::IfNull { .. } | RawInstruction::IfNonNull { .. } |
+        RawInstruction::TableSwitch(_) | RawInstruction::LookupSwitch(_) |
+        RawInstruction::IReturn | RawInstruction::LReturn | RawInstruction::FReturn |
+        RawInstruction::DReturn | RawInstruction::AReturn | RawInstruction::Return
+    )
+}
+
+/// A part of a bytecode block that is a block of its own.
+#[derive(Clone)]
+struct Piece{
+    instructions: Range<usize>,
+    /// Offsets of the handlers the part can throw to.
+    handlers: Vec<u32>,
+}
+
+/// Splits a block before every store to a local that follows an instruction
+/// that can throw to a handler, so that handlers see the locals the part
+/// ends with.
+fn pieces(instructions: &[(Offset, RawInstruction)], block: &Block, handlers: &[Handler]) -> Vec<Piece>{
+    let mut pieces = Vec::new();
+    let mut start = block.instructions.start;
+    let mut caught = Vec::new();
+    for i in block.instructions.clone(){
+        let (at, instruction) = &instructions[i];
+        let stores = code::is_store(instruction) || matches!(instruction, RawInstruction::IInc { .. } | RawInstruction::IIncW { .. });
+        if stores && !caught.is_empty(){
+            pieces.push(Piece{
+                instructions: start..i,
+                handlers: std::mem::take(&mut caught)
+            });
+            start = i;
+        }
+        if may_throw(instruction){
+            for handler in handlers.iter().filter(|h| h.covers(at.as_u32())){
+                if !caught.contains(&handler.handler){
+                    caught.push(handler.handler);
+                }
+            }
+        }
+    }
+    pieces.push(Piece{
+        instructions: start..block.instructions.end,
+        handlers: caught
+    });
+    pieces
+}
+
+fn empty_block<'a>(start: Option<u32>) -> BasicBlock<'a>{
+    BasicBlock{
+        start,
+        predecessors: vec![],
+        phis: vec![],
+        instructions: vec![],
+        // Replaced when the block is walked.
+        terminator: Terminator::Jump(Function::ENTRY),
+        handlers: vec![]
+    }
+}
+
+/// Builds the SSA form of a method, from its instructions split into blocks
+/// and the operand stack at the start of each block.
+///
+/// The first part of every bytecode block has the block's id, the parts it
+/// is split into and the entries of the handlers follow them.
+pub fn build<'a>(cp: &ConstantPool, instructions: &'a [(Offset, RawInstruction<'a>)], blocks: &[Block], handlers: &[Handler], entries: &[Option<Vec<Value>>], method: &ParsedMethod) -> anyhow::Result<Function<'a>>{
+    let Ok((params, _)) = descriptor_parser::method(&method.descriptor) else {
+        bail!("Invalid method descriptor {}", String::from_utf8_lossy(&method.descriptor));
+    };
+    let id = |offset: u32| -> anyhow::Result<BlockId>{
+        match blocks.binary_search_by_key(&offset, |b| b.start) {
+            Ok(index) => Ok(BlockId(index as u32 + 1)),
+            Err(_) => bail!("No block starts at {}", offset)
+        }
+    };
+
+    let mut function = Function{
+        blocks: Vec::with_capacity(blocks.len() + 1),
+        types: Vec::new(),
+        this: None,
+        handlers: BTreeMap::new()
+    };
+    let mut entry = empty_block(None);
+    entry.terminator = Terminator::Jump(BlockId(1));
+    function.blocks.push(Some(entry));
+    for (block, entry) in blocks.iter().zip(entries){
+        function.blocks.push(entry.as_ref().map(|_| empty_block(Some(block.start))));
+    }
+
+    // The ids of the parts of every reached block.
+    let mut parts = vec![Vec::new(); blocks.len()];
+    for (i, block) in blocks.iter().enumerate().filter(|(i, _)| entries[*i].is_some()){
+        for (n, piece) in pieces(instructions, block, handlers).into_iter().enumerate(){
+            let part = match n {
+                0 => BlockId(i as u32 + 1),
+                _ => {
+                    let start = instructions[piece.instructions.start].0.as_u32();
+                    function.blocks.push(Some(empty_block(Some(start))));
+                    BlockId(function.blocks.len() as u32 - 1)
+                }
+            };
+            parts[i].push((part, piece));
+        }
+    }
+    for handler in handlers.iter(){
+        let target = id(handler.handler)?;
+        if function.block(target).is_none() || function.handlers.contains_key(&handler.handler){
+            continue;
+        }
+        let mut entry = empty_block(None);
+        entry.terminator = Terminator::Jump(target);
+        function.blocks.push(Some(entry));
+        function.handlers.insert(handler.handler, BlockId(function.blocks.len() as u32 - 1));
+    }
+
+    let mut edges = vec![(Function::ENTRY, BlockId(1))];
+    for (i, block) in blocks.iter().enumerate(){
+        for (n, (part, piece)) in parts[i].iter().enumerate(){
+            if n > 0{
+                edges.push((parts[i][n - 1].0, *part));
+            }
+            for handler in piece.handlers.iter(){
+                let entry = function.handlers[handler];
+                edges.push((*part, entry));
+                function.block_mut(*part).unwrap().handlers.push(entry);
+            }
+        }
+        if let Some((last, _)) = parts[i].last(){
+            for successor in block.successors.iter(){
+                edges.push((*last, id(*successor)?));
+            }
+        }
+    }
+    for (offset, entry) in function.handlers.iter(){
+        edges.push((*entry, id(*offset)?));
+    }
+    for (from, to) in edges{
+        let Some(block) = function.block_mut(to) else {
+            bail!("{} is reached from {}, which isn't", to, from);
+        };
+        block.predecessors.push(from);
+    }
+
+    let mut builder = Builder{
+        sealed: vec![false; function.blocks.len()],
+        function,
+        definitions: HashMap::new(),
+        incomplete: HashMap::new()
+    };
+    builder.sealed[0] = true;
+
+    let mut slot = 0;
+    let mut index = 0;
+    if !method.flags.contains(AccessFlags::STATIC){
+        let this = builder.instruction(Function::ENTRY, Op::Param(0), vec![], Some(Value::Ref)).unwrap();
+        builder.write(Function::ENTRY, Variable::Local(0, Value::Ref), this);
+        builder.function.this = Some(this);
+        slot += 1;
+        index += 1;
+    }
+    for param in params.iter(){
+        let value = Value::of_type(param);
+        let param = builder.instruction(Function::ENTRY, Op::Param(index), vec![], Some(value)).unwrap();
+        builder.write(Function::ENTRY, Variable::Local(slot, value), param);
+        slot += if value.is_big() {2} else {1};
+        index += 1;
+    }
+
+    // Handlers are entered with the exception as the only operand.
+    let mut filled = vec![false; builder.function.blocks.len()];
+    filled[0] = true;
+    for entry in builder.function.handlers.values().copied().collect::<Vec<_>>(){
+        let exception = builder.instruction(entry, Op::Catch, vec![], Some(Value::Ref)).unwrap();
+        builder.write(entry, Variable::Stack(0, Value::Ref), exception);
+        filled[entry.0 as usize] = true;
+    }
+
+    for (i, entry) in entries.iter().enumerate(){
+        let Some(entry) = entry else {
+            continue;
+        };
+        let mut stack = Vec::new();
+        for (n, (current, piece)) in parts[i].iter().enumerate(){
+            let current = *current;
+            for (id, _) in builder.function.blocks().filter(|(id, _)| !builder.sealed[id.0 as usize]).map(|(id, b)| (id, b.predecessors.clone())).collect::<Vec<_>>().into_iter()
+                .filter(|(_, predecessors)| predecessors.iter().all(|p| filled[p.0 as usize])){
+                builder.seal(id);
+            }
+
+            if n == 0{
+                stack = entry.iter().enumerate().map(|(depth, value)| builder.read(current, Variable::Stack(depth, *value))).collect::<Vec<_>>();
+            }
+            let mut terminator = None;
+            for (at, instruction) in instructions[piece.instructions.clone()].iter(){
+                let types = stack.iter().map(|v| builder.function.type_of(*v)).collect::<Vec<_>>();
+                if let Some((window, order)) = code::shuffle(instruction, &types){
+                    let top = stack.split_off(stack.len() - window);
+                    stack.extend(order.iter().map(|i| top[*i]));
+                    continue;
+                }
+                match instruction {
+                    RawInstruction::Nop => continue,
+                    RawInstruction::Pop => {
+                        stack.pop();
+                        continue;
+                    }
+                    RawInstruction::Pop2 => {
+                        if types.last().is_some_and(|v| v.is_small()){
+                            stack.pop();
+                        }
+                        stack.pop();
+                        continue;
+                    }
+                    _ => ()
+                }
+
+                // Typing the instruction on its own gives what it pops and pushes.
+                let mut effect = Stack::with_values(&[]);
+                code::step(&mut effect, instruction, cp)?;
+                let popped = effect.inputs().len();
+                if popped > stack.len(){
+                    bail!("{:?} at {} pops more values than the stack has", instruction, at.as_u32());
+                }
+                let args = stack.split_off(stack.len() - popped);
+                let pushed = effect.values().first().copied();
+
+                if let Some((slot, value)) = local(instruction){
+                    match args.first() {
+                        Some(stored) => builder.write(current, Variable::Local(slot, value), *stored),
+                        None => {
+                            let loaded = builder.read(current, Variable::Local(slot, value));
+                            stack.push(builder.instruction(current, Op::Copy, vec![loaded], Some(value)).unwrap());
+                        }
+                    }
+                    continue;
+                }
+                if let Some(constant) = constant(instruction, cp)?{
+                    stack.push(builder.instruction(current, Op::Const(constant), vec![], Some(constant.value())).unwrap());
+                    continue;
+                }
+
+                let next = BlockId(i as u32 + 2);
+                match instruction {
+                    RawInstruction::IInc { index, value } => builder.increment(current, *at, *index as u16, *value as i32),
+                    RawInstruction::IIncW { index, value } => builder.increment(current, *at, *index, *value as i32),
+                    RawInstruction::Goto { offset } => terminator = Some(Terminator::Jump(id(blocks::target(*at, *offset as i32))?)),
+                    RawInstruction::GotoW { offset } => terminator = Some(Terminator::Jump(id(blocks::target(*at, *offset))?)),
+                    RawInstruction::IfEq { offset } |
+                    RawInstruction::IfNe { offset } |
+                    RawInstruction::IfLt { offset } |
+                    RawInstruction::IfGe { offset } |
+                    RawInstruction::IfGt { offset } |
+                    RawInstruction::IfLe { offset } |
+                    RawInstruction::IfICmpEq { offset } |
+                    RawInstruction::IfICmpNe { offset } |
+                    RawInstruction::IfICmpLt { offset } |
+                    RawInstruction::IfICmpGe { offset } |
+                    RawInstruction::IfICmpGt { offset } |
+                    RawInstruction::IfICmpLe { offset } |
+                    RawInstruction::IfACmpEq { offset } |
+                    RawInstruction::IfACmpNe { offset } |
+                    RawInstruction::IfNull { offset } |
+                    RawInstruction::IfNonNull { offset } => terminator = Some(Terminator::Branch{
+                        at: *at,
+                        instruction,
+                        args,
+                        then: id(blocks::target(*at, *offset as i32))?,
+                        otherwise: next
+                    }),
+                    RawInstruction::TableSwitch(table) => terminator = Some(Terminator::Switch{
+                        value: args[0],
+                        cases: table.pairs().map(|p| Ok((p.key(), id(blocks::target(*at, p.offset()))?))).collect::<anyhow::Result<_>>()?,
+                        default: id(blocks::target(*at, table.default_offset()))?
+                    }),
+                    RawInstruction::LookupSwitch(lookup) => terminator = Some(Terminator::Switch{
+                        value: args[0],
+                        cases: lookup.pairs().take(lookup.pairs().count()).map(|p| Ok((p.key(), id(blocks::target(*at, p.offset()))?))).collect::<anyhow::Result<_>>()?,
+                        default: id(blocks::target(*at, lookup.default_offset()))?
+                    }),
+                    RawInstruction::IReturn |
+                    RawInstruction::LReturn |
+                    RawInstruction::FReturn |
+                    RawInstruction::DReturn |
+                    RawInstruction::AReturn |
+                    RawInstruction::Return |
+                    RawInstruction::AThrow => terminator = Some(Terminator::Exit{
+                        at: *at,
+                        instruction,
+                        args
+                    }),
+                    _ => {
+                        if let Some(result) = builder.instruction(current, Op::Bytecode(*at, instruction), args, pushed){
+                            stack.push(result);
+                        }
+                    }
+                }
+            }
+
+            let last = n + 1 == parts[i].len();
+            let terminator = match terminator {
+                Some(terminator) => terminator,
+                None if last => Terminator::Jump(BlockId(i as u32 + 2)),
+                // The parts of a block pass the stack on as it is.
+                None => Terminator::Jump(parts[i][n + 1].0)
+            };
+            if last && !matches!(terminator, Terminator::Exit{..}){
+                for (depth, value) in stack.iter().enumerate(){
+                    let variable = Variable::Stack(depth, builder.function.type_of(*value));
+                    builder.write(current, variable, *value);
+                }
+            }
+            builder.block(current).terminator = terminator;
+            filled[current.0 as usize] = true;
+        }
+    }
+
+    for id in builder.function.blocks().map(|(id, _)| id).filter(|id| !builder.sealed[id.0 as usize]).collect::<Vec<_>>(){
+        builder.seal(id);
+    }
+    // Handlers that only cover instructions that can't throw are never entered.
+    builder.function.remove_unreachable();
+    Ok(builder.function)
+}
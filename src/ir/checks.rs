@@ -0,0 +1,348 @@
+//! Finds null checks and array bounds checks that can't fail.
+//!
+//! A forward dataflow analysis tracks which values are known not to be
+//! `null` and which values are known to be below the length of an array. A
+//! value isn't `null` once it has been dereferenced, since a failed check
+//! leaves the method, when it's `this`, a new object or a constant, after
+//! the branch of an `ifnull` or `ifnonnull` that excludes `null`, when
+//! it's a caught exception, and when it's a phi of such values. A block can
+//! throw anywhere, so its handlers only get the facts it starts with. Indices are below the length of an array on
+//! the branch of a comparison with `arraylength` that says so.
+//!
+//! Indices also have to be non-negative, which holds for constants, array
+//! lengths and loop induction variables starting at such values that are
+//! only incremented by one while below the length of an array, so they
+//! can't overflow.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use noak::reader::{attributes::RawInstruction, cpool::{ConstantPool, Item}};
+
+use crate::program::MethodKey;
+
+use super::{BlockId, Constant, Function, Inst, Op, Terminator, ValueId};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Facts{
+    non_null: BTreeSet<ValueId>,
+    /// Pairs of an index and an array whose length the index is below.
+    below_length: BTreeSet<(ValueId, ValueId)>,
+}
+
+impl Facts{
+    fn intersect(&mut self, other: &Facts){
+        self.non_null.retain(|v| other.non_null.contains(v));
+        self.below_length.retain(|v| other.below_length.contains(v));
+    }
+}
+
+/// Whether the instruction throws a `NullPointerException` if its first operand is `null`.
+fn dereferences(instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<bool>{
+    Ok(match instruction {
+        RawInstruction::GetField { .. } |
+        RawInstruction::PutField { .. } |
+        RawInstruction::ArrayLength |
+        RawInstruction::MonitorEnter |
+        RawInstruction::MonitorExit |
+        RawInstruction::AThrow |
+        RawInstruction::InvokeVirtual { .. } |
+        RawInstruction::InvokeInterface { .. } => true,
+        // Constructors are called on objects that were just created.
+        RawInstruction::InvokeSpecial { index } => &*MethodKey::read(cp, *index)?.name != b"<init>",
+        _ => is_array_access(instruction)
+    })
+}
+
+/// Array loads and stores, which take the array and the index as their first operands.
+fn is_array_access(instruction: &RawInstruction) -> bool{
+    matches!(
+        instruction,
+        RawInstruction::AALoad | RawInstruction::BALoad | RawInstruction::CALoad | RawInstruction::DALoad |
+        RawInstruction::FALoad | RawInstruction::IALoad | RawInstruction::LALoad | RawInstruction::SALoad |
+        RawInstruction::AAStore | RawInstruction::BAStore | RawInstruction::CAStore | RawInstruction::DAStore |
+        RawInstruction::FAStore | RawInstruction::IAStore | RawInstruction::LAStore | RawInstruction::SAStore
+    )
+}
+
+/// Whether the instruction results in a new object or a constant, which is never `null`.
+fn creates(instruction: &RawInstruction, cp: &ConstantPool) -> anyhow::Result<bool>{
+    Ok(match instruction {
+        RawInstruction::New { .. } |
+        RawInstruction::NewArray { .. } |
+        RawInstruction::ANewArray { .. } |
+        RawInstruction::MultiANewArray { .. } => true,
+        RawInstruction::LdC { index } |
+        RawInstruction::LdCW { index } => matches!(cp.get(*index)?, Item::String(_) | Item::Class(_)),
+        _ => false
+    })
+}
+
+/// Null checks and bounds checks of a function, and which of them can't fail.
+#[derive(Clone, Debug, Default)]
+pub struct Checks{
+    /// Positions of instructions whose checks can't fail, by block and index,
+    /// with the terminator after the last instruction.
+    non_null: HashSet<(BlockId, usize)>,
+    in_bounds: HashSet<(BlockId, usize)>,
+    pub null_checks: usize,
+    pub bounds_checks: usize,
+}
+
+impl Checks{
+    pub fn analyze(function: &Function, cp: &ConstantPool) -> anyhow::Result<Self>{
+        let mut analysis = Analysis{
+            function,
+            cp,
+            lengths: HashMap::new()
+        };
+        for (_, block) in function.blocks(){
+            for inst in block.instructions.iter(){
+                if let (Op::Bytecode(_, RawInstruction::ArrayLength), Some(result)) = (&inst.op, inst.result){
+                    analysis.lengths.insert(result, inst.args[0]);
+                }
+            }
+        }
+
+        let entries = analysis.entries()?;
+        let non_negative = analysis.non_negative(&entries);
+
+        let mut checks = Self::default();
+        for (id, block) in function.blocks(){
+            let Some(facts) = &entries[id.0 as usize] else {
+                continue;
+            };
+            let mut facts = facts.clone();
+            for (i, inst) in block.instructions.iter().enumerate(){
+                let Op::Bytecode(_, instruction) = &inst.op else {
+                    continue;
+                };
+                if is_array_access(instruction){
+                    checks.bounds_checks += 1;
+                    if non_negative.contains(&inst.args[1]) && facts.below_length.contains(&(inst.args[1], inst.args[0])){
+                        checks.in_bounds.insert((id, i));
+                    }
+                }
+                if dereferences(instruction, cp)?{
+                    checks.null_checks += 1;
+                    if facts.non_null.contains(&inst.args[0]){
+                        checks.non_null.insert((id, i));
+                    }
+                }
+                analysis.step(&mut facts, inst)?;
+            }
+            if let Terminator::Exit{instruction: RawInstruction::AThrow, args, ..} = &block.terminator{
+                checks.null_checks += 1;
+                if facts.non_null.contains(&args[0]){
+                    checks.non_null.insert((id, block.instructions.len()));
+                }
+            }
+        }
+        Ok(checks)
+    }
+
+    /// Whether the operand the instruction at `position` dereferences can't be `null`.
+    pub fn is_non_null(&self, block: BlockId, position: usize) -> bool{
+        self.non_null.contains(&(block, position))
+    }
+
+    /// Whether the index of the array access at `position` is known to be in bounds.
+    pub fn is_in_bounds(&self, block: BlockId, position: usize) -> bool{
+        self.in_bounds.contains(&(block, position))
+    }
+
+    pub fn null_checks_removed(&self) -> usize{
+        self.non_null.len()
+    }
+
+    pub fn bounds_checks_removed(&self) -> usize{
+        self.in_bounds.len()
+    }
+}
+
+struct Analysis<'f, 'a, 'c>{
+    function: &'f Function<'a>,
+    cp: &'c ConstantPool<'c>,
+    /// The array of each `arraylength` result.
+    lengths: HashMap<ValueId, ValueId>,
+}
+
+impl Analysis<'_, '_, '_>{
+    /// Facts after an instruction.
+    fn step(&self, facts: &mut Facts, inst: &Inst) -> anyhow::Result<()>{
+        let instruction = match &inst.op {
+            Op::Bytecode(_, instruction) => instruction,
+            // Only objects are thrown, `athrow` checks for `null`.
+            Op::Catch => {
+                facts.non_null.extend(inst.result);
+                return Ok(());
+            }
+            _ => return Ok(())
+        };
+        if dereferences(instruction, self.cp)?{
+            facts.non_null.insert(inst.args[0]);
+        }
+        if let Some(result) = inst.result{
+            if creates(instruction, self.cp)?{
+                facts.non_null.insert(result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Facts at the end of `from` that hold on the edge to `to`.
+    fn edge(&self, from: BlockId, to: BlockId, facts: &Facts) -> Facts{
+        let mut facts = facts.clone();
+        let Some(Terminator::Branch{instruction, args, then, otherwise, ..}) = self.function.block(from).map(|b| &b.terminator) else {
+            return facts;
+        };
+        if then == otherwise{
+            return facts;
+        }
+        let taken = to == *then;
+        match (instruction, &args[..]) {
+            (RawInstruction::IfNonNull { .. }, [value]) if taken => {facts.non_null.insert(*value);}
+            (RawInstruction::IfNull { .. }, [value]) if !taken => {facts.non_null.insert(*value);}
+            (RawInstruction::IfICmpGe { .. }, [index, length]) if !taken => self.below(&mut facts, *index, *length),
+            (RawInstruction::IfICmpLt { .. }, [index, length]) if taken => self.below(&mut facts, *index, *length),
+            (RawInstruction::IfICmpLe { .. }, [length, index]) if !taken => self.below(&mut facts, *index, *length),
+            (RawInstruction::IfICmpGt { .. }, [length, index]) if taken => self.below(&mut facts, *index, *length),
+            _ => ()
+        }
+        facts
+    }
+
+    /// Facts that hold on the edge from `from` to `to`, `None` until `from`
+    /// has been visited. A block can throw before its first instruction
+    /// finishes, so the handlers it throws to only get the facts it starts with.
+    fn leaving(&self, from: BlockId, to: BlockId, entries: &[Option<Facts>], exits: &[Option<Facts>]) -> Option<Facts>{
+        if self.function.block(from)?.handlers.contains(&to){
+            return entries[from.0 as usize].clone();
+        }
+        exits[from.0 as usize].as_ref().map(|exit| self.edge(from, to, exit))
+    }
+
+    fn below(&self, facts: &mut Facts, index: ValueId, length: ValueId){
+        if let Some(array) = self.lengths.get(&length){
+            facts.below_length.insert((index, *array));
+            // Taking the length checked the array.
+            facts.non_null.insert(*array);
+        }
+    }
+
+    /// Facts at the start of every block, including its phis, `None` for
+    /// blocks that aren't reached.
+    fn entries(&self) -> anyhow::Result<Vec<Option<Facts>>>{
+        let function = self.function;
+        let count = function.blocks.len();
+        // Facts at the end of each block, `None` until it has been visited,
+        // which optimistically assumes everything.
+        let mut exits: Vec<Option<Facts>> = vec![None; count];
+        let mut entries: Vec<Option<Facts>> = vec![None; count];
+        loop{
+            let mut changed = false;
+            for (id, block) in function.blocks(){
+                let mut facts = None::<Facts>;
+                for predecessor in block.predecessors.iter(){
+                    let Some(edge) = self.leaving(*predecessor, id, &entries, &exits) else {
+                        continue;
+                    };
+                    match &mut facts {
+                        Some(facts) => facts.intersect(&edge),
+                        None => facts = Some(edge)
+                    }
+                }
+                let mut facts = match facts {
+                    Some(facts) => facts,
+                    None if id == Function::ENTRY => Facts::default(),
+                    None => continue
+                };
+
+                for phi in block.phis.iter(){
+                    let non_null = phi.incoming.iter().all(|(predecessor, value)| match self.leaving(*predecessor, id, &entries, &exits) {
+                        Some(edge) => edge.non_null.contains(value),
+                        None => true
+                    });
+                    if non_null{
+                        facts.non_null.insert(phi.result);
+                    }
+                }
+                if id == Function::ENTRY{
+                    facts.non_null.extend(function.this);
+                }
+
+                let entry = Some(facts.clone());
+                for inst in block.instructions.iter(){
+                    self.step(&mut facts, inst)?;
+                }
+                let exit = Some(facts);
+                if exits[id.0 as usize] != exit || entries[id.0 as usize] != entry{
+                    exits[id.0 as usize] = exit;
+                    entries[id.0 as usize] = entry;
+                    changed = true;
+                }
+            }
+            if !changed{
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Values known to be at least 0, assuming it of every candidate and
+    /// dropping the ones that don't follow until nothing changes.
+    fn non_negative(&self, entries: &[Option<Facts>]) -> HashSet<ValueId>{
+        enum Rule{
+            Phi(Vec<ValueId>),
+            /// An increment by one, with the facts before it.
+            Increment(ValueId, BlockId),
+        }
+        let mut constants = HashMap::new();
+        let mut known = HashSet::new();
+        for (_, block) in self.function.blocks(){
+            for inst in block.instructions.iter(){
+                match (&inst.op, inst.result) {
+                    (Op::Const(Constant::Int(value)), Some(result)) => {
+                        constants.insert(result, *value);
+                        if *value >= 0{
+                            known.insert(result);
+                        }
+                    }
+                    (Op::Bytecode(_, RawInstruction::ArrayLength), Some(result)) => {known.insert(result);}
+                    _ => ()
+                }
+            }
+        }
+
+        let mut rules = HashMap::new();
+        for (id, block) in self.function.blocks(){
+            for phi in block.phis.iter(){
+                rules.insert(phi.result, Rule::Phi(phi.incoming.iter().map(|(_, v)| *v).collect()));
+            }
+            for inst in block.instructions.iter(){
+                if let (Op::Bytecode(_, RawInstruction::IAdd), Some(result)) = (&inst.op, inst.result){
+                    if let Some(i) = inst.args.iter().position(|a| constants.get(a) == Some(&1)){
+                        rules.insert(result, Rule::Increment(inst.args[1 - i], id));
+                    }
+                }
+            }
+        }
+
+        let mut assumed = rules.keys().copied().collect::<HashSet<_>>();
+        loop{
+            let holds = |value: &ValueId, assumed: &HashSet<ValueId>| known.contains(value) || assumed.contains(value);
+            let failing = assumed.iter().copied().filter(|value| match &rules[value] {
+                Rule::Phi(incoming) => !incoming.iter().all(|v| holds(v, &assumed)),
+                // Below the length of an array, adding one can't overflow.
+                Rule::Increment(base, block) => !holds(base, &assumed) || !entries[block.0 as usize].as_ref()
+                    .is_some_and(|f| f.below_length.iter().any(|(index, _)| index == base))
+            }).collect::<Vec<_>>();
+            if failing.is_empty(){
+                break;
+            }
+            for value in failing{
+                assumed.remove(&value);
+            }
+        }
+        known.extend(assumed);
+        known
+    }
+}
@@ -0,0 +1,387 @@
+//! An SSA form of method bodies, between bytecode and WebAssembly.
+//!
+//! Operand stack slots and local variables become values that are defined
+//! once, by an instruction or by a phi at the start of a block where control
+//! flow joins. Instructions other than loads, stores and stack shuffles are
+//! kept as the bytecode instruction they come from, taking their operands
+//! from their arguments, so lowering them back can reuse the bytecode
+//! lowering.
+//!
+//! Block 0 is a synthetic entry that defines the parameters, followed by
+//! the basic blocks of the bytecode in order. Exception handlers are entered
+//! through a block of their own that catches the exception, from every block
+//! that can throw where they cover it. Blocks that go on after an instruction
+//! that can throw are split there before their next store to a local, so
+//! that the locals at the end of a block are those the handlers see.
+
+mod build;
+mod checks;
+mod passes;
+mod verify;
+
+use std::{collections::BTreeMap, fmt};
+
+use noak::reader::attributes::{Index as Offset, RawInstruction};
+use wasm_encoder::Instruction;
+
+use crate::{code::Value, layout};
+
+pub use build::build;
+pub use checks::Checks;
+pub use passes::{eliminate_dead_values, fold, fold_constants, propagate_copies};
+pub use verify::verify;
+
+/// Runs the passes that simplify a freshly built function.
+pub fn optimize(function: &mut Function){
+    propagate_copies(function);
+    fold_constants(function);
+    // Folded branches can leave phis with one operand.
+    propagate_copies(function);
+    eliminate_dead_values(function);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueId(pub u32);
+
+impl fmt::Display for ValueId{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "v{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub u32);
+
+impl fmt::Display for BlockId{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "b{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Constant{
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Null,
+}
+
+impl Constant{
+    /// The zero of a type, which is what locals start with.
+    pub fn zero(value: Value) -> Self{
+        match value {
+            Value::I32 => Self::Int(0),
+            Value::I64 => Self::Long(0),
+            Value::F32 => Self::Float(0.0),
+            Value::F64 => Self::Double(0.0),
+            Value::Ref => Self::Null,
+        }
+    }
+
+    pub fn value(self) -> Value{
+        match self {
+            Self::Int(_) => Value::I32,
+            Self::Long(_) => Value::I64,
+            Self::Float(_) => Value::F32,
+            Self::Double(_) => Value::F64,
+            Self::Null => Value::Ref,
+        }
+    }
+
+    pub fn lower(self) -> Instruction<'static>{
+        match self {
+            Self::Int(i) => Instruction::I32Const(i),
+            Self::Long(l) => Instruction::I64Const(l),
+            Self::Float(f) => Instruction::F32Const(f),
+            Self::Double(d) => Instruction::F64Const(d),
+            Self::Null => Instruction::I32Const(layout::NULL as i32),
+        }
+    }
+}
+
+impl fmt::Display for Constant{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Long(l) => write!(f, "{}L", l),
+            Self::Float(x) => write!(f, "{:?}f", x),
+            Self::Double(d) => write!(f, "{:?}", d),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}
+
+pub enum Op<'a>{
+    /// The parameter in the given wasm local.
+    Param(u32),
+    Const(Constant),
+    /// The value of its argument, left by loads until copies are propagated.
+    Copy,
+    /// A bytecode instruction with its operands as arguments.
+    Bytecode(Offset, &'a RawInstruction<'a>),
+    /// The exception a handler catches, in its entry block.
+    Catch,
+}
+
+pub struct Inst<'a>{
+    pub result: Option<ValueId>,
+    pub op: Op<'a>,
+    pub args: Vec<ValueId>,
+}
+
+impl Inst<'_>{
+    /// Whether removing the instruction can only change the values it defines.
+    pub fn is_pure(&self) -> bool{
+        !matches!(self.op, Op::Bytecode(..) | Op::Catch)
+    }
+}
+
+pub struct Phi{
+    pub result: ValueId,
+    /// The value coming from each predecessor, in the order of the predecessors.
+    pub incoming: Vec<(BlockId, ValueId)>,
+}
+
+pub enum Terminator<'a>{
+    Jump(BlockId),
+    /// Continues with `then` if the conditional jump `instruction` is taken on the arguments.
+    Branch{
+        at: Offset,
+        instruction: &'a RawInstruction<'a>,
+        args: Vec<ValueId>,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Switch{
+        value: ValueId,
+        cases: Vec<(i32, BlockId)>,
+        default: BlockId,
+    },
+    /// A return or `athrow`.
+    Exit{
+        at: Offset,
+        instruction: &'a RawInstruction<'a>,
+        args: Vec<ValueId>,
+    },
+}
+
+impl Terminator<'_>{
+    /// Blocks control can continue to, without duplicates.
+    pub fn successors(&self) -> Vec<BlockId>{
+        let mut successors = match self {
+            Self::Jump(target) => vec![*target],
+            Self::Branch{then, otherwise, ..} => vec![*then, *otherwise],
+            Self::Switch{cases, default, ..} => cases.iter().map(|(_, b)| *b).chain([*default]).collect(),
+            Self::Exit{..} => vec![]
+        };
+        let mut seen = Vec::new();
+        successors.retain(|b| if seen.contains(b) {false} else {seen.push(*b); true});
+        successors
+    }
+
+    pub fn args(&self) -> Vec<ValueId>{
+        match self {
+            Self::Jump(_) => vec![],
+            Self::Branch{args, ..} | Self::Exit{args, ..} => args.clone(),
+            Self::Switch{value, ..} => vec![*value]
+        }
+    }
+
+    pub fn args_mut(&mut self) -> Vec<&mut ValueId>{
+        match self {
+            Self::Jump(_) => vec![],
+            Self::Branch{args, ..} | Self::Exit{args, ..} => args.iter_mut().collect(),
+            Self::Switch{value, ..} => vec![value]
+        }
+    }
+}
+
+pub struct BasicBlock<'a>{
+    /// Bytecode offset of the block, `None` for the entry and the entries of handlers.
+    pub start: Option<u32>,
+    pub predecessors: Vec<BlockId>,
+    pub phis: Vec<Phi>,
+    pub instructions: Vec<Inst<'a>>,
+    pub terminator: Terminator<'a>,
+    /// Entry blocks of the handlers control continues at when the block throws.
+    pub handlers: Vec<BlockId>,
+}
+
+impl BasicBlock<'_>{
+    /// Blocks control can continue to, normally or by throwing.
+    pub fn successors(&self) -> Vec<BlockId>{
+        let mut successors = self.terminator.successors();
+        successors.extend(self.handlers.iter().copied());
+        successors
+    }
+}
+
+pub struct Function<'a>{
+    /// Blocks by id, `None` for blocks that are never reached.
+    pub blocks: Vec<Option<BasicBlock<'a>>>,
+    /// Types of the values by id.
+    pub types: Vec<Value>,
+    /// The receiver of instance methods.
+    pub this: Option<ValueId>,
+    /// Entry block of the handler at each bytecode offset.
+    pub handlers: BTreeMap<u32, BlockId>,
+}
+
+impl<'a> Function<'a>{
+    pub const ENTRY: BlockId = BlockId(0);
+
+    pub fn block(&self, id: BlockId) -> Option<&BasicBlock<'a>>{
+        self.blocks.get(id.0 as usize)?.as_ref()
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> Option<&mut BasicBlock<'a>>{
+        self.blocks.get_mut(id.0 as usize)?.as_mut()
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockId, &BasicBlock<'a>)>{
+        self.blocks.iter().enumerate().filter_map(|(i, b)| Some((BlockId(i as u32), b.as_ref()?)))
+    }
+
+    pub fn new_value(&mut self, value: Value) -> ValueId{
+        self.types.push(value);
+        ValueId(self.types.len() as u32 - 1)
+    }
+
+    pub fn type_of(&self, value: ValueId) -> Value{
+        self.types[value.0 as usize]
+    }
+
+    /// Calls `f` with every use of a value, including phi operands.
+    pub fn for_each_use(&mut self, mut f: impl FnMut(&mut ValueId)){
+        for block in self.blocks.iter_mut().flatten(){
+            for phi in block.phis.iter_mut(){
+                for (_, value) in phi.incoming.iter_mut(){
+                    f(value);
+                }
+            }
+            for inst in block.instructions.iter_mut(){
+                for arg in inst.args.iter_mut(){
+                    f(arg);
+                }
+            }
+            for arg in block.terminator.args_mut(){
+                f(arg);
+            }
+        }
+    }
+
+    /// Removes the edge from `from` to `to`, with the phi operands coming along it.
+    pub fn remove_edge(&mut self, from: BlockId, to: BlockId){
+        if let Some(block) = self.block_mut(to){
+            block.predecessors.retain(|p| *p != from);
+            for phi in block.phis.iter_mut(){
+                phi.incoming.retain(|(p, _)| *p != from);
+            }
+        }
+    }
+
+    /// Drops the blocks that can't be reached from the entry anymore.
+    pub fn remove_unreachable(&mut self){
+        let mut reached = vec![false; self.blocks.len()];
+        let mut pending = vec![Self::ENTRY];
+        while let Some(id) = pending.pop(){
+            if std::mem::replace(&mut reached[id.0 as usize], true){
+                continue;
+            }
+            if let Some(block) = self.block(id){
+                pending.extend(block.successors());
+            }
+        }
+        for (i, reached) in reached.into_iter().enumerate(){
+            if reached{
+                continue;
+            }
+            let Some(block) = self.blocks[i].take() else {
+                continue;
+            };
+            for successor in block.successors(){
+                self.remove_edge(BlockId(i as u32), successor);
+            }
+        }
+    }
+}
+
+fn list(f: &mut fmt::Formatter<'_>, values: &[ValueId]) -> fmt::Result{
+    for (i, value) in values.iter().enumerate(){
+        if i > 0{
+            write!(f, ",")?;
+        }
+        write!(f, " {}", value)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Function<'_>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        for (id, block) in self.blocks(){
+            write!(f, "{}", id)?;
+            if let Some(start) = block.start{
+                write!(f, " @{}", start)?;
+            }
+            write!(f, ":")?;
+            if !block.predecessors.is_empty(){
+                write!(f, " preds")?;
+                for (i, p) in block.predecessors.iter().enumerate(){
+                    write!(f, "{} {}", if i > 0 {","} else {""}, p)?;
+                }
+            }
+            if !block.handlers.is_empty(){
+                write!(f, " catch")?;
+                for (i, h) in block.handlers.iter().enumerate(){
+                    write!(f, "{} {}", if i > 0 {","} else {""}, h)?;
+                }
+            }
+            writeln!(f)?;
+
+            for phi in block.phis.iter(){
+                write!(f, "    {}: {:?} = phi", phi.result, self.type_of(phi.result))?;
+                for (i, (p, v)) in phi.incoming.iter().enumerate(){
+                    write!(f, "{} [{}: {}]", if i > 0 {","} else {""}, p, v)?;
+                }
+                writeln!(f)?;
+            }
+            for inst in block.instructions.iter(){
+                write!(f, "    ")?;
+                if let Some(result) = inst.result{
+                    write!(f, "{}: {:?} = ", result, self.type_of(result))?;
+                }
+                match &inst.op {
+                    Op::Param(local) => write!(f, "param {}", local)?,
+                    Op::Const(constant) => write!(f, "const {}", constant)?,
+                    Op::Copy => write!(f, "copy")?,
+                    Op::Catch => write!(f, "catch")?,
+                    Op::Bytecode(at, instruction) => write!(f, "@{} {:?}", at.as_u32(), instruction)?,
+                }
+                list(f, &inst.args)?;
+                writeln!(f)?;
+            }
+            match &block.terminator {
+                Terminator::Jump(target) => writeln!(f, "    jump {}", target)?,
+                Terminator::Branch{at, instruction, args, then, otherwise} => {
+                    write!(f, "    @{} {:?}", at.as_u32(), instruction)?;
+                    list(f, args)?;
+                    writeln!(f, " then {} else {}", then, otherwise)?;
+                }
+                Terminator::Switch{value, cases, default} => {
+                    write!(f, "    switch {}", value)?;
+                    for (key, target) in cases.iter(){
+                        write!(f, " [{}: {}]", key, target)?;
+                    }
+                    writeln!(f, " default {}", default)?;
+                }
+                Terminator::Exit{at, instruction, args} => {
+                    write!(f, "    @{} {:?}", at.as_u32(), instruction)?;
+                    list(f, args)?;
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
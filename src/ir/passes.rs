@@ -0,0 +1,242 @@
+//! Passes over the SSA form.
+
+use std::collections::{HashMap, HashSet};
+
+use noak::reader::attributes::RawInstruction;
+
+use super::{Constant, Function, Op, Terminator, ValueId};
+
+fn resolve(replacements: &HashMap<ValueId, ValueId>, mut value: ValueId) -> ValueId{
+    while let Some(next) = replacements.get(&value){
+        value = *next;
+    }
+    value
+}
+
+/// Replaces copies and phis that only ever have one value by that value.
+pub fn propagate_copies(function: &mut Function){
+    let mut replacements = HashMap::new();
+    loop{
+        let mut changed = false;
+        for block in function.blocks.iter_mut().flatten(){
+            block.instructions.retain(|inst|{
+                if let (Op::Copy, Some(result)) = (&inst.op, inst.result){
+                    replacements.insert(result, resolve(&replacements, inst.args[0]));
+                    changed = true;
+                    return false;
+                }
+                true
+            });
+            block.phis.retain(|phi|{
+                let mut only = None;
+                for (_, value) in phi.incoming.iter(){
+                    let value = resolve(&replacements, *value);
+                    if value == phi.result || only == Some(value){
+                        continue;
+                    }
+                    if only.is_some(){
+                        return true;
+                    }
+                    only = Some(value);
+                }
+                match only {
+                    Some(value) => {
+                        replacements.insert(phi.result, value);
+                        changed = true;
+                        false
+                    }
+                    // Only reachable through itself.
+                    None => true
+                }
+            });
+        }
+        if !changed{
+            break;
+        }
+        function.for_each_use(|value| *value = resolve(&replacements, *value));
+    }
+}
+
+/// Evaluates an instruction on constant operands, unless it could throw or isn't pure.
+pub fn fold(instruction: &RawInstruction, args: &[Constant]) -> Option<Constant>{
+    use Constant::*;
+    Some(match (instruction, args) {
+        (RawInstruction::IAdd, [Int(a), Int(b)]) => Int(a.wrapping_add(*b)),
+        (RawInstruction::ISub, [Int(a), Int(b)]) => Int(a.wrapping_sub(*b)),
+        (RawInstruction::IMul, [Int(a), Int(b)]) => Int(a.wrapping_mul(*b)),
+        (RawInstruction::IDiv, [Int(a), Int(b)]) if *b != 0 => Int(a.wrapping_div(*b)),
+        (RawInstruction::IRem, [Int(a), Int(b)]) if *b != 0 => Int(a.wrapping_rem(*b)),
+        (RawInstruction::IAnd, [Int(a), Int(b)]) => Int(a & b),
+        (RawInstruction::IOr, [Int(a), Int(b)]) => Int(a | b),
+        (RawInstruction::IXor, [Int(a), Int(b)]) => Int(a ^ b),
+        (RawInstruction::IShL, [Int(a), Int(b)]) => Int(a.wrapping_shl(*b as u32)),
+        (RawInstruction::IShR, [Int(a), Int(b)]) => Int(a.wrapping_shr(*b as u32)),
+        (RawInstruction::IUShR, [Int(a), Int(b)]) => Int((*a as u32).wrapping_shr(*b as u32) as i32),
+        (RawInstruction::INeg, [Int(a)]) => Int(a.wrapping_neg()),
+        (RawInstruction::LAdd, [Long(a), Long(b)]) => Long(a.wrapping_add(*b)),
+        (RawInstruction::LSub, [Long(a), Long(b)]) => Long(a.wrapping_sub(*b)),
+        (RawInstruction::LMul, [Long(a), Long(b)]) => Long(a.wrapping_mul(*b)),
+        (RawInstruction::LDiv, [Long(a), Long(b)]) if *b != 0 => Long(a.wrapping_div(*b)),
+        (RawInstruction::LRem, [Long(a), Long(b)]) if *b != 0 => Long(a.wrapping_rem(*b)),
+        (RawInstruction::LAnd, [Long(a), Long(b)]) => Long(a & b),
+        (RawInstruction::LOr, [Long(a), Long(b)]) => Long(a | b),
+        (RawInstruction::LXor, [Long(a), Long(b)]) => Long(a ^ b),
+        (RawInstruction::LShL, [Long(a), Int(b)]) => Long(a.wrapping_shl(*b as u32)),
+        (RawInstruction::LShR, [Long(a), Int(b)]) => Long(a.wrapping_shr(*b as u32)),
+        (RawInstruction::LUShR, [Long(a), Int(b)]) => Long((*a as u64).wrapping_shr(*b as u32) as i64),
+        (RawInstruction::LNeg, [Long(a)]) => Long(a.wrapping_neg()),
+        (RawInstruction::LCmp, [Long(a), Long(b)]) => Int(a.cmp(b) as i32),
+        (RawInstruction::FAdd, [Float(a), Float(b)]) => Float(a + b),
+        (RawInstruction::FSub, [Float(a), Float(b)]) => Float(a - b),
+        (RawInstruction::FMul, [Float(a), Float(b)]) => Float(a * b),
+        (RawInstruction::FDiv, [Float(a), Float(b)]) => Float(a / b),
+        (RawInstruction::FRem, [Float(a), Float(b)]) => Float(a % b),
+        (RawInstruction::FNeg, [Float(a)]) => Float(-a),
+        (RawInstruction::DAdd, [Double(a), Double(b)]) => Double(a + b),
+        (RawInstruction::DSub, [Double(a), Double(b)]) => Double(a - b),
+        (RawInstruction::DMul, [Double(a), Double(b)]) => Double(a * b),
+        (RawInstruction::DDiv, [Double(a), Double(b)]) => Double(a / b),
+        (RawInstruction::DRem, [Double(a), Double(b)]) => Double(a % b),
+        (RawInstruction::DNeg, [Double(a)]) => Double(-a),
+        // Rust's casts saturate and turn NaN into 0, like the JVM.
+        (RawInstruction::I2L, [Int(a)]) => Long(*a as i64),
+        (RawInstruction::I2F, [Int(a)]) => Float(*a as f32),
+        (RawInstruction::I2D, [Int(a)]) => Double(*a as f64),
+        (RawInstruction::I2B, [Int(a)]) => Int(*a as i8 as i32),
+        (RawInstruction::I2C, [Int(a)]) => Int(*a as u16 as i32),
+        (RawInstruction::I2S, [Int(a)]) => Int(*a as i16 as i32),
+        (RawInstruction::L2I, [Long(a)]) => Int(*a as i32),
+        (RawInstruction::L2F, [Long(a)]) => Float(*a as f32),
+        (RawInstruction::L2D, [Long(a)]) => Double(*a as f64),
+        (RawInstruction::F2I, [Float(a)]) => Int(*a as i32),
+        (RawInstruction::F2L, [Float(a)]) => Long(*a as i64),
+        (RawInstruction::F2D, [Float(a)]) => Double(*a as f64),
+        (RawInstruction::D2I, [Double(a)]) => Int(*a as i32),
+        (RawInstruction::D2L, [Double(a)]) => Long(*a as i64),
+        (RawInstruction::D2F, [Double(a)]) => Float(*a as f32),
+        (RawInstruction::FCmpL, [Float(a), Float(b)]) => Int(compare(a.partial_cmp(b), -1)),
+        (RawInstruction::FCmpG, [Float(a), Float(b)]) => Int(compare(a.partial_cmp(b), 1)),
+        (RawInstruction::DCmpL, [Double(a), Double(b)]) => Int(compare(a.partial_cmp(b), -1)),
+        (RawInstruction::DCmpG, [Double(a), Double(b)]) => Int(compare(a.partial_cmp(b), 1)),
+        _ => return None
+    })
+}
+
+/// The result of a floating point comparison, `nan` if either side is NaN.
+fn compare(ordering: Option<std::cmp::Ordering>, nan: i32) -> i32{
+    ordering.map_or(nan, |o| o as i32)
+}
+
+/// Whether the conditional jump is taken on constant operands.
+fn taken(instruction: &RawInstruction, args: &[Constant]) -> Option<bool>{
+    use Constant::*;
+    Some(match (instruction, args) {
+        (RawInstruction::IfEq { .. }, [Int(a)]) => *a == 0,
+        (RawInstruction::IfNe { .. }, [Int(a)]) => *a != 0,
+        (RawInstruction::IfLt { .. }, [Int(a)]) => *a < 0,
+        (RawInstruction::IfGe { .. }, [Int(a)]) => *a >= 0,
+        (RawInstruction::IfGt { .. }, [Int(a)]) => *a > 0,
+        (RawInstruction::IfLe { .. }, [Int(a)]) => *a <= 0,
+        (RawInstruction::IfICmpEq { .. }, [Int(a), Int(b)]) => a == b,
+        (RawInstruction::IfICmpNe { .. }, [Int(a), Int(b)]) => a != b,
+        (RawInstruction::IfICmpLt { .. }, [Int(a), Int(b)]) => a < b,
+        (RawInstruction::IfICmpGe { .. }, [Int(a), Int(b)]) => a >= b,
+        (RawInstruction::IfICmpGt { .. }, [Int(a), Int(b)]) => a > b,
+        (RawInstruction::IfICmpLe { .. }, [Int(a), Int(b)]) => a <= b,
+        (RawInstruction::IfNull { .. }, [Null]) => true,
+        (RawInstruction::IfNonNull { .. }, [Null]) => false,
+        (RawInstruction::IfACmpEq { .. }, [Null, Null]) => true,
+        (RawInstruction::IfACmpNe { .. }, [Null, Null]) => false,
+        _ => return None
+    })
+}
+
+/// Evaluates instructions and branches whose operands are constants, and
+/// drops the blocks that can't be reached anymore.
+pub fn fold_constants(function: &mut Function){
+    let mut constants = HashMap::new();
+    loop{
+        let mut changed = false;
+        let mut removed = Vec::new();
+        for (i, block) in function.blocks.iter_mut().enumerate(){
+            let Some(block) = block else {
+                continue;
+            };
+            for inst in block.instructions.iter_mut(){
+                let Some(result) = inst.result else {
+                    continue;
+                };
+                let args = inst.args.iter().map(|a| constants.get(a).copied()).collect::<Option<Vec<Constant>>>();
+                let folded = match (&inst.op, args) {
+                    (Op::Const(constant), _) => Some(*constant),
+                    (Op::Bytecode(_, instruction), Some(args)) => fold(instruction, &args),
+                    _ => None
+                };
+                if let Some(constant) = folded{
+                    if !matches!(inst.op, Op::Const(_)){
+                        inst.op = Op::Const(constant);
+                        inst.args.clear();
+                        changed = true;
+                    }
+                    constants.insert(result, constant);
+                }
+            }
+
+            let target = match &block.terminator {
+                Terminator::Branch{instruction, args, then, otherwise, ..} => {
+                    let args = args.iter().map(|a| constants.get(a).copied()).collect::<Option<Vec<Constant>>>();
+                    args.and_then(|args| taken(instruction, &args)).map(|taken| if taken {*then} else {*otherwise})
+                }
+                Terminator::Switch{value, cases, default} => match constants.get(value) {
+                    Some(Constant::Int(key)) => Some(cases.iter().find(|(k, _)| k == key).map_or(*default, |(_, b)| *b)),
+                    _ => None
+                },
+                _ => None
+            };
+            if let Some(target) = target{
+                let from = super::BlockId(i as u32);
+                removed.extend(block.terminator.successors().into_iter().filter(|s| *s != target).map(|s| (from, s)));
+                block.terminator = Terminator::Jump(target);
+                changed = true;
+            }
+        }
+        for (from, to) in removed{
+            function.remove_edge(from, to);
+        }
+        if !changed{
+            break;
+        }
+    }
+    function.remove_unreachable();
+}
+
+/// Removes instructions without side effects and phis whose values are never used.
+pub fn eliminate_dead_values(function: &mut Function){
+    let mut definitions = HashMap::new();
+    let mut live = HashSet::new();
+    let mut pending = Vec::new();
+    for (_, block) in function.blocks(){
+        for phi in block.phis.iter(){
+            definitions.insert(phi.result, phi.incoming.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+        }
+        for inst in block.instructions.iter(){
+            match inst.result {
+                Some(result) if inst.is_pure() => {
+                    definitions.insert(result, inst.args.clone());
+                }
+                _ => pending.extend(inst.args.iter().copied())
+            }
+        }
+        pending.extend(block.terminator.args());
+    }
+    while let Some(value) = pending.pop(){
+        if live.insert(value){
+            pending.extend(definitions.get(&value).into_iter().flatten().copied());
+        }
+    }
+
+    for block in function.blocks.iter_mut().flatten(){
+        block.phis.retain(|phi| live.contains(&phi.result));
+        block.instructions.retain(|inst| !inst.is_pure() || inst.result.is_some_and(|r| live.contains(&r)));
+    }
+}
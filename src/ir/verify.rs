@@ -0,0 +1,146 @@
+//! Checks that a function is in valid SSA form, to catch passes that break it.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure};
+use noak::reader::{attributes::RawInstruction, cpool::ConstantPool};
+
+use crate::code::{self, Stack, Value};
+
+use super::{BlockId, Function, Op, Terminator, ValueId};
+
+/// Blocks dominating each block, including the block itself.
+fn dominators(function: &Function) -> Vec<Vec<bool>>{
+    let count = function.blocks.len();
+    let mut dominators = vec![vec![true; count]; count];
+    dominators[0] = (0..count).map(|i| i == 0).collect();
+    loop{
+        let mut changed = false;
+        for (id, block) in function.blocks().skip(1){
+            let mut dominated = vec![true; count];
+            for predecessor in block.predecessors.iter(){
+                for (d, p) in dominated.iter_mut().zip(dominators[predecessor.0 as usize].iter()){
+                    *d &= *p;
+                }
+            }
+            dominated[id.0 as usize] = true;
+            if dominated != dominators[id.0 as usize]{
+                dominators[id.0 as usize] = dominated;
+                changed = true;
+            }
+        }
+        if !changed{
+            return dominators;
+        }
+    }
+}
+
+/// Checks the types of a bytecode instruction's operands and result.
+fn check_bytecode(function: &Function, cp: &ConstantPool, instruction: &RawInstruction, args: &[ValueId], result: Option<ValueId>) -> anyhow::Result<()>{
+    let mut effect = Stack::with_values(&[]);
+    code::step(&mut effect, instruction, cp)?;
+    let expected = effect.inputs().iter().rev().copied().collect::<Vec<_>>();
+    let found = args.iter().map(|a| function.type_of(*a)).collect::<Vec<_>>();
+    ensure!(expected == found, "{:?} takes {:?}, not {:?}", instruction, expected, found);
+    let pushed = effect.values().first().copied();
+    ensure!(pushed == result.map(|r| function.type_of(r)), "{:?} results in {:?}", instruction, pushed);
+    Ok(())
+}
+
+/// Checks that every value is defined once before all of its uses, that
+/// phis have one operand per predecessor, that blocks only throw to the
+/// entries of handlers and that operands have the types their instructions
+/// take.
+pub fn verify(function: &Function, cp: &ConstantPool) -> anyhow::Result<()>{
+    ensure!(function.block(Function::ENTRY).is_some_and(|b| b.predecessors.is_empty()), "The entry block can't have predecessors");
+
+    let mut reached_from = HashMap::<BlockId, Vec<BlockId>>::new();
+    for (id, block) in function.blocks(){
+        for handler in block.handlers.iter(){
+            ensure!(function.handlers.values().any(|h| h == handler), "{} throws to {}, which doesn't enter a handler", id, handler);
+        }
+        for successor in block.successors(){
+            ensure!(function.block(successor).is_some(), "{} continues to {}, which isn't there", id, successor);
+            reached_from.entry(successor).or_default().push(id);
+        }
+    }
+
+    // Where each value is defined, by block and position, phis being at 0.
+    let mut definitions = HashMap::new();
+    for (id, block) in function.blocks(){
+        let mut predecessors = block.predecessors.clone();
+        predecessors.sort();
+        let expected = reached_from.remove(&id).unwrap_or_default();
+        ensure!(predecessors == expected, "{} has predecessors {:?}, but is reached from {:?}", id, predecessors, expected);
+
+        let results = block.phis.iter().map(|p| (p.result, 0))
+            .chain(block.instructions.iter().enumerate().filter_map(|(i, inst)| Some((inst.result?, i + 1))));
+        for (value, position) in results{
+            ensure!((value.0 as usize) < function.types.len(), "{} has no type", value);
+            if definitions.insert(value, (id, position)).is_some(){
+                bail!("{} is defined more than once", value);
+            }
+        }
+    }
+
+    let dominators = dominators(function);
+    let available = |value: ValueId, block: BlockId, position: usize| -> anyhow::Result<()>{
+        let Some((defined, at)) = definitions.get(&value) else {
+            bail!("{} is used in {} but never defined", value, block);
+        };
+        let dominates = match *defined == block {
+            true => *at < position,
+            false => dominators[block.0 as usize][defined.0 as usize]
+        };
+        ensure!(dominates, "{} is used in {} where its definition in {} doesn't dominate", value, block, defined);
+        Ok(())
+    };
+
+    for (id, block) in function.blocks(){
+        for phi in block.phis.iter(){
+            let mut incoming = phi.incoming.iter().map(|(p, _)| *p).collect::<Vec<_>>();
+            incoming.sort();
+            let mut predecessors = block.predecessors.clone();
+            predecessors.sort();
+            ensure!(incoming == predecessors, "{} in {} has operands from {:?}, not the predecessors {:?}", phi.result, id, incoming, predecessors);
+            for (predecessor, value) in phi.incoming.iter(){
+                available(*value, *predecessor, usize::MAX)?;
+                ensure!(function.type_of(*value) == function.type_of(phi.result), "{} has type {:?} but its operand {} is {:?}", phi.result, function.type_of(phi.result), value, function.type_of(*value));
+            }
+        }
+
+        for (i, inst) in block.instructions.iter().enumerate(){
+            for arg in inst.args.iter(){
+                available(*arg, id, i + 1)?;
+            }
+            match &inst.op {
+                Op::Param(_) => ensure!(id == Function::ENTRY && inst.args.is_empty() && inst.result.is_some(), "Parameters are defined in the entry block"),
+                Op::Const(constant) => ensure!(inst.result.map(|r| function.type_of(r)) == Some(constant.value()), "Constant {} has the wrong type", constant),
+                Op::Copy => {
+                    let (Some(result), [arg]) = (inst.result, &inst.args[..]) else {
+                        bail!("A copy takes one value and results in one");
+                    };
+                    ensure!(function.type_of(result) == function.type_of(*arg), "{} copies {} of another type", result, arg);
+                }
+                Op::Catch => {
+                    let entry = function.handlers.values().any(|h| *h == id);
+                    ensure!(entry && inst.args.is_empty() && inst.result.is_some_and(|r| function.type_of(r) == Value::Ref), "Only the entries of handlers catch an exception");
+                }
+                Op::Bytecode(at, instruction) => check_bytecode(function, cp, instruction, &inst.args, inst.result)
+                    .map_err(|e| e.context(format!("At {} in {}", at.as_u32(), id)))?
+            }
+        }
+
+        for arg in block.terminator.args(){
+            available(arg, id, usize::MAX)?;
+        }
+        match &block.terminator {
+            Terminator::Jump(_) => (),
+            Terminator::Branch{at, instruction, args, ..} |
+            Terminator::Exit{at, instruction, args} => check_bytecode(function, cp, instruction, args, None)
+                .map_err(|e| e.context(format!("At {} in {}", at.as_u32(), id)))?,
+            Terminator::Switch{value, ..} => ensure!(function.type_of(*value) == Value::I32, "Switches take an int")
+        }
+    }
+    Ok(())
+}
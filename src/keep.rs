@@ -0,0 +1,346 @@
+//! Keep rules, for classes and members only used through reflection.
+//!
+//! The syntax is a subset of ProGuard's `-keep` option:
+//!
+//! ```text
+//! # Loaded with Class.forName
+//! -keep public class com.example.** extends java.applet.Applet {
+//!     public <init>();
+//!     static int version;
+//!     *** get*(...);
+//! }
+//! ```
+//!
+//! Class names are written with dots. In names, `?` matches one character and
+//! `*` any number of characters other than the package separator, `**` also
+//! matches separators. `***` as a type matches every type and `...` as the
+//! arguments matches every argument list. Members can also be `<fields>`,
+//! `<methods>` or `*` for all of them. Modifiers can be negated with `!`.
+//!
+//! Everything a rule matches is a root of the reachability analysis, and
+//! keeps its name should names ever be shortened.
+
+use anyhow::bail;
+use noak::AccessFlags;
+use peg::parser;
+
+use crate::{classes::all_interfaces, data::ParsedClass, descriptor::descriptor_parser, program::{MethodKey, Program}, reach::{Item, Roots}};
+
+/// A name with wildcards, stored with `/` as the package separator.
+#[derive(Clone, Debug)]
+pub struct Pattern(Box<[u8]>);
+
+impl Pattern{
+    fn new(text: &str) -> Self{
+        Self(text.replace('.', "/").into_bytes().into_boxed_slice())
+    }
+
+    pub fn matches(&self, name: &[u8]) -> bool{
+        fn matches(pattern: &[u8], name: &[u8]) -> bool{
+            match pattern {
+                [] => name.is_empty(),
+                [b'*', b'*', rest @ ..] => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+                [b'*', rest @ ..] => {
+                    let segment = name.iter().position(|c| *c == b'/').unwrap_or(name.len());
+                    (0..=segment).any(|i| matches(rest, &name[i..]))
+                }
+                [b'?', rest @ ..] => name.first().is_some_and(|c| *c != b'/') && matches(rest, &name[1..]),
+                [c, rest @ ..] => name.first() == Some(c) && matches(rest, &name[1..])
+            }
+        }
+        matches(&self.0, name)
+    }
+}
+
+/// Modifiers a class or member has to have, and ones it must not have.
+#[derive(Clone, Copy, Debug)]
+pub struct Access{
+    required: AccessFlags,
+    forbidden: AccessFlags,
+}
+
+impl Access{
+    fn new(modifiers: Vec<(bool, AccessFlags)>) -> Self{
+        let mut access = Self{
+            required: AccessFlags::empty(),
+            forbidden: AccessFlags::empty()
+        };
+        for (negated, flag) in modifiers{
+            if negated{
+                access.forbidden |= flag;
+            }
+            else{
+                access.required |= flag;
+            }
+        }
+        access
+    }
+
+    fn matches(&self, flags: AccessFlags) -> bool{
+        flags.contains(self.required) && !flags.intersects(self.forbidden)
+    }
+}
+
+fn modifier(word: &str) -> Option<AccessFlags>{
+    Some(match word {
+        "public" => AccessFlags::PUBLIC,
+        "private" => AccessFlags::PRIVATE,
+        "protected" => AccessFlags::PROTECTED,
+        "static" => AccessFlags::STATIC,
+        "final" => AccessFlags::FINAL,
+        "abstract" => AccessFlags::ABSTRACT,
+        "native" => AccessFlags::NATIVE,
+        "synchronized" => AccessFlags::SYNCHRONIZED,
+        "volatile" => AccessFlags::VOLATILE,
+        "transient" => AccessFlags::TRANSIENT,
+        _ => return None
+    })
+}
+
+/// A field, argument or return type.
+#[derive(Clone, Debug)]
+pub enum TypePattern{
+    /// `***`
+    Any,
+    Void,
+    /// A primitive type by its descriptor and array dimensions.
+    Primitive(u8, usize),
+    Class(Pattern, usize),
+}
+
+impl TypePattern{
+    fn new(name: &str, dimensions: usize) -> Self{
+        let primitive = match name {
+            "***" => return Self::Any,
+            "void" if dimensions == 0 => return Self::Void,
+            "boolean" => b'Z',
+            "byte" => b'B',
+            "char" => b'C',
+            "short" => b'S',
+            "int" => b'I',
+            "long" => b'J',
+            "float" => b'F',
+            "double" => b'D',
+            _ => return Self::Class(Pattern::new(name), dimensions)
+        };
+        Self::Primitive(primitive, dimensions)
+    }
+
+    /// Matches a field descriptor, or `V`.
+    fn matches(&self, descriptor: &[u8]) -> bool{
+        let dimensions = descriptor.iter().take_while(|c| **c == b'[').count();
+        let base = &descriptor[dimensions..];
+        match self {
+            Self::Any => true,
+            Self::Void => descriptor == b"V",
+            Self::Primitive(c, d) => *d == dimensions && base == [*c],
+            Self::Class(pattern, d) => *d == dimensions && base.len() > 2 && base[0] == b'L' && pattern.matches(&base[1..base.len() - 1])
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Member{
+    /// `*`
+    Any,
+    /// `<fields>`
+    Fields,
+    /// `<methods>`
+    Methods,
+    Field(TypePattern, Pattern),
+    /// Return type, name and arguments, `None` for `...`.
+    Method(TypePattern, Pattern, Option<Vec<TypePattern>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct MemberSpec{
+    access: Access,
+    member: Member,
+}
+
+impl MemberSpec{
+    fn matches_field(&self, flags: AccessFlags, name: &[u8], descriptor: &[u8]) -> bool{
+        self.access.matches(flags) && match &self.member {
+            Member::Any | Member::Fields => true,
+            Member::Field(ty, pattern) => ty.matches(descriptor) && pattern.matches(name),
+            _ => false
+        }
+    }
+
+    fn matches_method(&self, flags: AccessFlags, name: &[u8], descriptor: &[u8]) -> bool{
+        if !self.access.matches(flags){
+            return false;
+        }
+        match &self.member {
+            Member::Any | Member::Methods => name != b"<clinit>",
+            Member::Method(result, pattern, arguments) => {
+                let Some(end) = descriptor.iter().position(|c| *c == b')') else {
+                    return false;
+                };
+                if !result.matches(&descriptor[end + 1..]) || !pattern.matches(name){
+                    return false;
+                }
+                let Some(arguments) = arguments else {
+                    return true;
+                };
+                let Ok((params, _)) = descriptor_parser::method(descriptor) else {
+                    return false;
+                };
+                params.len() == arguments.len() && params.iter().zip(arguments).all(|(p, a)| a.matches(&p.descriptor()))
+            }
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClassSpec{
+    access: Access,
+    interface: bool,
+    name: Pattern,
+    /// Pattern of a superclass or interface the class has to have.
+    supertype: Option<Pattern>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule{
+    pub line: usize,
+    class: ClassSpec,
+    members: Vec<MemberSpec>,
+}
+
+parser!(
+    grammar rules_parser() for str{
+        rule space() = quiet!{[' ' | '\t' | '\r' | '\n'] / "#" [^'\n']*}
+        rule _ = space()*
+        rule __ = space()+
+
+        rule word_char() = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '$' | '*' | '?' | '.']
+        rule word() -> &'input str
+        = $(word_char()+)
+
+        rule modifier() -> (bool, AccessFlags)
+        = negated:("!" _)? w:$(
+            "public" / "private" / "protected" / "static" / "final" / "abstract" /
+            "native" / "synchronized" / "volatile" / "transient"
+        ) !word_char() {(negated.is_some(), modifier(w).unwrap())}
+
+        rule access() -> Access
+        = m:(m:modifier() __ {m})* {Access::new(m)}
+
+        rule type_() -> TypePattern
+        = n:word() d:(_ "[" _ "]")* {TypePattern::new(n, d.len())}
+
+        rule arguments() -> Option<Vec<TypePattern>>
+        = "(" _ a:("..." {None} / a:(type_() ** (_ "," _)) {Some(a)}) _ ")" {a}
+
+        rule member() -> Member
+        = "*" &(_ ";") {Member::Any}
+        / "<fields>" {Member::Fields}
+        / "<methods>" {Member::Methods}
+        / "<init>" _ a:arguments() {Member::Method(TypePattern::Void, Pattern::new("<init>"), a)}
+        / t:type_() __ n:word() _ a:arguments() {Member::Method(t, Pattern::new(n), a)}
+        / t:type_() __ n:word() {Member::Field(t, Pattern::new(n))}
+
+        rule member_spec() -> MemberSpec
+        = access:access() member:member() _ ";" {MemberSpec{access, member}}
+
+        rule class_spec() -> ClassSpec
+        = access:access() interface:("class" !word_char() {false} / "interface" !word_char() {true}) __ name:word()
+          supertype:(__ ("extends" / "implements") __ s:word() {s})?
+        {ClassSpec{access, interface, name: Pattern::new(name), supertype: supertype.map(Pattern::new)}}
+
+        rule rule_() -> (usize, ClassSpec, Vec<MemberSpec>)
+        = at:position!() "-keep" __ class:class_spec() _ members:("{" _ m:(m:member_spec() _ {m})* "}" {m})?
+        {(at, class, members.unwrap_or_default())}
+
+        pub rule rules() -> Vec<(usize, ClassSpec, Vec<MemberSpec>)>
+        = _ r:(r:rule_() _ {r})* ![_] {r}
+    }
+);
+
+/// Every rule of one or more rule files.
+#[derive(Clone, Debug, Default)]
+pub struct KeepRules{
+    rules: Vec<Rule>,
+}
+
+impl KeepRules{
+    pub fn parse(text: &str) -> anyhow::Result<Self>{
+        let parsed = match rules_parser::rules(text) {
+            Ok(parsed) => parsed,
+            Err(e) => bail!("Line {}, column {}: expected {}", e.location.line, e.location.column, e.expected)
+        };
+        Ok(Self{
+            rules: parsed.into_iter()
+                .map(|(at, class, members)| Rule{
+                    line: text[..at].matches('\n').count() + 1,
+                    class,
+                    members
+                })
+                .collect()
+        })
+    }
+
+    pub fn extend(&mut self, other: KeepRules){
+        self.rules.extend(other.rules);
+    }
+
+    pub fn rules(&self) -> &[Rule]{
+        &self.rules
+    }
+
+    fn matching<'a>(&'a self, program: &'a Program, class: &'a ParsedClass) -> impl Iterator<Item = &'a Rule> + 'a{
+        self.rules.iter().filter(move |rule|{
+            let spec = &rule.class;
+            if !spec.access.matches(class.flags) || (spec.interface && !class.flags.contains(AccessFlags::INTERFACE)) || !spec.name.matches(&class.name){
+                return false;
+            }
+            let Some(supertype) = &spec.supertype else {
+                return true;
+            };
+            // Superclasses outside the program are only known by name.
+            let superclasses = program.superclasses(&class.name).filter_map(|c| c.inherited.as_deref());
+            superclasses.chain(all_interfaces(program, &class.name)).any(|s| supertype.matches(s))
+        })
+    }
+
+    /// Whether a rule keeps the item.
+    pub fn keeps(&self, program: &Program, item: &Item) -> bool{
+        let class_name = match item {
+            Item::Class(class) | Item::Field(class, _) => class,
+            Item::Method(key) => &key.class
+        };
+        let Some(class) = program.class(class_name) else {
+            return false;
+        };
+        self.matching(program, class).any(|rule| match item {
+            Item::Class(_) => true,
+            Item::Method(key) => class.method(&key.name, &key.descriptor)
+                .is_some_and(|m| rule.members.iter().any(|spec| spec.matches_method(m.flags, &m.name, &m.descriptor))),
+            Item::Field(_, name) => class.field(name)
+                .is_some_and(|f| rule.members.iter().any(|spec| spec.matches_field(f.flags, &f.name, &f.descriptor)))
+        })
+    }
+
+    /// Adds everything the rules keep to the roots.
+    pub fn add_roots(&self, program: &Program, roots: &mut Roots){
+        for class in program.classes(){
+            if self.matching(program, class).next().is_none(){
+                continue;
+            }
+            roots.classes.push(class.name.clone());
+            for method in class.methods.iter(){
+                let key = MethodKey::new(&class.name, &method.name, &method.descriptor);
+                if self.keeps(program, &Item::Method(key.clone())){
+                    roots.methods.push(key);
+                }
+            }
+            for field in class.fields.iter(){
+                if self.keeps(program, &Item::Field(class.name.clone(), field.name.clone())){
+                    roots.fields.push((class.name.clone(), field.name.clone()));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Memory layout of objects in linear memory.
+//!
+//! Every object starts with a header holding its class id and a word reserved
+//! for the runtime. Address 0 is `null`, static data is placed from
+//! [`DATA_BASE`] and upwards.
+//!
+//! Arrays continue with their length and, for arrays of references, the
+//! [type tag](type_tag) of their component type, followed by the elements.
+//! Primitive arrays have a class id for each element type, arrays of
+//! references all have [`class_id::OBJECT_ARRAY`].
+
+pub const NULL: u32 = 0;
+pub const DATA_BASE: u32 = 16;
+
+pub const HEADER_CLASS_OFFSET: u32 = 0;
+pub const HEADER_MONITOR_OFFSET: u32 = 4;
+pub const OBJECT_HEADER_SIZE: u32 = 8;
+
+pub const ARRAY_LENGTH_OFFSET: u32 = 8;
+pub const ARRAY_COMPONENT_OFFSET: u32 = 12;
+pub const ARRAY_DATA_OFFSET: u32 = 16;
+
+/// Type tags name a reference type in one word, as the class id of a class
+/// or primitive array wrapped in this many dimensions of arrays of references.
+pub const TAG_DIMENSIONS_SHIFT: u32 = 24;
+
+/// Type tag of `dimensions` levels of arrays around the class or primitive array `id`.
+pub const fn type_tag(id: u32, dimensions: u32) -> u32{
+    dimensions << TAG_DIMENSIONS_SHIFT | id
+}
+
+pub const STRING_VALUE_OFFSET: u32 = 8;
+pub const STRING_CODER_OFFSET: u32 = 12;
+pub const STRING_HASH_OFFSET: u32 = 16;
+pub const STRING_SIZE: u32 = 20;
+
+pub const CODER_LATIN1: u8 = 0;
+pub const CODER_UTF16: u8 = 1;
+
+/// Class ids the runtime needs to know about before any class is translated.
+pub mod class_id{
+    pub const OBJECT: u32 = 1;
+    pub const STRING: u32 = 2;
+    pub const BYTE_ARRAY: u32 = 3;
+    pub const BOOLEAN_ARRAY: u32 = 4;
+    pub const CHAR_ARRAY: u32 = 5;
+    pub const SHORT_ARRAY: u32 = 6;
+    pub const INT_ARRAY: u32 = 7;
+    pub const FLOAT_ARRAY: u32 = 8;
+    pub const LONG_ARRAY: u32 = 9;
+    pub const DOUBLE_ARRAY: u32 = 10;
+    pub const OBJECT_ARRAY: u32 = 11;
+
+    /// First id handed out to translated classes.
+    pub const FIRST_FREE: u32 = 16;
+}
+
+/// Class id of the array of the primitive type with the descriptor character.
+pub fn primitive_array(descriptor: u8) -> Option<u32>{
+    Some(match descriptor {
+        b'B' => class_id::BYTE_ARRAY,
+        b'Z' => class_id::BOOLEAN_ARRAY,
+        b'C' => class_id::CHAR_ARRAY,
+        b'S' => class_id::SHORT_ARRAY,
+        b'I' => class_id::INT_ARRAY,
+        b'F' => class_id::FLOAT_ARRAY,
+        b'J' => class_id::LONG_ARRAY,
+        b'D' => class_id::DOUBLE_ARRAY,
+        _ => return None
+    })
+}
+
+/// Log2 of the element size of arrays by class id, two bits each, elements
+/// of arrays of references taking 4 bytes.
+pub const ELEMENT_SHIFTS: u32 = {
+    let mut shifts = 0xAAAA_AAAA;
+    let sizes = [
+        (class_id::BYTE_ARRAY, 0),
+        (class_id::BOOLEAN_ARRAY, 0),
+        (class_id::CHAR_ARRAY, 1),
+        (class_id::SHORT_ARRAY, 1),
+        (class_id::LONG_ARRAY, 3),
+        (class_id::DOUBLE_ARRAY, 3),
+    ];
+    let mut i = 0;
+    while i < sizes.len(){
+        let (id, shift) = sizes[i];
+        shifts = shifts & !(3 << (2 * id)) | shift << (2 * id);
+        i += 1;
+    }
+    shifts
+};
+
+/// Log2 of the element size of the array with the class id.
+pub const fn element_shift(id: u32) -> u32{
+    ELEMENT_SHIFTS >> (2 * id) & 3
+}
+
+/// Bytes placed in the data segment, addressed from `base`.
+pub struct DataSegment{
+    base: u32,
+    bytes: Vec<u8>
+}
+
+impl DataSegment{
+    pub fn new(base: u32) -> Self{
+        Self{
+            base,
+            bytes: Vec::new()
+        }
+    }
+
+    pub fn base(&self) -> u32{
+        self.base
+    }
+
+    /// First address after the segment.
+    pub fn end(&self) -> u32{
+        self.base + self.bytes.len() as u32
+    }
+
+    pub fn bytes(&self) -> &[u8]{
+        &self.bytes
+    }
+
+    /// Reserves `size` zeroed bytes aligned to `align` and returns their address.
+    pub fn alloc(&mut self, size: u32, align: u32) -> u32{
+        assert!(align.is_power_of_two(), "Alignment must be a power of two!");
+        let address = (self.end() + align - 1) & !(align - 1);
+        let new_len = (address + size - self.base) as usize;
+        self.bytes.resize(new_len, 0);
+        address
+    }
+
+    pub fn write_bytes(&mut self, address: u32, bytes: &[u8]){
+        let start = (address - self.base) as usize;
+        self.bytes[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, address: u32, value: u8){
+        self.write_bytes(address, &[value]);
+    }
+
+    pub fn write_u32(&mut self, address: u32, value: u32){
+        self.write_bytes(address, &value.to_le_bytes());
+    }
+
+    /// Writes an object header for `class` at `address`.
+    pub fn write_header(&mut self, address: u32, class: u32){
+        self.write_u32(address + HEADER_CLASS_OFFSET, class);
+        self.write_u32(address + HEADER_MONITOR_OFFSET, 0);
+    }
+}
+
+/// Class info records, one per class, found through the class table indexed
+/// by class id. Arrays have records too, with the vtable of `Object`.
+///
+/// A record holds the id of the superclass (0 for none), the instance size,
+/// a pointer to the itable, the monitor locked by static synchronized methods,
+/// a pointer to the display, a pointer to the interface set, the interface
+/// bit of an interface (-1 for classes), the address of the `Class` object
+/// (null if the program has no `Class`) and the vtable entries. The itable is
+/// a count followed by pairs of interface selector and table index. Vtable and
+/// itable entries are indices into the function table.
+///
+/// The display is a count followed by the ids of the superclasses from
+/// `Object` down to the class itself, so a class at depth `d` is a subclass
+/// of another if its display holds that class at index `d`. The interface set
+/// has the bit of every interface the class implements.
+pub const INFO_SUPER_OFFSET: u32 = 0;
+pub const INFO_SIZE_OFFSET: u32 = 4;
+pub const INFO_ITABLE_OFFSET: u32 = 8;
+pub const INFO_MONITOR_OFFSET: u32 = 12;
+pub const INFO_DISPLAY_OFFSET: u32 = 16;
+pub const INFO_INTERFACES_OFFSET: u32 = 20;
+pub const INFO_INTERFACE_BIT_OFFSET: u32 = 24;
+pub const INFO_CLASS_OFFSET: u32 = 28;
+pub const INFO_VTABLE_OFFSET: u32 = 32;
+
+/// Bytes a field with the given descriptor takes in an object.
+pub fn field_size(descriptor: &[u8]) -> u32{
+    match descriptor.first() {
+        Some(b'B' | b'Z') => 1,
+        Some(b'C' | b'S') => 2,
+        Some(b'J' | b'D') => 8,
+        _ => 4
+    }
+}
@@ -0,0 +1,30 @@
+pub mod blocks;
+pub mod cache;
+pub mod classes;
+pub mod classpath;
+pub mod code;
+pub mod data;
+pub mod debug;
+pub mod deps;
+pub mod descriptor;
+pub mod disassemble;
+pub mod dot;
+pub mod indy;
+pub mod init;
+pub mod ir;
+pub mod keep;
+pub mod layout;
+pub mod link;
+pub mod lower;
+pub mod manifest;
+pub mod module;
+pub mod natives;
+pub mod optimize;
+pub mod program;
+pub mod reach;
+pub mod resources;
+pub mod runtime;
+pub mod strings;
+pub mod trace;
+pub mod translate;
+pub mod work;
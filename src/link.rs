@@ -0,0 +1,386 @@
+//! Separate compilation into linked modules.
+//!
+//! A translation can be split into [`Units`], every class going into one of
+//! them. The first module, [`CORE`], defines the memory, the function table
+//! and every global, holds the data segment and the functions that don't
+//! implement a method, like the runtime helpers and the wrappers of the
+//! exports. Every other module imports those from it, so it's instantiated
+//! first, and the others follow in any order.
+//!
+//! Functions keep the table slot they would have in a single module, and
+//! every module fills the slots of its own functions, so vtables and
+//! interface tables in the data segment stay valid. Calls to the core go
+//! through imports, calls to functions of other units go through the table.
+//! Units never import from each other, so cycles between them don't matter.
+//!
+//! As the initializers the start function runs can be in any unit, the core
+//! exports it as [`START_EXPORT`] instead of starting it itself. Hosts call it
+//! once every module is instantiated, the [manifest] lists what they need.
+//!
+//! With [`Chunks`] the core is a startup module and the other units are
+//! loaded on demand, the way applets streamed their classes. The classes
+//! the startup code may use without a barrier stay in the core: the entry
+//! classes, the classes whose methods the runtime calls and their
+//! supertypes. Every other class is first used through a class
+//! initialization barrier, which now also asks the host to load the chunks
+//! holding the class and its supertypes through [`LOAD_CHUNK`], once per
+//! chunk. Instances only exist after such a barrier, so the methods virtual
+//! calls reach are loaded too. Chunks have no start function, loading one
+//! only fills its table slots.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::bail;
+use serde_json::json;
+use wasm_encoder::{BlockType, ConstExpr, Encode, Instruction, ValType};
+
+use crate::{classes::all_interfaces, debug::SourceMap, module::ModuleBuilder, program::Program, runtime::RUNTIME_MODULE};
+
+/// Name of the unit that defines what the others import.
+pub const CORE: &str = "core";
+
+/// Export of the core's start function, to be called after instantiating every module.
+pub const START_EXPORT: &str = "start";
+
+/// Import from [`RUNTIME_MODULE`], `(chunk id) -> ()` instantiates the
+/// module of the unit with the id, see [`Chunks`].
+pub const LOAD_CHUNK: &str = "loadChunk";
+
+/// Export names the core gives the globals and functions other modules import.
+pub fn global_export(index: u32) -> String{
+    format!("global.{}", index)
+}
+
+pub fn function_export(index: u32) -> String{
+    format!("function.{}", index)
+}
+
+/// Which unit each class is translated into.
+#[derive(Clone, Debug)]
+pub struct Units{
+    /// Names of the units, the core first.
+    names: Vec<String>,
+    classes: HashMap<Box<[u8]>, usize>,
+}
+
+impl Default for Units{
+    fn default() -> Self{
+        Self{
+            names: vec![CORE.into()],
+            classes: HashMap::new()
+        }
+    }
+}
+
+impl Units{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// A unit for every package of the program, named like `java.lang`.
+    /// Classes without a package go into `default`.
+    pub fn by_package(program: &Program) -> Self{
+        let mut units = Self::new();
+        for class in program.classes(){
+            let package = match class.name.iter().rposition(|b| *b == b'/') {
+                Some(end) => String::from_utf8_lossy(&class.name[..end]).replace('/', "."),
+                None => "default".into()
+            };
+            units.assign(&package, &class.name);
+        }
+        units
+    }
+
+    /// Puts the class into the unit, which is added if it doesn't exist.
+    pub fn assign(&mut self, unit: &str, class: &[u8]){
+        let index = match self.names.iter().position(|n| n == unit) {
+            Some(index) => index,
+            None => {
+                self.names.push(unit.into());
+                self.names.len() - 1
+            }
+        };
+        self.classes.insert(class.into(), index);
+    }
+
+    /// Index of the class's unit, the core for classes that weren't assigned.
+    pub fn unit(&self, class: &[u8]) -> usize{
+        self.classes.get(class).copied().unwrap_or(0)
+    }
+
+    pub fn names(&self) -> &[String]{
+        &self.names
+    }
+
+    pub fn len(&self) -> usize{
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.names.is_empty()
+    }
+}
+
+/// Units that are loaded once a barrier of one of their classes runs.
+pub struct Chunks{
+    units: Units,
+    /// Global telling whether the chunk was loaded, by unit.
+    loaded: BTreeMap<usize, u32>,
+    load: u32,
+}
+
+impl Chunks{
+    /// Imports [`LOAD_CHUNK`], this has to happen with the other imports.
+    pub fn import(module: &mut ModuleBuilder) -> u32{
+        module.import(RUNTIME_MODULE, LOAD_CHUNK, &[ValType::I32], &[])
+    }
+
+    /// Moves the classes the startup code needs into the core, `startup`
+    /// being the entry classes and the classes the runtime uses, and adds
+    /// the globals of the chunks that are left.
+    pub fn new(program: &Program, mut units: Units, startup: impl IntoIterator<Item = Box<[u8]>>, module: &mut ModuleBuilder, load: u32) -> Self{
+        let mut pending = startup.into_iter().collect::<Vec<_>>();
+        let mut core = BTreeSet::new();
+        while let Some(class) = pending.pop(){
+            if !core.insert(class.clone()){
+                continue;
+            }
+            pending.extend(program.superclasses(&class).map(|c| c.name.clone()));
+            pending.extend(all_interfaces(program, &class).into_iter().map(Box::from));
+        }
+        for class in core{
+            units.assign(CORE, &class);
+        }
+
+        let used = units.classes.values().copied().filter(|u| *u != 0).collect::<BTreeSet<_>>();
+        let loaded = used.into_iter().map(|unit| (unit, module.add_global(ValType::I32, true, ConstExpr::i32_const(0)))).collect();
+        Self{
+            units,
+            loaded,
+            load
+        }
+    }
+
+    pub fn units(&self) -> &Units{
+        &self.units
+    }
+
+    /// Emits the loads of the chunks holding the class and its supertypes.
+    /// Returns whether anything was emitted.
+    pub fn emit_loads(&self, program: &Program, out: &mut Vec<Instruction<'static>>, class: &[u8]) -> bool{
+        let mut chunks = program.superclasses(class).map(|c| self.units.unit(&c.name)).collect::<BTreeSet<_>>();
+        chunks.insert(self.units.unit(class));
+        chunks.extend(all_interfaces(program, class).into_iter().map(|i| self.units.unit(i)));
+        let mut emitted = false;
+        for (unit, loaded) in chunks.into_iter().filter_map(|u| self.loaded.get(&u).map(|l| (u, *l))){
+            out.push(Instruction::GlobalGet(loaded));
+            out.push(Instruction::I32Eqz);
+            out.push(Instruction::If(BlockType::Empty));
+            out.push(Instruction::I32Const(1));
+            out.push(Instruction::GlobalSet(loaded));
+            out.push(Instruction::I32Const(unit as i32));
+            out.push(Instruction::Call(self.load));
+            out.push(Instruction::End);
+            emitted = true;
+        }
+        emitted
+    }
+}
+
+/// One module of a split translation.
+pub struct LinkedModule{
+    /// Index of the unit, which identifies the chunk to [`LOAD_CHUNK`].
+    pub id: usize,
+    pub name: String,
+    pub wasm: Vec<u8>,
+    pub source_map: SourceMap,
+    pub functions: usize,
+    /// Units whose functions it calls.
+    pub depends: Vec<String>,
+}
+
+/// Offsets in a body where lines start, with the line.
+pub type Lines = Vec<(u32, u32)>;
+
+/// How a call to a function of the whole program is made from a module.
+pub enum Callee{
+    /// A function of the module or one it imports, by its index there.
+    Direct(u32),
+    /// Another unit's function, by its table slot and type.
+    Table{
+        slot: u32,
+        type_index: u32
+    },
+}
+
+/// Reads encoded instructions, see the binary format of the spec.
+struct Decoder<'a>{
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Decoder<'_>{
+    fn byte(&mut self) -> anyhow::Result<u8>{
+        let Some(byte) = self.bytes.get(self.at) else {
+            bail!("Function body ends early");
+        };
+        self.at += 1;
+        Ok(*byte)
+    }
+
+    /// Reads a LEB128, signed or not.
+    fn leb(&mut self) -> anyhow::Result<u64>{
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift < 64{
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0{
+                return Ok(value);
+            }
+        }
+    }
+
+    fn skip(&mut self, len: usize){
+        self.at += len;
+    }
+
+    /// Skips the immediates of an instruction, returns the function index of calls.
+    fn immediates(&mut self, opcode: u8) -> anyhow::Result<Option<u32>>{
+        match opcode {
+            // Block types are one byte unless they are type indices.
+            0x02..=0x04 => {
+                if !matches!(self.bytes.get(self.at), Some(0x40 | 0x6F | 0x70 | 0x7B..=0x7F)){
+                    self.leb()?;
+                }
+                else{
+                    self.skip(1);
+                }
+            }
+            0x0C | 0x0D | 0x20..=0x26 | 0x41 | 0x42 | 0xD0 => {
+                self.leb()?;
+            }
+            0x0E => {
+                for _ in 0..=self.leb()?{
+                    self.leb()?;
+                }
+            }
+            0x10 => return Ok(Some(self.leb()? as u32)),
+            0x11 | 0x28..=0x3E => {
+                self.leb()?;
+                self.leb()?;
+            }
+            0x1C => {
+                let count = self.leb()?;
+                self.skip(count as usize);
+            }
+            0x3F | 0x40 => self.skip(1),
+            0x43 => self.skip(4),
+            0x44 => self.skip(8),
+            0xD2 => bail!("ref.func can't be relinked"),
+            0xFC => match self.leb()? {
+                0..=7 => {}
+                8 | 12 | 14 => {
+                    self.leb()?;
+                    self.leb()?;
+                }
+                9 | 13 | 15..=17 => {
+                    self.leb()?;
+                }
+                10 => self.skip(2),
+                11 => self.skip(1),
+                other => bail!("Unknown instruction 0xFC {}", other)
+            },
+            0xFE => match self.leb()? {
+                0x03 => self.skip(1),
+                _ => {
+                    self.leb()?;
+                    self.leb()?;
+                }
+            },
+            0x00 | 0x01 | 0x05 | 0x0B | 0x0F | 0x1A | 0x1B | 0x45..=0xC4 | 0xD1 => {}
+            other => bail!("Unknown instruction 0x{:02X}", other)
+        }
+        Ok(None)
+    }
+
+    /// Skips the local declarations at the start of a body.
+    fn locals(&mut self) -> anyhow::Result<()>{
+        for _ in 0..self.leb()?{
+            self.leb()?;
+            self.skip(1);
+        }
+        Ok(())
+    }
+}
+
+/// Functions the encoded body calls.
+pub fn calls(body: &[u8]) -> anyhow::Result<Vec<u32>>{
+    let mut decoder = Decoder{bytes: body, at: 0};
+    decoder.locals()?;
+    let mut calls = Vec::new();
+    while decoder.at < body.len(){
+        let opcode = decoder.byte()?;
+        calls.extend(decoder.immediates(opcode)?);
+    }
+    Ok(calls)
+}
+
+/// Rewrites the calls of an encoded body for another module, moving the
+/// offsets of its lines along.
+pub fn relink(body: &[u8], lines: &[(u32, u32)], mut callee: impl FnMut(u32) -> Callee) -> anyhow::Result<(Vec<u8>, Lines)>{
+    let mut decoder = Decoder{bytes: body, at: 0};
+    decoder.locals()?;
+    let mut out = body[..decoder.at].to_vec();
+    // Offsets after which the body grew by the amount, in order.
+    let mut shifts = Vec::<(u32, i64)>::new();
+    while decoder.at < body.len(){
+        let start = decoder.at;
+        let opcode = decoder.byte()?;
+        let Some(function) = decoder.immediates(opcode)? else {
+            out.extend(&body[start..decoder.at]);
+            continue;
+        };
+        match callee(function) {
+            Callee::Direct(index) => Instruction::Call(index).encode(&mut out),
+            Callee::Table{slot, type_index} => {
+                Instruction::I32Const(slot as i32).encode(&mut out);
+                Instruction::CallIndirect{ty: type_index, table: 0}.encode(&mut out);
+            }
+        }
+        let grown = out.len() as i64 - decoder.at as i64;
+        if shifts.last().is_none_or(|(_, last)| *last != grown){
+            shifts.push((start as u32, grown));
+        }
+    }
+
+    let lines = lines.iter().map(|(offset, line)|{
+        let shift = match shifts.partition_point(|(start, _)| start < offset) {
+            0 => 0,
+            i => shifts[i - 1].1
+        };
+        ((*offset as i64 + shift) as u32, *line)
+    }).collect();
+    Ok((out, lines))
+}
+
+/// JSON description of how the modules of a split translation are linked,
+/// `lazy` if the modules besides the core are chunks.
+pub fn manifest(modules: &[LinkedModule], lazy: bool) -> serde_json::Value{
+    let units = modules.iter().map(|m| json!({
+        "id": m.id,
+        "name": m.name,
+        "functions": m.functions,
+        "depends": m.depends,
+    })).collect::<Vec<_>>();
+    let imports = BTreeMap::from([("memory", "memory"), ("table", "table"), ("globals", "global.<index>"), ("functions", "function.<index>")]);
+    json!({
+        "core": CORE,
+        "imports": imports,
+        "start": START_EXPORT,
+        "load_chunk": lazy.then(|| format!("{}.{}", RUNTIME_MODULE, LOAD_CHUNK)),
+        "modules": units
+    })
+}
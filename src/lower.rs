@@ -0,0 +1,1887 @@
+//! Lowering of bytecode instructions to WebAssembly instructions.
+//!
+//! A method becomes a loop around a `br_table` on a `pc` local that picks the
+//! basic block to run next, with the blocks laid out in bytecode order inside
+//! nested wasm blocks so that falling through to the next block needs no
+//! branch. Within a block the operand stack is the wasm operand stack; values
+//! left on it at the end of a block are passed in one local per stack depth
+//! and type.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::bail;
+use noak::{AccessFlags, reader::{attributes::{ArrayType, Index as Offset, RawInstruction}, cpool::{self, ConstantPool, Index, Item}}};
+use wasm_encoder::{BlockType, Function, Instruction, MemArg, ValType};
+
+use crate::{blocks::{self, Block, Handler}, cache::Cache, classes::Layouts, code::{self, Stack, Value}, data::{ParsedClass, ParsedMethod}, debug::MethodDebug, descriptor::{descriptor_parser, JavaType}, indy::{self, CallSite, Piece}, init::{InitPlan, Slot, Statics, CLINIT, CLINIT_DESCRIPTOR, ERRONEOUS, INITIALIZED}, ir, layout, link::Chunks, module::{signature, ModuleBuilder}, optimize::{Inline, Optimizer}, program::{MethodKey, Program}, runtime::{self, Monitors, Runtime, ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, ARRAY_STORE_EXCEPTION, CLASS_CAST_EXCEPTION, NO_CLASS_DEF_FOUND_ERROR, NULL_POINTER_EXCEPTION}, strings::StringTable, trace::{self, TraceTable}};
+
+/// Lowers the constant loaded by `ldc`, `ldc_w` and `ldc2_w`.
+pub fn lower_constant(cp: &ConstantPool, index: Index<Item>, strings: &StringTable) -> anyhow::Result<Instruction<'static>>{
+    Ok(match cp.get(index)? {
+        Item::Integer(i) => Instruction::I32Const(i.value),
+        Item::Float(f) => Instruction::F32Const(f.value),
+        Item::Long(l) => Instruction::I64Const(l.value),
+        Item::Double(d) => Instruction::F64Const(d.value),
+        Item::String(s) => {
+            let content = cp.get(s.string)?.content;
+            Instruction::I32Const(strings.address_of(content.as_bytes())? as i32)
+        }
+        x => bail!("Unsupported constant {:?}", x)
+    })
+}
+
+/// Everything the lowering of a method needs to know about the rest of the program.
+pub struct Env<'a>{
+    pub program: &'a Program,
+    pub strings: &'a StringTable,
+    pub init: &'a InitPlan,
+    pub statics: &'a Statics,
+    pub module: &'a ModuleBuilder,
+    pub layouts: &'a Layouts,
+    pub runtime: &'a Runtime,
+    pub optimizer: &'a Optimizer,
+    pub trace: &'a TraceTable,
+    /// Where the operand stacks at the start of blocks are kept between runs.
+    pub cache: Option<&'a Cache>,
+    /// Units loaded by the barriers of their classes.
+    pub chunks: Option<&'a Chunks>,
+}
+
+/// Emits the initialization barrier of `class` unless it's in `initialized`,
+/// which first loads the chunks of the class if there are any.
+/// Returns whether anything was emitted, the barrier may leave an exception
+/// pending.
+pub fn emit_barrier(env: &Env, initialized: &mut HashSet<Box<[u8]>>, out: &mut Vec<Instruction<'static>>, class: &[u8]) -> bool{
+    if initialized.contains(class){
+        return false;
+    }
+    let loaded = env.chunks.is_some_and(|chunks| chunks.emit_loads(env.program, out, class));
+    if !env.init.needs_barrier(class){
+        if loaded{
+            initialized.insert(class.into());
+        }
+        return loaded;
+    }
+    initialized.insert(class.into());
+
+    let state = match env.statics.init_state(class).expect("Classes with barriers have an init state!") {
+        Slot::Global(state) => state,
+        // Threads claim the initialization in the runtime, so it runs once.
+        Slot::Memory(address) => {
+            let (Some(init_enter), Some(init_exit)) = (env.runtime.init_enter, env.runtime.init_exit) else {
+                panic!("Init states are only in memory with atomic monitors!");
+            };
+            out.push(Instruction::I32Const(address as i32));
+            out.push(Instruction::I32AtomicLoad(mem(0, 2)));
+            out.push(Instruction::I32Const(INITIALIZED));
+            out.push(Instruction::I32Ne);
+            out.push(Instruction::If(BlockType::Empty));
+            out.push(Instruction::I32Const(address as i32));
+            out.push(Instruction::Call(init_enter));
+            out.push(Instruction::If(BlockType::Empty));
+            emit_initializer(env, initialized, out, class);
+            out.push(Instruction::I32Const(address as i32));
+            out.push(Instruction::Call(init_exit));
+            out.push(Instruction::End);
+            out.push(Instruction::End);
+            return true;
+        }
+    };
+    out.push(Instruction::GlobalGet(state));
+    out.push(Instruction::I32Eqz);
+    out.push(Instruction::If(BlockType::Empty));
+    out.push(Instruction::I32Const(INITIALIZED));
+    out.push(Instruction::GlobalSet(state));
+    emit_initializer(env, initialized, out, class);
+    out.push(Instruction::GlobalGet(env.runtime.exception));
+    out.push(Instruction::If(BlockType::Empty));
+    out.push(Instruction::I32Const(ERRONEOUS));
+    out.push(Instruction::GlobalSet(state));
+    out.push(Instruction::End);
+    out.push(Instruction::Else);
+    out.push(Instruction::GlobalGet(state));
+    out.push(Instruction::I32Const(ERRONEOUS));
+    out.push(Instruction::I32Eq);
+    out.push(Instruction::If(BlockType::Empty));
+    out.push(Instruction::Call(env.runtime.throw(NO_CLASS_DEF_FOUND_ERROR)));
+    out.push(Instruction::End);
+    out.push(Instruction::End);
+    true
+}
+
+/// Initializes the superclass, then runs `<clinit>` unless that failed.
+fn emit_initializer(env: &Env, initialized: &mut HashSet<Box<[u8]>>, out: &mut Vec<Instruction<'static>>, class: &[u8]){
+    let super_class = env.program.class(class).and_then(|c| c.inherited.clone());
+    let after_super = super_class.is_some_and(|s| emit_barrier(env, initialized, out, &s));
+    if let Some(clinit) = env.module.function(&MethodKey::new(class, CLINIT, CLINIT_DESCRIPTOR)){
+        // A superclass that failed to initialize fails this class too.
+        if after_super{
+            out.push(Instruction::GlobalGet(env.runtime.exception));
+            out.push(Instruction::I32Eqz);
+            out.push(Instruction::If(BlockType::Empty));
+        }
+        out.push(Instruction::Call(clinit));
+        if after_super{
+            out.push(Instruction::End);
+        }
+    }
+}
+
+/// Computes whether the conditional jump `instruction` is taken from its operands.
+pub fn condition(instruction: &RawInstruction) -> anyhow::Result<Vec<Instruction<'static>>>{
+    Ok(match instruction {
+        RawInstruction::IfEq { .. } |
+        RawInstruction::IfNull { .. } => vec![Instruction::I32Eqz],
+        RawInstruction::IfNe { .. } |
+        RawInstruction::IfNonNull { .. } => vec![],
+        RawInstruction::IfLt { .. } => vec![Instruction::I32Const(0), Instruction::I32LtS],
+        RawInstruction::IfGe { .. } => vec![Instruction::I32Const(0), Instruction::I32GeS],
+        RawInstruction::IfGt { .. } => vec![Instruction::I32Const(0), Instruction::I32GtS],
+        RawInstruction::IfLe { .. } => vec![Instruction::I32Const(0), Instruction::I32LeS],
+        RawInstruction::IfICmpEq { .. } |
+        RawInstruction::IfACmpEq { .. } => vec![Instruction::I32Eq],
+        RawInstruction::IfICmpNe { .. } |
+        RawInstruction::IfACmpNe { .. } => vec![Instruction::I32Ne],
+        RawInstruction::IfICmpLt { .. } => vec![Instruction::I32LtS],
+        RawInstruction::IfICmpGe { .. } => vec![Instruction::I32GeS],
+        RawInstruction::IfICmpGt { .. } => vec![Instruction::I32GtS],
+        RawInstruction::IfICmpLe { .. } => vec![Instruction::I32LeS],
+        x => bail!("{:?} is not a conditional jump", x)
+    })
+}
+
+/// The instructions of an `x2y` conversion, see [`code::conversion`].
+///
+/// Float to integer conversions saturate and turn NaN into 0 like the JVM's.
+pub fn conversion(instruction: &RawInstruction) -> anyhow::Result<Vec<Instruction<'static>>>{
+    Ok(match instruction {
+        RawInstruction::I2L => vec![Instruction::I64ExtendI32S],
+        RawInstruction::I2F => vec![Instruction::F32ConvertI32S],
+        RawInstruction::I2D => vec![Instruction::F64ConvertI32S],
+        RawInstruction::I2B => vec![Instruction::I32Extend8S],
+        RawInstruction::I2C => vec![Instruction::I32Const(0xFFFF), Instruction::I32And],
+        RawInstruction::I2S => vec![Instruction::I32Extend16S],
+        RawInstruction::L2I => vec![Instruction::I32WrapI64],
+        RawInstruction::L2F => vec![Instruction::F32ConvertI64S],
+        RawInstruction::L2D => vec![Instruction::F64ConvertI64S],
+        RawInstruction::F2I => vec![Instruction::I32TruncSatF32S],
+        RawInstruction::F2L => vec![Instruction::I64TruncSatF32S],
+        RawInstruction::F2D => vec![Instruction::F64PromoteF32],
+        RawInstruction::D2I => vec![Instruction::I32TruncSatF64S],
+        RawInstruction::D2L => vec![Instruction::I64TruncSatF64S],
+        RawInstruction::D2F => vec![Instruction::F32DemoteF64],
+        x => bail!("{:?} is not a conversion", x)
+    })
+}
+
+/// The element type of an array load or store, the log2 of its size and the
+/// instruction accessing it.
+fn array_access(instruction: &RawInstruction) -> Option<(Value, u32, Instruction<'static>)>{
+    let data = layout::ARRAY_DATA_OFFSET;
+    Some(match instruction {
+        // Booleans are stored as bytes.
+        RawInstruction::BALoad => (Value::I32, 0, Instruction::I32Load8S(mem(data, 0))),
+        RawInstruction::CALoad => (Value::I32, 1, Instruction::I32Load16U(mem(data, 1))),
+        RawInstruction::SALoad => (Value::I32, 1, Instruction::I32Load16S(mem(data, 1))),
+        RawInstruction::IALoad => (Value::I32, 2, Instruction::I32Load(mem(data, 2))),
+        RawInstruction::FALoad => (Value::F32, 2, Instruction::F32Load(mem(data, 2))),
+        RawInstruction::AALoad => (Value::Ref, 2, Instruction::I32Load(mem(data, 2))),
+        RawInstruction::LALoad => (Value::I64, 3, Instruction::I64Load(mem(data, 3))),
+        RawInstruction::DALoad => (Value::F64, 3, Instruction::F64Load(mem(data, 3))),
+        RawInstruction::BAStore => (Value::I32, 0, Instruction::I32Store8(mem(data, 0))),
+        RawInstruction::CAStore |
+        RawInstruction::SAStore => (Value::I32, 1, Instruction::I32Store16(mem(data, 1))),
+        RawInstruction::IAStore => (Value::I32, 2, Instruction::I32Store(mem(data, 2))),
+        RawInstruction::FAStore => (Value::F32, 2, Instruction::F32Store(mem(data, 2))),
+        RawInstruction::AAStore => (Value::Ref, 2, Instruction::I32Store(mem(data, 2))),
+        RawInstruction::LAStore => (Value::I64, 3, Instruction::I64Store(mem(data, 3))),
+        RawInstruction::DAStore => (Value::F64, 3, Instruction::F64Store(mem(data, 3))),
+        _ => return None
+    })
+}
+
+/// Descriptor character of the element type `newarray` creates an array of.
+fn array_type_descriptor(atype: ArrayType) -> u8{
+    match atype {
+        ArrayType::Boolean => b'Z',
+        ArrayType::Char => b'C',
+        ArrayType::Float => b'F',
+        ArrayType::Double => b'D',
+        ArrayType::Byte => b'B',
+        ArrayType::Short => b'S',
+        ArrayType::Int => b'I',
+        ArrayType::Long => b'J',
+    }
+}
+
+fn zero(value: Value) -> Instruction<'static>{
+    match value.val_type() {
+        ValType::I64 => Instruction::I64Const(0),
+        ValType::F32 => Instruction::F32Const(0.0),
+        ValType::F64 => Instruction::F64Const(0.0),
+        _ => Instruction::I32Const(0),
+    }
+}
+
+fn mem(offset: u32, align: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align,
+        memory_index: 0
+    }
+}
+
+fn load_field(descriptor: &[u8], offset: u32) -> Instruction<'static>{
+    match descriptor.first() {
+        Some(b'B') => Instruction::I32Load8S(mem(offset, 0)),
+        Some(b'Z') => Instruction::I32Load8U(mem(offset, 0)),
+        Some(b'C') => Instruction::I32Load16U(mem(offset, 1)),
+        Some(b'S') => Instruction::I32Load16S(mem(offset, 1)),
+        Some(b'F') => Instruction::F32Load(mem(offset, 2)),
+        Some(b'J') => Instruction::I64Load(mem(offset, 3)),
+        Some(b'D') => Instruction::F64Load(mem(offset, 3)),
+        _ => Instruction::I32Load(mem(offset, 2)),
+    }
+}
+
+fn store_field(descriptor: &[u8], offset: u32) -> Instruction<'static>{
+    match descriptor.first() {
+        Some(b'B' | b'Z') => Instruction::I32Store8(mem(offset, 0)),
+        Some(b'C' | b'S') => Instruction::I32Store16(mem(offset, 1)),
+        Some(b'F') => Instruction::F32Store(mem(offset, 2)),
+        Some(b'J') => Instruction::I64Store(mem(offset, 3)),
+        Some(b'D') => Instruction::F64Store(mem(offset, 3)),
+        _ => Instruction::I32Store(mem(offset, 2)),
+    }
+}
+
+/// Local variables of the function being built.
+struct Locals{
+    params: u32,
+    types: Vec<ValType>,
+    variables: HashMap<(u16, Value), u32>,
+    stack: HashMap<(usize, Value), u32>,
+    /// Scratch locals per type and how many are in use by the current instruction.
+    temps: HashMap<Value, (Vec<u32>, usize)>,
+}
+
+impl Locals{
+    fn add(&mut self, value: Value) -> u32{
+        self.types.push(value.val_type());
+        self.params + self.types.len() as u32 - 1
+    }
+
+    /// Local holding the JVM local variable `slot` while it has type `value`.
+    fn variable(&mut self, slot: u16, value: Value) -> u32{
+        if let Some(local) = self.variables.get(&(slot, value)){
+            return *local;
+        }
+        let local = self.add(value);
+        self.variables.insert((slot, value), local);
+        local
+    }
+
+    /// Local passing the operand stack value at `depth` between blocks.
+    fn stack(&mut self, depth: usize, value: Value) -> u32{
+        if let Some(local) = self.stack.get(&(depth, value)){
+            return *local;
+        }
+        let local = self.add(value);
+        self.stack.insert((depth, value), local);
+        local
+    }
+
+    fn temp(&mut self, value: Value) -> u32{
+        let (pool, used) = self.temps.entry(value).or_default();
+        if *used < pool.len(){
+            *used += 1;
+            return pool[*used - 1];
+        }
+        let (params, index) = (self.params, self.types.len() as u32);
+        pool.push(params + index);
+        *used += 1;
+        self.types.push(value.val_type());
+        params + index
+    }
+
+    fn release_temps(&mut self){
+        for (_, used) in self.temps.values_mut(){
+            *used = 0;
+        }
+    }
+}
+
+/// Operand stack types at the start of every block, `None` for blocks that are never reached.
+pub fn entry_stacks(blocks: &[Block], instructions: &[(Offset, RawInstruction)], handlers: &[Handler], cp: &ConstantPool) -> anyhow::Result<Vec<Option<Vec<Value>>>>{
+    fn reach(blocks: &[Block], entries: &mut [Option<Vec<Value>>], pending: &mut Vec<usize>, offset: u32, values: &[Value]) -> anyhow::Result<()>{
+        let Ok(index) = blocks.binary_search_by_key(&offset, |b| b.start) else {
+            bail!("No block starts at {}", offset);
+        };
+        match &entries[index] {
+            None => {
+                entries[index] = Some(values.to_vec());
+                pending.push(index);
+            }
+            Some(entry) if entry != values => bail!("Stack mismatch at {}: {:?} and {:?}", offset, entry, values),
+            Some(_) => ()
+        }
+        Ok(())
+    }
+
+    let mut entries = vec![None; blocks.len()];
+    let mut pending = Vec::new();
+    reach(blocks, &mut entries, &mut pending, 0, &[])?;
+
+    while let Some(index) = pending.pop(){
+        let block = &blocks[index];
+        let mut stack = Stack::with_values(entries[index].as_ref().unwrap());
+        for (at, instruction) in instructions[block.instructions.clone()].iter(){
+            for handler in handlers.iter().filter(|h| h.covers(at.as_u32())){
+                reach(blocks, &mut entries, &mut pending, handler.handler, &[Value::Ref])?;
+            }
+            code::step(&mut stack, instruction, cp)?;
+        }
+        for successor in block.successors.iter(){
+            reach(blocks, &mut entries, &mut pending, *successor, stack.values())?;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Copies the operands the phis of `to` take from `from` into their locals.
+fn phi_copies(function: &ir::Function, values: &HashMap<ir::ValueId, u32>, from: ir::BlockId, to: ir::BlockId) -> anyhow::Result<Vec<Instruction<'static>>>{
+    let Some(block) = function.block(to) else {
+        bail!("{} continues to {}, which isn't there", from, to);
+    };
+    // All operands are read before any phi is written, as phis can be
+    // operands of the phis of the same block.
+    let mut copies = Vec::with_capacity(2 * block.phis.len());
+    for phi in block.phis.iter(){
+        let Some((_, value)) = phi.incoming.iter().find(|(p, _)| *p == from) else {
+            bail!("{} has no operand for {}", phi.result, from);
+        };
+        copies.push(Instruction::LocalGet(values[value]));
+    }
+    for phi in block.phis.iter().rev(){
+        copies.push(Instruction::LocalSet(values[&phi.result]));
+    }
+    Ok(copies)
+}
+
+/// Lowers a method with code to the body of its function.
+pub fn lower_method(env: &Env, class: &ParsedClass, method: &ParsedMethod) -> anyhow::Result<Lowered>{
+    let lowered = class.with_code(&method.name, &method.descriptor, |cp, code|{
+        let instructions = code.raw_instructions().collect::<Result<Vec<_>, _>>()?;
+        let handlers = Handler::read_all(code, cp)?;
+        let blocks = blocks::split(&instructions, &handlers)?;
+        let entries = match env.cache {
+            Some(cache) => cache.entry_stacks(class, method, || entry_stacks(&blocks, &instructions, &handlers, cp))?,
+            None => entry_stacks(&blocks, &instructions, &handlers, cp)?
+        };
+
+        if env.optimizer.options().ssa{
+            let mut function = ir::build(cp, &instructions, &blocks, &handlers, &entries, method)?;
+            ir::optimize(&mut function);
+            ir::verify(&function, cp)?;
+            let checks = ir::Checks::analyze(&function, cp)?;
+            let mut lowerer = Lowerer::new(env, class, method, cp, handlers, &blocks)?;
+            lowerer.debug = MethodDebug::read(code, cp)?;
+            lowerer.ssa(&function, &checks)?;
+            env.optimizer.record_checks(&lowerer.key, &checks);
+            return Ok(lowerer.finish());
+        }
+        let mut lowerer = Lowerer::new(env, class, method, cp, handlers, &blocks)?;
+        lowerer.debug = MethodDebug::read(code, cp)?;
+        lowerer.method(&blocks, &instructions, &entries)?;
+        Ok(lowerer.finish())
+    })?;
+    match lowered {
+        Some(lowered) => Ok(lowered),
+        None => bail!("{} has no code", MethodKey::new(&class.name, &method.name, &method.descriptor))
+    }
+}
+
+/// Lowers the instructions of one method.
+pub struct Lowerer<'a, 'p>{
+    env: &'a Env<'a>,
+    class: &'a ParsedClass,
+    /// The method being lowered, for the optimization log.
+    key: MethodKey,
+    cp: &'a ConstantPool<'p>,
+    handlers: Vec<Handler>,
+    /// Index of the block starting at each leader.
+    block_indices: HashMap<u32, u32>,
+    /// In the SSA form, the entry block of each handler the current block
+    /// throws to, by the handler's offset, with the copies into its phis.
+    handler_edges: Option<HashMap<u32, (u32, Vec<Instruction<'static>>)>>,
+    /// Classes known to be initialized at the current instruction.
+    initialized: HashSet<Box<[u8]>>,
+    locals: Locals,
+    pc: u32,
+    result: Option<Value>,
+    /// Local holding the address of the monitor word a synchronized method holds.
+    monitor: Option<u32>,
+    /// Labels between the current instruction and the dispatch loop.
+    depth: u32,
+    /// Offset of the instruction being lowered.
+    offset: u32,
+    /// Id of the method in its frame words.
+    id: u32,
+    /// Operand stack types before and after the instruction being lowered.
+    before: Vec<Value>,
+    after: Vec<Value>,
+    /// Whether the operand the current instruction checks for `null` is known not to be.
+    non_null: bool,
+    /// Whether the index of the current array access is known to be in bounds.
+    in_bounds: bool,
+    /// Line numbers and variable names of the method.
+    debug: MethodDebug,
+    /// Instructions in `out` that start a line, with the line.
+    lines: Vec<(usize, u32)>,
+    pub out: Vec<Instruction<'static>>,
+}
+
+/// The body of a method's function, with what the debug sections need.
+pub struct Lowered{
+    pub body: Function,
+    /// Offsets in the body where lines start, with the line.
+    pub lines: Vec<(u32, u32)>,
+    /// Names of the locals that hold JVM local variables.
+    pub locals: Vec<(u32, String)>,
+}
+
+impl<'a, 'p> Lowerer<'a, 'p>{
+    pub fn new(env: &'a Env<'a>, class: &'a ParsedClass, method: &'a ParsedMethod, cp: &'a ConstantPool<'p>, handlers: Vec<Handler>, blocks: &[Block]) -> anyhow::Result<Self>{
+        let Ok((params, result)) = descriptor_parser::method(&method.descriptor) else {
+            bail!("Invalid method descriptor {}", String::from_utf8_lossy(&method.descriptor));
+        };
+
+        let mut locals = Locals{
+            params: 0,
+            types: Vec::new(),
+            variables: HashMap::new(),
+            stack: HashMap::new(),
+            temps: HashMap::new()
+        };
+        let mut slot = 0;
+        if !method.flags.contains(AccessFlags::STATIC){
+            locals.variables.insert((0, Value::Ref), 0);
+            slot += 1;
+        }
+        for param in params.iter(){
+            let value = Value::of_type(param);
+            locals.variables.insert((slot, value), locals.variables.len() as u32);
+            slot += if value.is_big() {2} else {1};
+        }
+        locals.params = locals.variables.len() as u32;
+        let pc = locals.add(Value::I32);
+        let monitor = method.flags.contains(AccessFlags::SYNCHRONIZED).then(|| locals.add(Value::I32));
+        let key = MethodKey::new(&class.name, &method.name, &method.descriptor);
+        let Some(id) = env.trace.id(&key) else {
+            bail!("{} has no method id", key);
+        };
+
+        let mut lowerer = Self{
+            env,
+            class,
+            key,
+            cp,
+            handlers,
+            block_indices: blocks.iter().enumerate().map(|(i, b)| (b.start, i as u32)).collect(),
+            handler_edges: None,
+            initialized: HashSet::new(),
+            locals,
+            pc,
+            result: result.as_ref().map(Value::of_type),
+            monitor,
+            depth: 0,
+            offset: 0,
+            id,
+            before: Vec::new(),
+            after: Vec::new(),
+            non_null: false,
+            in_bounds: false,
+            debug: MethodDebug::default(),
+            lines: Vec::new(),
+            out: Vec::new()
+        };
+        lowerer.start_block();
+        lowerer.out.push(Instruction::I32Const(trace::frame_word(id, 0) as i32));
+        lowerer.out.push(Instruction::Call(env.runtime.enter_frame));
+
+        if let Some(monitor) = monitor{
+            // Static methods lock the class, which has its monitor word in the class info.
+            if method.flags.contains(AccessFlags::STATIC){
+                let Some(layout) = env.layouts.class(&class.name) else {
+                    bail!("{} has no layout", String::from_utf8_lossy(&class.name));
+                };
+                lowerer.out.push(Instruction::I32Const((layout.info + layout::INFO_MONITOR_OFFSET) as i32));
+            }
+            else{
+                lowerer.out.push(Instruction::LocalGet(0));
+                lowerer.out.push(Instruction::I32Const(layout::HEADER_MONITOR_OFFSET as i32));
+                lowerer.out.push(Instruction::I32Add);
+            }
+            lowerer.out.push(Instruction::LocalTee(monitor));
+            lowerer.out.push(Instruction::Call(env.runtime.monitor_enter));
+            // Atomic monitors throw when the recursion count runs out, the
+            // method returns without owning the monitor then.
+            if env.runtime.monitors == Monitors::Atomic{
+                lowerer.out.push(Instruction::GlobalGet(env.runtime.exception));
+                lowerer.open(Instruction::If(BlockType::Empty));
+                lowerer.return_default();
+                lowerer.close();
+            }
+        }
+        Ok(lowerer)
+    }
+
+    fn method(&mut self, blocks: &[Block], instructions: &[(Offset, RawInstruction)], entries: &[Option<Vec<Value>>]) -> anyhow::Result<()>{
+        let count = blocks.len() as u32;
+        self.out.push(Instruction::Loop(BlockType::Empty));
+        for _ in 0..count{
+            self.out.push(Instruction::Block(BlockType::Empty));
+        }
+        self.out.push(Instruction::LocalGet(self.pc));
+        self.out.push(Instruction::BrTable((0..count).collect(), 0));
+
+        for (i, block) in blocks.iter().enumerate(){
+            self.out.push(Instruction::End);
+            self.depth = count - 1 - i as u32;
+            self.start_block();
+
+            let Some(entry) = &entries[i] else {
+                self.out.push(Instruction::Unreachable);
+                continue;
+            };
+            for (depth, value) in entry.iter().enumerate(){
+                let local = self.locals.stack(depth, *value);
+                self.out.push(Instruction::LocalGet(local));
+            }
+
+            let mut stack = entry.clone();
+            for (at, instruction) in instructions[block.instructions.clone()].iter(){
+                self.offset = at.as_u32();
+                let mut after = Stack::with_values(&stack);
+                code::step(&mut after, instruction, self.cp)?;
+                self.before = std::mem::replace(&mut stack, after.values().to_vec());
+                self.after = stack.clone();
+                self.locals.release_temps();
+                self.mark_line(*at);
+                self.instruction(*at, instruction)?;
+            }
+
+            let (at, last) = &instructions[block.instructions.end - 1];
+            if blocks::jump_targets(*at, last)?.is_none(){
+                self.spill();
+            }
+        }
+
+        self.out.push(Instruction::End);
+        self.out.push(Instruction::Unreachable);
+        self.out.push(Instruction::End);
+        Ok(())
+    }
+
+    /// Lowers the SSA form of the method, with a local for every value. The
+    /// blocks are laid out like the bytecode blocks in [`Self::method`], and
+    /// the operands of the phis of a block are copied into their locals on
+    /// the edges to it.
+    fn ssa(&mut self, function: &ir::Function, checks: &ir::Checks) -> anyhow::Result<()>{
+        let mut values = HashMap::new();
+        for (_, block) in function.blocks(){
+            for phi in block.phis.iter(){
+                values.insert(phi.result, self.locals.add(function.type_of(phi.result)));
+            }
+            for inst in block.instructions.iter(){
+                let Some(result) = inst.result else {
+                    continue;
+                };
+                let local = match inst.op {
+                    ir::Op::Param(local) => local,
+                    _ => self.locals.add(function.type_of(result))
+                };
+                values.insert(result, local);
+            }
+        }
+        let get = |value: &ir::ValueId| Instruction::LocalGet(values[value]);
+
+        let count = function.blocks.len() as u32;
+        self.out.push(Instruction::Loop(BlockType::Empty));
+        for _ in 0..count{
+            self.out.push(Instruction::Block(BlockType::Empty));
+        }
+        self.out.push(Instruction::LocalGet(self.pc));
+        self.out.push(Instruction::BrTable((0..count).collect(), 0));
+
+        for (i, block) in function.blocks.iter().enumerate(){
+            self.out.push(Instruction::End);
+            self.depth = count - 1 - i as u32;
+            self.start_block();
+            let id = ir::BlockId(i as u32);
+
+            let Some(block) = block else {
+                self.out.push(Instruction::Unreachable);
+                continue;
+            };
+            let mut handler_edges = HashMap::new();
+            for (offset, entry) in function.handlers.iter().filter(|(_, e)| block.handlers.contains(e)){
+                handler_edges.insert(*offset, (entry.0, phi_copies(function, &values, id, *entry)?));
+            }
+            self.handler_edges = Some(handler_edges);
+            for (position, inst) in block.instructions.iter().enumerate(){
+                if let ir::Op::Bytecode(at, _) = &inst.op{
+                    self.mark_line(*at);
+                }
+                self.out.extend(inst.args.iter().map(get));
+                match &inst.op {
+                    ir::Op::Param(_) => continue,
+                    ir::Op::Const(constant) => self.out.push(constant.lower()),
+                    ir::Op::Copy => (),
+                    // The exception stays pending until the handler's entry takes it.
+                    ir::Op::Catch => self.out.extend([
+                        Instruction::GlobalGet(self.env.runtime.exception),
+                        Instruction::I32Const(layout::NULL as i32),
+                        Instruction::GlobalSet(self.env.runtime.exception),
+                    ]),
+                    ir::Op::Bytecode(at, instruction) => {
+                        self.offset = at.as_u32();
+                        self.before = inst.args.iter().map(|a| function.type_of(*a)).collect();
+                        self.after = inst.result.iter().map(|r| function.type_of(*r)).collect();
+                        self.locals.release_temps();
+                        self.non_null = checks.is_non_null(id, position);
+                        self.in_bounds = checks.is_in_bounds(id, position);
+                        self.instruction(*at, instruction)?;
+                        self.non_null = false;
+                        self.in_bounds = false;
+                    }
+                }
+                if let Some(result) = inst.result{
+                    self.out.push(Instruction::LocalSet(values[&result]));
+                }
+            }
+
+            self.locals.release_temps();
+            match &block.terminator {
+                ir::Terminator::Jump(target) => self.edge(function, &values, id, *target, true)?,
+                ir::Terminator::Branch{at, instruction, args, then, otherwise} => {
+                    self.offset = at.as_u32();
+                    self.out.extend(args.iter().map(get));
+                    self.out.extend(condition(instruction)?);
+                    self.open(Instruction::If(BlockType::Empty));
+                    self.edge(function, &values, id, *then, false)?;
+                    self.close();
+                    self.edge(function, &values, id, *otherwise, true)?;
+                }
+                ir::Terminator::Switch{value, cases, default} => {
+                    for (key, target) in cases.iter(){
+                        self.out.push(get(value));
+                        self.out.push(Instruction::I32Const(*key));
+                        self.out.push(Instruction::I32Eq);
+                        self.open(Instruction::If(BlockType::Empty));
+                        self.edge(function, &values, id, *target, false)?;
+                        self.close();
+                    }
+                    self.edge(function, &values, id, *default, true)?;
+                }
+                ir::Terminator::Exit{at, instruction, args} => {
+                    self.offset = at.as_u32();
+                    self.out.extend(args.iter().map(get));
+                    self.before = args.iter().map(|a| function.type_of(*a)).collect();
+                    self.after = Vec::new();
+                    self.non_null = checks.is_non_null(id, block.instructions.len());
+                    self.instruction(*at, instruction)?;
+                    self.non_null = false;
+                }
+            }
+        }
+
+        self.out.push(Instruction::End);
+        self.out.push(Instruction::Unreachable);
+        self.out.push(Instruction::End);
+        Ok(())
+    }
+
+    /// Continues from the block `from` with `to`, passing the operands of its
+    /// phis. At the end of `from` a jump to the next block falls through.
+    fn edge(&mut self, function: &ir::Function, values: &HashMap<ir::ValueId, u32>, from: ir::BlockId, to: ir::BlockId, at_end: bool) -> anyhow::Result<()>{
+        self.out.extend(phi_copies(function, values, from, to)?);
+        if !at_end || to.0 != from.0 + 1{
+            self.goto(to.0);
+        }
+        Ok(())
+    }
+
+    /// Remembers that the code of the instruction at `at` starts here, if
+    /// it starts a new line.
+    fn mark_line(&mut self, at: Offset){
+        let Some(line) = self.debug.line(at.as_u32()) else {
+            return;
+        };
+        if self.lines.last().is_none_or(|(_, last)| *last != line){
+            self.lines.push((self.out.len(), line));
+        }
+    }
+
+    fn finish(self) -> Lowered{
+        let mut locals = Vec::new();
+        for ((slot, value), local) in self.locals.variables.iter(){
+            let name = self.debug.variable_name(*slot, *value).unwrap_or_else(|| format!("local{}", slot));
+            locals.push((*local, name));
+        }
+        locals.push((self.pc, "pc".into()));
+        locals.extend(self.monitor.map(|m| (m, "monitor".into())));
+        locals.sort();
+
+        let mut body = Function::new_with_locals_types(self.locals.types);
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut marks = self.lines.iter().peekable();
+        for (i, instruction) in self.out.iter().enumerate(){
+            while let Some((_, line)) = marks.next_if(|(at, _)| *at == i){
+                lines.push((body.byte_len() as u32, *line));
+            }
+            body.instruction(instruction);
+        }
+        Lowered{
+            body,
+            lines,
+            locals
+        }
+    }
+
+    /// Forgets what is known from straight-line code, to be called at the start of every basic block.
+    ///
+    /// Code in a class only runs once the class has been initialized, which
+    /// also initialized its superclasses.
+    pub fn start_block(&mut self){
+        self.initialized.clear();
+        for c in self.env.program.superclasses(&self.class.name){
+            self.initialized.insert(c.name.clone());
+        }
+    }
+
+    fn open(&mut self, instruction: Instruction<'static>){
+        self.out.push(instruction);
+        self.depth += 1;
+    }
+
+    fn close(&mut self){
+        self.out.push(Instruction::End);
+        self.depth -= 1;
+    }
+
+    /// Moves the operand stack into the locals that pass it to the next block.
+    fn spill(&mut self){
+        for (depth, value) in self.after.clone().into_iter().enumerate().rev(){
+            let local = self.locals.stack(depth, value);
+            self.out.push(Instruction::LocalSet(local));
+        }
+    }
+
+    /// Continues with the block at `target`, the stack has to be spilled already.
+    fn jump(&mut self, target: u32) -> anyhow::Result<()>{
+        let Some(index) = self.block_indices.get(&target) else {
+            bail!("No block starts at {}", target);
+        };
+        self.goto(*index);
+        Ok(())
+    }
+
+    /// Continues with the block at `index` in the dispatch loop.
+    fn goto(&mut self, index: u32){
+        self.out.push(Instruction::I32Const(index as i32));
+        self.out.push(Instruction::LocalSet(self.pc));
+        self.out.push(Instruction::Br(self.depth));
+    }
+
+    /// Jumps to `target` if the condition computed by `condition` from the operands holds.
+    fn branch(&mut self, condition: &[Instruction<'static>], target: u32) -> anyhow::Result<()>{
+        self.out.extend(condition.iter().cloned());
+        let cond = self.locals.temp(Value::I32);
+        self.out.push(Instruction::LocalSet(cond));
+        self.spill();
+        self.out.push(Instruction::LocalGet(cond));
+        self.open(Instruction::If(BlockType::Empty));
+        self.jump(target)?;
+        self.close();
+        Ok(())
+    }
+
+    /// Returns default values, leaving the exception for the caller.
+    fn propagate(&mut self){
+        if let Some(monitor) = self.monitor{
+            self.out.push(Instruction::LocalGet(monitor));
+            self.out.push(Instruction::Call(self.env.runtime.monitor_exit));
+        }
+        self.return_default();
+    }
+
+    fn return_default(&mut self){
+        if let Some(result) = self.result{
+            self.out.push(zero(result));
+        }
+        self.exit_frame();
+        self.out.push(Instruction::Return);
+    }
+
+    /// Returns the value on the operand stack, if any. Synchronized methods
+    /// release their monitor first, which throws if the method exited it
+    /// already.
+    fn return_value(&mut self){
+        if let Some(monitor) = self.monitor{
+            self.out.push(Instruction::LocalGet(monitor));
+            self.out.push(Instruction::Call(self.env.runtime.monitor_exit));
+            self.out.push(Instruction::GlobalGet(self.env.runtime.exception));
+            self.open(Instruction::If(BlockType::Empty));
+            self.return_default();
+            self.close();
+        }
+        self.exit_frame();
+        self.out.push(Instruction::Return);
+    }
+
+    /// Pops the method's frame off the shadow stack.
+    fn exit_frame(&mut self){
+        self.out.push(Instruction::GlobalGet(self.env.runtime.frame));
+        self.out.push(Instruction::I32Const(4));
+        self.out.push(Instruction::I32Sub);
+        self.out.push(Instruction::GlobalSet(self.env.runtime.frame));
+    }
+
+    /// Stores the offset of the current instruction in the method's frame,
+    /// for the stack trace of an exception thrown by what it calls.
+    fn record_offset(&mut self){
+        self.out.extend(self.offset_instructions());
+    }
+
+    fn offset_instructions(&self) -> [Instruction<'static>; 3]{
+        [
+            Instruction::GlobalGet(self.env.runtime.frame),
+            Instruction::I32Const(trace::frame_word(self.id, self.offset) as i32),
+            Instruction::I32Store(mem(0, 2)),
+        ]
+    }
+
+    /// `monitorenter` and `monitorexit` on the object on the operand stack.
+    fn monitor(&mut self, enter: bool) -> anyhow::Result<()>{
+        let object = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::LocalSet(object));
+        self.null_check(object)?;
+        self.out.push(Instruction::LocalGet(object));
+        self.out.push(Instruction::I32Const(layout::HEADER_MONITOR_OFFSET as i32));
+        self.out.push(Instruction::I32Add);
+        match (enter, self.env.runtime.monitors) {
+            (true, Monitors::SingleThreaded) => {
+                self.out.push(Instruction::Call(self.env.runtime.monitor_enter));
+                Ok(())
+            }
+            // Atomic monitors throw when the recursion count runs out.
+            (true, Monitors::Atomic) => self.call(self.env.runtime.monitor_enter),
+            (false, _) => self.call(self.env.runtime.monitor_exit)
+        }
+    }
+
+    /// Continues at the first handler covering the current instruction that
+    /// catches the pending exception, or returns if there is none.
+    fn dispatch(&mut self) -> anyhow::Result<()>{
+        let exception = self.env.runtime.exception;
+        let offset = self.offset;
+        for handler in self.handlers.clone().iter().filter(|h| h.covers(offset)){
+            let caught = match &handler.catch_type {
+                None => None,
+                // A class that isn't part of the program can't have instances.
+                Some(class) => match self.env.layouts.id(class) {
+                    Some(id) => Some(id),
+                    None => continue
+                }
+            };
+            if let Some(id) = caught{
+                self.out.push(Instruction::GlobalGet(exception));
+                self.out.push(Instruction::I32Const(layout::type_tag(id, 0) as i32));
+                self.out.push(Instruction::Call(self.env.runtime.instance_of));
+                self.open(Instruction::If(BlockType::Empty));
+            }
+
+            match &self.handler_edges {
+                // The entry of the handler takes the exception.
+                Some(edges) => {
+                    let Some((entry, copies)) = edges.get(&handler.handler).cloned() else {
+                        bail!("The instruction at {} isn't known to throw to the handler at {}", offset, handler.handler);
+                    };
+                    self.out.extend(copies);
+                    self.goto(entry);
+                }
+                None => {
+                    let local = self.locals.stack(0, Value::Ref);
+                    self.out.push(Instruction::GlobalGet(exception));
+                    self.out.push(Instruction::LocalSet(local));
+                    self.out.push(Instruction::I32Const(layout::NULL as i32));
+                    self.out.push(Instruction::GlobalSet(exception));
+                    self.jump(handler.handler)?;
+                }
+            }
+
+            if caught.is_none(){
+                return Ok(());
+            }
+            self.close();
+        }
+        self.propagate();
+        Ok(())
+    }
+
+    /// Dispatches the pending exception if there is one.
+    fn check_exception(&mut self) -> anyhow::Result<()>{
+        self.out.push(Instruction::GlobalGet(self.env.runtime.exception));
+        self.open(Instruction::If(BlockType::Empty));
+        self.dispatch()?;
+        self.close();
+        Ok(())
+    }
+
+    /// Calls a function that may throw.
+    fn call(&mut self, function: u32) -> anyhow::Result<()>{
+        self.record_offset();
+        self.out.push(Instruction::Call(function));
+        self.check_exception()
+    }
+
+    /// Throws the exception of the given runtime class.
+    fn throw(&mut self, class: &str) -> anyhow::Result<()>{
+        self.record_offset();
+        self.out.push(Instruction::Call(self.env.runtime.throw(class)));
+        self.dispatch()
+    }
+
+    /// Throws a `NullPointerException` if the local is `null`, unless it's known not to be.
+    fn null_check(&mut self, local: u32) -> anyhow::Result<()>{
+        if self.non_null{
+            return Ok(());
+        }
+        self.out.push(Instruction::LocalGet(local));
+        self.out.push(Instruction::I32Eqz);
+        self.open(Instruction::If(BlockType::Empty));
+        self.throw(NULL_POINTER_EXCEPTION)?;
+        self.close();
+        Ok(())
+    }
+
+    /// Emits the initialization barrier of `class` unless it's known to have run.
+    pub fn barrier(&mut self, class: &[u8]) -> anyhow::Result<()>{
+        let start = self.out.len();
+        if emit_barrier(self.env, &mut self.initialized, &mut self.out, class){
+            let record = self.offset_instructions();
+            self.out.splice(start..start, record);
+            self.check_exception()?;
+        }
+        Ok(())
+    }
+
+    pub fn constant(&mut self, index: Index<Item>) -> anyhow::Result<()>{
+        if let Item::Class(class) = self.cp.get(index)?{
+            let name = self.cp.get(class.name)?.content.as_bytes();
+            let Some(address) = self.env.layouts.class_object(name) else {
+                bail!("No Class object for {}", String::from_utf8_lossy(name));
+            };
+            self.out.push(Instruction::I32Const(address as i32));
+            return Ok(());
+        }
+        self.out.push(lower_constant(self.cp, index, self.env.strings)?);
+        Ok(())
+    }
+
+    /// Slot of the static field, the class declaring it and the field's value type.
+    fn static_field(&self, index: Index<cpool::FieldRef>) -> anyhow::Result<(Box<[u8]>, Slot, Value)>{
+        let fr = self.cp.get(index)?;
+        let class = self.cp.get(self.cp.get(fr.class)?.name)?.content.as_bytes();
+        let nat = self.cp.get(fr.name_and_type)?;
+        let name = self.cp.get(nat.name)?.content.as_bytes();
+        let value = Value::of_descriptor(self.cp.get(nat.descriptor)?.content.as_bytes());
+
+        let Some((declaring, _)) = self.env.program.resolve_field(class, name) else {
+            bail!("Unresolved static field {}.{}", String::from_utf8_lossy(class), String::from_utf8_lossy(name));
+        };
+        let Some(slot) = self.env.statics.field(&declaring.name, name) else {
+            bail!("{}.{} is not static", String::from_utf8_lossy(class), String::from_utf8_lossy(name));
+        };
+        Ok((declaring.name.clone(), slot, value))
+    }
+
+    pub fn get_static(&mut self, index: Index<cpool::FieldRef>) -> anyhow::Result<()>{
+        let (class, slot, value) = self.static_field(index)?;
+        self.barrier(&class)?;
+        match slot {
+            Slot::Global(global) => self.out.push(Instruction::GlobalGet(global)),
+            Slot::Memory(address) => {
+                self.out.push(Instruction::I32Const(address as i32));
+                self.out.push(match value.val_type() {
+                    ValType::I64 => Instruction::I64Load(mem(0, 3)),
+                    ValType::F32 => Instruction::F32Load(mem(0, 2)),
+                    ValType::F64 => Instruction::F64Load(mem(0, 3)),
+                    _ => Instruction::I32Load(mem(0, 2)),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The barrier runs with the value already on the operand stack, which
+    /// doesn't matter since it leaves the stack as it found it.
+    pub fn put_static(&mut self, index: Index<cpool::FieldRef>) -> anyhow::Result<()>{
+        let (class, slot, value) = self.static_field(index)?;
+        self.barrier(&class)?;
+        match slot {
+            Slot::Global(global) => self.out.push(Instruction::GlobalSet(global)),
+            Slot::Memory(address) => {
+                let temp = self.locals.temp(value);
+                self.out.push(Instruction::LocalSet(temp));
+                self.out.push(Instruction::I32Const(address as i32));
+                self.out.push(Instruction::LocalGet(temp));
+                self.out.push(match value.val_type() {
+                    ValType::I64 => Instruction::I64Store(mem(0, 3)),
+                    ValType::F32 => Instruction::F32Store(mem(0, 2)),
+                    ValType::F64 => Instruction::F64Store(mem(0, 3)),
+                    _ => Instruction::I32Store(mem(0, 2)),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Offset and descriptor of an instance field.
+    fn instance_field(&self, index: Index<cpool::FieldRef>) -> anyhow::Result<(u32, Box<[u8]>)>{
+        let fr = self.cp.get(index)?;
+        let class = self.cp.get(self.cp.get(fr.class)?.name)?.content.as_bytes();
+        let name = self.cp.get(self.cp.get(fr.name_and_type)?.name)?.content.as_bytes();
+
+        let Some((declaring, field)) = self.env.program.resolve_field(class, name) else {
+            bail!("Unresolved field {}.{}", String::from_utf8_lossy(class), String::from_utf8_lossy(name));
+        };
+        let Some(offset) = self.env.layouts.field(&declaring.name, name) else {
+            bail!("{}.{} is static", String::from_utf8_lossy(class), String::from_utf8_lossy(name));
+        };
+        Ok((offset, field.descriptor.clone()))
+    }
+
+    pub fn get_field(&mut self, index: Index<cpool::FieldRef>) -> anyhow::Result<()>{
+        let (offset, descriptor) = self.instance_field(index)?;
+        let object = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::LocalSet(object));
+        self.null_check(object)?;
+        self.out.push(Instruction::LocalGet(object));
+        self.out.push(load_field(&descriptor, offset));
+        Ok(())
+    }
+
+    pub fn put_field(&mut self, index: Index<cpool::FieldRef>) -> anyhow::Result<()>{
+        let (offset, descriptor) = self.instance_field(index)?;
+        let value = self.locals.temp(Value::of_descriptor(&descriptor));
+        let object = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::LocalSet(value));
+        self.out.push(Instruction::LocalSet(object));
+        self.null_check(object)?;
+        self.out.push(Instruction::LocalGet(object));
+        self.out.push(Instruction::LocalGet(value));
+        self.out.push(store_field(&descriptor, offset));
+        Ok(())
+    }
+
+    /// Barrier for the class named by the constant, as done by `new`.
+    pub fn class_barrier(&mut self, index: Index<cpool::Class>) -> anyhow::Result<()>{
+        let class = self.cp.get(self.cp.get(index)?.name)?.content.as_bytes();
+        self.barrier(class)
+    }
+
+    /// Allocates an array of the type with the tag, taking the length from the stack.
+    fn new_array(&mut self, tag: u32) -> anyhow::Result<()>{
+        self.out.push(Instruction::I32Const(tag as i32));
+        self.call(self.env.runtime.new_array)
+    }
+
+    /// `multianewarray` passes the counts to the runtime in an int array.
+    fn multi_new_array(&mut self, index: Index<cpool::Class>, dimensions: u8) -> anyhow::Result<()>{
+        let name = self.cp.get(self.cp.get(index)?.name)?.content.as_bytes();
+        let tag = self.env.layouts.type_tag(name)?;
+        let counts = (0..dimensions).map(|_| self.locals.temp(Value::I32)).collect::<Vec<_>>();
+        for count in counts.iter().rev(){
+            self.out.push(Instruction::LocalSet(*count));
+        }
+        let array = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::I32Const(dimensions as i32));
+        self.out.push(Instruction::I32Const(layout::class_id::INT_ARRAY as i32));
+        self.out.push(Instruction::Call(self.env.runtime.new_array));
+        self.out.push(Instruction::LocalSet(array));
+        for (i, count) in counts.iter().enumerate(){
+            self.out.push(Instruction::LocalGet(array));
+            self.out.push(Instruction::LocalGet(*count));
+            self.out.push(Instruction::I32Store(mem(layout::ARRAY_DATA_OFFSET + 4 * i as u32, 2)));
+        }
+        self.out.push(Instruction::LocalGet(array));
+        self.out.push(Instruction::I32Const(0));
+        self.out.push(Instruction::I32Const(tag as i32));
+        self.call(self.env.runtime.multi_new_array)
+    }
+
+    pub fn array_length(&mut self) -> anyhow::Result<()>{
+        let array = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::LocalSet(array));
+        self.null_check(array)?;
+        self.out.push(Instruction::LocalGet(array));
+        self.out.push(Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET, 2)));
+        Ok(())
+    }
+
+    /// Takes the array and the index from the stack into temporaries, checking
+    /// that the array isn't `null` and the index is in bounds.
+    fn element(&mut self) -> anyhow::Result<(u32, u32)>{
+        let (index, array) = (self.locals.temp(Value::I32), self.locals.temp(Value::Ref));
+        self.out.push(Instruction::LocalSet(index));
+        self.out.push(Instruction::LocalSet(array));
+        self.null_check(array)?;
+        if !self.in_bounds{
+            // Negative indices are out of bounds as unsigned ones.
+            self.out.push(Instruction::LocalGet(index));
+            self.out.push(Instruction::LocalGet(array));
+            self.out.push(Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET, 2)));
+            self.out.push(Instruction::I32GeU);
+            self.open(Instruction::If(BlockType::Empty));
+            self.throw(ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION)?;
+            self.close();
+        }
+        Ok((array, index))
+    }
+
+    /// Pushes the address of the element, minus [`layout::ARRAY_DATA_OFFSET`].
+    fn element_address(&mut self, array: u32, index: u32, shift: u32){
+        self.out.push(Instruction::LocalGet(array));
+        self.out.push(Instruction::LocalGet(index));
+        if shift > 0{
+            self.out.push(Instruction::I32Const(shift as i32));
+            self.out.push(Instruction::I32Shl);
+        }
+        self.out.push(Instruction::I32Add);
+    }
+
+    fn array_load(&mut self, instruction: &RawInstruction) -> anyhow::Result<()>{
+        let (_, shift, load) = array_access(instruction).expect("Array loads access an element");
+        let (array, index) = self.element()?;
+        self.element_address(array, index, shift);
+        self.out.push(load);
+        Ok(())
+    }
+
+    fn array_store(&mut self, instruction: &RawInstruction) -> anyhow::Result<()>{
+        let (element, shift, store) = array_access(instruction).expect("Array stores access an element");
+        let value = self.locals.temp(element);
+        self.out.push(Instruction::LocalSet(value));
+        let (array, index) = self.element()?;
+        // References have to be instances of the component type the array was created with.
+        if let RawInstruction::AAStore = instruction{
+            self.out.push(Instruction::LocalGet(value));
+            self.open(Instruction::If(BlockType::Empty));
+            self.out.push(Instruction::LocalGet(value));
+            self.out.push(Instruction::LocalGet(array));
+            self.out.push(Instruction::I32Load(mem(layout::ARRAY_COMPONENT_OFFSET, 2)));
+            self.out.push(Instruction::Call(self.env.runtime.instance_of));
+            self.out.push(Instruction::I32Eqz);
+            self.open(Instruction::If(BlockType::Empty));
+            self.throw(ARRAY_STORE_EXCEPTION)?;
+            self.close();
+            self.close();
+        }
+        self.element_address(array, index, shift);
+        self.out.push(Instruction::LocalGet(value));
+        self.out.push(store);
+        Ok(())
+    }
+
+    /// Pushes whether the object in the local is an instance of the class
+    /// constant. Classes and interfaces are tested inline, through the
+    /// display and the interface set of the object's class.
+    fn instance_of(&mut self, object: u32, index: Index<cpool::Class>) -> anyhow::Result<()>{
+        let name = self.cp.get(self.cp.get(index)?.name)?.content.as_bytes();
+        let class_table = self.env.layouts.class_table();
+        let interface_bit = self.env.layouts.interface_bit(name);
+        let class = self.env.layouts.class(name);
+        if name == b"java/lang/Object"{
+            self.out.push(Instruction::LocalGet(object));
+            self.out.push(Instruction::I32Const(layout::NULL as i32));
+            self.out.push(Instruction::I32Ne);
+            return Ok(());
+        }
+        if interface_bit.is_none() && class.is_none(){
+            self.out.push(Instruction::LocalGet(object));
+            self.out.push(Instruction::I32Const(self.env.layouts.type_tag(name)? as i32));
+            self.out.push(Instruction::Call(self.env.runtime.instance_of));
+            return Ok(());
+        }
+
+        self.out.push(Instruction::LocalGet(object));
+        self.open(Instruction::If(BlockType::Result(ValType::I32)));
+        self.out.push(Instruction::LocalGet(object));
+        self.out.push(Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET, 2)));
+        self.out.extend(runtime::load_class_info(class_table));
+        match (interface_bit, class) {
+            (Some(bit), _) => {
+                self.out.push(Instruction::I32Load(mem(layout::INFO_INTERFACES_OFFSET, 2)));
+                self.out.push(Instruction::I32Load(mem(4 * (bit / 32), 2)));
+                self.out.push(Instruction::I32Const((bit % 32) as i32));
+                self.out.push(Instruction::I32ShrU);
+                self.out.push(Instruction::I32Const(1));
+                self.out.push(Instruction::I32And);
+            }
+            (None, Some(class)) => {
+                let display = self.locals.temp(Value::I32);
+                self.out.push(Instruction::I32Load(mem(layout::INFO_DISPLAY_OFFSET, 2)));
+                self.out.push(Instruction::LocalTee(display));
+                self.out.push(Instruction::I32Load(mem(0, 2)));
+                self.out.push(Instruction::I32Const(class.depth as i32));
+                self.out.push(Instruction::I32GtU);
+                self.open(Instruction::If(BlockType::Result(ValType::I32)));
+                self.out.push(Instruction::LocalGet(display));
+                self.out.push(Instruction::I32Load(mem(4 + 4 * class.depth, 2)));
+                self.out.push(Instruction::I32Const(class.id as i32));
+                self.out.push(Instruction::I32Eq);
+                self.out.push(Instruction::Else);
+                self.out.push(Instruction::I32Const(0));
+                self.close();
+            }
+            (None, None) => unreachable!()
+        }
+        self.out.push(Instruction::Else);
+        self.out.push(Instruction::I32Const(0));
+        self.close();
+        Ok(())
+    }
+
+    /// Throws a `ClassCastException` unless the object on the stack is `null`
+    /// or an instance of the class constant.
+    fn check_cast(&mut self, index: Index<cpool::Class>) -> anyhow::Result<()>{
+        let object = self.locals.temp(Value::Ref);
+        self.out.push(Instruction::LocalTee(object));
+        self.out.push(Instruction::LocalGet(object));
+        self.open(Instruction::If(BlockType::Empty));
+        self.instance_of(object, index)?;
+        self.out.push(Instruction::I32Eqz);
+        self.open(Instruction::If(BlockType::Empty));
+        self.throw(CLASS_CAST_EXCEPTION)?;
+        self.close();
+        self.close();
+        Ok(())
+    }
+
+    pub fn new_object(&mut self, index: Index<cpool::Class>) -> anyhow::Result<()>{
+        let class = self.cp.get(self.cp.get(index)?.name)?.content.as_bytes();
+        let Some(layout) = self.env.layouts.class(class) else {
+            bail!("Can't instantiate {}, it's not part of the program", String::from_utf8_lossy(class));
+        };
+        let (size, id) = (layout.size, layout.id);
+        self.class_barrier(index)?;
+        self.out.push(Instruction::I32Const(size as i32));
+        self.out.push(Instruction::I32Const(id as i32));
+        self.out.push(Instruction::Call(self.env.runtime.alloc));
+        Ok(())
+    }
+
+    /// `invokestatic` initializes the class declaring the method before calling it.
+    /// Methods outside the program are called through their import.
+    pub fn invoke_static(&mut self, index: Index<Item>) -> anyhow::Result<()>{
+        let target = MethodKey::read(self.cp, index)?;
+        let key = match self.env.program.resolve_method(&target.class, &target.name, &target.descriptor) {
+            Some((declaring, _)) => {
+                let declaring = declaring.name.clone();
+                self.barrier(&declaring)?;
+                MethodKey{class: declaring, ..target}
+            }
+            None => target
+        };
+        self.call_direct(&key, true)
+    }
+
+    /// Calls the method `key` names, or inlines it if it's small enough.
+    fn call_direct(&mut self, key: &MethodKey, is_static: bool) -> anyhow::Result<()>{
+        if let Some(inline) = self.env.optimizer.inline(&self.key, self.offset, key){
+            let inline = inline.clone();
+            return self.inline(&inline, &key.descriptor, is_static);
+        }
+        let Some(function) = self.env.module.function(key) else {
+            bail!("No function for {}", key);
+        };
+        self.call(function)
+    }
+
+    /// Replaces a call with the body of the method, the arguments are on the stack.
+    /// The receiver has been checked for `null` if the method needs it.
+    fn inline(&mut self, inline: &Inline, descriptor: &[u8], is_static: bool) -> anyhow::Result<()>{
+        let (params, _) = signature(descriptor, is_static)?;
+        match inline {
+            Inline::Empty => self.out.extend(params.iter().map(|_| Instruction::Drop)),
+            Inline::Constant(constant) => {
+                self.out.extend(params.iter().map(|_| Instruction::Drop));
+                self.out.push(constant.clone());
+            }
+            Inline::Getter{descriptor, offset} => self.out.push(load_field(descriptor, *offset)),
+            Inline::Setter{descriptor, offset} => self.out.push(store_field(descriptor, *offset)),
+        }
+        Ok(())
+    }
+
+    /// Calls an instance method of a class outside the program through its
+    /// import, the receiver is passed as the first argument.
+    pub fn invoke_import<I>(&mut self, index: Index<I>) -> anyhow::Result<()>{
+        let key = MethodKey::read(self.cp, index)?;
+        let Some(function) = self.env.module.function(&key) else {
+            bail!("No import for {}", key);
+        };
+        self.call(function)
+    }
+
+    /// Moves the arguments of an instance method call into temporaries,
+    /// checks the receiver for `null` and puts them back.
+    /// Returns the local holding the receiver.
+    fn checked_receiver(&mut self, descriptor: &[u8]) -> anyhow::Result<u32>{
+        let (params, _) = signature(descriptor, false)?;
+        let count = params.len();
+        let values = self.before[self.before.len() - count..].to_vec();
+        let locals = values.iter().map(|v| self.locals.temp(*v)).collect::<Vec<_>>();
+        for local in locals.iter().rev(){
+            self.out.push(Instruction::LocalSet(*local));
+        }
+        self.null_check(locals[0])?;
+        for local in locals.iter(){
+            self.out.push(Instruction::LocalGet(*local));
+        }
+        Ok(locals[0])
+    }
+
+    /// `invokevirtual`, `invokeinterface` and `invokespecial` other than constructors.
+    ///
+    /// Calls that can only have one target are direct, the rest go through
+    /// the vtable or, for interface methods, the itable of the receiver.
+    pub fn invoke_instance<I>(&mut self, index: Index<I>, special: bool) -> anyhow::Result<()>{
+        let target = MethodKey::read(self.cp, index)?;
+        let Some((declaring, method)) = self.env.program.resolve_method(&target.class, &target.name, &target.descriptor) else {
+            if &*target.name != b"<init>"{
+                self.checked_receiver(&target.descriptor)?;
+            }
+            return self.invoke_import(index);
+        };
+        let key = MethodKey{class: declaring.name.clone(), ..target.clone()};
+
+        if &*target.name == b"<init>"{
+            return self.call_direct(&key, false);
+        }
+
+        let receiver = self.checked_receiver(&target.descriptor)?;
+        let is_final = method.flags.intersects(AccessFlags::PRIVATE | AccessFlags::FINAL) || declaring.flags.contains(AccessFlags::FINAL);
+        if special || is_final{
+            return self.call_direct(&key, false);
+        }
+        if let Some(only) = self.env.optimizer.devirtualize(self.env.program, &self.key, self.offset, &target){
+            return self.call_direct(&only, false);
+        }
+
+        let (params, results) = signature(&target.descriptor, false)?;
+        let Some(type_index) = self.env.module.find_type(&params, &results) else {
+            bail!("No function type for {}", key);
+        };
+
+        let class_table = self.env.layouts.class_table();
+        if declaring.flags.contains(AccessFlags::INTERFACE){
+            let Some(selector) = self.env.layouts.interface_selector(&target.name, &target.descriptor) else {
+                bail!("No interface selector for {}", key);
+            };
+            self.out.push(Instruction::LocalGet(receiver));
+            self.out.push(Instruction::I32Const(selector as i32));
+            self.out.push(Instruction::Call(self.env.runtime.itable_lookup));
+        }
+        else{
+            // Interface types are only known to have the methods of `Object`.
+            let class = if self.env.program.class(&target.class).is_some_and(|c| c.flags.contains(AccessFlags::INTERFACE)) {
+                &declaring.name
+            }
+            else{
+                &target.class
+            };
+            let Some(slot) = self.env.layouts.vtable_slot(class, &target.name, &target.descriptor) else {
+                bail!("No vtable slot for {}", key);
+            };
+            self.out.push(Instruction::LocalGet(receiver));
+            self.out.push(Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET, 2)));
+            self.out.extend(runtime::load_class_info(class_table));
+            self.out.push(Instruction::I32Load(mem(layout::INFO_VTABLE_OFFSET + 4 * slot, 2)));
+        }
+        self.record_offset();
+        self.out.push(Instruction::CallIndirect{ty: type_index, table: 0});
+        self.check_exception()
+    }
+
+    /// Moves the top `count` values into temporaries, bottom first.
+    fn pop_into_temps(&mut self, count: usize) -> Vec<u32>{
+        let values = self.before[self.before.len() - count..].to_vec();
+        let locals = values.iter().map(|v| self.locals.temp(*v)).collect::<Vec<_>>();
+        for local in locals.iter().rev(){
+            self.out.push(Instruction::LocalSet(*local));
+        }
+        locals
+    }
+
+    /// `invokedynamic`, lambdas allocate their synthetic class and string
+    /// concatenation is done inline.
+    pub fn invoke_dynamic(&mut self, index: Index<cpool::InvokeDynamic>) -> anyhow::Result<()>{
+        match CallSite::read(self.class, self.cp, index)? {
+            CallSite::Lambda(lambda) => {
+                let captured = self.pop_into_temps(lambda.captured.len());
+                let Some(layout) = self.env.layouts.class(&lambda.class) else {
+                    bail!("No layout for {}, its class has to be added with indy::lambda_classes", String::from_utf8_lossy(&lambda.class));
+                };
+                let (size, id) = (layout.size, layout.id);
+                // Lambda classes have no initializer, but they can be in a chunk.
+                self.barrier(&lambda.class)?;
+                let object = self.locals.temp(Value::Ref);
+                self.out.push(Instruction::I32Const(size as i32));
+                self.out.push(Instruction::I32Const(id as i32));
+                self.out.push(Instruction::Call(self.env.runtime.alloc));
+                self.out.push(Instruction::LocalSet(object));
+                for (i, (ty, local)) in lambda.captured.iter().zip(captured).enumerate(){
+                    let Some(offset) = self.env.layouts.field(&lambda.class, indy::captured_field(i).as_bytes()) else {
+                        bail!("No field for captured value {} of {}", i, String::from_utf8_lossy(&lambda.class));
+                    };
+                    self.out.push(Instruction::LocalGet(object));
+                    self.out.push(Instruction::LocalGet(local));
+                    self.out.push(store_field(&ty.descriptor(), offset));
+                }
+                self.out.push(Instruction::LocalGet(object));
+            }
+            CallSite::Concat(concat) => {
+                let arguments = self.pop_into_temps(concat.arguments.len());
+                let literal = |strings: &StringTable, s: &str| -> anyhow::Result<i32>{
+                    match strings.address(&s.encode_utf16().collect::<Vec<_>>()) {
+                        Some(address) => Ok(address as i32),
+                        None => bail!("Runtime string {:?} was never interned", s)
+                    }
+                };
+                let null = literal(self.env.strings, "null")?;
+                let runtime = self.env.runtime;
+                if concat.pieces.is_empty(){
+                    self.out.push(Instruction::I32Const(literal(self.env.strings, "")?));
+                }
+                for (i, piece) in concat.pieces.iter().enumerate(){
+                    match piece {
+                        Piece::Literal(units) => {
+                            let Some(address) = self.env.strings.address(units) else {
+                                bail!("String {:?} was never interned", String::from_utf16_lossy(units));
+                            };
+                            self.out.push(Instruction::I32Const(address as i32));
+                        }
+                        Piece::Argument(argument) => {
+                            let local = arguments[*argument];
+                            match &concat.arguments[*argument] {
+                                JavaType::Reference(0, class) if &**class == b"java/lang/String" => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::I32Const(null),
+                                    Instruction::LocalGet(local),
+                                    Instruction::Select,
+                                ]),
+                                JavaType::Bool(0) => self.out.extend([
+                                    Instruction::I32Const(literal(self.env.strings, "true")?),
+                                    Instruction::I32Const(literal(self.env.strings, "false")?),
+                                    Instruction::LocalGet(local),
+                                    Instruction::Select,
+                                ]),
+                                JavaType::Char(0) => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::Call(runtime.string_of_char),
+                                ]),
+                                JavaType::Byte(0) | JavaType::Short(0) | JavaType::Int(0) => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::I64ExtendI32S,
+                                    Instruction::Call(runtime.string_of_long),
+                                ]),
+                                JavaType::Long(0) => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::Call(runtime.string_of_long),
+                                ]),
+                                JavaType::Float(0) => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::F64PromoteF32,
+                                    Instruction::I32Const(1),
+                                    Instruction::Call(runtime.string_of_double),
+                                ]),
+                                JavaType::Double(0) => self.out.extend([
+                                    Instruction::LocalGet(local),
+                                    Instruction::I32Const(0),
+                                    Instruction::Call(runtime.string_of_double),
+                                ]),
+                                // Other references and arrays, including those of primitives.
+                                _ => {
+                                    self.out.push(Instruction::LocalGet(local));
+                                    self.call(runtime.string_of_object)?;
+                                }
+                            }
+                        }
+                    }
+                    if i > 0{
+                        self.out.push(Instruction::Call(runtime.concat));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Duplicates and reorders the top `window` values, `order` lists the
+    /// pushed values by their position in the window from the bottom.
+    fn shuffle(&mut self, window: usize, order: &[usize]){
+        let values = self.before[self.before.len() - window..].to_vec();
+        let locals = values.iter().map(|v| self.locals.temp(*v)).collect::<Vec<_>>();
+        for local in locals.iter().rev(){
+            self.out.push(Instruction::LocalSet(*local));
+        }
+        for i in order{
+            self.out.push(Instruction::LocalGet(locals[*i]));
+        }
+    }
+
+    fn load(&mut self, slot: u16, value: Value){
+        let local = self.locals.variable(slot, value);
+        self.out.push(Instruction::LocalGet(local));
+    }
+
+    fn store(&mut self, slot: u16, value: Value){
+        let local = self.locals.variable(slot, value);
+        self.out.push(Instruction::LocalSet(local));
+    }
+
+    fn increment(&mut self, slot: u16, amount: i32){
+        let local = self.locals.variable(slot, Value::I32);
+        self.out.push(Instruction::LocalGet(local));
+        self.out.push(Instruction::I32Const(amount));
+        self.out.push(Instruction::I32Add);
+        self.out.push(Instruction::LocalSet(local));
+    }
+
+    /// `fcmpl`, `fcmpg`, `dcmpl` and `dcmpg`, which only differ in the result
+    /// for NaN, and `lcmp`, which is `fcmpl` on longs.
+    fn compare(&mut self, value: Value, nan_greater: bool){
+        let (a, b) = (self.locals.temp(value), self.locals.temp(value));
+        let (gt, ge, lt, le) = match value {
+            Value::F32 => (Instruction::F32Gt, Instruction::F32Ge, Instruction::F32Lt, Instruction::F32Le),
+            Value::I64 => (Instruction::I64GtS, Instruction::I64GeS, Instruction::I64LtS, Instruction::I64LeS),
+            _ => (Instruction::F64Gt, Instruction::F64Ge, Instruction::F64Lt, Instruction::F64Le),
+        };
+        self.out.push(Instruction::LocalSet(b));
+        self.out.push(Instruction::LocalSet(a));
+        // cmpl is (a > b) - !(a >= b), cmpg is !(a <= b) - (a < b).
+        let (first, second) = if nan_greater {(le, lt)} else {(gt, ge)};
+        self.out.extend([Instruction::LocalGet(a), Instruction::LocalGet(b), first]);
+        if nan_greater{
+            self.out.push(Instruction::I32Eqz);
+        }
+        self.out.extend([Instruction::LocalGet(a), Instruction::LocalGet(b), second]);
+        if !nan_greater{
+            self.out.push(Instruction::I32Eqz);
+        }
+        self.out.push(Instruction::I32Sub);
+    }
+
+    fn switch(&mut self, default: u32, cases: &[(i32, u32)]) -> anyhow::Result<()>{
+        let key = self.locals.temp(Value::I32);
+        self.out.push(Instruction::LocalSet(key));
+        self.spill();
+        for (value, target) in cases{
+            self.out.push(Instruction::LocalGet(key));
+            self.out.push(Instruction::I32Const(*value));
+            self.out.push(Instruction::I32Eq);
+            self.open(Instruction::If(BlockType::Empty));
+            self.jump(*target)?;
+            self.close();
+        }
+        self.jump(default)
+    }
+
+    fn instruction(&mut self, at: Offset, instruction: &RawInstruction) -> anyhow::Result<()>{
+        let unsupported = || anyhow::anyhow!("{:?} is not supported yet", instruction);
+        match instruction {
+            RawInstruction::AConstNull => self.out.push(Instruction::I32Const(layout::NULL as i32)),
+            RawInstruction::IConstM1 => self.out.push(Instruction::I32Const(-1)),
+            RawInstruction::IConst0 => self.out.push(Instruction::I32Const(0)),
+            RawInstruction::IConst1 => self.out.push(Instruction::I32Const(1)),
+            RawInstruction::IConst2 => self.out.push(Instruction::I32Const(2)),
+            RawInstruction::IConst3 => self.out.push(Instruction::I32Const(3)),
+            RawInstruction::IConst4 => self.out.push(Instruction::I32Const(4)),
+            RawInstruction::IConst5 => self.out.push(Instruction::I32Const(5)),
+            RawInstruction::FConst0 => self.out.push(Instruction::F32Const(0.0)),
+            RawInstruction::FConst1 => self.out.push(Instruction::F32Const(1.0)),
+            RawInstruction::FConst2 => self.out.push(Instruction::F32Const(2.0)),
+            RawInstruction::DConst0 => self.out.push(Instruction::F64Const(0.0)),
+            RawInstruction::DConst1 => self.out.push(Instruction::F64Const(1.0)),
+            RawInstruction::LConst0 => self.out.push(Instruction::I64Const(0)),
+            RawInstruction::LConst1 => self.out.push(Instruction::I64Const(1)),
+            RawInstruction::BIPush { value } => self.out.push(Instruction::I32Const(*value as i32)),
+            RawInstruction::SIPush { value } => self.out.push(Instruction::I32Const(*value as i32)),
+            RawInstruction::LdC { index } |
+            RawInstruction::LdCW { index } |
+            RawInstruction::LdC2W { index } => self.constant(*index)?,
+
+            RawInstruction::ILoad { index } => self.load(*index as u16, Value::I32),
+            RawInstruction::ILoadW { index } => self.load(*index, Value::I32),
+            RawInstruction::ILoad0 => self.load(0, Value::I32),
+            RawInstruction::ILoad1 => self.load(1, Value::I32),
+            RawInstruction::ILoad2 => self.load(2, Value::I32),
+            RawInstruction::ILoad3 => self.load(3, Value::I32),
+            RawInstruction::LLoad { index } => self.load(*index as u16, Value::I64),
+            RawInstruction::LLoadW { index } => self.load(*index, Value::I64),
+            RawInstruction::LLoad0 => self.load(0, Value::I64),
+            RawInstruction::LLoad1 => self.load(1, Value::I64),
+            RawInstruction::LLoad2 => self.load(2, Value::I64),
+            RawInstruction::LLoad3 => self.load(3, Value::I64),
+            RawInstruction::FLoad { index } => self.load(*index as u16, Value::F32),
+            RawInstruction::FLoadW { index } => self.load(*index, Value::F32),
+            RawInstruction::FLoad0 => self.load(0, Value::F32),
+            RawInstruction::FLoad1 => self.load(1, Value::F32),
+            RawInstruction::FLoad2 => self.load(2, Value::F32),
+            RawInstruction::FLoad3 => self.load(3, Value::F32),
+            RawInstruction::DLoad { index } => self.load(*index as u16, Value::F64),
+            RawInstruction::DLoadW { index } => self.load(*index, Value::F64),
+            RawInstruction::DLoad0 => self.load(0, Value::F64),
+            RawInstruction::DLoad1 => self.load(1, Value::F64),
+            RawInstruction::DLoad2 => self.load(2, Value::F64),
+            RawInstruction::DLoad3 => self.load(3, Value::F64),
+            RawInstruction::ALoad { index } => self.load(*index as u16, Value::Ref),
+            RawInstruction::ALoadW { index } => self.load(*index, Value::Ref),
+            RawInstruction::ALoad0 => self.load(0, Value::Ref),
+            RawInstruction::ALoad1 => self.load(1, Value::Ref),
+            RawInstruction::ALoad2 => self.load(2, Value::Ref),
+            RawInstruction::ALoad3 => self.load(3, Value::Ref),
+            RawInstruction::IStore { index } => self.store(*index as u16, Value::I32),
+            RawInstruction::IStoreW { index } => self.store(*index, Value::I32),
+            RawInstruction::IStore0 => self.store(0, Value::I32),
+            RawInstruction::IStore1 => self.store(1, Value::I32),
+            RawInstruction::IStore2 => self.store(2, Value::I32),
+            RawInstruction::IStore3 => self.store(3, Value::I32),
+            RawInstruction::LStore { index } => self.store(*index as u16, Value::I64),
+            RawInstruction::LStoreW { index } => self.store(*index, Value::I64),
+            RawInstruction::LStore0 => self.store(0, Value::I64),
+            RawInstruction::LStore1 => self.store(1, Value::I64),
+            RawInstruction::LStore2 => self.store(2, Value::I64),
+            RawInstruction::LStore3 => self.store(3, Value::I64),
+            RawInstruction::FStore { index } => self.store(*index as u16, Value::F32),
+            RawInstruction::FStoreW { index } => self.store(*index, Value::F32),
+            RawInstruction::FStore0 => self.store(0, Value::F32),
+            RawInstruction::FStore1 => self.store(1, Value::F32),
+            RawInstruction::FStore2 => self.store(2, Value::F32),
+            RawInstruction::FStore3 => self.store(3, Value::F32),
+            RawInstruction::DStore { index } => self.store(*index as u16, Value::F64),
+            RawInstruction::DStoreW { index } => self.store(*index, Value::F64),
+            RawInstruction::DStore0 => self.store(0, Value::F64),
+            RawInstruction::DStore1 => self.store(1, Value::F64),
+            RawInstruction::DStore2 => self.store(2, Value::F64),
+            RawInstruction::DStore3 => self.store(3, Value::F64),
+            RawInstruction::AStore { index } => self.store(*index as u16, Value::Ref),
+            RawInstruction::AStoreW { index } => self.store(*index, Value::Ref),
+            RawInstruction::AStore0 => self.store(0, Value::Ref),
+            RawInstruction::AStore1 => self.store(1, Value::Ref),
+            RawInstruction::AStore2 => self.store(2, Value::Ref),
+            RawInstruction::AStore3 => self.store(3, Value::Ref),
+            RawInstruction::IInc { index, value } => self.increment(*index as u16, *value as i32),
+            RawInstruction::IIncW { index, value } => self.increment(*index, *value as i32),
+
+            RawInstruction::IAdd => self.out.push(Instruction::I32Add),
+            RawInstruction::ISub => self.out.push(Instruction::I32Sub),
+            RawInstruction::IMul => self.out.push(Instruction::I32Mul),
+            RawInstruction::IDiv => self.call(self.env.runtime.idiv)?,
+            RawInstruction::IRem => self.call(self.env.runtime.irem)?,
+            RawInstruction::IAnd => self.out.push(Instruction::I32And),
+            RawInstruction::IOr => self.out.push(Instruction::I32Or),
+            RawInstruction::IXor => self.out.push(Instruction::I32Xor),
+            // Wasm masks the shift count to 5 bits like the JVM does.
+            RawInstruction::IShL => self.out.push(Instruction::I32Shl),
+            RawInstruction::IShR => self.out.push(Instruction::I32ShrS),
+            RawInstruction::IUShR => self.out.push(Instruction::I32ShrU),
+            RawInstruction::INeg => {
+                let value = self.locals.temp(Value::I32);
+                self.out.push(Instruction::LocalSet(value));
+                self.out.push(Instruction::I32Const(0));
+                self.out.push(Instruction::LocalGet(value));
+                self.out.push(Instruction::I32Sub);
+            }
+            RawInstruction::LAdd => self.out.push(Instruction::I64Add),
+            RawInstruction::LSub => self.out.push(Instruction::I64Sub),
+            RawInstruction::LMul => self.out.push(Instruction::I64Mul),
+            RawInstruction::LDiv => self.call(self.env.runtime.ldiv)?,
+            RawInstruction::LRem => self.call(self.env.runtime.lrem)?,
+            RawInstruction::LAnd => self.out.push(Instruction::I64And),
+            RawInstruction::LOr => self.out.push(Instruction::I64Or),
+            RawInstruction::LXor => self.out.push(Instruction::I64Xor),
+            // The count is an int, wasm masks it to 6 bits like the JVM does.
+            RawInstruction::LShL => self.out.extend([Instruction::I64ExtendI32U, Instruction::I64Shl]),
+            RawInstruction::LShR => self.out.extend([Instruction::I64ExtendI32U, Instruction::I64ShrS]),
+            RawInstruction::LUShR => self.out.extend([Instruction::I64ExtendI32U, Instruction::I64ShrU]),
+            RawInstruction::LNeg => {
+                let value = self.locals.temp(Value::I64);
+                self.out.push(Instruction::LocalSet(value));
+                self.out.push(Instruction::I64Const(0));
+                self.out.push(Instruction::LocalGet(value));
+                self.out.push(Instruction::I64Sub);
+            }
+            RawInstruction::LCmp => self.compare(Value::I64, false),
+            RawInstruction::FAdd => self.out.push(Instruction::F32Add),
+            RawInstruction::FSub => self.out.push(Instruction::F32Sub),
+            RawInstruction::FMul => self.out.push(Instruction::F32Mul),
+            RawInstruction::FDiv => self.out.push(Instruction::F32Div),
+            RawInstruction::FNeg => self.out.push(Instruction::F32Neg),
+            RawInstruction::FRem => {
+                // The remainder of two floats is exact, so it can be computed on doubles.
+                let divisor = self.locals.temp(Value::F32);
+                self.out.push(Instruction::LocalSet(divisor));
+                self.out.push(Instruction::F64PromoteF32);
+                self.out.push(Instruction::LocalGet(divisor));
+                self.out.push(Instruction::F64PromoteF32);
+                self.out.push(Instruction::Call(self.env.runtime.drem));
+                self.out.push(Instruction::F32DemoteF64);
+            }
+            RawInstruction::DAdd => self.out.push(Instruction::F64Add),
+            RawInstruction::DSub => self.out.push(Instruction::F64Sub),
+            RawInstruction::DMul => self.out.push(Instruction::F64Mul),
+            RawInstruction::DDiv => self.out.push(Instruction::F64Div),
+            RawInstruction::DNeg => self.out.push(Instruction::F64Neg),
+            RawInstruction::DRem => self.out.push(Instruction::Call(self.env.runtime.drem)),
+            RawInstruction::FCmpL => self.compare(Value::F32, false),
+            RawInstruction::FCmpG => self.compare(Value::F32, true),
+            RawInstruction::DCmpL => self.compare(Value::F64, false),
+            RawInstruction::DCmpG => self.compare(Value::F64, true),
+
+            RawInstruction::I2L |
+            RawInstruction::I2F |
+            RawInstruction::I2D |
+            RawInstruction::I2B |
+            RawInstruction::I2C |
+            RawInstruction::I2S |
+            RawInstruction::L2I |
+            RawInstruction::L2F |
+            RawInstruction::L2D |
+            RawInstruction::F2I |
+            RawInstruction::F2L |
+            RawInstruction::F2D |
+            RawInstruction::D2I |
+            RawInstruction::D2L |
+            RawInstruction::D2F => self.out.extend(conversion(instruction)?),
+
+            RawInstruction::Nop => (),
+            RawInstruction::Pop => self.out.push(Instruction::Drop),
+            RawInstruction::Pop2 => {
+                self.out.push(Instruction::Drop);
+                if self.before.last().is_some_and(|v| v.is_small()){
+                    self.out.push(Instruction::Drop);
+                }
+            }
+            RawInstruction::Dup |
+            RawInstruction::DupX1 |
+            RawInstruction::DupX2 |
+            RawInstruction::Dup2 |
+            RawInstruction::Dup2X1 |
+            RawInstruction::Dup2X2 |
+            RawInstruction::Swap => {
+                let (window, order) = code::shuffle(instruction, &self.before).expect("Shuffles have an order");
+                self.shuffle(window, order);
+            }
+
+            RawInstruction::Goto { offset } => {
+                self.spill();
+                self.jump(blocks::target(at, *offset as i32))?;
+            }
+            RawInstruction::GotoW { offset } => {
+                self.spill();
+                self.jump(blocks::target(at, *offset))?;
+            }
+            RawInstruction::IfEq { offset } |
+            RawInstruction::IfNe { offset } |
+            RawInstruction::IfLt { offset } |
+            RawInstruction::IfGe { offset } |
+            RawInstruction::IfGt { offset } |
+            RawInstruction::IfLe { offset } |
+            RawInstruction::IfICmpEq { offset } |
+            RawInstruction::IfICmpNe { offset } |
+            RawInstruction::IfICmpLt { offset } |
+            RawInstruction::IfICmpGe { offset } |
+            RawInstruction::IfICmpGt { offset } |
+            RawInstruction::IfICmpLe { offset } |
+            RawInstruction::IfACmpEq { offset } |
+            RawInstruction::IfACmpNe { offset } |
+            RawInstruction::IfNull { offset } |
+            RawInstruction::IfNonNull { offset } => self.branch(&condition(instruction)?, blocks::target(at, *offset as i32))?,
+            RawInstruction::TableSwitch(table) => {
+                let cases = table.pairs().map(|p| (p.key(), blocks::target(at, p.offset()))).collect::<Vec<_>>();
+                self.switch(blocks::target(at, table.default_offset()), &cases)?;
+            }
+            RawInstruction::LookupSwitch(lookup) => {
+                let cases = lookup.pairs().take(lookup.pairs().count()).map(|p| (p.key(), blocks::target(at, p.offset()))).collect::<Vec<_>>();
+                self.switch(blocks::target(at, lookup.default_offset()), &cases)?;
+            }
+            RawInstruction::IReturn |
+            RawInstruction::LReturn |
+            RawInstruction::FReturn |
+            RawInstruction::AReturn |
+            RawInstruction::DReturn |
+            RawInstruction::Return => self.return_value(),
+
+            RawInstruction::GetStatic { index } => self.get_static(*index)?,
+            RawInstruction::PutStatic { index } => self.put_static(*index)?,
+            RawInstruction::GetField { index } => self.get_field(*index)?,
+            RawInstruction::PutField { index } => self.put_field(*index)?,
+            RawInstruction::New { index } => self.new_object(*index)?,
+            RawInstruction::NewArray { atype } => {
+                let id = layout::primitive_array(array_type_descriptor(*atype)).expect("Primitive types have arrays");
+                self.new_array(layout::type_tag(id, 0))?;
+            }
+            RawInstruction::ANewArray { index } => {
+                let component = self.env.layouts.type_tag(self.cp.get(self.cp.get(*index)?.name)?.content.as_bytes())?;
+                self.new_array(component + layout::type_tag(0, 1))?;
+            }
+            RawInstruction::MultiANewArray { index, dimensions } => self.multi_new_array(*index, *dimensions)?,
+            RawInstruction::ArrayLength => self.array_length()?,
+            RawInstruction::BALoad |
+            RawInstruction::CALoad |
+            RawInstruction::SALoad |
+            RawInstruction::IALoad |
+            RawInstruction::FALoad |
+            RawInstruction::AALoad |
+            RawInstruction::LALoad |
+            RawInstruction::DALoad => self.array_load(instruction)?,
+            RawInstruction::BAStore |
+            RawInstruction::CAStore |
+            RawInstruction::SAStore |
+            RawInstruction::IAStore |
+            RawInstruction::FAStore |
+            RawInstruction::AAStore |
+            RawInstruction::LAStore |
+            RawInstruction::DAStore => self.array_store(instruction)?,
+            RawInstruction::InvokeStatic { index } => self.invoke_static(*index)?,
+            RawInstruction::InvokeSpecial { index } => self.invoke_instance(*index, true)?,
+            RawInstruction::InvokeVirtual { index } => self.invoke_instance(*index, false)?,
+            RawInstruction::InvokeInterface { index, .. } => self.invoke_instance(*index, false)?,
+            RawInstruction::InvokeDynamic { index } => self.invoke_dynamic(*index)?,
+            RawInstruction::AThrow => {
+                let exception = self.locals.temp(Value::Ref);
+                self.out.push(Instruction::LocalSet(exception));
+                self.null_check(exception)?;
+                self.out.push(Instruction::LocalGet(exception));
+                self.out.push(Instruction::GlobalSet(self.env.runtime.exception));
+                self.dispatch()?;
+            }
+            RawInstruction::CheckCast { index } => self.check_cast(*index)?,
+            RawInstruction::InstanceOf { index } => {
+                let object = self.locals.temp(Value::Ref);
+                self.out.push(Instruction::LocalSet(object));
+                self.instance_of(object, *index)?;
+            }
+            RawInstruction::MonitorEnter => self.monitor(true)?,
+            RawInstruction::MonitorExit => self.monitor(false)?,
+
+            _ => return Err(unsupported())
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,13 @@
-use std::{fs::File, io::Read, cell::OnceCell, sync::OnceLock, future::Future};
+use std::{collections::BTreeSet, fs::File, io::Read, sync::{Arc, OnceLock}};
 
+use anyhow::Context;
+use dashmap::{DashMap, mapref::entry::Entry};
 use futures::future::FutureExt;
-use dashmap::DashMap;
-use noak::reader::cpool::Item;
-use peg::parser;
-use tokio::{task::JoinHandle, pin};
+use tokio::task::JoinHandle;
+use noak::AccessFlags;
+use wasm_shit::{cache::Cache, classpath::{self, read_jar, Seals}, data::ParsedClass, deps::{self, DependencyGraph}, disassemble, dot, indy, keep::KeepRules, link::{self, Units}, manifest::Manifest, program::{MethodKey, Program}, optimize::Optimizations, reach::{Reachability, Roots}, resources::Resources, runtime::Monitors, strings::StringPool, translate::{translate, Options}};
 use zip::ZipArchive;
 
-pub mod work;
-pub mod data;
-
 macro_rules! run {
     ($it:ident => $f:ident) => {
         {
@@ -20,93 +18,110 @@ macro_rules! run {
                         $f(i).await
                     })
                 );
-                
+
             }
             for h in handles{
                 h.await??;
             }
-        }   
+        }
     };
 }
 
+static CLASS_DATA: OnceLock<DashMap<Box<[u8]>, Vec<u8>>> = OnceLock::new();
+static PARSED_CLASSES: OnceLock<DashMap<Box<[u8]>, ParsedClass>> = OnceLock::new();
+static DEPENDENCIES: OnceLock<DashMap<Box<[u8]>, Vec<deps::Dependency>>> = OnceLock::new();
+/// `None` with `--no-cache`.
+static CACHE: OnceLock<Option<Arc<Cache>>> = OnceLock::new();
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error>{
-    static class_data: OnceLock<DashMap<Box<[u8]>, Vec<u8>>> = OnceLock::new();
-    class_data.get_or_init(|| DashMap::new());
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|a| a == "disassemble"){
+        return disassemble(&args[2..]);
+    }
+    if args.get(1).is_some_and(|a| a == "dot"){
+        return dot(&args[2..]);
+    }
+
+    CLASS_DATA.get_or_init(DashMap::new);
+    let cache = match args.iter().any(|a| a == "--no-cache") {
+        true => None,
+        false => Some(Arc::new(Cache::open("./cache")?))
+    };
+    CACHE.get_or_init(|| cache.clone());
+
+    // The JAR to translate is given as `--jar <file>`, the JARs the
+    // `Class-Path` of its manifest lists are read after it.
+    let jar = args.windows(2).find(|pair| pair[0] == "--jar").map_or("./jagexappletviewer.jar", |pair| &pair[1]);
+    let manifest = Manifest::read(jar)?.unwrap_or_default();
+    let mut jars = classpath::class_path(jar)?;
+    // The runtime library replaces the JDK classes the program uses, it comes
+    // last so the program's own classes win. `--runtime <file>` overrides it.
+    let runtime = args.windows(2).find(|pair| pair[0] == "--runtime").map_or("./runtime/runtime.jar", |pair| &pair[1]);
+    jars.push(runtime.into());
+    println!("{} JARs on the class path: {}", jars.len(), jars.iter().map(|j| j.display().to_string()).collect::<Vec<_>>().join(", "));
 
     let mut names = Vec::new();
-    for file in ["./jagexappletviewer.jar"]{
-        let mut archive = ZipArchive::new(File::open(file)?)?;
-        
+    let mut resources = Resources::new();
+    let mut seals = Seals::new();
+    for file in jars.iter(){
+        let path = file.as_path();
+        let is_runtime = path == std::path::Path::new(runtime);
+        // Classes of the packages its manifest seals can only come from this JAR.
+        let sealing = Manifest::read(path)?.unwrap_or_default();
+        let mut archive = ZipArchive::new(File::open(file).with_context(|| format!("Opening {}", file.display()))?)?;
+
         for i in 0..archive.len(){
             let mut file = archive.by_index(i)?;
-            let name = file.name();
-            if name.ends_with(".class") {
-                let mut bytes = Vec::new();
-                let name = name[..name.len()-"class".len()].bytes().collect::<Vec<_>>().into_boxed_slice();
-                file.read_to_end(&mut bytes)?;
-                class_data.get().unwrap().insert(name.clone(), bytes);
-                names.push(name);
+            if file.is_dir(){
+                continue;
+            }
+            let name = file.name().to_string();
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            match name.strip_suffix(".class") {
+                // Earlier JARs win, like on the JVM's class path.
+                Some(name) => {
+                    let class = name;
+                    let name = name.bytes().collect::<Vec<_>>().into_boxed_slice();
+                    if let Entry::Vacant(v) = CLASS_DATA.get().unwrap().entry(name.clone()){
+                        seals.add(path, &sealing, class)?;
+                        v.insert(bytes);
+                        names.push(name);
+                    }
+                }
+                // Images, configs and the like, found through `getResourceAsStream`.
+                None if !is_runtime => resources.add(&name, bytes),
+                None => {}
             }
         }
     }
 
-    static PARSED_CLASSES: OnceLock<DashMap<Box<[u8]>, Vec<u8>>> = OnceLock::new();
-    class_data.get_or_init(|| DashMap::new());
+    PARSED_CLASSES.get_or_init(DashMap::new);
+    DEPENDENCIES.get_or_init(DashMap::new);
     async fn parse_class(name: Box<[u8]>) -> anyhow::Result<()>{
         if PARSED_CLASSES.get().unwrap().contains_key(&name){
             return Ok(());
         }
 
-        if let Some(bytes) = class_data.get().unwrap().get(&name){
-            let mut class = noak::reader::Class::new(&bytes.value())?;
-
-            let pool = class.pool()?;
+        if let Some(bytes) = CLASS_DATA.get().unwrap().get(&name){
+            let mut class = noak::reader::Class::new(bytes.value())?;
 
-            for item in pool.iter(){
-                match item {
-                    Item::Class(c) => {
-                        let name = pool.get(c.name)?;
-                        let name = Vec::from_iter(name.content.as_bytes().iter().cloned()).into_boxed_slice();
-                        spawn_parse_class(name).await??;
-                    }
-                    Item::FieldRef(f) => {
-                        let reference = pool.get(f.name_and_type)?;
-                        let descriptor = pool.get(reference.descriptor)?;
-                        let type_ = descriptor_parser::field(descriptor.content.as_bytes())?;
-                        match type_ {
-                            JavaType::Reference(_, n) => {
-                                spawn_parse_class(n).await??
-                            },
-                            _ => ()
-                        }
-                    },
-                    Item::MethodRef(m) => {
-                        let reference = pool.get(m.name_and_type)?;
-                        let descriptor = pool.get(reference.descriptor)?;
-                        let types = descriptor_parser::class_names(descriptor.content.as_bytes())?;
-                        for type_ in types{
-                            if let JavaType::Reference(_,n) = type_ {
-                                spawn_parse_class(n).await??;
-                            }
-                        }
-                    },
-                    Item::InterfaceMethodRef(_) => todo!(),
-                    Item::String(_) => todo!(),
-                    Item::Integer(_) => todo!(),
-                    Item::Long(_) => todo!(),
-                    Item::Float(_) => todo!(),
-                    Item::Double(_) => todo!(),
-                    Item::NameAndType(_) => todo!(),
-                    Item::Utf8(_) => todo!(),
-                    Item::MethodHandle(_) => todo!(),
-                    Item::MethodType(_) => todo!(),
-                    Item::Dynamic(_) => todo!(),
-                    Item::InvokeDynamic(_) => todo!(),
-                    Item::Module(_) => todo!(),
-                    Item::Package(_) => todo!(),
-                }
+            let parsed = ParsedClass::read(&mut class, bytes.value())?;
+            let dependencies = match CACHE.get().unwrap() {
+                Some(cache) => cache.dependencies(&parsed, || deps::dependencies(&parsed))?,
+                None => deps::dependencies(&parsed)?
+            };
+            match PARSED_CLASSES.get().unwrap().entry(name.clone()) {
+                Entry::Occupied(_) => return Ok(()),
+                Entry::Vacant(v) => {v.insert(parsed);}
+            }
+            let targets = dependencies.iter().map(|(target, _)| target.clone()).collect::<BTreeSet<_>>();
+            for target in targets{
+                spawn_parse_class(target).await??;
             }
+            // The edges are kept for the dependency graph.
+            DEPENDENCIES.get().unwrap().insert(name, dependencies);
         }
         Ok(())
     }
@@ -116,46 +131,169 @@ async fn main() -> Result<(), anyhow::Error>{
         }.boxed())
     }
     run!(names => parse_class);
-    
-    Ok(())
-}
 
+    let mut classes = PARSED_CLASSES.get().unwrap().iter().map(|c| c.value().clone()).collect::<Vec<_>>();
+    let mut dependencies = DependencyGraph::default();
+    for class in classes.iter(){
+        if let Some(found) = DEPENDENCIES.get().unwrap().get(&class.name){
+            dependencies.add(class, found.value());
+        }
+    }
+    std::fs::write("./dependencies.dot", dependencies.dot())?;
+    std::fs::write("./dependencies.json", serde_json::to_string_pretty(&dependencies.json())?)?;
+    let cycles = dependencies.report();
+    std::fs::write("./cycles.txt", &cycles)?;
+    println!("{} dependency cycles, written to cycles.txt, graph written to dependencies.dot and dependencies.json", cycles.lines().count());
+    classes.extend(indy::lambda_classes(&classes)?);
+    let crawled = Program::new(classes);
 
-parser!(
-    grammar descriptor_parser() for [u8]{
-        rule primitive(c: usize) -> JavaType
-        = "B" {JavaType::Byte(c as u8)}
-        / "C" {JavaType::Char(c as u8)}
-        / "D" {JavaType::Double(c as u8)}
-        / "F" {JavaType::Float(c as u8)}
-        / "I" {JavaType::Int(c as u8)}
-        / "J" {JavaType::Long(c as u8)}
-        / "L" n:([^ 59]+) ";" {JavaType::Reference(c as u8, n.into_boxed_slice())}
-        / "S" {JavaType::Short(c as u8)}
-        / "Z" {JavaType::Bool(c as u8)}
-
-        pub rule field() -> JavaType
-        = n:$("["*) p:primitive({n.len()}) {p}
-
-        pub rule method() -> (Vec<JavaType>, JavaType)
-        = "(" x:field()* ")" r:field() {(x, r)}
-
-        pub rule class_names() -> Vec<JavaType>
-         = &"(" x: method() {let mut y = x.0; y.push(x.1); y}
-         / x:field() {vec![x]}
+    // The `Main-Class` of the manifest is the entry point, without one every
+    // `static void main(String[])` is.
+    let main_class = manifest.main_class();
+    let entries = crawled.classes().into_iter()
+        .filter(|c| main_class.as_ref().is_none_or(|m| *c.name == *m.as_bytes()))
+        .filter(|c| c.method(b"main", b"([Ljava/lang/String;)V").is_some_and(|m| m.flags.contains(AccessFlags::STATIC)))
+        .map(|c| MethodKey::new(&c.name, b"main", b"([Ljava/lang/String;)V"))
+        .collect::<Vec<_>>();
+    if let Some(main_class) = &main_class{
+        anyhow::ensure!(!entries.is_empty(), "Main-Class {} has no static main method", main_class);
     }
-);
 
+    // Applet parameters are given as `--param name=value`, the host's
+    // `Applet.getParameter` answers with them from applet.json.
+    let mut parameters = serde_json::Map::new();
+    for pair in args.windows(2).filter(|pair| pair[0] == "--param"){
+        let Some((name, value)) = pair[1].split_once('=') else {
+            anyhow::bail!("Expected `--param name=value`, got {}", pair[1]);
+        };
+        parameters.insert(name.into(), value.into());
+    }
 
+    let mut roots = Roots{
+        methods: entries.clone(),
+        applets: true,
+        ..Roots::default()
+    };
+    // Keep rules for reflection are passed as `--keep <file>`.
+    for pair in args.windows(2).filter(|pair| pair[0] == "--keep"){
+        let text = std::fs::read_to_string(&pair[1])?;
+        let rules = KeepRules::parse(&text).with_context(|| format!("Parsing keep rules in {}", pair[1]))?;
+        rules.add_roots(&crawled, &mut roots);
+    }
+    let reachability = Reachability::analyze(&crawled, &roots)?;
+    std::fs::write("./reachability.txt", reachability.report())?;
+    let program = reachability.prune(&crawled);
+    let strings = StringPool::collect(&program)?;
+    println!(
+        "{} of {} classes reachable, {} string literals, reasons written to reachability.txt",
+        program.classes().len(),
+        crawled.classes().len(),
+        strings.len()
+    );
+
+    let options = Options{
+        // Shared memory builds opt into thread-safe monitors.
+        monitors: if args.iter().any(|a| a == "--atomic-monitors") {Monitors::Atomic} else {Monitors::SingleThreaded},
+        optimizations: Optimizations::default(),
+        // For hosts without BigInt, which can't pass `long`s as they are.
+        split_longs: args.iter().any(|a| a == "--split-longs"),
+        // Next to the module, so debuggers find it relative to it.
+        source_map_url: Some("out.wasm.map".into()),
+        // Kept in ./cache between runs unless `--no-cache` is given.
+        cache,
+        // One module per package, linked by the host.
+        units: args.iter().any(|a| a == "--split-packages" || a == "--lazy-packages").then(|| Units::by_package(&program)),
+        // Only the startup classes in out.core.wasm, the packages as chunks loaded on demand.
+        lazy: args.iter().any(|a| a == "--lazy-packages"),
+        resources: Arc::new(resources),
+        // Deflated resources are inflated by the host.
+        compress_resources: args.iter().any(|a| a == "--compress-resources"),
+    };
+    let translation = translate(&program, &strings, &entries, &options)?;
+    std::fs::write("./natives.json", serde_json::to_string_pretty(&translation.natives.manifest()?)?)?;
+    println!("{} imports written to natives.json", translation.natives.len());
+    // The main attributes of the manifest carry custom ones like the applet's size.
+    let attributes = manifest.main.iter().map(|(name, value)| (name.to_string(), value.into())).collect::<serde_json::Map<_, _>>();
+    let applet = serde_json::json!({"parameters": parameters, "attributes": attributes});
+    std::fs::write("./applet.json", serde_json::to_string_pretty(&applet)?)?;
+    println!("{} applet parameters and {} manifest attributes written to applet.json", parameters.len(), attributes.len());
+    let log = translation.decisions.iter().map(|d| format!("{}\n", d)).collect::<String>();
+    std::fs::write("./optimizations.log", log)?;
+    if args.iter().any(|a| a == "--report-checks"){
+        let report = translation.checks.iter().map(|c| format!("{}\n", c)).collect::<String>();
+        std::fs::write("./checks.log", report)?;
+        println!(
+            "{} of {} null checks removed, counts per method written to checks.log",
+            translation.checks.iter().map(|c| c.null_checks_removed).sum::<usize>(),
+            translation.checks.iter().map(|c| c.null_checks).sum::<usize>()
+        );
+    }
+    std::fs::write("./out.wasm", &translation.wasm)?;
+    // Every failure with the chain of errors that caused it, the methods trap when called.
+    let failures = translation.failures.iter().map(|(method, e)| format!("{}: {:#}\n", method, e)).collect::<String>();
+    std::fs::write("./failures.txt", failures)?;
+    println!(
+        "{} bytes written to out.wasm, {} entry points, {} methods failed to translate, listed in failures.txt",
+        translation.wasm.len(),
+        entries.len(),
+        translation.failures.len()
+    );
+    println!("{} resources embedded", options.resources.len());
+    if options.cache.is_some(){
+        println!("{} lowered methods reused from ./cache", translation.cached);
+    }
+    std::fs::write("./out.wasm.map", serde_json::to_string(&translation.source_map.json())?)?;
+    println!("{} lines of {} source files mapped in out.wasm.map", translation.source_map.mappings.len(), translation.source_map.sources.len());
+    if !translation.modules.is_empty(){
+        for module in translation.modules.iter(){
+            std::fs::write(format!("./out.{}.wasm", module.name), &module.wasm)?;
+            std::fs::write(format!("./out.{}.wasm.map", module.name), serde_json::to_string(&module.source_map.json())?)?;
+        }
+        std::fs::write("./link.json", serde_json::to_string_pretty(&link::manifest(&translation.modules, options.lazy))?)?;
+        println!("{} modules written to out.<unit>.wasm, linked as described in link.json", translation.modules.len());
+    }
+
+    Ok(())
+}
+
+/// `disassemble <class file or jar> [class]` prints the annotated bytecode of
+/// the class, or of every class in the JAR.
+fn disassemble(args: &[String]) -> anyhow::Result<()>{
+    let Some(path) = args.first() else {
+        anyhow::bail!("Usage: disassemble <class file or jar> [class]");
+    };
+    let classes = if path.ends_with(".jar") {
+        read_jar(path)?
+    }
+    else{
+        vec![ParsedClass::parse(&std::fs::read(path)?)?]
+    };
+    let name = args.get(1).map(|n| n.replace('.', "/"));
+    for class in classes.iter().filter(|c| name.as_ref().is_none_or(|n| *c.name == *n.as_bytes())){
+        println!("{}", disassemble::disassemble(class)?);
+    }
+    Ok(())
+}
 
-enum JavaType{
-    Bool(u8),
-    Byte(u8),
-    Char(u8),
-    Short(u8),
-    Int(u8),
-    Float(u8),
-    Double(u8),
-    Long(u8), 
-    Reference(u8, Box<[u8]>)
+/// `dot <class file or jar> <class>[#method] [--stacks]` prints the control
+/// flow graphs of the class's methods, with the inferred operand stacks at
+/// the start and end of every block if `--stacks` is given.
+fn dot(args: &[String]) -> anyhow::Result<()>{
+    let (Some(path), Some(selector)) = (args.first(), args.get(1)) else {
+        anyhow::bail!("Usage: dot <class file or jar> <class>[#method] [--stacks]");
+    };
+    let classes = if path.ends_with(".jar") {
+        read_jar(path)?
+    }
+    else{
+        vec![ParsedClass::parse(&std::fs::read(path)?)?]
+    };
+    let (name, method) = match selector.split_once('#') {
+        Some((name, method)) => (name, Some(method)),
+        None => (selector.as_str(), None)
+    };
+    let name = name.replace('.', "/");
+    let class = classes.iter().find(|c| *c.name == *name.as_bytes()).with_context(|| format!("No class {}", name))?;
+    print!("{}", dot::class_graph(class, method, args.iter().any(|a| a == "--stacks"))?);
+    Ok(())
 }
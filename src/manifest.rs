@@ -0,0 +1,143 @@
+//! JAR manifests, `META-INF/MANIFEST.MF`.
+//!
+//! A manifest is a main section followed by a section per entry, separated
+//! by blank lines. Every line is a `Name: value` header, and lines starting
+//! with a space continue the one before. Each entry's section starts with a
+//! `Name` header holding the path it's about, like `app/` for a package.
+//! Header names are compared ignoring case.
+
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+use anyhow::{bail, Context};
+use zip::{result::ZipError, ZipArchive};
+
+pub const MANIFEST_PATH: &str = "META-INF/MANIFEST.MF";
+
+/// The headers of one section, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Attributes{
+    headers: Vec<(String, String)>,
+}
+
+impl Attributes{
+    /// Value of the header with the name, which is compared ignoring case.
+    pub fn get(&self, name: &str) -> Option<&str>{
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)>{
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Manifest{
+    pub main: Attributes,
+    /// Sections of the entries by the path they are about.
+    pub entries: BTreeMap<String, Attributes>,
+}
+
+impl Manifest{
+    pub fn parse(text: &str) -> anyhow::Result<Self>{
+        let mut manifest = Self::default();
+        // The entry section being read.
+        let mut section: Option<Attributes> = None;
+        let mut in_main = true;
+        let mut lines = Vec::<(usize, String)>::new();
+        for (number, line) in text.lines().enumerate(){
+            match line.strip_prefix(' ') {
+                Some(rest) => match lines.last_mut() {
+                    Some((_, last)) => last.push_str(rest),
+                    None => bail!("Line {}: continuation of nothing", number + 1)
+                },
+                None => lines.push((number + 1, line.to_string()))
+            }
+        }
+        for (number, line) in lines{
+            if line.is_empty(){
+                if let Some(done) = section.take(){
+                    manifest.add_section(done)?;
+                }
+                in_main = false;
+                continue;
+            }
+            let Some((name, value)) = line.split_once(": ").or_else(|| line.strip_suffix(':').map(|n| (n, ""))) else {
+                bail!("Line {}: expected a `Name: value` header", number);
+            };
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'){
+                bail!("Line {}: invalid header name {:?}", number, name);
+            }
+            let header = (name.to_string(), value.to_string());
+            match (&mut section, in_main) {
+                (Some(section), _) => section.headers.push(header),
+                (None, true) => manifest.main.headers.push(header),
+                (None, false) => section = Some(Attributes{headers: vec![header]})
+            }
+        }
+        if let Some(done) = section{
+            manifest.add_section(done)?;
+        }
+        Ok(manifest)
+    }
+
+    fn add_section(&mut self, section: Attributes) -> anyhow::Result<()>{
+        let Some(name) = section.get("Name") else {
+            bail!("Section without a Name header: {:?}", section.headers);
+        };
+        self.entries.insert(name.to_string(), section);
+        Ok(())
+    }
+
+    /// Reads the manifest of the JAR, `None` if it has none.
+    pub fn read(jar: impl AsRef<Path>) -> anyhow::Result<Option<Self>>{
+        let jar = jar.as_ref();
+        let mut archive = ZipArchive::new(std::fs::File::open(jar).with_context(|| format!("Opening {}", jar.display()))?)?;
+        let mut text = String::new();
+        match archive.by_name(MANIFEST_PATH) {
+            Ok(mut file) => file.read_to_string(&mut text)?,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+        Self::parse(&text).with_context(|| format!("Parsing the manifest of {}", jar.display())).map(Some)
+    }
+
+    /// `Main-Class` as an internal name, like `com/example/Main`.
+    pub fn main_class(&self) -> Option<String>{
+        self.main.get("Main-Class").map(|c| c.trim().replace('.', "/"))
+    }
+
+    /// The relative URLs `Class-Path` lists, with escapes like `%20` decoded.
+    pub fn class_path(&self) -> Vec<String>{
+        self.main.get("Class-Path").unwrap_or_default().split_ascii_whitespace().map(decode_url).collect()
+    }
+
+    /// Whether the package, like `com/example`, is sealed, by its own
+    /// section or else by the main one.
+    pub fn is_sealed(&self, package: &str) -> bool{
+        let sealed = |attributes: &Attributes| attributes.get("Sealed").map(|s| s.trim().eq_ignore_ascii_case("true"));
+        self.entries.get(&format!("{}/", package)).and_then(sealed)
+            .or_else(|| sealed(&self.main))
+            .unwrap_or(false)
+    }
+}
+
+/// Decodes the `%XX` escapes of a URL path, leaving malformed ones as they are.
+fn decode_url(url: &str) -> String{
+    let bytes = url.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len(){
+        let escaped = (bytes[i] == b'%').then(|| url.get(i + 1..i + 3)).flatten().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,596 @@
+//! Index spaces of the WebAssembly module being produced.
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::bail;
+use wasm_encoder::{CodeSection, ConstExpr, CustomSection, DataCountSection, DataSection, ElementSection, Elements, Encode, EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection, GlobalType, ImportSection, IndirectNameMap, MemorySection, MemoryType, Module, NameMap, NameSection, RefType, StartSection, TableSection, TableType, TypeSection, ValType};
+
+use crate::{code::Value, debug::SourceMap, descriptor::descriptor_parser, layout::DataSegment, link::{self, Callee, LinkedModule, Units, CORE, START_EXPORT}, program::MethodKey};
+
+/// Size of a WebAssembly memory page.
+pub const PAGE_SIZE: u32 = 0x10000;
+
+/// Pages in a 32 bit address space, the limit shared memories have to declare.
+const MAX_PAGES: u64 = 0x10000;
+
+/// Parameter and result types of the function implementing a method.
+/// Instance methods take their receiver as the first parameter.
+pub fn signature(descriptor: &[u8], is_static: bool) -> anyhow::Result<(Vec<ValType>, Vec<ValType>)>{
+    let Ok((params, result)) = descriptor_parser::method(descriptor) else {
+        bail!("Invalid method descriptor {}", String::from_utf8_lossy(descriptor));
+    };
+    let mut types = Vec::with_capacity(params.len() + 1);
+    if !is_static{
+        types.push(ValType::I32);
+    }
+    types.extend(params.iter().map(|p| Value::of_type(p).val_type()));
+    Ok((types, result.iter().map(|r| Value::of_type(r).val_type()).collect()))
+}
+
+/// A function provided by the host.
+pub struct Import{
+    pub module: String,
+    pub name: String,
+    pub type_index: u32,
+}
+
+#[derive(Default)]
+pub struct ModuleBuilder{
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    type_indices: HashMap<(Vec<ValType>, Vec<ValType>), u32>,
+    globals: Vec<(GlobalType, ConstExpr)>,
+    imports: Vec<Import>,
+    functions: Vec<u32>,
+    /// Encoded bodies, without their size.
+    bodies: Vec<Option<Vec<u8>>>,
+    symbols: HashMap<MethodKey, u32>,
+    exports: Vec<(String, ExportKind, u32)>,
+    start: Option<u32>,
+    /// Module and name of the shared memory, which is imported rather than defined.
+    shared_memory: Option<(String, String)>,
+    /// Names of functions without a [`MethodKey`] or import name.
+    names: HashMap<u32, String>,
+    local_names: HashMap<u32, Vec<(u32, String)>>,
+    /// Source file and the offsets in the body where lines start, per function.
+    lines: HashMap<u32, (String, Vec<(u32, u32)>)>,
+    source_map_url: Option<String>,
+}
+
+/// Number of bytes of `value` as an unsigned LEB128.
+fn leb_len(value: usize) -> usize{
+    let mut len = 1;
+    let mut rest = value >> 7;
+    while rest != 0{
+        len += 1;
+        rest >>= 7;
+    }
+    len
+}
+
+/// The encoded body of a function, without the size [`Encode`] puts in front.
+pub fn body_bytes(body: &Function) -> Vec<u8>{
+    let mut bytes = Vec::with_capacity(body.byte_len() + 5);
+    body.encode(&mut bytes);
+    bytes.split_off(leb_len(body.byte_len()))
+}
+
+impl ModuleBuilder{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Index of the function type, added if it isn't there yet.
+    pub fn type_index(&mut self, params: &[ValType], results: &[ValType]) -> u32{
+        let key = (params.to_vec(), results.to_vec());
+        if let Some(index) = self.type_indices.get(&key){
+            return *index;
+        }
+        let index = self.types.len() as u32;
+        self.types.push(key.clone());
+        self.type_indices.insert(key, index);
+        index
+    }
+
+    pub fn add_global(&mut self, val_type: ValType, mutable: bool, init: ConstExpr) -> u32{
+        self.globals.push((GlobalType{val_type, mutable}, init));
+        self.globals.len() as u32 - 1
+    }
+
+    /// Index of the function type if it has been added.
+    pub fn find_type(&self, params: &[ValType], results: &[ValType]) -> Option<u32>{
+        self.type_indices.get(&(params.to_vec(), results.to_vec())).copied()
+    }
+
+    /// Imports a function.
+    ///
+    /// Imported functions come first in the function index space, so all
+    /// imports have to be added before any function is declared.
+    pub fn import(&mut self, module: &str, name: &str, params: &[ValType], results: &[ValType]) -> u32{
+        assert!(self.functions.is_empty(), "Imports have to be added before functions!");
+        let type_index = self.type_index(params, results);
+        self.imports.push(Import{
+            module: module.into(),
+            name: name.into(),
+            type_index
+        });
+        self.imports.len() as u32 - 1
+    }
+
+    /// Imports the method from `module` under its [`MethodKey`] display name.
+    pub fn import_function(&mut self, module: &str, key: MethodKey, params: &[ValType], results: &[ValType]) -> u32{
+        assert!(!self.symbols.contains_key(&key), "{} declared twice!", key);
+        let index = self.import(module, &key.to_string(), params, results);
+        self.symbols.insert(key, index);
+        index
+    }
+
+    /// Reserves a function index, the body is provided later with [`Self::define`].
+    pub fn declare(&mut self, params: &[ValType], results: &[ValType]) -> u32{
+        let type_index = self.type_index(params, results);
+        let index = (self.imports.len() + self.functions.len()) as u32;
+        self.functions.push(type_index);
+        self.bodies.push(None);
+        index
+    }
+
+    /// Reserves a function index for the method.
+    pub fn declare_function(&mut self, key: MethodKey, params: &[ValType], results: &[ValType]) -> u32{
+        assert!(!self.symbols.contains_key(&key), "{} declared twice!", key);
+        let index = self.declare(params, results);
+        self.symbols.insert(key, index);
+        index
+    }
+
+    pub fn define(&mut self, index: u32, body: Function){
+        self.define_raw(index, body_bytes(&body));
+    }
+
+    /// Defines a function with a body encoded earlier, see [`body_bytes`].
+    pub fn define_raw(&mut self, index: u32, body: Vec<u8>){
+        let slot = &mut self.bodies[(index - self.imports.len() as u32) as usize];
+        assert!(slot.is_none(), "Function {} defined twice!", index);
+        *slot = Some(body);
+    }
+
+    /// Methods called by the bodies defined so far.
+    pub fn called_methods(&self) -> anyhow::Result<Vec<MethodKey>>{
+        let keys = self.symbols.iter().map(|(key, index)| (*index, key)).collect::<HashMap<_, _>>();
+        let mut called = Vec::new();
+        for body in self.bodies.iter().flatten(){
+            called.extend(link::calls(body)?.iter().filter_map(|index| keys.get(index).map(|key| (*key).clone())));
+        }
+        Ok(called)
+    }
+
+    /// Parameter and result types of a function.
+    pub fn function_type(&self, index: u32) -> &(Vec<ValType>, Vec<ValType>){
+        let type_index = match index.checked_sub(self.imports.len() as u32) {
+            Some(i) => self.functions[i as usize],
+            None => self.imports[index as usize].type_index
+        };
+        &self.types[type_index as usize]
+    }
+
+    /// Number of imported and declared functions.
+    pub fn function_count(&self) -> u32{
+        (self.imports.len() + self.functions.len()) as u32
+    }
+
+    pub fn export(&mut self, name: &str, kind: ExportKind, index: u32){
+        self.exports.push((name.into(), kind, index));
+    }
+
+    /// Names a function in the `name` section.
+    pub fn name(&mut self, function: u32, name: &str){
+        self.names.insert(function, name.into());
+    }
+
+    /// Names locals of a function in the `name` section.
+    pub fn name_locals(&mut self, function: u32, names: Vec<(u32, String)>){
+        self.local_names.insert(function, names);
+    }
+
+    /// Maps offsets in the body of a function to lines of the source file,
+    /// for the source map.
+    pub fn map_lines(&mut self, function: u32, source: &str, lines: Vec<(u32, u32)>){
+        self.lines.insert(function, (source.into(), lines));
+    }
+
+    /// Points debuggers at the source map with a `sourceMappingURL` section.
+    pub fn set_source_map_url(&mut self, url: &str){
+        self.source_map_url = Some(url.into());
+    }
+
+    pub fn set_start(&mut self, function: u32){
+        self.start = Some(function);
+    }
+
+    /// Imports the memory as a shared one, which atomic waits and notifies
+    /// need. The data segment is passive then, copied in by the start function.
+    pub fn import_shared_memory(&mut self, module: &str, name: &str){
+        self.shared_memory = Some((module.into(), name.into()));
+    }
+
+    pub fn function(&self, key: &MethodKey) -> Option<u32>{
+        self.symbols.get(key).copied()
+    }
+
+    pub fn globals(&self) -> &[(GlobalType, ConstExpr)]{
+        &self.globals
+    }
+
+    pub fn imports(&self) -> &[Import]{
+        &self.imports
+    }
+
+    pub fn types(&self) -> &[(Vec<ValType>, Vec<ValType>)]{
+        &self.types
+    }
+
+    fn memory_type(&self, pages: u32) -> MemoryType{
+        MemoryType{
+            minimum: pages as u64,
+            maximum: self.shared_memory.is_some().then_some(MAX_PAGES),
+            memory64: false,
+            shared: self.shared_memory.is_some()
+        }
+    }
+
+    /// Imports the shared memory, if there is one.
+    fn import_memory(&self, imports: &mut ImportSection, pages: u32){
+        if let Some((module, name)) = &self.shared_memory{
+            imports.import(module, name, EntityType::Memory(self.memory_type(pages)));
+        }
+    }
+
+    /// Defines the memory unless it's imported.
+    fn memory_section(&self, module: &mut Module, pages: u32){
+        if self.shared_memory.is_none(){
+            let mut memories = MemorySection::new();
+            memories.memory(self.memory_type(pages));
+            module.section(&memories);
+        }
+    }
+
+    /// Declares the data segment for `memory.init`, which needs a data count
+    /// section before the code, if it's passive.
+    fn data_count_section(&self, module: &mut Module){
+        if self.shared_memory.is_some(){
+            module.section(&DataCountSection{count: 1});
+        }
+    }
+
+    /// A shared memory gets a passive segment, copying it in again would
+    /// overwrite what other instances already did with it.
+    fn data_section(&self, module: &mut Module, data: &DataSegment){
+        let mut data_section = DataSection::new();
+        match self.shared_memory {
+            Some(_) => data_section.passive(data.bytes().iter().copied()),
+            None => data_section.active(0, &ConstExpr::i32_const(data.base() as i32), data.bytes().iter().copied())
+        };
+        module.section(&data_section);
+    }
+
+    /// The table holding every function at its index plus one.
+    fn table_type(&self) -> TableType{
+        TableType{
+            element_type: RefType::FUNCREF,
+            minimum: self.function_count() + 1,
+            maximum: Some(self.function_count() + 1)
+        }
+    }
+
+    fn type_section(&self) -> TypeSection{
+        let mut types = TypeSection::new();
+        for (params, results) in self.types.iter(){
+            types.function(params.iter().copied(), results.iter().copied());
+        }
+        types
+    }
+
+    /// Names of the functions, by index.
+    fn function_names(&self) -> HashMap<u32, String>{
+        let mut names = self.imports.iter().enumerate().map(|(index, import)| (index as u32, format!("{}.{}", import.module, import.name))).collect::<HashMap<_, _>>();
+        names.extend(self.symbols.iter().map(|(key, index)| (*index, key.to_string())));
+        names.extend(self.names.iter().map(|(index, name)| (*index, name.clone())));
+        names
+    }
+
+    fn body(&self, index: u32) -> &[u8]{
+        match &self.bodies[(index - self.imports.len() as u32) as usize] {
+            Some(body) => body,
+            None => panic!("Function {} was declared but never defined!", index)
+        }
+    }
+
+    /// Encodes the module, along with the source map of its code.
+    ///
+    /// The memory is exported as `memory` and holds `data`, even if it's imported. Every function is
+    /// placed in a table at its function index plus one, so that table index 0
+    /// stays empty and calling it traps.
+    pub fn finish(self, data: &DataSegment, memory_pages: u32) -> (Vec<u8>, SourceMap){
+        let mut module = Module::new();
+        module.section(&self.type_section());
+
+        let mut imports = ImportSection::new();
+        self.import_memory(&mut imports, memory_pages);
+        for import in self.imports.iter(){
+            imports.import(&import.module, &import.name, EntityType::Function(import.type_index));
+        }
+        module.section(&imports);
+
+        let mut functions = FunctionSection::new();
+        for type_index in self.functions.iter(){
+            functions.function(*type_index);
+        }
+        module.section(&functions);
+
+        let mut tables = TableSection::new();
+        tables.table(self.table_type());
+        module.section(&tables);
+
+        self.memory_section(&mut module, memory_pages);
+
+        let mut globals = GlobalSection::new();
+        for (global_type, init) in self.globals.iter(){
+            globals.global(*global_type, init);
+        }
+        module.section(&globals);
+
+        let mut exports = ExportSection::new();
+        exports.export("memory", ExportKind::Memory, 0);
+        for (name, kind, index) in self.exports.iter(){
+            exports.export(name, *kind, *index);
+        }
+        module.section(&exports);
+
+        if let Some(function_index) = self.start{
+            module.section(&StartSection{function_index});
+        }
+
+        let function_count = self.function_count();
+        let mut elements = ElementSection::new();
+        let all = (0..function_count).collect::<Vec<_>>();
+        elements.active(None, &ConstExpr::i32_const(1), Elements::Functions(&all));
+        module.section(&elements);
+
+        self.data_count_section(&mut module);
+        let first = self.imports.len() as u32;
+        let bodies = (first..function_count).map(|index| (self.body(index), self.lines.get(&index).map(|(source, lines)| (source.as_str(), lines.as_slice())))).collect::<Vec<_>>();
+        let source_map = code_section(&mut module, &bodies);
+
+        self.data_section(&mut module, data);
+
+        let names = self.function_names();
+        let indices = (0..function_count).map(|index| (index, index)).collect::<Vec<_>>();
+        module.section(&self.name_section(&names, &indices));
+
+        if let Some(url) = &self.source_map_url{
+            let mut bytes = Vec::new();
+            url.encode(&mut bytes);
+            module.section(&CustomSection{
+                name: "sourceMappingURL".into(),
+                data: bytes.into()
+            });
+        }
+
+        (module.finish(), source_map)
+    }
+
+    /// Names the functions and their locals, given as pairs of the index in
+    /// the module being encoded and in the whole program.
+    fn name_section(&self, names: &HashMap<u32, String>, functions: &[(u32, u32)]) -> NameSection{
+        let mut function_names = NameMap::new();
+        let mut local_names = IndirectNameMap::new();
+        for (local, index) in functions.iter(){
+            if let Some(name) = names.get(index){
+                function_names.append(*local, name);
+            }
+            if let Some(locals) = self.local_names.get(index){
+                let mut names = NameMap::new();
+                for (local, name) in locals.iter(){
+                    names.append(*local, name);
+                }
+                local_names.append(*local, &names);
+            }
+        }
+        let mut section = NameSection::new();
+        section.functions(&function_names);
+        section.locals(&local_names);
+        section
+    }
+
+    /// Encodes one module per unit, see [`crate::link`].
+    ///
+    /// The functions implementing methods go into the unit of their class,
+    /// all others into the core. There is no `sourceMappingURL` section, as
+    /// each module has a source map of its own.
+    pub fn finish_split(self, data: &DataSegment, memory_pages: u32, units: &Units) -> anyhow::Result<Vec<LinkedModule>>{
+        let first = self.imports.len() as u32;
+        let function_count = self.function_count();
+        let mut unit_of = vec![0; (function_count - first) as usize];
+        for (key, index) in self.symbols.iter(){
+            if let Some(slot) = index.checked_sub(first){
+                unit_of[slot as usize] = units.unit(&key.class);
+            }
+        }
+        let unit = |index: u32| index.checked_sub(first).map(|i| unit_of[i as usize]);
+        let names = self.function_names();
+
+        let mut modules = Vec::with_capacity(units.len());
+        for (current, unit_name) in units.names().iter().enumerate(){
+            let is_core = current == 0;
+            let defined = (first..function_count).filter(|i| unit(*i) == Some(current)).collect::<Vec<_>>();
+            if defined.is_empty() && !is_core{
+                continue;
+            }
+
+            // The core imports every host function, as they have table slots too.
+            let mut imported = match is_core {
+                true => (0..first).collect::<Vec<_>>(),
+                false => Vec::new()
+            };
+            let mut depends = BTreeSet::new();
+            if !is_core{
+                let mut called = BTreeSet::new();
+                for index in defined.iter(){
+                    called.extend(link::calls(self.body(*index))?);
+                }
+                imported.extend(called.into_iter().filter(|i| match unit(*i) {
+                    None => true,
+                    Some(0) => {
+                        depends.insert(0);
+                        true
+                    }
+                    Some(_) => false
+                }));
+            }
+            let local = imported.iter().chain(defined.iter()).enumerate().map(|(local, index)| (*index, local as u32)).collect::<HashMap<_, _>>();
+
+            let mut module = Module::new();
+            module.section(&self.type_section());
+
+            let mut imports = ImportSection::new();
+            if is_core{
+                self.import_memory(&mut imports, memory_pages);
+            }
+            else{
+                imports.import(CORE, "memory", EntityType::Memory(self.memory_type(memory_pages)));
+                imports.import(CORE, "table", EntityType::Table(self.table_type()));
+                for (index, (global_type, _)) in self.globals.iter().enumerate(){
+                    imports.import(CORE, &link::global_export(index as u32), EntityType::Global(*global_type));
+                }
+            }
+            for index in imported.iter(){
+                let type_index = match self.imports.get(*index as usize) {
+                    Some(import) => {
+                        imports.import(&import.module, &import.name, EntityType::Function(import.type_index));
+                        continue;
+                    }
+                    None => self.functions[(index - first) as usize]
+                };
+                imports.import(CORE, &link::function_export(*index), EntityType::Function(type_index));
+            }
+            module.section(&imports);
+
+            let mut functions = FunctionSection::new();
+            for index in defined.iter(){
+                functions.function(self.functions[(index - first) as usize]);
+            }
+            module.section(&functions);
+
+            if is_core{
+                let mut tables = TableSection::new();
+                tables.table(self.table_type());
+                module.section(&tables);
+                self.memory_section(&mut module, memory_pages);
+                let mut globals = GlobalSection::new();
+                for (global_type, init) in self.globals.iter(){
+                    globals.global(*global_type, init);
+                }
+                module.section(&globals);
+            }
+
+            // Every module exports the memory and the named globals, so that
+            // host functions find them whichever module calls them.
+            let mut exports = ExportSection::new();
+            exports.export("memory", ExportKind::Memory, 0);
+            for (name, kind, index) in self.exports.iter(){
+                match kind {
+                    ExportKind::Func => if let Some(local) = local.get(index).filter(|_| unit(*index) == Some(current)){
+                        exports.export(name, ExportKind::Func, *local);
+                    },
+                    _ => {
+                        exports.export(name, *kind, *index);
+                    }
+                }
+            }
+            if is_core{
+                exports.export("table", ExportKind::Table, 0);
+                for index in 0..self.globals.len() as u32{
+                    exports.export(&link::global_export(index), ExportKind::Global, index);
+                }
+                for index in defined.iter(){
+                    exports.export(&link::function_export(*index), ExportKind::Func, local[index]);
+                }
+                if let Some(start) = self.start{
+                    exports.export(START_EXPORT, ExportKind::Func, local[&start]);
+                }
+            }
+            module.section(&exports);
+
+            let mut elements = ElementSection::new();
+            let slots = imported.iter().filter(|_| is_core).chain(defined.iter()).copied().collect::<Vec<_>>();
+            for run in slots.chunk_by(|a, b| a + 1 == *b){
+                let functions = run.iter().map(|index| local[index]).collect::<Vec<_>>();
+                elements.active(None, &ConstExpr::i32_const(run[0] as i32 + 1), Elements::Functions(&functions));
+            }
+            module.section(&elements);
+
+            let mut bodies = Vec::with_capacity(defined.len());
+            for index in defined.iter(){
+                let lines = self.lines.get(index);
+                let (body, moved) = link::relink(self.body(*index), lines.map_or(&[], |(_, lines)| lines), |callee| match local.get(&callee) {
+                    Some(local) => Callee::Direct(*local),
+                    None => {
+                        depends.insert(unit(callee).expect("Imports are imported where they are called"));
+                        Callee::Table{
+                            slot: callee + 1,
+                            type_index: self.functions[(callee - first) as usize]
+                        }
+                    }
+                })?;
+                bodies.push((body, lines.map(|(source, _)| (source.as_str(), moved))));
+            }
+            let bodies = bodies.iter().map(|(body, lines)| (body.as_slice(), lines.as_ref().map(|(source, lines)| (*source, lines.as_slice())))).collect::<Vec<_>>();
+            if is_core{
+                self.data_count_section(&mut module);
+            }
+            let source_map = code_section(&mut module, &bodies);
+
+            if is_core{
+                self.data_section(&mut module, data);
+            }
+
+            let indices = imported.iter().chain(defined.iter()).map(|index| (local[index], *index)).collect::<Vec<_>>();
+            module.section(&self.name_section(&names, &indices));
+
+            depends.remove(&current);
+            modules.push(LinkedModule{
+                id: current,
+                name: unit_name.clone(),
+                wasm: module.finish(),
+                source_map,
+                functions: defined.len(),
+                depends: depends.into_iter().map(|u| units.names()[u].clone()).collect()
+            });
+        }
+        Ok(modules)
+    }
+}
+
+/// An encoded body, with its source file and lines if it has any.
+type Body<'a> = (&'a [u8], Option<(&'a str, &'a [(u32, u32)])>);
+
+/// Adds the code section with the bodies, returns the source map of the
+/// bodies that have lines.
+fn code_section(module: &mut Module, bodies: &[Body]) -> SourceMap{
+    let mut code = CodeSection::new();
+    for (body, _) in bodies.iter(){
+        code.raw(body);
+    }
+    module.section(&code);
+
+    // Bodies follow their size, after the count at the start of the section.
+    let mut source_map = SourceMap::default();
+    let mut offset = module.as_slice().len() - code.byte_len() + leb_len(bodies.len());
+    for (body, lines) in bodies.iter(){
+        offset += leb_len(body.len());
+        if let Some((source, lines)) = lines{
+            let source = source_map.source(source);
+            source_map.mappings.extend(lines.iter().map(|(at, line)| (offset as u32 + at, source, *line)));
+        }
+        offset += body.len();
+    }
+    source_map
+}
@@ -0,0 +1,261 @@
+//! Methods provided by the host.
+//!
+//! Every `native` method and every method that is invoked but can't be
+//! resolved inside the program becomes a function import from
+//! [`IMPORT_MODULE`], named like `java/lang/System.arraycopy(Ljava/lang/Object;ILjava/lang/Object;II)V`.
+//! The manifest lists them so host side shims can be written against it.
+//! Only the `Throwable` natives [`crate::trace`] implements and the
+//! `ClassLoader` ones of [`crate::resources`] are left out.
+//!
+//! Hosts that can't pass `i64` across the boundary, like JavaScript without
+//! BigInt integration, can have every `long` split into two ints: parameters
+//! are passed as their low and then their high half, results return their low
+//! half and leave the high one in the [`HIGH_BITS_EXPORT`] global. Thunks with
+//! the original signature join the halves again, so calls inside the module
+//! don't change.
+
+use std::collections::BTreeMap;
+
+use noak::{AccessFlags, reader::attributes::RawInstruction};
+use serde_json::json;
+use wasm_encoder::{ExportKind, Function, Instruction, ValType};
+
+use crate::{module::{signature, ModuleBuilder}, program::{MethodKey, Program}, classes, resources, trace};
+
+pub const IMPORT_MODULE: &str = "java";
+
+/// Export of the global holding the high half of a split `long` result.
+pub const HIGH_BITS_EXPORT: &str = "high_bits";
+
+/// Parameter and result types at the host boundary, with `split` every `long`
+/// parameter takes two ints and a `long` result is returned as one.
+pub fn boundary_signature(params: &[ValType], results: &[ValType], split: bool) -> (Vec<ValType>, Vec<ValType>){
+    if !split{
+        return (params.to_vec(), results.to_vec());
+    }
+    let params = params.iter().flat_map(|p| match p {
+        ValType::I64 => vec![ValType::I32, ValType::I32],
+        p => vec![*p]
+    }).collect();
+    let results = results.iter().map(|r| if *r == ValType::I64 {ValType::I32} else {*r}).collect();
+    (params, results)
+}
+
+/// Pushes the `long` with its halves in the locals.
+fn join(low: u32, high: u32) -> [Instruction<'static>; 7]{
+    [
+        Instruction::LocalGet(high),
+        Instruction::I64ExtendI32U,
+        Instruction::I64Const(32),
+        Instruction::I64Shl,
+        Instruction::LocalGet(low),
+        Instruction::I64ExtendI32U,
+        Instruction::I64Or,
+    ]
+}
+
+/// Exports `function` under `name`, behind a wrapper taking and returning
+/// split `long`s if its signature has any.
+pub fn export_split(module: &mut ModuleBuilder, name: &str, function: u32, high_bits: u32){
+    let (params, results) = module.function_type(function).clone();
+    let (outer, outer_results) = boundary_signature(&params, &results, true);
+    if outer == params && outer_results == results{
+        module.export(name, ExportKind::Func, function);
+        return;
+    }
+
+    let wrapper = module.declare(&outer, &outer_results);
+    module.name(wrapper, &format!("export {}", name));
+    // A scratch long after the parameters.
+    let scratch = outer.len() as u32;
+    let mut body = Function::new_with_locals_types([ValType::I64]);
+    let mut local = 0;
+    for param in params.iter(){
+        if *param == ValType::I64{
+            for instruction in join(local, local + 1){
+                body.instruction(&instruction);
+            }
+            local += 2;
+        }
+        else{
+            body.instruction(&Instruction::LocalGet(local));
+            local += 1;
+        }
+    }
+    body.instruction(&Instruction::Call(function));
+    if results.first() == Some(&ValType::I64){
+        for instruction in [
+            Instruction::LocalTee(scratch),
+            Instruction::I64Const(32),
+            Instruction::I64ShrU,
+            Instruction::I32WrapI64,
+            Instruction::GlobalSet(high_bits),
+            Instruction::LocalGet(scratch),
+            Instruction::I32WrapI64,
+        ]{
+            body.instruction(&instruction);
+        }
+    }
+    body.instruction(&Instruction::End);
+    module.define(wrapper, body);
+    module.export(name, ExportKind::Func, wrapper);
+}
+
+pub struct Native{
+    pub is_static: bool,
+    /// Declared `native`, as opposed to missing from the program.
+    pub declared: bool,
+}
+
+pub struct Natives{
+    methods: BTreeMap<MethodKey, Native>,
+    split_longs: bool,
+    /// Imports taking or returning split `long`s, by the method they implement.
+    split: Vec<(MethodKey, u32)>,
+}
+
+impl Natives{
+    pub fn collect(program: &Program) -> anyhow::Result<Self>{
+        let mut methods = BTreeMap::new();
+
+        for class in program.classes(){
+            for method in class.methods.iter(){
+                let key = MethodKey::new(&class.name, &method.name, &method.descriptor);
+                if method.flags.contains(AccessFlags::NATIVE) && !trace::implements(&key) && !resources::implements(&key) && !classes::implements(&key){
+                    methods.insert(key, Native{
+                        is_static: method.flags.contains(AccessFlags::STATIC),
+                        declared: true
+                    });
+                }
+
+                class.with_code(&method.name, &method.descriptor, |cp, code|{
+                    for instruction in code.raw_instructions(){
+                        let (key, is_static) = match instruction?.1 {
+                            RawInstruction::InvokeStatic { index } => (MethodKey::read(cp, index)?, true),
+                            RawInstruction::InvokeSpecial { index } => (MethodKey::read(cp, index)?, false),
+                            RawInstruction::InvokeVirtual { index } => (MethodKey::read(cp, index)?, false),
+                            RawInstruction::InvokeInterface { index, .. } => (MethodKey::read(cp, index)?, false),
+                            _ => continue
+                        };
+                        if program.resolve_method(&key.class, &key.name, &key.descriptor).is_none(){
+                            methods.entry(key).or_insert(Native{
+                                is_static,
+                                declared: false
+                            });
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(Self{
+            methods,
+            split_longs: false,
+            split: Vec::new()
+        })
+    }
+
+    pub fn len(&self) -> usize{
+        self.methods.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.methods.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MethodKey, &Native)>{
+        self.methods.iter()
+    }
+
+    /// Adds the imports, this has to happen before any function is declared.
+    ///
+    /// With `split_longs` the imports that take or return a `long` need
+    /// thunks, which [`Self::define_thunks`] adds once all imports are there.
+    pub fn import_all(&mut self, module: &mut ModuleBuilder, split_longs: bool) -> anyhow::Result<()>{
+        self.split_longs = split_longs;
+        for (key, native) in self.methods.iter(){
+            let (params, results) = signature(&key.descriptor, native.is_static)?;
+            let (outer, outer_results) = boundary_signature(&params, &results, split_longs);
+            if outer == params && outer_results == results{
+                module.import_function(IMPORT_MODULE, key.clone(), &params, &results);
+            }
+            else{
+                let import = module.import(IMPORT_MODULE, &key.to_string(), &outer, &outer_results);
+                self.split.push((key.clone(), import));
+            }
+        }
+        Ok(())
+    }
+
+    /// Declares the functions calls to imports with split `long`s go through.
+    pub fn define_thunks(&self, module: &mut ModuleBuilder, high_bits: u32) -> anyhow::Result<()>{
+        for (key, import) in self.split.iter(){
+            let (params, results) = signature(&key.descriptor, self.methods[key].is_static)?;
+            let thunk = module.declare_function(key.clone(), &params, &results);
+            let mut body = Function::new([]);
+            for (i, param) in params.iter().enumerate(){
+                body.instruction(&Instruction::LocalGet(i as u32));
+                if *param == ValType::I64{
+                    body.instruction(&Instruction::I32WrapI64);
+                    body.instruction(&Instruction::LocalGet(i as u32));
+                    body.instruction(&Instruction::I64Const(32));
+                    body.instruction(&Instruction::I64ShrU);
+                    body.instruction(&Instruction::I32WrapI64);
+                }
+            }
+            body.instruction(&Instruction::Call(*import));
+            if results.first() == Some(&ValType::I64){
+                for instruction in [
+                    Instruction::I64ExtendI32U,
+                    Instruction::GlobalGet(high_bits),
+                    Instruction::I64ExtendI32U,
+                    Instruction::I64Const(32),
+                    Instruction::I64Shl,
+                    Instruction::I64Or,
+                ]{
+                    body.instruction(&instruction);
+                }
+            }
+            body.instruction(&Instruction::End);
+            module.define(thunk, body);
+        }
+        Ok(())
+    }
+
+    /// JSON description of every import.
+    pub fn manifest(&self) -> anyhow::Result<serde_json::Value>{
+        let mut imports = Vec::with_capacity(self.methods.len());
+        for (key, native) in self.methods.iter(){
+            let (params, results) = signature(&key.descriptor, native.is_static)?;
+            let (params, results) = boundary_signature(&params, &results, self.split_longs);
+            imports.push(json!({
+                "name": key.to_string(),
+                "class": String::from_utf8_lossy(&key.class),
+                "method": String::from_utf8_lossy(&key.name),
+                "descriptor": String::from_utf8_lossy(&key.descriptor),
+                "static": native.is_static,
+                "native": native.declared,
+                "params": params.iter().map(|t| val_type_name(*t)).collect::<Vec<_>>(),
+                "results": results.iter().map(|t| val_type_name(*t)).collect::<Vec<_>>(),
+            }));
+        }
+
+        Ok(json!({
+            "module": IMPORT_MODULE,
+            "split_longs": self.split_longs,
+            "imports": imports
+        }))
+    }
+}
+
+fn val_type_name(val_type: ValType) -> &'static str{
+    match val_type {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        ValType::Ref(_) => "ref",
+    }
+}
@@ -0,0 +1,344 @@
+//! Devirtualization and inlining of calls.
+//!
+//! Class-hierarchy analysis finds the implementations a virtual or interface
+//! call can reach among the classes of the program. Calls with exactly one
+//! are made directly. Direct calls to tiny leaf methods, like getters,
+//! setters and methods returning a constant, are replaced by their body.
+//! Every decision is logged, so missed optimizations can be looked into.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use noak::{AccessFlags, reader::{attributes::RawInstruction, cpool::{self, Index}}};
+use wasm_encoder::Instruction;
+
+use crate::{classes::Layouts, ir::Checks, layout, lower::lower_constant, program::{MethodKey, Program}, reach::{is_concrete, is_subtype}, strings::StringTable};
+
+#[derive(Clone, Debug)]
+pub struct Optimizations{
+    /// Whether calls with one possible target are made directly.
+    pub devirtualize: bool,
+    /// Methods with at most this many bytes of bytecode can be inlined, 0 disables inlining.
+    pub inline_limit: u32,
+    /// Whether methods are lowered through their SSA form, which gets
+    /// constant folding and copy propagation.
+    pub ssa: bool,
+}
+
+impl Default for Optimizations{
+    fn default() -> Self{
+        Self{
+            devirtualize: true,
+            // Enough for `aload_0; aload_1; putfield; return`.
+            inline_limit: 6,
+            ssa: true
+        }
+    }
+}
+
+/// The body of a method that can replace calls to it.
+#[derive(Clone, Debug)]
+pub enum Inline{
+    /// Only returns, like the constructor of `Object`.
+    Empty,
+    /// Returns a constant.
+    Constant(Instruction<'static>),
+    /// Returns an instance field of the receiver.
+    Getter{
+        descriptor: Box<[u8]>,
+        offset: u32
+    },
+    /// Stores its only argument in an instance field of the receiver.
+    Setter{
+        descriptor: Box<[u8]>,
+        offset: u32
+    },
+}
+
+impl Inline{
+    fn kind(&self) -> &'static str{
+        match self {
+            Inline::Empty => "empty method",
+            Inline::Constant(_) => "constant",
+            Inline::Getter{..} => "getter",
+            Inline::Setter{..} => "setter",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Action{
+    /// The call has one possible target.
+    Devirtualized(MethodKey),
+    /// The call stays virtual, with the number of possible targets or `None`
+    /// if some are outside the program.
+    Virtual(Option<usize>),
+    Inlined(&'static str),
+}
+
+/// What was done with the call to `target` at `offset` in `caller`.
+#[derive(Clone, Debug)]
+pub struct Decision{
+    pub caller: MethodKey,
+    pub offset: u32,
+    pub target: MethodKey,
+    pub action: Action,
+}
+
+impl std::fmt::Display for Decision{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "{} @{}: {} ", self.caller, self.offset, self.target)?;
+        match &self.action {
+            Action::Devirtualized(method) => write!(f, "devirtualized to {}", method),
+            Action::Virtual(Some(count)) => write!(f, "stays virtual, {} targets", count),
+            Action::Virtual(None) => write!(f, "stays virtual, targets outside the program"),
+            Action::Inlined(kind) => write!(f, "inlined, {}", kind),
+        }
+    }
+}
+
+/// How many of the null checks and array bounds checks of a method could be removed.
+#[derive(Clone, Debug)]
+pub struct CheckReport{
+    pub method: MethodKey,
+    pub null_checks: usize,
+    pub null_checks_removed: usize,
+    pub bounds_checks: usize,
+    pub bounds_checks_removed: usize,
+}
+
+impl std::fmt::Display for CheckReport{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(
+            f,
+            "{}: {} of {} null checks, {} of {} bounds checks removed",
+            self.method,
+            self.null_checks_removed,
+            self.null_checks,
+            self.bounds_checks_removed,
+            self.bounds_checks
+        )
+    }
+}
+
+pub struct Optimizer{
+    options: Optimizations,
+    inlines: HashMap<MethodKey, Inline>,
+    /// Implementations of virtual call targets, `None` if some are unknown.
+    targets: Mutex<HashMap<MethodKey, Option<Vec<MethodKey>>>>,
+    log: Mutex<Vec<Decision>>,
+    checks: Mutex<Vec<CheckReport>>,
+}
+
+/// Recognizes methods that are simple enough to inline.
+fn inline(program: &Program, layouts: &Layouts, strings: &StringTable, key: &MethodKey, limit: u32) -> anyhow::Result<Option<Inline>>{
+    let Some(class) = program.class(&key.class) else {
+        return Ok(None);
+    };
+    let Some(method) = class.method(&key.name, &key.descriptor) else {
+        return Ok(None);
+    };
+    // Synchronized methods would need their monitor taken.
+    if method.flags.intersects(AccessFlags::SYNCHRONIZED | AccessFlags::NATIVE | AccessFlags::ABSTRACT){
+        return Ok(None);
+    }
+    let is_static = method.flags.contains(AccessFlags::STATIC);
+    let found = class.with_code(&key.name, &key.descriptor, |cp, code|{
+        // Every shape ends with a one byte return, so the last offset gives the size.
+        let mut instructions = Vec::new();
+        for instruction in code.raw_instructions(){
+            let (offset, instruction) = instruction?;
+            if offset.as_u32() + 1 > limit{
+                return Ok(None);
+            }
+            instructions.push(instruction);
+        }
+        let field = |index: Index<cpool::FieldRef>| -> anyhow::Result<Option<(Box<[u8]>, u32)>>{
+            let field = cp.get(index)?;
+            let owner = cp.get(cp.get(field.class)?.name)?.content.as_bytes();
+            let name = cp.get(cp.get(field.name_and_type)?.name)?.content.as_bytes();
+            let Some((declaring, parsed)) = program.resolve_field(owner, name) else {
+                return Ok(None);
+            };
+            if parsed.flags.contains(AccessFlags::STATIC){
+                return Ok(None);
+            }
+            Ok(layouts.field(&declaring.name, name).map(|offset| (parsed.descriptor.clone(), offset)))
+        };
+
+        Ok(match instructions.as_slice() {
+            [RawInstruction::Return] => Some(Inline::Empty),
+            [constant, RawInstruction::IReturn | RawInstruction::LReturn | RawInstruction::FReturn | RawInstruction::DReturn | RawInstruction::AReturn] => {
+                let instruction = match constant {
+                    RawInstruction::AConstNull => Instruction::I32Const(layout::NULL as i32),
+                    RawInstruction::IConstM1 => Instruction::I32Const(-1),
+                    RawInstruction::IConst0 => Instruction::I32Const(0),
+                    RawInstruction::IConst1 => Instruction::I32Const(1),
+                    RawInstruction::IConst2 => Instruction::I32Const(2),
+                    RawInstruction::IConst3 => Instruction::I32Const(3),
+                    RawInstruction::IConst4 => Instruction::I32Const(4),
+                    RawInstruction::IConst5 => Instruction::I32Const(5),
+                    RawInstruction::LConst0 => Instruction::I64Const(0),
+                    RawInstruction::LConst1 => Instruction::I64Const(1),
+                    RawInstruction::FConst0 => Instruction::F32Const(0.0),
+                    RawInstruction::FConst1 => Instruction::F32Const(1.0),
+                    RawInstruction::FConst2 => Instruction::F32Const(2.0),
+                    RawInstruction::DConst0 => Instruction::F64Const(0.0),
+                    RawInstruction::DConst1 => Instruction::F64Const(1.0),
+                    RawInstruction::BIPush { value } => Instruction::I32Const(*value as i32),
+                    RawInstruction::SIPush { value } => Instruction::I32Const(*value as i32),
+                    RawInstruction::LdC { index } |
+                    RawInstruction::LdCW { index } |
+                    RawInstruction::LdC2W { index } => match lower_constant(cp, *index, strings) {
+                        Ok(instruction) => instruction,
+                        Err(_) => return Ok(None)
+                    },
+                    _ => return Ok(None)
+                };
+                Some(Inline::Constant(instruction))
+            }
+            [
+                RawInstruction::ALoad0,
+                RawInstruction::GetField { index },
+                RawInstruction::IReturn | RawInstruction::LReturn | RawInstruction::FReturn | RawInstruction::DReturn | RawInstruction::AReturn
+            ] if !is_static => {
+                field(*index)?.map(|(descriptor, offset)| Inline::Getter{descriptor, offset})
+            }
+            [
+                RawInstruction::ALoad0,
+                RawInstruction::ILoad1 | RawInstruction::LLoad1 | RawInstruction::FLoad1 | RawInstruction::DLoad1 | RawInstruction::ALoad1,
+                RawInstruction::PutField { index },
+                RawInstruction::Return
+            ] if !is_static => {
+                field(*index)?.map(|(descriptor, offset)| Inline::Setter{descriptor, offset})
+            }
+            _ => None
+        })
+    })?;
+    Ok(found.flatten())
+}
+
+impl Optimizer{
+    pub fn new(program: &Program, layouts: &Layouts, strings: &StringTable, options: &Optimizations) -> anyhow::Result<Self>{
+        let mut inlines = HashMap::new();
+        if options.inline_limit > 0{
+            for class in program.classes(){
+                for method in class.methods.iter(){
+                    let key = MethodKey::new(&class.name, &method.name, &method.descriptor);
+                    if let Some(found) = inline(program, layouts, strings, &key, options.inline_limit)?{
+                        inlines.insert(key, found);
+                    }
+                }
+            }
+        }
+        Ok(Self{
+            options: options.clone(),
+            inlines,
+            targets: Mutex::new(HashMap::new()),
+            log: Mutex::new(Vec::new()),
+            checks: Mutex::new(Vec::new())
+        })
+    }
+
+    pub fn options(&self) -> &Optimizations{
+        &self.options
+    }
+
+    /// The body replacing direct calls to `key`, without logging a decision.
+    pub fn inline_body(&self, key: &MethodKey) -> Option<&Inline>{
+        self.inlines.get(key)
+    }
+
+    /// Marks the end of the logs, see [`Self::logged_since`].
+    pub fn log_len(&self) -> (usize, usize){
+        (self.log.lock().unwrap().len(), self.checks.lock().unwrap().len())
+    }
+
+    /// What was logged after [`Self::log_len`] returned `start`.
+    pub fn logged_since(&self, start: (usize, usize)) -> (Vec<Decision>, Option<CheckReport>){
+        let decisions = self.log.lock().unwrap()[start.0..].to_vec();
+        let checks = self.checks.lock().unwrap()[start.1..].last().cloned();
+        (decisions, checks)
+    }
+
+    /// Logs what was logged for a method that was lowered in an earlier run.
+    pub fn replay(&self, decisions: Vec<Decision>, checks: Option<CheckReport>){
+        self.log.lock().unwrap().extend(decisions);
+        self.checks.lock().unwrap().extend(checks);
+    }
+
+    /// Implementations a call to `target` can reach, `None` if some are outside the program.
+    pub fn targets(&self, program: &Program, target: &MethodKey) -> Option<Vec<MethodKey>>{
+        if let Some(targets) = self.targets.lock().unwrap().get(target){
+            return targets.clone();
+        }
+        let mut targets = Some(Vec::new());
+        for class in program.classes().into_iter().filter(|c| is_concrete(c) && is_subtype(program, &c.name, &target.class)){
+            match program.resolve_method(&class.name, &target.name, &target.descriptor) {
+                Some((declaring, method)) if !method.flags.contains(AccessFlags::ABSTRACT) => {
+                    let key = MethodKey::new(&declaring.name, &target.name, &target.descriptor);
+                    if let Some(targets) = targets.as_mut().filter(|t| !t.contains(&key)){
+                        targets.push(key);
+                    }
+                }
+                // Inherited from outside the program.
+                _ => {
+                    targets = None;
+                    break;
+                }
+            }
+        }
+        self.targets.lock().unwrap().insert(target.clone(), targets.clone());
+        targets
+    }
+
+    /// The only method a virtual call to `target` can reach, if there is one.
+    pub fn devirtualize(&self, program: &Program, caller: &MethodKey, offset: u32, target: &MethodKey) -> Option<MethodKey>{
+        if !self.options.devirtualize{
+            return None;
+        }
+        let targets = self.targets(program, target);
+        let (found, action) = match targets.as_deref() {
+            Some([only]) => (Some(only.clone()), Action::Devirtualized(only.clone())),
+            Some(targets) => (None, Action::Virtual(Some(targets.len()))),
+            None => (None, Action::Virtual(None))
+        };
+        self.record(caller, offset, target, action);
+        found
+    }
+
+    /// The body to use instead of a direct call to `target`, if it's small enough.
+    pub fn inline(&self, caller: &MethodKey, offset: u32, target: &MethodKey) -> Option<&Inline>{
+        let inline = self.inlines.get(target)?;
+        self.record(caller, offset, target, Action::Inlined(inline.kind()));
+        Some(inline)
+    }
+
+    fn record(&self, caller: &MethodKey, offset: u32, target: &MethodKey, action: Action){
+        self.log.lock().unwrap().push(Decision{
+            caller: caller.clone(),
+            offset,
+            target: target.clone(),
+            action
+        });
+    }
+
+    /// Records which checks of a method lowered through its SSA form were removed.
+    pub fn record_checks(&self, method: &MethodKey, checks: &Checks){
+        self.checks.lock().unwrap().push(CheckReport{
+            method: method.clone(),
+            null_checks: checks.null_checks,
+            null_checks_removed: checks.null_checks_removed(),
+            bounds_checks: checks.bounds_checks,
+            bounds_checks_removed: checks.bounds_checks_removed()
+        });
+    }
+
+    /// Every decision, by caller and offset, and the checks removed from each method.
+    pub fn into_logs(self) -> (Vec<Decision>, Vec<CheckReport>){
+        let mut log = self.log.into_inner().unwrap();
+        log.sort_by(|a, b| (&a.caller, a.offset).cmp(&(&b.caller, b.offset)));
+        let mut checks = self.checks.into_inner().unwrap();
+        checks.sort_by(|a, b| a.method.cmp(&b.method));
+        (log, checks)
+    }
+}
@@ -0,0 +1,140 @@
+//! The set of classes taking part in a translation.
+
+use std::{collections::HashMap, hash::Hash};
+
+use anyhow::bail;
+use noak::reader::cpool::{ConstantPool, Index, Item};
+
+use crate::{cache::Fingerprint, data::{ParsedClass, ParsedField, ParsedMethod}};
+
+/// Identifies a method by its owner, name and descriptor.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MethodKey{
+    pub class: Box<[u8]>,
+    pub name: Box<[u8]>,
+    pub descriptor: Box<[u8]>,
+}
+
+impl MethodKey{
+    pub fn new(class: &[u8], name: &[u8], descriptor: &[u8]) -> Self{
+        Self{
+            class: class.into(),
+            name: name.into(),
+            descriptor: descriptor.into()
+        }
+    }
+
+    /// Reads the target of a `MethodRef` or `InterfaceMethodRef`.
+    pub fn read<I>(cp: &ConstantPool, index: Index<I>) -> anyhow::Result<Self>{
+        let (class, name_and_type) = match cp.get(Index::<Item>::new(index.as_u16())?)? {
+            Item::MethodRef(m) => (m.class, m.name_and_type),
+            Item::InterfaceMethodRef(m) => (m.class, m.name_and_type),
+            x => bail!("Expected a method reference, got {:?}", x)
+        };
+        let nt = cp.get(name_and_type)?;
+        Ok(Self::new(
+            cp.get(cp.get(class)?.name)?.content.as_bytes(),
+            cp.get(nt.name)?.content.as_bytes(),
+            cp.get(nt.descriptor)?.content.as_bytes()
+        ))
+    }
+}
+
+impl std::fmt::Display for MethodKey{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(
+            f,
+            "{}.{}{}",
+            String::from_utf8_lossy(&self.class),
+            String::from_utf8_lossy(&self.name),
+            String::from_utf8_lossy(&self.descriptor)
+        )
+    }
+}
+
+pub struct Program{
+    classes: HashMap<Box<[u8]>, ParsedClass>
+}
+
+impl Program{
+    pub fn new(classes: impl IntoIterator<Item = ParsedClass>) -> Self{
+        Self{
+            classes: classes.into_iter().map(|c| (c.name.clone(), c)).collect()
+        }
+    }
+
+    pub fn class(&self, name: &[u8]) -> Option<&ParsedClass>{
+        self.classes.get(name)
+    }
+
+    /// Every class, sorted by name.
+    pub fn classes(&self) -> Vec<&ParsedClass>{
+        let mut classes = self.classes.values().collect::<Vec<_>>();
+        classes.sort_by(|a, b| a.name.cmp(&b.name));
+        classes
+    }
+
+    /// Hashes what the class declares, but not the code of its methods, and
+    /// the names of its superclasses.
+    pub fn fingerprint_class(&self, name: &[u8], fp: &mut Fingerprint){
+        let Some(class) = self.class(name) else {
+            return;
+        };
+        (&class.name, class.flags.bits(), &class.inherited, &class.interfaces, &class.source_file).hash(fp);
+        for field in class.fields.iter(){
+            (&field.name, &field.descriptor, field.flags.bits(), format!("{:?}", field.constant)).hash(fp);
+        }
+        for method in class.methods.iter(){
+            (&method.name, &method.descriptor, method.flags.bits()).hash(fp);
+        }
+        for superclass in self.superclasses(name){
+            superclass.name.hash(fp);
+        }
+    }
+
+    /// The class itself followed by every superclass that is part of the program.
+    pub fn superclasses<'a>(&'a self, name: &[u8]) -> impl Iterator<Item = &'a ParsedClass> + 'a{
+        let mut next = self.class(name);
+        std::iter::from_fn(move ||{
+            let current = next?;
+            next = current.inherited.as_deref().and_then(|n| self.class(n));
+            Some(current)
+        })
+    }
+
+    /// Whether `ancestor` is `class` or one of its superclasses.
+    pub fn is_subclass(&self, class: &[u8], ancestor: &[u8]) -> bool{
+        self.superclasses(class).any(|c| &*c.name == ancestor)
+    }
+
+    /// Finds the class declaring the field, searching superinterfaces before superclasses.
+    pub fn resolve_field<'a>(&'a self, class: &[u8], name: &[u8]) -> Option<(&'a ParsedClass, &'a ParsedField)>{
+        let c = self.class(class)?;
+        if let Some(f) = c.field(name){
+            return Some((c, f));
+        }
+        for interface in c.interfaces.iter(){
+            if let Some(found) = self.resolve_field(interface, name){
+                return Some(found);
+            }
+        }
+        c.inherited.as_deref().and_then(|s| self.resolve_field(s, name))
+    }
+
+    /// Finds the class declaring the method, searching superclasses before superinterfaces.
+    pub fn resolve_method<'a>(&'a self, class: &[u8], name: &[u8], descriptor: &[u8]) -> Option<(&'a ParsedClass, &'a ParsedMethod)>{
+        for c in self.superclasses(class){
+            if let Some(m) = c.method(name, descriptor){
+                return Some((c, m));
+            }
+        }
+        for c in self.superclasses(class){
+            for interface in c.interfaces.iter(){
+                if let Some(found) = self.resolve_method(interface, name, descriptor){
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,434 @@
+//! Whole-program reachability, used to shake out unused classes and members.
+//!
+//! This is rapid type analysis: a virtual call can only reach implementations
+//! in classes that some reachable code instantiates with `new`. Starting from
+//! the roots, every reachable method body is scanned once; calls, field
+//! accesses and instantiations found there make more items reachable, and a
+//! class instantiated late is matched against every virtual call seen so far.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use noak::{AccessFlags, reader::{attributes::RawInstruction, cpool::{self, ConstantPool, Index}}};
+
+use crate::{blocks::Handler, classes::{self, all_interfaces, CLASS}, data::ParsedClass, descriptor::{descriptor_parser, JavaType}, indy::CallSite, init::{CLINIT, CLINIT_DESCRIPTOR}, program::{MethodKey, Program}, runtime::THROWN, trace};
+
+const APPLET: &[u8] = b"java/applet/Applet";
+/// Methods the browser calls on an applet, besides its no-argument constructor.
+const APPLET_LIFECYCLE: &[(&[u8], &[u8])] = &[
+    (b"init", b"()V"),
+    (b"start", b"()V"),
+    (b"paint", b"(Ljava/awt/Graphics;)V"),
+];
+
+/// A field by its class and name.
+pub type FieldKey = (Box<[u8]>, Box<[u8]>);
+
+/// Where the analysis starts.
+#[derive(Clone, Debug, Default)]
+pub struct Roots{
+    /// Methods called from outside the program, like `main`, or through reflection.
+    pub methods: Vec<MethodKey>,
+    /// Classes loaded through reflection, they are instantiated unless abstract.
+    pub classes: Vec<Box<[u8]>>,
+    /// Fields accessed through reflection, by class and name.
+    pub fields: Vec<FieldKey>,
+    /// Whether every subclass of `java.applet.Applet` is instantiated and
+    /// driven through its lifecycle methods.
+    pub applets: bool,
+}
+
+/// A class or member that can be kept.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Item{
+    Class(Box<[u8]>),
+    Method(MethodKey),
+    Field(Box<[u8]>, Box<[u8]>),
+}
+
+impl std::fmt::Display for Item{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self {
+            Item::Class(class) => write!(f, "class {}", String::from_utf8_lossy(class)),
+            Item::Method(method) => write!(f, "method {}", method),
+            Item::Field(class, name) => write!(f, "field {}.{}", String::from_utf8_lossy(class), String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+/// Why an item was kept, the first reason found wins.
+#[derive(Clone, Debug)]
+pub enum Reason{
+    Root(&'static str),
+    /// The runtime allocates, throws or calls it.
+    Runtime,
+    /// Named by an instruction or exception handler of the method.
+    Referenced(MethodKey),
+    /// Instantiated with `new` in the method.
+    Instantiated(MethodKey),
+    /// An implementation a reachable virtual call to the method may dispatch to.
+    Dispatched(MethodKey),
+    /// Superclass or interface of the class.
+    Supertype(Box<[u8]>),
+    /// May override a method of a supertype outside the program, which the
+    /// host or the JDK calls on instances of the class.
+    Outside(Box<[u8]>),
+    /// Static initializer of a kept class.
+    Initializer,
+}
+
+impl std::fmt::Display for Reason{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self {
+            Reason::Root(root) => write!(f, "{}", root),
+            Reason::Runtime => write!(f, "used by the runtime"),
+            Reason::Referenced(method) => write!(f, "referenced by {}", method),
+            Reason::Instantiated(method) => write!(f, "instantiated by {}", method),
+            Reason::Dispatched(method) => write!(f, "may be called through {}", method),
+            Reason::Supertype(class) => write!(f, "supertype of {}", String::from_utf8_lossy(class)),
+            Reason::Outside(class) => write!(f, "may be called from outside the program on {}", String::from_utf8_lossy(class)),
+            Reason::Initializer => write!(f, "initializer of a kept class"),
+        }
+    }
+}
+
+/// Whether `class` is `ancestor` or one of its subclasses or implementations.
+pub fn is_subtype(program: &Program, class: &[u8], ancestor: &[u8]) -> bool{
+    program.is_subclass(class, ancestor) || all_interfaces(program, class).contains(ancestor)
+}
+
+/// Name of the class an array type or class constant refers to, `None` for primitive arrays.
+fn element_class(name: &[u8]) -> Option<Box<[u8]>>{
+    if !name.starts_with(b"["){
+        return Some(name.into());
+    }
+    match descriptor_parser::field(name) {
+        Ok(JavaType::Reference(_, class)) => Some(class),
+        _ => None
+    }
+}
+
+/// Whether `new` can create instances of the class.
+pub fn is_concrete(class: &ParsedClass) -> bool{
+    !class.flags.intersects(AccessFlags::ABSTRACT | AccessFlags::INTERFACE)
+}
+
+pub struct Reachability{
+    kept: BTreeMap<Item, Reason>,
+}
+
+struct Analysis<'a>{
+    program: &'a Program,
+    kept: BTreeMap<Item, Reason>,
+    /// Methods whose code hasn't been scanned yet.
+    pending: Vec<MethodKey>,
+    instantiated: BTreeSet<Box<[u8]>>,
+    /// Targets of the virtual calls seen so far.
+    virtual_calls: BTreeSet<MethodKey>,
+}
+
+impl<'a> Analysis<'a>{
+    fn keep(&mut self, item: Item, reason: Reason) -> bool{
+        if self.kept.contains_key(&item){
+            return false;
+        }
+        self.kept.insert(item, reason);
+        true
+    }
+
+    fn keep_class(&mut self, name: &[u8], reason: Reason){
+        let Some(class) = self.program.class(name) else {
+            return;
+        };
+        if !self.keep(Item::Class(name.into()), reason){
+            return;
+        }
+        for supertype in class.inherited.iter().chain(class.interfaces.iter()){
+            self.keep_class(supertype, Reason::Supertype(name.into()));
+        }
+        if class.method(CLINIT, CLINIT_DESCRIPTOR).is_some(){
+            self.keep_method(&MethodKey::new(name, CLINIT, CLINIT_DESCRIPTOR), Reason::Initializer);
+        }
+    }
+
+    /// Keeps a method declared by `key.class`.
+    fn keep_method(&mut self, key: &MethodKey, reason: Reason){
+        if self.program.class(&key.class).and_then(|c| c.method(&key.name, &key.descriptor)).is_none(){
+            return;
+        }
+        self.keep_class(&key.class, reason.clone());
+        if self.keep(Item::Method(key.clone()), reason){
+            self.pending.push(key.clone());
+        }
+    }
+
+    fn keep_field(&mut self, class: &[u8], name: &[u8], reason: Reason){
+        let Some((declaring, _)) = self.program.resolve_field(class, name) else {
+            return;
+        };
+        let declaring = declaring.name.clone();
+        self.keep_class(&declaring, reason.clone());
+        self.keep(Item::Field(declaring, name.into()), reason);
+    }
+
+    /// Keeps the method `class` runs for a call to `target`, if it has one.
+    fn dispatch(&mut self, class: &[u8], target: &MethodKey){
+        if let Some((declaring, method)) = self.program.resolve_method(class, &target.name, &target.descriptor){
+            if !method.flags.contains(AccessFlags::ABSTRACT){
+                let key = MethodKey::new(&declaring.name, &target.name, &target.descriptor);
+                self.keep_method(&key, Reason::Dispatched(target.clone()));
+            }
+        }
+    }
+
+    fn instantiate(&mut self, name: &[u8], reason: Reason){
+        self.keep_class(name, reason);
+        if self.program.class(name).is_none() || !self.instantiated.insert(name.into()){
+            return;
+        }
+        let calls = self.virtual_calls.iter()
+            .filter(|call| is_subtype(self.program, name, &call.class))
+            .cloned()
+            .collect::<Vec<_>>();
+        for call in calls{
+            self.dispatch(name, &call);
+        }
+        self.outside_overrides(name);
+    }
+
+    /// Keeps what an instantiated class runs for the methods that may override
+    /// or implement a method of a supertype outside the program. Those
+    /// supertypes can't be read, so every public or protected instance method
+    /// counts.
+    fn outside_overrides(&mut self, name: &[u8]){
+        let program = self.program;
+        let interfaces = all_interfaces(program, name);
+        let outside = program.superclasses(name).any(|c| c.inherited.as_deref().is_some_and(|s| program.class(s).is_none()))
+            || interfaces.iter().any(|i| program.class(i).is_none());
+        if !outside{
+            return;
+        }
+        let declaring = program.superclasses(name).chain(interfaces.iter().filter_map(|i| program.class(i)));
+        let methods = declaring
+            .flat_map(|c| c.methods.iter())
+            .filter(|m| m.flags.intersects(AccessFlags::PUBLIC | AccessFlags::PROTECTED) && !m.flags.contains(AccessFlags::STATIC) && &*m.name != b"<init>")
+            .map(|m| MethodKey::new(name, &m.name, &m.descriptor))
+            .collect::<BTreeSet<_>>();
+        for method in methods{
+            if let Some((declaring, implementation)) = program.resolve_method(name, &method.name, &method.descriptor){
+                if !implementation.flags.contains(AccessFlags::ABSTRACT){
+                    let key = MethodKey::new(&declaring.name, &method.name, &method.descriptor);
+                    self.keep_method(&key, Reason::Outside(name.into()));
+                }
+            }
+        }
+    }
+
+    /// A call that can reach every override of `target` in instantiated classes.
+    fn virtual_call(&mut self, target: MethodKey, reason: Reason){
+        if let Some((declaring, _)) = self.program.resolve_method(&target.class, &target.name, &target.descriptor){
+            // The declaration gives the call its vtable slot or interface selector.
+            let declared = MethodKey::new(&declaring.name, &target.name, &target.descriptor);
+            self.keep_method(&declared, reason);
+        }
+        if !self.virtual_calls.insert(target.clone()){
+            return;
+        }
+        let classes = self.instantiated.iter()
+            .filter(|class| is_subtype(self.program, class, &target.class))
+            .cloned()
+            .collect::<Vec<_>>();
+        for class in classes{
+            self.dispatch(&class, &target);
+        }
+    }
+
+    /// A call that always runs the method `target` resolves to.
+    fn direct_call(&mut self, target: MethodKey, reason: Reason){
+        if let Some((declaring, _)) = self.program.resolve_method(&target.class, &target.name, &target.descriptor){
+            let key = MethodKey{class: declaring.name.clone(), ..target};
+            self.keep_method(&key, reason);
+        }
+    }
+
+    /// `Class` objects are laid out by the translator with their fields.
+    fn class_objects(&mut self, reason: Reason){
+        self.instantiate(CLASS, reason.clone());
+        if let Some(class) = self.program.class(CLASS){
+            for field in class.fields.iter().filter(|f| !f.flags.contains(AccessFlags::STATIC)){
+                self.keep_field(CLASS, &field.name, reason.clone());
+            }
+        }
+    }
+
+    fn class_constant(&mut self, cp: &ConstantPool, index: Index<cpool::Class>, reason: Reason) -> anyhow::Result<()>{
+        let name = cp.get(cp.get(index)?.name)?.content.as_bytes();
+        if let Some(class) = element_class(name){
+            self.keep_class(&class, reason);
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, method: &MethodKey) -> anyhow::Result<()>{
+        let Some(class) = self.program.class(&method.class) else {
+            return Ok(());
+        };
+        if *method == classes::get_class(){
+            self.class_objects(Reason::Referenced(method.clone()));
+        }
+        class.with_code(&method.name, &method.descriptor, |cp, code|{
+            let referenced = || Reason::Referenced(method.clone());
+            for handler in Handler::read_all(code, cp)?{
+                if let Some(catch_type) = handler.catch_type{
+                    self.keep_class(&catch_type, referenced());
+                }
+            }
+
+            for instruction in code.raw_instructions(){
+                match instruction?.1 {
+                    RawInstruction::New { index } => {
+                        let name = cp.get(cp.get(index)?.name)?.content.as_bytes();
+                        self.instantiate(name, Reason::Instantiated(method.clone()));
+                    }
+                    RawInstruction::ANewArray { index } |
+                    RawInstruction::MultiANewArray { index, .. } |
+                    RawInstruction::CheckCast { index } |
+                    RawInstruction::InstanceOf { index } => self.class_constant(cp, index, referenced())?,
+                    RawInstruction::LdC { index } |
+                    RawInstruction::LdCW { index } => match cp.get(index)? {
+                        cpool::Item::String(_) => self.instantiate(b"java/lang/String", referenced()),
+                        cpool::Item::Class(c) => {
+                            let name = cp.get(c.name)?.content.as_bytes();
+                            if let Some(class) = element_class(name){
+                                self.keep_class(&class, referenced());
+                            }
+                            self.class_objects(referenced());
+                        }
+                        _ => ()
+                    },
+                    RawInstruction::GetField { index } |
+                    RawInstruction::PutField { index } |
+                    RawInstruction::GetStatic { index } |
+                    RawInstruction::PutStatic { index } => {
+                        let field = cp.get(index)?;
+                        let owner = cp.get(cp.get(field.class)?.name)?.content.as_bytes();
+                        let name = cp.get(cp.get(field.name_and_type)?.name)?.content.as_bytes();
+                        self.keep_field(owner, name, referenced());
+                    }
+                    RawInstruction::InvokeStatic { index } => self.direct_call(MethodKey::read(cp, index)?, referenced()),
+                    RawInstruction::InvokeSpecial { index } => self.direct_call(MethodKey::read(cp, index)?, referenced()),
+                    RawInstruction::InvokeVirtual { index } => self.virtual_call(MethodKey::read(cp, index)?, referenced()),
+                    RawInstruction::InvokeInterface { index, .. } => self.virtual_call(MethodKey::read(cp, index)?, referenced()),
+                    // Call sites that can't be desugared fail when the method is lowered.
+                    RawInstruction::InvokeDynamic { index } => if let Ok(CallSite::Lambda(lambda)) = CallSite::read(class, cp, index) {
+                        self.instantiate(&lambda.class, Reason::Instantiated(method.clone()));
+                    },
+                    _ => ()
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn roots(&mut self, roots: &Roots){
+        let program = self.program;
+
+        self.instantiate(b"java/lang/String", Reason::Runtime);
+        // String literals are laid out with every field of `String`.
+        if let Some(string) = program.class(b"java/lang/String"){
+            for field in string.fields.iter().filter(|f| !f.flags.contains(AccessFlags::STATIC)){
+                self.keep_field(&string.name, &field.name, Reason::Runtime);
+            }
+        }
+        for class in THROWN{
+            self.instantiate(class.as_bytes(), Reason::Runtime);
+            self.keep_method(&MethodKey::new(class.as_bytes(), b"<init>", b"()V"), Reason::Runtime);
+        }
+        // Exceptions escaping exported methods are printed by `Throwable`.
+        self.keep_method(&trace::uncaught(), Reason::Runtime);
+        // String concatenation converts objects with their `toString`.
+        self.virtual_call(MethodKey::new(b"java/lang/Object", b"toString", b"()Ljava/lang/String;"), Reason::Runtime);
+
+        for method in roots.methods.iter(){
+            self.keep_method(method, Reason::Root("root method"));
+        }
+        for name in roots.classes.iter(){
+            match program.class(name) {
+                Some(class) if is_concrete(class) => self.instantiate(name, Reason::Root("root class")),
+                _ => self.keep_class(name, Reason::Root("root class"))
+            }
+        }
+        for (class, name) in roots.fields.iter(){
+            self.keep_field(class, name, Reason::Root("root field"));
+        }
+
+        if roots.applets{
+            for class in program.classes().into_iter().filter(|c| is_concrete(c)){
+                // The applet class itself usually isn't part of the program.
+                let is_applet = program.superclasses(&class.name).any(|c| c.inherited.as_deref() == Some(APPLET));
+                if !is_applet{
+                    continue;
+                }
+                self.instantiate(&class.name, Reason::Root("applet"));
+                self.keep_method(&MethodKey::new(&class.name, b"<init>", b"()V"), Reason::Root("applet constructor"));
+                for (name, descriptor) in APPLET_LIFECYCLE{
+                    let target = MethodKey::new(APPLET, name, descriptor);
+                    if let Some((declaring, _)) = program.resolve_method(&class.name, name, descriptor){
+                        let key = MethodKey::new(&declaring.name, name, descriptor);
+                        self.keep_method(&key, Reason::Dispatched(target));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Reachability{
+    pub fn analyze(program: &Program, roots: &Roots) -> anyhow::Result<Self>{
+        let mut analysis = Analysis{
+            program,
+            kept: BTreeMap::new(),
+            pending: Vec::new(),
+            instantiated: BTreeSet::new(),
+            virtual_calls: BTreeSet::new()
+        };
+        analysis.roots(roots);
+        while let Some(method) = analysis.pending.pop(){
+            analysis.scan(&method)?;
+        }
+        Ok(Self{
+            kept: analysis.kept
+        })
+    }
+
+    pub fn contains(&self, item: &Item) -> bool{
+        self.kept.contains_key(item)
+    }
+
+    pub fn reason(&self, item: &Item) -> Option<&Reason>{
+        self.kept.get(item)
+    }
+
+    /// The program with only the kept classes, methods and fields.
+    pub fn prune(&self, program: &Program) -> Program{
+        let classes = program.classes().into_iter()
+            .filter(|c| self.contains(&Item::Class(c.name.clone())))
+            .map(|c|{
+                let mut class = c.clone();
+                class.methods.retain(|m| self.contains(&Item::Method(MethodKey::new(&c.name, &m.name, &m.descriptor))));
+                class.fields.retain(|f| self.contains(&Item::Field(c.name.clone(), f.name.clone())));
+                class
+            })
+            .collect::<Vec<_>>();
+        Program::new(classes)
+    }
+
+    /// One line per kept item saying why it was kept, sorted by item.
+    pub fn report(&self) -> String{
+        let mut report = String::new();
+        for (item, reason) in self.kept.iter(){
+            report.push_str(&format!("{}: {}\n", item, reason));
+        }
+        report
+    }
+}
@@ -0,0 +1,392 @@
+//! Files of the input JARs besides classes, embedded as a read-only filesystem.
+//!
+//! Every resource is written to the data segment and listed in an index: the
+//! number of resources followed by a record per resource, sorted by the
+//! UTF-16 units of the name like `String.compareTo` orders them. A
+//! record holds the name as a string, the address and length of the stored
+//! bytes, their size once inflated and how they are stored, [`STORED`] or
+//! [`DEFLATED`] like in ZIP files. Resources are only deflated when asked to
+//! and if that makes them smaller.
+//!
+//! `ClassLoader` looks resources up with the natives defined here, which
+//! binary search the index and copy a resource into a new byte array,
+//! leaving deflated ones to the host's [`INFLATE`] import.
+
+use std::{collections::BTreeMap, io::Write};
+
+use flate2::{write::DeflateEncoder, Compression};
+use noak::AccessFlags;
+use wasm_encoder::{BlockType, Function, Instruction, MemArg, ValType};
+
+use crate::{layout::{self, class_id, DataSegment}, module::{signature, ModuleBuilder}, program::{MethodKey, Program}, runtime::{Runtime, RUNTIME_MODULE}, strings::{StringPool, StringTable}};
+
+pub const RECORD_NAME_OFFSET: u32 = 0;
+pub const RECORD_DATA_OFFSET: u32 = 4;
+pub const RECORD_LENGTH_OFFSET: u32 = 8;
+pub const RECORD_SIZE_OFFSET: u32 = 12;
+pub const RECORD_METHOD_OFFSET: u32 = 16;
+pub const RECORD_SIZE: u32 = 20;
+
+/// Compression methods of a record.
+pub const STORED: u32 = 0;
+pub const DEFLATED: u32 = 8;
+
+/// Import from [`RUNTIME_MODULE`], `(destination, source, length, size) -> ()`
+/// inflates the raw deflate stream of `length` bytes at `source` into the
+/// `size` bytes at `destination`.
+pub const INFLATE: &str = "inflate";
+
+const CLASS_LOADER: &[u8] = b"java/lang/ClassLoader";
+
+/// `ClassLoader` natives defined here rather than imported, by name and descriptor.
+const NATIVES: &[(&str, &str)] = &[
+    ("findResource", "(Ljava/lang/String;)I"),
+    ("resourceData", "(I)[B"),
+];
+
+/// Whether the native is one of those this module implements.
+pub fn implements(key: &MethodKey) -> bool{
+    &*key.class == CLASS_LOADER && NATIVES.iter().any(|(name, descriptor)| &*key.name == name.as_bytes() && &*key.descriptor == descriptor.as_bytes())
+}
+
+fn mem(offset: u32, align: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align,
+        memory_index: 0
+    }
+}
+
+/// The files to embed by name, like `images/logo.png`.
+#[derive(Clone, Debug, Default)]
+pub struct Resources{
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Resources{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Adds the file unless there already is one with the name, so earlier
+    /// JARs on the classpath win.
+    pub fn add(&mut self, name: &str, bytes: Vec<u8>){
+        self.files.entry(name.into()).or_insert(bytes);
+    }
+
+    pub fn len(&self) -> usize{
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.files.is_empty()
+    }
+
+    /// Interns the names, before the strings are laid out.
+    pub fn intern_names(&self, strings: &StringPool){
+        for name in self.files.keys(){
+            strings.intern_units(&name.encode_utf16().collect::<Vec<_>>());
+        }
+    }
+}
+
+/// A resource as it's stored.
+struct Stored{
+    name: String,
+    bytes: Vec<u8>,
+    size: u32,
+    method: u32,
+}
+
+/// The resources of a translation and where their index is.
+pub struct ResourceIndex{
+    stored: Vec<Stored>,
+    index: u32,
+    inflate: Option<u32>,
+    /// `(a, b) -> order` of two strings, declared for `findResource`.
+    compare: Option<u32>,
+    natives: Vec<(&'static str, u32)>,
+}
+
+impl ResourceIndex{
+    /// Deflates the resources if `compress` is set and imports [`INFLATE`]
+    /// if any of them is, this has to happen with the other imports.
+    pub fn import(module: &mut ModuleBuilder, resources: &Resources, compress: bool) -> anyhow::Result<Self>{
+        let mut stored: Vec<Stored> = Vec::with_capacity(resources.len());
+        for (name, bytes) in resources.files.iter(){
+            let deflated = match compress {
+                true => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+                    encoder.write_all(bytes)?;
+                    Some(encoder.finish()?).filter(|d| d.len() < bytes.len())
+                }
+                false => None
+            };
+            stored.push(match deflated {
+                Some(deflated) => Stored{name: name.clone(), bytes: deflated, size: bytes.len() as u32, method: DEFLATED},
+                None => Stored{name: name.clone(), bytes: bytes.clone(), size: bytes.len() as u32, method: STORED}
+            });
+        }
+        stored.sort_by(|a, b| a.name.encode_utf16().cmp(b.name.encode_utf16()));
+        let i32 = ValType::I32;
+        let inflate = stored.iter().any(|s| s.method == DEFLATED).then(|| module.import(RUNTIME_MODULE, INFLATE, &[i32, i32, i32, i32], &[]));
+        Ok(Self{
+            stored,
+            index: layout::NULL,
+            inflate,
+            compare: None,
+            natives: Vec::new()
+        })
+    }
+
+    /// Declares the `ClassLoader` natives the program kept.
+    pub fn declare_natives(&mut self, module: &mut ModuleBuilder, program: &Program) -> anyhow::Result<()>{
+        let Some(loader) = program.class(CLASS_LOADER) else {
+            return Ok(());
+        };
+        for (name, descriptor) in NATIVES{
+            let Some(method) = loader.method(name.as_bytes(), descriptor.as_bytes()) else {
+                continue;
+            };
+            let (params, results) = signature(&method.descriptor, method.flags.contains(AccessFlags::STATIC))?;
+            let function = module.declare_function(MethodKey::new(CLASS_LOADER, &method.name, &method.descriptor), &params, &results);
+            self.natives.push((name, function));
+            if *name == "findResource"{
+                let compare = module.declare(&[ValType::I32; 2], &[ValType::I32]);
+                module.name(compare, "resources.compare");
+                self.compare = Some(compare);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the resources and their index to the data segment.
+    pub fn write(&mut self, strings: &StringTable, segment: &mut DataSegment) -> anyhow::Result<()>{
+        let mut records = Vec::with_capacity(self.stored.len());
+        for resource in self.stored.iter(){
+            let data = segment.alloc(resource.bytes.len() as u32, 1);
+            segment.write_bytes(data, &resource.bytes);
+            let Some(name) = strings.address(&resource.name.encode_utf16().collect::<Vec<_>>()) else {
+                anyhow::bail!("The name of resource {} wasn't interned", resource.name);
+            };
+            records.push((name, data));
+        }
+        self.index = segment.alloc(4 + RECORD_SIZE * records.len() as u32, 4);
+        segment.write_u32(self.index, records.len() as u32);
+        for (i, (resource, (name, data))) in self.stored.iter().zip(records).enumerate(){
+            let record = self.index + 4 + RECORD_SIZE * i as u32;
+            segment.write_u32(record + RECORD_NAME_OFFSET, name);
+            segment.write_u32(record + RECORD_DATA_OFFSET, data);
+            segment.write_u32(record + RECORD_LENGTH_OFFSET, resource.bytes.len() as u32);
+            segment.write_u32(record + RECORD_SIZE_OFFSET, resource.size);
+            segment.write_u32(record + RECORD_METHOD_OFFSET, resource.method);
+        }
+        Ok(())
+    }
+
+    /// Defines the natives, after [`Self::write`].
+    pub fn define_natives(&self, module: &mut ModuleBuilder, runtime: &Runtime){
+        // Pushes the address of the record with the index in local 0.
+        let record = [
+            Instruction::LocalGet(0),
+            Instruction::I32Const(RECORD_SIZE as i32),
+            Instruction::I32Mul,
+            Instruction::I32Const((self.index + 4) as i32),
+            Instruction::I32Add,
+        ];
+        for (name, function) in self.natives.iter(){
+            let (locals, instructions) = match *name {
+                "findResource" => (vec![ValType::I32; 4], self.find_resource()),
+                // Copies or inflates the bytes into a new array.
+                _ => {
+                    let (record_address, array) = (1, 2);
+                    let mut instructions = record.to_vec();
+                    instructions.extend([
+                        Instruction::LocalSet(record_address),
+                        Instruction::LocalGet(record_address),
+                        Instruction::I32Load(mem(RECORD_SIZE_OFFSET, 2)),
+                        Instruction::I32Const(layout::type_tag(class_id::BYTE_ARRAY, 0) as i32),
+                        Instruction::Call(runtime.new_array),
+                        Instruction::LocalSet(array),
+                    ]);
+                    let field = |offset| [Instruction::LocalGet(record_address), Instruction::I32Load(mem(offset, 2))];
+                    let operands = |instructions: &mut Vec<Instruction<'static>>|{
+                        instructions.extend([
+                            Instruction::LocalGet(array),
+                            Instruction::I32Const(layout::ARRAY_DATA_OFFSET as i32),
+                            Instruction::I32Add,
+                        ]);
+                        instructions.extend(field(RECORD_DATA_OFFSET));
+                    };
+                    if let Some(inflate) = self.inflate{
+                        instructions.extend(field(RECORD_METHOD_OFFSET));
+                        instructions.extend([
+                            Instruction::I32Const(DEFLATED as i32),
+                            Instruction::I32Eq,
+                            Instruction::If(BlockType::Empty),
+                        ]);
+                        operands(&mut instructions);
+                        instructions.extend(field(RECORD_LENGTH_OFFSET));
+                        instructions.extend(field(RECORD_SIZE_OFFSET));
+                        instructions.extend([
+                            Instruction::Call(inflate),
+                            Instruction::LocalGet(array),
+                            Instruction::Return,
+                            Instruction::End,
+                        ]);
+                    }
+                    operands(&mut instructions);
+                    instructions.extend(field(RECORD_SIZE_OFFSET));
+                    instructions.push(Instruction::MemoryCopy{src_mem: 0, dst_mem: 0});
+                    instructions.extend([Instruction::LocalGet(array), Instruction::End]);
+                    (vec![ValType::I32; 2], instructions)
+                }
+            };
+            let mut body = Function::new_with_locals_types(locals);
+            for instruction in instructions{
+                body.instruction(&instruction);
+            }
+            module.define(*function, body);
+        }
+        if let Some(compare) = self.compare{
+            let mut body = Function::new_with_locals_types(vec![ValType::I32; 8]);
+            for instruction in compare_strings(){
+                body.instruction(&instruction);
+            }
+            module.define(compare, body);
+        }
+    }
+
+    /// Binary searches the index for the name in local 0, with the bounds in
+    /// locals 1 and 2, the middle in 3 and the order there in 4.
+    fn find_resource(&self) -> Vec<Instruction<'static>>{
+        let (name, low, high, middle, order) = (0, 1, 2, 3, 4);
+        vec![
+            Instruction::I32Const(self.index as i32),
+            Instruction::I32Load(mem(0, 2)),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalSet(high),
+            Instruction::Block(BlockType::Empty),
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(low),
+            Instruction::LocalGet(high),
+            Instruction::I32GtS,
+            Instruction::BrIf(1),
+            Instruction::LocalGet(low),
+            Instruction::LocalGet(high),
+            Instruction::I32Add,
+            Instruction::I32Const(1),
+            Instruction::I32ShrU,
+            Instruction::LocalSet(middle),
+            Instruction::LocalGet(name),
+            Instruction::LocalGet(middle),
+            Instruction::I32Const(RECORD_SIZE as i32),
+            Instruction::I32Mul,
+            Instruction::I32Const((self.index + 4) as i32),
+            Instruction::I32Add,
+            Instruction::I32Load(mem(RECORD_NAME_OFFSET, 2)),
+            Instruction::Call(self.compare.expect("findResource declares compare")),
+            Instruction::LocalTee(order),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(middle),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(order),
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(middle),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalSet(high),
+            Instruction::Else,
+            Instruction::LocalGet(middle),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalSet(low),
+            Instruction::End,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::I32Const(-1),
+            Instruction::End,
+        ]
+    }
+}
+
+/// Compares the strings in locals 0 and 1 like `String.compareTo`, by their
+/// first differing char or else their lengths.
+fn compare_strings() -> Vec<Instruction<'static>>{
+    let index = 2;
+    // Value array, coder and length of each string.
+    let strings = [(0, 3, 4, 5), (1, 6, 7, 8)];
+    let mut instructions = Vec::new();
+    for (string, value, coder, length) in strings{
+        instructions.extend([
+            Instruction::LocalGet(string),
+            Instruction::I32Load(mem(layout::STRING_VALUE_OFFSET, 2)),
+            Instruction::LocalSet(value),
+            Instruction::LocalGet(string),
+            Instruction::I32Load8U(mem(layout::STRING_CODER_OFFSET, 0)),
+            Instruction::LocalSet(coder),
+            Instruction::LocalGet(value),
+            Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET, 2)),
+            Instruction::LocalGet(coder),
+            Instruction::I32ShrU,
+            Instruction::LocalSet(length),
+        ]);
+    }
+    // Strings are stored little-endian, like `String.charAt` reads them.
+    let char_at = |(_, value, coder, _): (u32, u32, u32, u32)| [
+        Instruction::LocalGet(coder),
+        Instruction::If(BlockType::Result(ValType::I32)),
+        Instruction::LocalGet(value),
+        Instruction::LocalGet(index),
+        Instruction::I32Const(1),
+        Instruction::I32Shl,
+        Instruction::I32Add,
+        Instruction::I32Load16U(mem(layout::ARRAY_DATA_OFFSET, 1)),
+        Instruction::Else,
+        Instruction::LocalGet(value),
+        Instruction::LocalGet(index),
+        Instruction::I32Add,
+        Instruction::I32Load8U(mem(layout::ARRAY_DATA_OFFSET, 0)),
+        Instruction::End,
+    ];
+    instructions.extend([
+        Instruction::Block(BlockType::Empty),
+        Instruction::Loop(BlockType::Empty),
+        Instruction::LocalGet(index),
+        Instruction::LocalGet(strings[0].3),
+        Instruction::I32GeS,
+        Instruction::LocalGet(index),
+        Instruction::LocalGet(strings[1].3),
+        Instruction::I32GeS,
+        Instruction::I32Or,
+        Instruction::BrIf(1),
+    ]);
+    instructions.extend(char_at(strings[0]));
+    instructions.extend(char_at(strings[1]));
+    instructions.extend([
+        Instruction::I32Sub,
+        Instruction::LocalTee(index + 7),
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(index + 7),
+        Instruction::Return,
+        Instruction::End,
+        Instruction::LocalGet(index),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalSet(index),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(strings[0].3),
+        Instruction::LocalGet(strings[1].3),
+        Instruction::I32Sub,
+        Instruction::End,
+    ]);
+    instructions
+}
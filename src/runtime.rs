@@ -0,0 +1,1525 @@
+//! Helper functions every translated module contains.
+//!
+//! Exceptions are passed around in the [`Runtime::exception`] global: a
+//! throwing function stores the exception there and returns, and callers
+//! check it after every call. The host finds uncaught exceptions there once
+//! an exported method returns.
+//!
+//! The monitor word in the object header counts how often the monitor has
+//! been entered. With [`Monitors::Atomic`] it also holds the id of the owning
+//! thread in the upper half, and contended monitors are waited on with
+//! `memory.atomic.wait32`.
+//!
+//! Lowered methods push a frame word on a shadow stack placed between the
+//! static data and the heap, and keep it updated with the offset of the call
+//! they are making, see [`crate::trace`].
+//!
+//! With [`Monitors::Atomic`] every instance of the module is a thread sharing
+//! one imported memory. The data segment is copied in by the first instance
+//! to start, the heap pointer is a word of the memory bumped atomically, and
+//! every instance allocates its own shadow stack from the heap.
+
+use std::{collections::BTreeMap, hash::Hash};
+
+use wasm_encoder::{BlockType, ConstExpr, Function, Instruction, MemArg, ValType};
+
+use crate::{cache::Fingerprint, classes::Layouts, init::{ERRONEOUS, INITIALIZED, INITIALIZING, UNINITIALIZED}, layout::{self, class_id, DataSegment}, module::ModuleBuilder, program::{MethodKey, Program}, strings::StringTable};
+
+/// Module of the imports that aren't Java methods.
+pub const RUNTIME_MODULE: &str = "runtime";
+
+/// Import from [`RUNTIME_MODULE`] of the shared memory with [`Monitors::Atomic`].
+pub const MEMORY_IMPORT: &str = "memory";
+
+/// Words below [`layout::DATA_BASE`] that the instances sharing a memory use
+/// with [`Monitors::Atomic`]: whether the data segment has been copied in, the
+/// number of threads started and the first free heap address.
+pub const MEMORY_STATE_ADDRESS: u32 = 4;
+pub const THREAD_COUNT_ADDRESS: u32 = 8;
+pub const HEAP_ADDRESS: u32 = 12;
+
+/// Values of the word at [`MEMORY_STATE_ADDRESS`].
+const MEMORY_EMPTY: i32 = 0;
+const MEMORY_COPYING: i32 = 1;
+const MEMORY_READY: i32 = 2;
+
+/// Exceptions thrown by the translated code itself rather than by `athrow`.
+pub const NULL_POINTER_EXCEPTION: &str = "java/lang/NullPointerException";
+pub const ARITHMETIC_EXCEPTION: &str = "java/lang/ArithmeticException";
+pub const ILLEGAL_MONITOR_STATE_EXCEPTION: &str = "java/lang/IllegalMonitorStateException";
+pub const NEGATIVE_ARRAY_SIZE_EXCEPTION: &str = "java/lang/NegativeArraySizeException";
+pub const ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/ArrayIndexOutOfBoundsException";
+pub const CLASS_CAST_EXCEPTION: &str = "java/lang/ClassCastException";
+pub const ARRAY_STORE_EXCEPTION: &str = "java/lang/ArrayStoreException";
+pub const NO_CLASS_DEF_FOUND_ERROR: &str = "java/lang/NoClassDefFoundError";
+pub const THROWN: &[&str] = &[
+    NULL_POINTER_EXCEPTION,
+    ARITHMETIC_EXCEPTION,
+    ILLEGAL_MONITOR_STATE_EXCEPTION,
+    NEGATIVE_ARRAY_SIZE_EXCEPTION,
+    ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION,
+    CLASS_CAST_EXCEPTION,
+    ARRAY_STORE_EXCEPTION,
+    NO_CLASS_DEF_FOUND_ERROR,
+];
+
+/// Strings the runtime and inline string concatenation refer to, which have
+/// to be interned before the strings are laid out.
+pub const STRINGS: &[&str] = &["null", "true", "false", ""];
+
+/// Bytes `format_double` may write.
+const FORMATTED_DOUBLE_SIZE: i32 = 32;
+
+/// Bytes reserved for the shadow stack, running out of it traps.
+pub const FRAME_STACK_SIZE: u32 = 64 * 1024;
+
+/// Export of the global holding the id of the current thread with
+/// [`Monitors::Atomic`], which each instance takes when it starts.
+pub const THREAD_EXPORT: &str = "thread";
+
+/// How `monitorenter`, `monitorexit` and synchronized methods are lowered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Monitors{
+    /// Only counts recursion, for modules that never run on more than one thread.
+    #[default]
+    SingleThreaded,
+    /// Uses atomic instructions on a shared memory the host provides as
+    /// [`MEMORY_IMPORT`], with an instance of the module per thread. Static
+    /// fields and class init states are kept in the memory, at most 2^16 - 1
+    /// threads can be started.
+    Atomic,
+}
+
+#[derive(Clone, Debug)]
+pub struct Runtime{
+    /// Global holding the pending exception, `null` if there is none.
+    pub exception: u32,
+    /// Global holding the first free heap address, which is the word at
+    /// [`HEAP_ADDRESS`] instead with [`Monitors::Atomic`].
+    heap: Option<u32>,
+    /// Where the heap starts.
+    heap_start: u32,
+    /// Address of the shadow stack, [`FRAME_STACK_SIZE`] bytes that the heap
+    /// follows. With [`Monitors::Atomic`] it's a global holding the address
+    /// of the stack the instance allocated instead, see [`Self::frames`].
+    frames: Frames,
+    /// Global holding the address of the innermost frame word, 4 below
+    /// [`Self::frames`] while no method runs.
+    pub frame: u32,
+    /// `(frame word) -> ()` pushes the frame of a method that is entered.
+    pub enter_frame: u32,
+    /// `(size, class id) -> address` allocates a zeroed object.
+    pub alloc: u32,
+    /// `(length, type tag) -> array` allocates a zeroed array of the type,
+    /// throwing `NegativeArraySizeException` for a negative length.
+    pub new_array: u32,
+    /// `(counts, dimension, type tag) -> array` allocates the nested arrays of
+    /// `multianewarray` from `dimension` on, with the lengths in the int array `counts`.
+    pub multi_new_array: u32,
+    /// `(object, type tag) -> i32` whether the object is an instance of the
+    /// type, `false` for `null`.
+    pub instance_of: u32,
+    /// `(type tag, type tag) -> i32` whether a value of the first type can be
+    /// assigned to the second, with arrays being covariant.
+    pub assignable: u32,
+    /// `(object, selector) -> table index` looks up an interface method.
+    pub itable_lookup: u32,
+    /// `(a, b) -> a / b` and `a % b` with JVM semantics, for ints and longs.
+    pub idiv: u32,
+    pub irem: u32,
+    pub ldiv: u32,
+    pub lrem: u32,
+    /// `(a, b) -> a % b` for doubles, provided by the host like C's `fmod`.
+    pub drem: u32,
+    /// `(value, is float, buffer) -> length` writes `Double.toString` or
+    /// `Float.toString` of the value as Latin-1, provided by the host.
+    pub format_double: u32,
+    /// `(a, b) -> a + b` concatenates two non-null strings.
+    pub concat: u32,
+    /// `String.valueOf` of a long, a char, a double or float and an object,
+    /// which is `"null"` for `null`. Ints are passed as longs and floats as
+    /// doubles with a flag set.
+    pub string_of_long: u32,
+    pub string_of_char: u32,
+    pub string_of_double: u32,
+    pub string_of_object: u32,
+    /// `(monitor word address) -> ()` enter and exit a monitor, exiting one
+    /// the thread doesn't own throws `IllegalMonitorStateException`.
+    pub monitor_enter: u32,
+    pub monitor_exit: u32,
+    /// Global holding the id of the current thread, only used with [`Monitors::Atomic`].
+    pub thread: u32,
+    /// `(init state address) -> i32` claims the initialization of a class
+    /// whose init state is in memory, returning whether the caller has to run
+    /// it. It waits while another thread runs it, and throws
+    /// `NoClassDefFoundError` if it failed. Only with [`Monitors::Atomic`].
+    pub init_enter: Option<u32>,
+    /// `(init state address) -> ()` marks the class initialized, or erroneous
+    /// if an exception is pending, and wakes the threads waiting for it.
+    pub init_exit: Option<u32>,
+    pub monitors: Monitors,
+    /// `() -> ()` allocates and stores an exception of the class.
+    throws: BTreeMap<&'static str, u32>,
+}
+
+/// Functions the host provides to every module.
+pub struct Imports{
+    drem: u32,
+    format_double: u32,
+}
+
+#[derive(Clone, Debug)]
+enum Frames{
+    Fixed(u32),
+    PerInstance(u32),
+}
+
+/// Recursion count and owner of a monitor word with [`Monitors::Atomic`].
+const COUNT_MASK: i32 = 0xFFFF;
+const OWNER_SHIFT: i32 = 16;
+
+fn mem(offset: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align: 2,
+        memory_index: 0
+    }
+}
+
+fn byte(offset: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align: 0,
+        memory_index: 0
+    }
+}
+
+fn half(offset: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align: 1,
+        memory_index: 0
+    }
+}
+
+/// Loads the address of the class info record for the class id on the stack.
+pub fn load_class_info(class_table: u32) -> [Instruction<'static>; 3]{
+    [Instruction::I32Const(2), Instruction::I32Shl, Instruction::I32Load(mem(class_table))]
+}
+
+impl Runtime{
+    /// Imports the helpers provided by the host.
+    pub fn import(module: &mut ModuleBuilder) -> Imports{
+        Imports{
+            drem: module.import(RUNTIME_MODULE, "drem", &[ValType::F64, ValType::F64], &[ValType::F64]),
+            format_double: module.import(RUNTIME_MODULE, "format_double", &[ValType::F64, ValType::I32, ValType::I32], &[ValType::I32])
+        }
+    }
+
+    /// Declares the helpers, the shadow stack and then the heap start at
+    /// `data_end`. With [`Monitors::Atomic`] the heap starts right there.
+    pub fn declare(module: &mut ModuleBuilder, imports: Imports, data_end: u32, monitors: Monitors) -> Self{
+        let i32 = ValType::I32;
+        let atomic = monitors == Monitors::Atomic;
+        if atomic{
+            module.import_shared_memory(RUNTIME_MODULE, MEMORY_IMPORT);
+        }
+        let (frames, heap_start) = match atomic {
+            true => (Frames::PerInstance(module.add_global(i32, true, ConstExpr::i32_const(layout::NULL as i32))), (data_end + 7) & !7),
+            false => {
+                let frames = (data_end + 3) & !3;
+                (Frames::Fixed(frames), (frames + FRAME_STACK_SIZE + 7) & !7)
+            }
+        };
+        let first_frame = match frames {
+            Frames::Fixed(frames) => frames as i32 - 4,
+            Frames::PerInstance(_) => layout::NULL as i32
+        };
+        let runtime = Self{
+            exception: module.add_global(i32, true, ConstExpr::i32_const(layout::NULL as i32)),
+            heap: (!atomic).then(|| module.add_global(i32, true, ConstExpr::i32_const(heap_start as i32))),
+            heap_start,
+            frames,
+            frame: module.add_global(i32, true, ConstExpr::i32_const(first_frame)),
+            enter_frame: module.declare(&[i32], &[]),
+            alloc: module.declare(&[i32, i32], &[i32]),
+            new_array: module.declare(&[i32, i32], &[i32]),
+            multi_new_array: module.declare(&[i32, i32, i32], &[i32]),
+            instance_of: module.declare(&[i32, i32], &[i32]),
+            assignable: module.declare(&[i32, i32], &[i32]),
+            itable_lookup: module.declare(&[i32, i32], &[i32]),
+            idiv: module.declare(&[i32, i32], &[i32]),
+            irem: module.declare(&[i32, i32], &[i32]),
+            ldiv: module.declare(&[ValType::I64, ValType::I64], &[ValType::I64]),
+            lrem: module.declare(&[ValType::I64, ValType::I64], &[ValType::I64]),
+            drem: imports.drem,
+            format_double: imports.format_double,
+            concat: module.declare(&[i32, i32], &[i32]),
+            string_of_long: module.declare(&[ValType::I64], &[i32]),
+            string_of_char: module.declare(&[i32], &[i32]),
+            string_of_double: module.declare(&[ValType::F64, i32], &[i32]),
+            string_of_object: module.declare(&[i32], &[i32]),
+            monitor_enter: module.declare(&[i32], &[]),
+            monitor_exit: module.declare(&[i32], &[]),
+            thread: module.add_global(i32, true, ConstExpr::i32_const(1)),
+            init_enter: atomic.then(|| module.declare(&[i32], &[i32])),
+            init_exit: atomic.then(|| module.declare(&[i32], &[])),
+            monitors,
+            throws: THROWN.iter().map(|c| (*c, module.declare(&[], &[]))).collect()
+        };
+        for (name, function) in [
+            ("alloc", runtime.alloc),
+            ("enter_frame", runtime.enter_frame),
+            ("new_array", runtime.new_array),
+            ("multi_new_array", runtime.multi_new_array),
+            ("instance_of", runtime.instance_of),
+            ("assignable", runtime.assignable),
+            ("itable_lookup", runtime.itable_lookup),
+            ("idiv", runtime.idiv),
+            ("irem", runtime.irem),
+            ("ldiv", runtime.ldiv),
+            ("lrem", runtime.lrem),
+            ("concat", runtime.concat),
+            ("string_of_long", runtime.string_of_long),
+            ("string_of_char", runtime.string_of_char),
+            ("string_of_double", runtime.string_of_double),
+            ("string_of_object", runtime.string_of_object),
+            ("monitor_enter", runtime.monitor_enter),
+            ("monitor_exit", runtime.monitor_exit),
+        ]{
+            module.name(function, &format!("runtime.{}", name));
+        }
+        for (name, function) in [("init_enter", runtime.init_enter), ("init_exit", runtime.init_exit)]{
+            if let Some(function) = function{
+                module.name(function, &format!("runtime.{}", name));
+            }
+        }
+        for (class, function) in runtime.throws.iter(){
+            module.name(*function, &format!("runtime.throw {}", class));
+        }
+        runtime
+    }
+
+    /// Helper throwing an exception of the class, which has to be one of the known ones.
+    pub fn throw(&self, class: &str) -> u32{
+        self.throws[class]
+    }
+
+    /// Hashes the functions and globals lowered code uses, but not where the
+    /// heap and the shadow stack start, which move with the data segment.
+    pub fn fingerprint(&self, fp: &mut Fingerprint){
+        let frames = match self.frames {
+            Frames::Fixed(_) => Frames::Fixed(0),
+            Frames::PerInstance(global) => Frames::PerInstance(global)
+        };
+        format!("{:?}", Self{heap_start: 0, frames, ..self.clone()}).hash(fp);
+    }
+
+    /// Pushes the address of the shadow stack.
+    pub fn frames(&self) -> Instruction<'static>{
+        match self.frames {
+            Frames::Fixed(address) => Instruction::I32Const(address as i32),
+            Frames::PerInstance(global) => Instruction::GlobalGet(global)
+        }
+    }
+
+    /// Where the heap ends before anything is allocated.
+    pub fn initial_end(&self) -> u32{
+        match self.frames {
+            Frames::Fixed(_) => self.heap_start,
+            // The first instance allocates its shadow stack.
+            Frames::PerInstance(_) => self.heap_start + FRAME_STACK_SIZE
+        }
+    }
+
+    /// Instructions starting an instance with [`Monitors::Atomic`], before
+    /// any class is initialized: the first instance copies the data segment
+    /// into the memory while the others wait for it, then each takes a
+    /// thread id and allocates its shadow stack.
+    pub fn start_thread(&self, data: &DataSegment) -> Vec<Instruction<'static>>{
+        let Frames::PerInstance(frames) = self.frames else {
+            return Vec::new();
+        };
+        vec![
+            Instruction::I32Const(MEMORY_STATE_ADDRESS as i32),
+            Instruction::I32Const(MEMORY_EMPTY),
+            Instruction::I32Const(MEMORY_COPYING),
+            Instruction::I32AtomicRmwCmpxchg(mem(0)),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(data.base() as i32),
+            Instruction::I32Const(0),
+            Instruction::I32Const(data.bytes().len() as i32),
+            Instruction::MemoryInit{mem: 0, data_index: 0},
+            Instruction::I32Const(HEAP_ADDRESS as i32),
+            Instruction::I32Const(self.heap_start as i32),
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::I32Const(MEMORY_STATE_ADDRESS as i32),
+            Instruction::I32Const(MEMORY_READY),
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::I32Const(MEMORY_STATE_ADDRESS as i32),
+            Instruction::I32Const(-1),
+            Instruction::MemoryAtomicNotify(mem(0)),
+            Instruction::Drop,
+            Instruction::Else,
+            Instruction::Loop(BlockType::Empty),
+            Instruction::I32Const(MEMORY_STATE_ADDRESS as i32),
+            Instruction::I32AtomicLoad(mem(0)),
+            Instruction::I32Const(MEMORY_READY),
+            Instruction::I32Ne,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(MEMORY_STATE_ADDRESS as i32),
+            Instruction::I32Const(MEMORY_COPYING),
+            Instruction::I64Const(-1),
+            Instruction::MemoryAtomicWait32(mem(0)),
+            Instruction::Drop,
+            Instruction::Br(1),
+            Instruction::End,
+            Instruction::End,
+            Instruction::End,
+            Instruction::DataDrop(0),
+            // Thread ids start at 1, as a monitor word owned by no one is 0.
+            Instruction::I32Const(THREAD_COUNT_ADDRESS as i32),
+            Instruction::I32Const(1),
+            Instruction::I32AtomicRmwAdd(mem(0)),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::GlobalSet(self.thread),
+            Instruction::GlobalGet(self.thread),
+            Instruction::I32Const(COUNT_MASK),
+            Instruction::I32GtU,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::I32Const(FRAME_STACK_SIZE as i32),
+            Instruction::I32Const(0),
+            Instruction::Call(self.alloc),
+            Instruction::GlobalSet(frames),
+            Instruction::GlobalGet(frames),
+            Instruction::I32Const(4),
+            Instruction::I32Sub,
+            Instruction::GlobalSet(self.frame),
+        ]
+    }
+
+    pub fn define(&self, module: &mut ModuleBuilder, program: &Program, layouts: &Layouts, strings: &StringTable){
+        let class_table = layouts.class_table();
+
+        let mut alloc = Function::new_with_locals_types([ValType::I32, ValType::I32]);
+        let (size, class, address, end) = (0, 1, 2, 3);
+        let bump = match self.heap {
+            Some(heap) => vec![
+                Instruction::GlobalGet(heap),
+                Instruction::LocalTee(address),
+                Instruction::LocalGet(size),
+                Instruction::I32Add,
+                Instruction::I32Const(7),
+                Instruction::I32Add,
+                Instruction::I32Const(!7),
+                Instruction::I32And,
+                Instruction::LocalTee(end),
+                Instruction::GlobalSet(heap),
+            ],
+            None => vec![
+                Instruction::I32Const(HEAP_ADDRESS as i32),
+                Instruction::LocalGet(size),
+                Instruction::I32Const(7),
+                Instruction::I32Add,
+                Instruction::I32Const(!7),
+                Instruction::I32And,
+                Instruction::LocalTee(end),
+                Instruction::I32AtomicRmwAdd(mem(0)),
+                Instruction::LocalTee(address),
+                Instruction::LocalGet(end),
+                Instruction::I32Add,
+                Instruction::LocalSet(end),
+            ]
+        };
+        for instruction in bump.into_iter().chain([
+            Instruction::LocalGet(end),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::I32GtU,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(end),
+            Instruction::I32Const(0xFFFF),
+            Instruction::I32Add,
+            Instruction::I32Const(16),
+            Instruction::I32ShrU,
+            Instruction::MemorySize(0),
+            Instruction::I32Sub,
+            Instruction::MemoryGrow(0),
+            Instruction::I32Const(-1),
+            Instruction::I32Eq,
+            // Another thread may have grown the memory in the meantime.
+            Instruction::LocalGet(end),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::I32GtU,
+            Instruction::I32And,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::End,
+            // Memory past the heap pointer is never written, so the object is already zeroed.
+            Instruction::LocalGet(address),
+            Instruction::LocalGet(class),
+            Instruction::I32Store(mem(layout::HEADER_CLASS_OFFSET)),
+            Instruction::LocalGet(address),
+            Instruction::End,
+        ]){
+            alloc.instruction(&instruction);
+        }
+        module.define(self.alloc, alloc);
+
+        let mut enter_frame = Function::new_with_locals_types([ValType::I32]);
+        let (word, address) = (0, 1);
+        for instruction in [
+            Instruction::GlobalGet(self.frame),
+            Instruction::I32Const(4),
+            Instruction::I32Add,
+            Instruction::LocalTee(address),
+            self.frames(),
+            Instruction::I32Const(FRAME_STACK_SIZE as i32),
+            Instruction::I32Add,
+            Instruction::I32GeU,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::LocalGet(address),
+            Instruction::LocalGet(word),
+            Instruction::I32Store(mem(0)),
+            Instruction::LocalGet(address),
+            Instruction::GlobalSet(self.frame),
+            Instruction::End,
+        ]{
+            enter_frame.instruction(&instruction);
+        }
+        module.define(self.enter_frame, enter_frame);
+
+        let mut new_array = Function::new_with_locals_types([ValType::I32, ValType::I32]);
+        let (length, tag, shift, array) = (0, 1, 2, 3);
+        let reference = [Instruction::LocalGet(tag), Instruction::I32Const(layout::TAG_DIMENSIONS_SHIFT as i32), Instruction::I32ShrU];
+        let mut instructions = vec![
+            Instruction::LocalGet(length),
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(NEGATIVE_ARRAY_SIZE_EXCEPTION)),
+            Instruction::I32Const(layout::NULL as i32),
+            Instruction::Return,
+            Instruction::End,
+        ];
+        instructions.extend(reference.clone());
+        instructions.extend([
+            Instruction::If(BlockType::Result(ValType::I32)),
+            Instruction::I32Const(layout::element_shift(class_id::OBJECT_ARRAY) as i32),
+            Instruction::Else,
+            Instruction::I32Const(layout::ELEMENT_SHIFTS as i32),
+            Instruction::LocalGet(tag),
+            Instruction::I32Const(1),
+            Instruction::I32Shl,
+            Instruction::I32ShrU,
+            Instruction::I32Const(3),
+            Instruction::I32And,
+            Instruction::End,
+            Instruction::LocalSet(shift),
+            // Sizes that don't fit in the address space can't be allocated.
+            Instruction::LocalGet(length),
+            Instruction::I32Const(i32::MAX - layout::ARRAY_DATA_OFFSET as i32),
+            Instruction::LocalGet(shift),
+            Instruction::I32ShrU,
+            Instruction::I32GtU,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::I32Const(layout::ARRAY_DATA_OFFSET as i32),
+            Instruction::LocalGet(length),
+            Instruction::LocalGet(shift),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Const(class_id::OBJECT_ARRAY as i32),
+            Instruction::LocalGet(tag),
+        ]);
+        instructions.extend(reference.clone());
+        instructions.extend([
+            Instruction::Select,
+            Instruction::Call(self.alloc),
+            Instruction::LocalTee(array),
+            Instruction::LocalGet(length),
+            Instruction::I32Store(mem(layout::ARRAY_LENGTH_OFFSET)),
+        ]);
+        instructions.extend(reference);
+        instructions.extend([
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(tag),
+            Instruction::I32Const(layout::type_tag(0, 1) as i32),
+            Instruction::I32Sub,
+            Instruction::I32Store(mem(layout::ARRAY_COMPONENT_OFFSET)),
+            Instruction::End,
+            Instruction::LocalGet(array),
+            Instruction::End,
+        ]);
+        for instruction in instructions{
+            new_array.instruction(&instruction);
+        }
+        module.define(self.new_array, new_array);
+
+        let mut multi_new_array = Function::new_with_locals_types([ValType::I32, ValType::I32, ValType::I32]);
+        let (counts, dimension, tag, array, length, i) = (0, 1, 2, 3, 4, 5);
+        let count = |index: u32| [
+            Instruction::LocalGet(counts),
+            Instruction::LocalGet(index),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Load(mem(layout::ARRAY_DATA_OFFSET)),
+        ];
+        // Every count is checked before anything is allocated.
+        let mut instructions = vec![
+            Instruction::LocalGet(dimension),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(i),
+            Instruction::LocalGet(counts),
+            Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET)),
+            Instruction::I32LtU,
+            Instruction::If(BlockType::Empty),
+        ];
+        instructions.extend(count(i));
+        instructions.extend([
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(NEGATIVE_ARRAY_SIZE_EXCEPTION)),
+            Instruction::I32Const(layout::NULL as i32),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(i),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalSet(i),
+            Instruction::Br(1),
+            Instruction::End,
+            Instruction::End,
+            Instruction::I32Const(0),
+            Instruction::LocalSet(i),
+            Instruction::End,
+        ]);
+        instructions.extend(count(dimension));
+        instructions.extend([
+            Instruction::LocalTee(length),
+            Instruction::LocalGet(tag),
+            Instruction::Call(self.new_array),
+            Instruction::LocalSet(array),
+            // The last counted dimension keeps its zeroed elements.
+            Instruction::LocalGet(dimension),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalGet(counts),
+            Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET)),
+            Instruction::I32LtU,
+            Instruction::If(BlockType::Empty),
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(i),
+            Instruction::LocalGet(length),
+            Instruction::I32LtU,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(i),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::LocalGet(counts),
+            Instruction::LocalGet(dimension),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalGet(tag),
+            Instruction::I32Const(layout::type_tag(0, 1) as i32),
+            Instruction::I32Sub,
+            Instruction::Call(self.multi_new_array),
+            Instruction::I32Store(mem(layout::ARRAY_DATA_OFFSET)),
+            Instruction::LocalGet(i),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalSet(i),
+            Instruction::Br(1),
+            Instruction::End,
+            Instruction::End,
+            Instruction::End,
+            Instruction::LocalGet(array),
+            Instruction::End,
+        ]);
+        for instruction in instructions{
+            multi_new_array.instruction(&instruction);
+        }
+        module.define(self.multi_new_array, multi_new_array);
+
+        let mut instance_of = Function::new_with_locals_types([]);
+        let (object, tag) = (0, 1);
+        let instructions = [
+            Instruction::LocalGet(object),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            // Arrays of references are typed by their component.
+            Instruction::LocalGet(object),
+            Instruction::I32Load(mem(layout::ARRAY_COMPONENT_OFFSET)),
+            Instruction::I32Const(layout::type_tag(0, 1) as i32),
+            Instruction::I32Add,
+            Instruction::LocalGet(object),
+            Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET)),
+            Instruction::LocalTee(object),
+            Instruction::LocalGet(object),
+            Instruction::I32Const(class_id::OBJECT_ARRAY as i32),
+            Instruction::I32Eq,
+            Instruction::Select,
+            Instruction::LocalGet(tag),
+            Instruction::Call(self.assignable),
+            Instruction::End,
+        ];
+        for instruction in instructions{
+            instance_of.instruction(&instruction);
+        }
+        module.define(self.instance_of, instance_of);
+
+        let mut assignable = Function::new_with_locals_types([ValType::I32, ValType::I32]);
+        let (from, to, info, depth) = (0, 1, 2, 3);
+        let dimensions = |tag: u32| [Instruction::LocalGet(tag), Instruction::I32Const(layout::TAG_DIMENSIONS_SHIFT as i32), Instruction::I32ShrU];
+        let mut instructions = vec![
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(from),
+            Instruction::LocalGet(to),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(1),
+            Instruction::Return,
+            Instruction::End,
+        ];
+        instructions.extend(dimensions(to));
+        instructions.push(Instruction::If(BlockType::Empty));
+        // Both are arrays of references, their components have to be assignable.
+        instructions.extend(dimensions(from));
+        instructions.extend([
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(from),
+            Instruction::I32Const(layout::type_tag(0, 1) as i32),
+            Instruction::I32Sub,
+            Instruction::LocalSet(from),
+            Instruction::LocalGet(to),
+            Instruction::I32Const(layout::type_tag(0, 1) as i32),
+            Instruction::I32Sub,
+            Instruction::LocalSet(to),
+            Instruction::Br(1),
+            Instruction::End,
+            Instruction::End,
+            // Assigning to a class or a primitive array, arrays of references
+            // have the record of `Object[]`.
+            Instruction::I32Const(class_id::OBJECT_ARRAY as i32),
+            Instruction::LocalGet(from),
+        ]);
+        instructions.extend(dimensions(from));
+        instructions.extend([
+            Instruction::Select,
+            Instruction::LocalSet(from),
+            // Missing classes have no record, they are only known to extend `Object`.
+            Instruction::LocalGet(from),
+            Instruction::I32Eqz,
+            Instruction::LocalGet(to),
+            Instruction::I32Eqz,
+            Instruction::I32Or,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(to),
+            Instruction::I32Const(class_id::OBJECT as i32),
+            Instruction::I32Eq,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(to),
+        ]);
+        instructions.extend(load_class_info(class_table));
+        instructions.extend([
+            Instruction::LocalTee(info),
+            Instruction::I32Load(mem(layout::INFO_INTERFACE_BIT_OFFSET)),
+            Instruction::I32Const(0),
+            Instruction::I32GeS,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(from),
+        ]);
+        instructions.extend(load_class_info(class_table));
+        instructions.extend([
+            Instruction::I32Load(mem(layout::INFO_INTERFACES_OFFSET)),
+            Instruction::LocalGet(info),
+            Instruction::I32Load(mem(layout::INFO_INTERFACE_BIT_OFFSET)),
+            Instruction::I32Const(5),
+            Instruction::I32ShrU,
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Load(mem(0)),
+            Instruction::LocalGet(info),
+            Instruction::I32Load(mem(layout::INFO_INTERFACE_BIT_OFFSET)),
+            Instruction::I32ShrU,
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::Return,
+            Instruction::End,
+            // The depth of the target class is its index in its own display.
+            Instruction::LocalGet(info),
+            Instruction::I32Load(mem(layout::INFO_DISPLAY_OFFSET)),
+            Instruction::I32Load(mem(0)),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::LocalSet(depth),
+            Instruction::LocalGet(from),
+        ]);
+        instructions.extend(load_class_info(class_table));
+        instructions.extend([
+            Instruction::I32Load(mem(layout::INFO_DISPLAY_OFFSET)),
+            Instruction::LocalTee(info),
+            Instruction::I32Load(mem(0)),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::LocalGet(depth),
+            Instruction::I32LeU,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(info),
+            Instruction::LocalGet(depth),
+            Instruction::I32Add,
+            Instruction::I32Load(mem(4)),
+            Instruction::LocalGet(to),
+            Instruction::I32Eq,
+            Instruction::End,
+        ]);
+        for instruction in instructions{
+            assignable.instruction(&instruction);
+        }
+        module.define(self.assignable, assignable);
+
+        let mut itable_lookup = Function::new_with_locals_types([ValType::I32, ValType::I32]);
+        let (object, selector, entry, count) = (0, 1, 2, 3);
+        let mut instructions = vec![
+            Instruction::LocalGet(object),
+            Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET)),
+        ];
+        instructions.extend(load_class_info(class_table));
+        instructions.extend([
+            Instruction::I32Load(mem(layout::INFO_ITABLE_OFFSET)),
+            Instruction::LocalTee(entry),
+            Instruction::I32Load(mem(0)),
+            Instruction::LocalSet(count),
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(count),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(entry),
+            Instruction::I32Load(mem(4)),
+            Instruction::LocalGet(selector),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(entry),
+            Instruction::I32Load(mem(8)),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(entry),
+            Instruction::I32Const(8),
+            Instruction::I32Add,
+            Instruction::LocalSet(entry),
+            Instruction::LocalGet(count),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalSet(count),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::Unreachable,
+            Instruction::End,
+        ]);
+        for instruction in instructions{
+            itable_lookup.instruction(&instruction);
+        }
+        module.define(self.itable_lookup, itable_lookup);
+
+        let throw_arithmetic = self.throw(ARITHMETIC_EXCEPTION);
+        for (function, long, divide) in [(self.idiv, false, true), (self.irem, false, false), (self.ldiv, true, true), (self.lrem, true, false)]{
+            let mut body = Function::new_with_locals_types([]);
+            let (a, b) = (0, 1);
+            let (zero, minus_one) = match long {
+                true => (Instruction::I64Const(0), Instruction::I64Const(-1)),
+                false => (Instruction::I32Const(0), Instruction::I32Const(-1))
+            };
+            let mut instructions = vec![
+                Instruction::LocalGet(b),
+                if long {Instruction::I64Eqz} else {Instruction::I32Eqz},
+                Instruction::If(BlockType::Empty),
+                Instruction::Call(throw_arithmetic),
+                zero.clone(),
+                Instruction::Return,
+                Instruction::End,
+            ];
+            if divide{
+                // `div_s` traps on `MIN_VALUE / -1` where Java wraps around.
+                instructions.extend([
+                    Instruction::LocalGet(b),
+                    minus_one,
+                    if long {Instruction::I64Eq} else {Instruction::I32Eq},
+                    Instruction::If(BlockType::Empty),
+                    zero,
+                    Instruction::LocalGet(a),
+                    if long {Instruction::I64Sub} else {Instruction::I32Sub},
+                    Instruction::Return,
+                    Instruction::End,
+                ]);
+            }
+            instructions.extend([
+                Instruction::LocalGet(a),
+                Instruction::LocalGet(b),
+                match (long, divide) {
+                    (false, true) => Instruction::I32DivS,
+                    (false, false) => Instruction::I32RemS,
+                    (true, true) => Instruction::I64DivS,
+                    (true, false) => Instruction::I64RemS,
+                },
+                Instruction::End,
+            ]);
+            for instruction in instructions{
+                body.instruction(&instruction);
+            }
+            module.define(function, body);
+        }
+
+        let (enter, exit) = match self.monitors {
+            Monitors::SingleThreaded => self.counting_monitor(),
+            Monitors::Atomic => self.atomic_monitor(),
+        };
+        let mut body = Function::new_with_locals_types([ValType::I32]);
+        for instruction in enter{
+            body.instruction(&instruction);
+        }
+        module.define(self.monitor_enter, body);
+        let mut body = Function::new_with_locals_types([ValType::I32]);
+        for instruction in exit{
+            body.instruction(&instruction);
+        }
+        module.define(self.monitor_exit, body);
+        self.define_class_init(module);
+
+        self.define_strings(module, layouts, strings);
+
+        // Exception classes of the runtime have no static initializers, so
+        // no barrier is needed here.
+        for (class, function) in self.throws.iter(){
+            let class = class.as_bytes();
+            let mut body = Function::new_with_locals_types([ValType::I32]);
+            let init = module.function(&MethodKey::new(class, b"<init>", b"()V"));
+            match (layouts.class(class), init) {
+                (Some(layout), Some(init)) if program.class(class).is_some() => {
+                    for instruction in [
+                        Instruction::I32Const(layout.size as i32),
+                        Instruction::I32Const(layout.id as i32),
+                        Instruction::Call(self.alloc),
+                        Instruction::LocalTee(0),
+                        Instruction::Call(init),
+                        Instruction::LocalGet(0),
+                        Instruction::GlobalSet(self.exception),
+                        Instruction::End,
+                    ]{
+                        body.instruction(&instruction);
+                    }
+                }
+                _ => {
+                    body.instruction(&Instruction::Unreachable);
+                    body.instruction(&Instruction::End);
+                }
+            }
+            module.define(*function, body);
+        }
+    }
+
+    /// Bodies of the monitor helpers that count recursion in the monitor word.
+    fn counting_monitor(&self) -> (Vec<Instruction<'static>>, Vec<Instruction<'static>>){
+        let word = 0;
+        let enter = vec![
+            Instruction::LocalGet(word),
+            Instruction::LocalGet(word),
+            Instruction::I32Load(mem(0)),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::I32Store(mem(0)),
+            Instruction::End,
+        ];
+        let exit = vec![
+            Instruction::LocalGet(word),
+            Instruction::I32Load(mem(0)),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(ILLEGAL_MONITOR_STATE_EXCEPTION)),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(word),
+            Instruction::LocalGet(word),
+            Instruction::I32Load(mem(0)),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::I32Store(mem(0)),
+            Instruction::End,
+        ];
+        (enter, exit)
+    }
+
+    /// Bodies of the monitor helpers for shared memories, the monitor word
+    /// holds the owner's thread id above the recursion count.
+    fn atomic_monitor(&self) -> (Vec<Instruction<'static>>, Vec<Instruction<'static>>){
+        let (word, old) = (0, 1);
+        let enter = vec![
+            Instruction::Loop(BlockType::Empty),
+            // Take the monitor if it's free.
+            Instruction::LocalGet(word),
+            Instruction::I32Const(0),
+            Instruction::GlobalGet(self.thread),
+            Instruction::I32Const(OWNER_SHIFT),
+            Instruction::I32Shl,
+            Instruction::I32Const(1),
+            Instruction::I32Or,
+            Instruction::I32AtomicRmwCmpxchg(mem(0)),
+            Instruction::LocalTee(old),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::Return,
+            Instruction::End,
+            // Only the owner changes a taken monitor, so entering it again needs no compare and swap.
+            Instruction::LocalGet(old),
+            Instruction::I32Const(OWNER_SHIFT),
+            Instruction::I32ShrU,
+            Instruction::GlobalGet(self.thread),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            // The recursion count would carry into the owner.
+            Instruction::LocalGet(old),
+            Instruction::I32Const(COUNT_MASK),
+            Instruction::I32And,
+            Instruction::I32Const(COUNT_MASK),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(ILLEGAL_MONITOR_STATE_EXCEPTION)),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(word),
+            Instruction::LocalGet(old),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(word),
+            Instruction::LocalGet(old),
+            Instruction::I64Const(-1),
+            Instruction::MemoryAtomicWait32(mem(0)),
+            Instruction::Drop,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+        ];
+        let exit = vec![
+            Instruction::LocalGet(word),
+            Instruction::I32AtomicLoad(mem(0)),
+            Instruction::LocalTee(old),
+            Instruction::I32Const(OWNER_SHIFT),
+            Instruction::I32ShrU,
+            Instruction::GlobalGet(self.thread),
+            Instruction::I32Ne,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(ILLEGAL_MONITOR_STATE_EXCEPTION)),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(old),
+            Instruction::I32Const(COUNT_MASK),
+            Instruction::I32And,
+            Instruction::I32Const(1),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(word),
+            Instruction::I32Const(0),
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::LocalGet(word),
+            Instruction::I32Const(1),
+            Instruction::MemoryAtomicNotify(mem(0)),
+            Instruction::Drop,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(word),
+            Instruction::LocalGet(old),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::End,
+        ];
+        (enter, exit)
+    }
+
+    /// Bodies of [`Self::init_enter`] and [`Self::init_exit`]. A class being
+    /// initialized has the id of the thread doing it above [`INITIALIZING`].
+    fn define_class_init(&self, module: &mut ModuleBuilder){
+        let (Some(init_enter), Some(init_exit)) = (self.init_enter, self.init_exit) else {
+            return;
+        };
+        let (address, old, mine) = (0, 1, 2);
+        let mut body = Function::new_with_locals_types([ValType::I32, ValType::I32]);
+        for instruction in [
+            Instruction::GlobalGet(self.thread),
+            Instruction::I32Const(OWNER_SHIFT),
+            Instruction::I32Shl,
+            Instruction::I32Const(INITIALIZING),
+            Instruction::I32Or,
+            Instruction::LocalSet(mine),
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(address),
+            Instruction::I32Const(UNINITIALIZED),
+            Instruction::LocalGet(mine),
+            Instruction::I32AtomicRmwCmpxchg(mem(0)),
+            Instruction::LocalTee(old),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(1),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(old),
+            Instruction::I32Const(ERRONEOUS),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::Call(self.throw(NO_CLASS_DEF_FOUND_ERROR)),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            // Initialized, or used by its own initializer.
+            Instruction::LocalGet(old),
+            Instruction::I32Const(INITIALIZED),
+            Instruction::I32Eq,
+            Instruction::LocalGet(old),
+            Instruction::LocalGet(mine),
+            Instruction::I32Eq,
+            Instruction::I32Or,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::LocalGet(address),
+            Instruction::LocalGet(old),
+            Instruction::I64Const(-1),
+            Instruction::MemoryAtomicWait32(mem(0)),
+            Instruction::Drop,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::Unreachable,
+            Instruction::End,
+        ]{
+            body.instruction(&instruction);
+        }
+        module.define(init_enter, body);
+
+        let mut body = Function::new([]);
+        for instruction in [
+            Instruction::LocalGet(address),
+            Instruction::I32Const(ERRONEOUS),
+            Instruction::I32Const(INITIALIZED),
+            Instruction::GlobalGet(self.exception),
+            Instruction::Select,
+            Instruction::I32AtomicStore(mem(0)),
+            Instruction::LocalGet(address),
+            Instruction::I32Const(-1),
+            Instruction::MemoryAtomicNotify(mem(0)),
+            Instruction::Drop,
+            Instruction::End,
+        ]{
+            body.instruction(&instruction);
+        }
+        module.define(init_exit, body);
+    }
+
+    /// Allocates a byte array with the length in local `length`, and stores it in local `array`.
+    fn alloc_bytes(&self, length: u32, array: u32) -> [Instruction<'static>; 8]{
+        [
+            Instruction::I32Const(layout::ARRAY_DATA_OFFSET as i32),
+            Instruction::LocalGet(length),
+            Instruction::I32Add,
+            Instruction::I32Const(class_id::BYTE_ARRAY as i32),
+            Instruction::Call(self.alloc),
+            Instruction::LocalTee(array),
+            Instruction::LocalGet(length),
+            Instruction::I32Store(mem(layout::ARRAY_LENGTH_OFFSET)),
+        ]
+    }
+
+    /// Allocates a string of the byte array in local `value` with the coder
+    /// `coder` leaves on the stack, using local `string` as scratch.
+    fn new_string(&self, value: u32, coder: &[Instruction<'static>], string: u32) -> Vec<Instruction<'static>>{
+        let mut instructions = vec![
+            Instruction::I32Const(layout::STRING_SIZE as i32),
+            Instruction::I32Const(class_id::STRING as i32),
+            Instruction::Call(self.alloc),
+            Instruction::LocalTee(string),
+            Instruction::LocalGet(value),
+            Instruction::I32Store(mem(layout::STRING_VALUE_OFFSET)),
+            Instruction::LocalGet(string),
+        ];
+        instructions.extend(coder.iter().cloned());
+        instructions.extend([
+            Instruction::I32Store8(byte(layout::STRING_CODER_OFFSET)),
+            Instruction::LocalGet(string),
+        ]);
+        instructions
+    }
+
+    fn define_strings(&self, module: &mut ModuleBuilder, layouts: &Layouts, strings: &StringTable){
+        let literal = |s: &str| strings.address(&s.encode_utf16().collect::<Vec<_>>()).expect("Runtime strings are interned") as i32;
+        let data = layout::ARRAY_DATA_OFFSET;
+        let i32 = ValType::I32;
+
+        // Latin-1 strings are copied as they are, mixing in a UTF-16 one
+        // widens them to two bytes per character.
+        let mut concat = Function::new_with_locals_types([i32; 9]);
+        let (a, b, value_a, value_b, length_a, length_b, array, i, string, wide_a, wide_b) = (0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10);
+        let mut instructions = Vec::new();
+        for (s, value, length) in [(a, value_a, length_a), (b, value_b, length_b)]{
+            instructions.extend([
+                Instruction::LocalGet(s),
+                Instruction::I32Load(mem(layout::STRING_VALUE_OFFSET)),
+                Instruction::LocalTee(value),
+                Instruction::I32Load(mem(layout::ARRAY_LENGTH_OFFSET)),
+                Instruction::LocalSet(length),
+            ]);
+        }
+        for (empty, other) in [(length_a, b), (length_b, a)]{
+            instructions.extend([
+                Instruction::LocalGet(empty),
+                Instruction::I32Eqz,
+                Instruction::If(BlockType::Empty),
+                Instruction::LocalGet(other),
+                Instruction::Return,
+                Instruction::End,
+            ]);
+        }
+        let coder = |s| [Instruction::LocalGet(s), Instruction::I32Load8U(byte(layout::STRING_CODER_OFFSET))];
+        instructions.extend(coder(a));
+        instructions.extend(coder(b));
+        instructions.extend([
+            Instruction::I32Or,
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(length_a),
+            Instruction::LocalGet(length_b),
+            Instruction::I32Add,
+            Instruction::LocalSet(i),
+        ]);
+        instructions.extend(self.alloc_bytes(i, array));
+        instructions.extend([
+            Instruction::LocalGet(array),
+            Instruction::I32Const(data as i32),
+            Instruction::I32Add,
+            Instruction::LocalGet(value_a),
+            Instruction::I32Const(data as i32),
+            Instruction::I32Add,
+            Instruction::LocalGet(length_a),
+            Instruction::MemoryCopy{src_mem: 0, dst_mem: 0},
+            Instruction::LocalGet(array),
+            Instruction::I32Const(data as i32),
+            Instruction::I32Add,
+            Instruction::LocalGet(length_a),
+            Instruction::I32Add,
+            Instruction::LocalGet(value_b),
+            Instruction::I32Const(data as i32),
+            Instruction::I32Add,
+            Instruction::LocalGet(length_b),
+            Instruction::MemoryCopy{src_mem: 0, dst_mem: 0},
+        ]);
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_LATIN1 as i32)], string));
+        instructions.extend([
+            Instruction::Return,
+            Instruction::End,
+        ]);
+        // Byte lengths of both strings once widened.
+        for (s, length, wide) in [(a, length_a, wide_a), (b, length_b, wide_b)]{
+            instructions.extend(coder(s));
+            instructions.extend([
+                Instruction::LocalGet(length),
+                Instruction::LocalGet(length),
+                Instruction::I32Const(1),
+                Instruction::I32Shl,
+                Instruction::LocalGet(s),
+                Instruction::I32Load8U(byte(layout::STRING_CODER_OFFSET)),
+                Instruction::Select,
+                Instruction::LocalSet(wide),
+                Instruction::Drop,
+            ]);
+        }
+        instructions.extend([
+            Instruction::LocalGet(wide_a),
+            Instruction::LocalGet(wide_b),
+            Instruction::I32Add,
+            Instruction::LocalSet(i),
+        ]);
+        instructions.extend(self.alloc_bytes(i, array));
+        for (s, value, length, offset) in [(a, value_a, length_a, None), (b, value_b, length_b, Some(wide_a))]{
+            let destination = |instructions: &mut Vec<Instruction<'static>>|{
+                instructions.extend([
+                    Instruction::LocalGet(array),
+                    Instruction::I32Const(data as i32),
+                    Instruction::I32Add,
+                ]);
+                if let Some(offset) = offset{
+                    instructions.extend([Instruction::LocalGet(offset), Instruction::I32Add]);
+                }
+            };
+            instructions.extend(coder(s));
+            instructions.push(Instruction::If(BlockType::Empty));
+            destination(&mut instructions);
+            instructions.extend([
+                Instruction::LocalGet(value),
+                Instruction::I32Const(data as i32),
+                Instruction::I32Add,
+                Instruction::LocalGet(length),
+                Instruction::MemoryCopy{src_mem: 0, dst_mem: 0},
+                Instruction::Else,
+                Instruction::I32Const(0),
+                Instruction::LocalSet(i),
+                Instruction::Block(BlockType::Empty),
+                Instruction::Loop(BlockType::Empty),
+                Instruction::LocalGet(i),
+                Instruction::LocalGet(length),
+                Instruction::I32GeU,
+                Instruction::BrIf(1),
+            ]);
+            destination(&mut instructions);
+            instructions.extend([
+                Instruction::LocalGet(i),
+                Instruction::I32Const(1),
+                Instruction::I32Shl,
+                Instruction::I32Add,
+                Instruction::LocalGet(value),
+                Instruction::LocalGet(i),
+                Instruction::I32Add,
+                Instruction::I32Load8U(byte(data)),
+                Instruction::I32Store16(half(0)),
+                Instruction::LocalGet(i),
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+                Instruction::LocalSet(i),
+                Instruction::Br(0),
+                Instruction::End,
+                Instruction::End,
+                Instruction::End,
+            ]);
+        }
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_UTF16 as i32)], string));
+        instructions.push(Instruction::End);
+        for instruction in instructions{
+            concat.instruction(&instruction);
+        }
+        module.define(self.concat, concat);
+
+        // Digits are produced from the negated value, which also covers `Long.MIN_VALUE`.
+        let mut string_of_long = Function::new_with_locals_types([i32, i32, ValType::I64, i32, i32, i32]);
+        let (value, negative, length, rest, array, position, string) = (0, 1, 2, 3, 4, 5, 6);
+        let negated = [
+            Instruction::LocalGet(value),
+            Instruction::I64Const(0),
+            Instruction::LocalGet(value),
+            Instruction::I64Sub,
+            Instruction::LocalGet(negative),
+            Instruction::Select,
+            Instruction::LocalSet(rest),
+        ];
+        let mut instructions = vec![
+            Instruction::LocalGet(value),
+            Instruction::I64Const(0),
+            Instruction::I64LtS,
+            Instruction::LocalTee(negative),
+            Instruction::LocalSet(length),
+        ];
+        instructions.extend(negated.iter().cloned());
+        instructions.extend([
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(length),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::LocalSet(length),
+            Instruction::LocalGet(rest),
+            Instruction::I64Const(10),
+            Instruction::I64DivS,
+            Instruction::LocalTee(rest),
+            Instruction::I64Eqz,
+            Instruction::I32Eqz,
+            Instruction::BrIf(0),
+            Instruction::End,
+        ]);
+        instructions.extend(self.alloc_bytes(length, array));
+        instructions.extend([
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(length),
+            Instruction::I32Add,
+            Instruction::LocalSet(position),
+        ]);
+        instructions.extend(negated.iter().cloned());
+        instructions.extend([
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(position),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalTee(position),
+            Instruction::I32Const('0' as i32),
+            Instruction::LocalGet(rest),
+            Instruction::I64Const(10),
+            Instruction::I64RemS,
+            Instruction::I32WrapI64,
+            Instruction::I32Sub,
+            Instruction::I32Store8(byte(data)),
+            Instruction::LocalGet(rest),
+            Instruction::I64Const(10),
+            Instruction::I64DivS,
+            Instruction::LocalTee(rest),
+            Instruction::I64Eqz,
+            Instruction::I32Eqz,
+            Instruction::BrIf(0),
+            Instruction::End,
+            Instruction::LocalGet(negative),
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(array),
+            Instruction::I32Const('-' as i32),
+            Instruction::I32Store8(byte(data)),
+            Instruction::End,
+        ]);
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_LATIN1 as i32)], string));
+        instructions.push(Instruction::End);
+        for instruction in instructions{
+            string_of_long.instruction(&instruction);
+        }
+        module.define(self.string_of_long, string_of_long);
+
+        let mut string_of_char = Function::new_with_locals_types([i32, i32, i32]);
+        let (c, length, array, string) = (0, 1, 2, 3);
+        let mut instructions = vec![
+            Instruction::LocalGet(c),
+            Instruction::I32Const(0xFF),
+            Instruction::I32LeU,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(1),
+            Instruction::LocalSet(length),
+        ];
+        instructions.extend(self.alloc_bytes(length, array));
+        instructions.extend([
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(c),
+            Instruction::I32Store8(byte(data)),
+        ]);
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_LATIN1 as i32)], string));
+        instructions.extend([
+            Instruction::Return,
+            Instruction::End,
+            Instruction::I32Const(2),
+            Instruction::LocalSet(length),
+        ]);
+        instructions.extend(self.alloc_bytes(length, array));
+        instructions.extend([
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(c),
+            Instruction::I32Store16(half(data)),
+        ]);
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_UTF16 as i32)], string));
+        instructions.push(Instruction::End);
+        for instruction in instructions{
+            string_of_char.instruction(&instruction);
+        }
+        module.define(self.string_of_char, string_of_char);
+
+        // The host writes into an array of the largest size, which is then
+        // shortened to the length written.
+        let mut string_of_double = Function::new_with_locals_types([i32, i32, i32]);
+        let (value, is_float, length, array, string) = (0, 1, 2, 3, 4);
+        let mut instructions = vec![
+            Instruction::I32Const(FORMATTED_DOUBLE_SIZE),
+            Instruction::LocalSet(length),
+        ];
+        instructions.extend(self.alloc_bytes(length, array));
+        instructions.extend([
+            Instruction::LocalGet(array),
+            Instruction::LocalGet(value),
+            Instruction::LocalGet(is_float),
+            Instruction::LocalGet(array),
+            Instruction::I32Const(data as i32),
+            Instruction::I32Add,
+            Instruction::Call(self.format_double),
+            Instruction::I32Store(mem(layout::ARRAY_LENGTH_OFFSET)),
+        ]);
+        instructions.extend(self.new_string(array, &[Instruction::I32Const(layout::CODER_LATIN1 as i32)], string));
+        instructions.push(Instruction::End);
+        for instruction in instructions{
+            string_of_double.instruction(&instruction);
+        }
+        module.define(self.string_of_double, string_of_double);
+
+        // `toString` is called through the vtable, classes without a record
+        // use the one of `Object`.
+        let mut string_of_object = Function::new_with_locals_types([i32]);
+        let (object, info) = (0, 1);
+        let to_string = (b"java/lang/Object".as_slice(), b"toString".as_slice(), b"()Ljava/lang/String;".as_slice());
+        let direct = module.function(&MethodKey::new(to_string.0, to_string.1, to_string.2));
+        let slot = layouts.vtable_slot(to_string.0, to_string.1, to_string.2);
+        let type_index = module.type_index(&[i32], &[i32]);
+        let mut instructions = vec![
+            Instruction::LocalGet(object),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(literal("null")),
+            Instruction::Return,
+            Instruction::End,
+        ];
+        match (direct, slot) {
+            (Some(direct), Some(slot)) => {
+                instructions.extend([
+                    Instruction::LocalGet(object),
+                    Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET)),
+                ]);
+                instructions.extend(load_class_info(layouts.class_table()));
+                instructions.extend([
+                    Instruction::LocalTee(info),
+                    Instruction::I32Eqz,
+                    Instruction::If(BlockType::Result(i32)),
+                    Instruction::LocalGet(object),
+                    Instruction::Call(direct),
+                    Instruction::Else,
+                    Instruction::LocalGet(object),
+                    Instruction::LocalGet(info),
+                    Instruction::I32Load(mem(layout::INFO_VTABLE_OFFSET + 4 * slot)),
+                    Instruction::CallIndirect{ty: type_index, table: 0},
+                    Instruction::End,
+                    Instruction::LocalTee(info),
+                    Instruction::I32Const(literal("null")),
+                    Instruction::LocalGet(info),
+                    Instruction::Select,
+                ]);
+            }
+            _ => instructions.push(Instruction::Unreachable)
+        }
+        instructions.push(Instruction::End);
+        for instruction in instructions{
+            string_of_object.instruction(&instruction);
+        }
+        module.define(self.string_of_object, string_of_object);
+    }
+}
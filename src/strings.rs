@@ -0,0 +1,163 @@
+//! String literals.
+//!
+//! Literals are collected from the `String` entries of every crawled constant
+//! pool, deduplicated, and laid out in the data segment as prebuilt
+//! `java.lang.String` objects so that `ldc` can reference them by address.
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+use dashmap::DashSet;
+
+use noak::reader::{cpool::Item, Class};
+
+use crate::{layout::{self, DataSegment}, program::Program};
+
+/// Decodes a modified UTF-8 constant into UTF-16 code units.
+///
+/// Surrogate pairs are stored as two separately encoded surrogates, so they
+/// come out as two units here. NUL is encoded as `C0 80`, a raw zero byte is
+/// not allowed.
+pub fn decode_mutf8(bytes: &[u8]) -> anyhow::Result<Vec<u16>>{
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    let continuation = |i: usize| -> anyhow::Result<u16>{
+        match bytes.get(i) {
+            Some(b) if b & 0xC0 == 0x80 => Ok((b & 0x3F) as u16),
+            Some(b) => bail!("Invalid continuation byte {:#04x} at {}", b, i),
+            None => bail!("Truncated modified UTF-8 sequence at {}", i)
+        }
+    };
+
+    while i < bytes.len(){
+        let b = bytes[i];
+        match b {
+            0x00 => bail!("Raw NUL byte at {} in modified UTF-8", i),
+            0x01..=0x7F => {
+                units.push(b as u16);
+                i += 1;
+            }
+            0xC0..=0xDF => {
+                units.push((((b & 0x1F) as u16) << 6) | continuation(i + 1)?);
+                i += 2;
+            }
+            0xE0..=0xEF => {
+                units.push((((b & 0x0F) as u16) << 12) | (continuation(i + 1)? << 6) | continuation(i + 2)?);
+                i += 3;
+            }
+            _ => bail!("Invalid modified UTF-8 lead byte {:#04x} at {}", b, i)
+        }
+    }
+
+    Ok(units)
+}
+
+/// `String.hashCode()` of the given code units.
+pub fn java_hash(units: &[u16]) -> i32{
+    units.iter().fold(0i32, |h, u| h.wrapping_mul(31).wrapping_add(*u as i32))
+}
+
+/// Every string literal seen while crawling.
+#[derive(Default)]
+pub struct StringPool{
+    strings: DashSet<Box<[u16]>>
+}
+
+impl StringPool{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Interns the content of a `Utf8` pool entry referenced by a `String` entry.
+    pub fn intern(&self, mutf8: &[u8]) -> anyhow::Result<()>{
+        self.strings.insert(decode_mutf8(mutf8)?.into_boxed_slice());
+        Ok(())
+    }
+
+    /// Interns a string that is already decoded.
+    pub fn intern_units(&self, units: &[u16]){
+        self.strings.insert(units.into());
+    }
+
+    /// Interns every string literal of the program.
+    pub fn collect(program: &Program) -> anyhow::Result<Self>{
+        let pool = Self::new();
+        for class in program.classes(){
+            let mut parsed = Class::new(&class.bytes)?;
+            let cp = parsed.pool()?;
+            for item in cp.iter(){
+                if let Item::String(s) = item{
+                    pool.intern(cp.get(s.string)?.content.as_bytes())?;
+                }
+            }
+        }
+        Ok(pool)
+    }
+
+    pub fn len(&self) -> usize{
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.strings.is_empty()
+    }
+
+    /// Places every interned string in `segment`.
+    ///
+    /// Strings that only contain Latin-1 characters are stored compactly with
+    /// one byte per character, the rest as UTF-16. The order is sorted so the
+    /// output is the same between runs.
+    pub fn layout(&self, segment: &mut DataSegment) -> StringTable{
+        let mut strings = self.strings.iter().map(|s| s.key().clone()).collect::<Vec<_>>();
+        strings.sort();
+
+        let mut addresses = HashMap::with_capacity(strings.len());
+        for units in strings{
+            let latin1 = units.iter().all(|u| *u <= 0xFF);
+            let data = if latin1 {
+                units.iter().map(|u| *u as u8).collect::<Vec<_>>()
+            }
+            else{
+                units.iter().flat_map(|u| u.to_le_bytes()).collect::<Vec<_>>()
+            };
+
+            let value = segment.alloc(layout::ARRAY_DATA_OFFSET + data.len() as u32, 8);
+            segment.write_header(value, layout::class_id::BYTE_ARRAY);
+            segment.write_u32(value + layout::ARRAY_LENGTH_OFFSET, data.len() as u32);
+            segment.write_bytes(value + layout::ARRAY_DATA_OFFSET, &data);
+
+            let string = segment.alloc(layout::STRING_SIZE, 8);
+            segment.write_header(string, layout::class_id::STRING);
+            segment.write_u32(string + layout::STRING_VALUE_OFFSET, value);
+            segment.write_u8(string + layout::STRING_CODER_OFFSET, if latin1 {layout::CODER_LATIN1} else {layout::CODER_UTF16});
+            segment.write_u32(string + layout::STRING_HASH_OFFSET, java_hash(&units) as u32);
+
+            addresses.insert(units, string);
+        }
+
+        StringTable{
+            addresses
+        }
+    }
+}
+
+/// Addresses of the laid out string literals.
+pub struct StringTable{
+    addresses: HashMap<Box<[u16]>, u32>
+}
+
+impl StringTable{
+    pub fn address(&self, units: &[u16]) -> Option<u32>{
+        self.addresses.get(units).copied()
+    }
+
+    /// Address of the literal with the given modified UTF-8 content.
+    pub fn address_of(&self, mutf8: &[u8]) -> anyhow::Result<u32>{
+        let units = decode_mutf8(mutf8)?;
+        match self.address(&units) {
+            Some(address) => Ok(address),
+            None => bail!("String {:?} was never interned", String::from_utf16_lossy(&units))
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Java stack traces of the translated code.
+//!
+//! Every lowered method has an id, and pushes a frame word holding it on the
+//! [runtime's](crate::runtime) shadow stack when it's entered. Before each
+//! call that may throw, the lower half of the word is set to the bytecode
+//! offset of the call, so the stack holds the position of every running
+//! method. `Throwable` copies the words when it's created, and its natives
+//! look up the class, method, file and line of a frame in the tables written
+//! here.
+//!
+//! The method table holds the address of a record per method id: the class
+//! name, the method name and the file name as strings, the file being `null`
+//! if unknown, the class id and the number of line entries, followed by the
+//! entries as pairs of 16 bit bytecode offset and line, sorted by offset. The
+//! class name table holds the name of every class by class id.
+
+use std::collections::HashMap;
+
+use anyhow::ensure;
+use noak::AccessFlags;
+use wasm_encoder::{BlockType, Function, Instruction, MemArg, ValType};
+
+use crate::{classes::Layouts, data::{ParsedClass, ParsedMethod}, debug::MethodDebug, layout::{self, class_id, DataSegment}, module::{signature, ModuleBuilder}, program::{MethodKey, Program}, runtime::Runtime, strings::{StringPool, StringTable}};
+
+pub const RECORD_CLASS_OFFSET: u32 = 0;
+pub const RECORD_METHOD_OFFSET: u32 = 4;
+pub const RECORD_FILE_OFFSET: u32 = 8;
+pub const RECORD_CLASS_ID_OFFSET: u32 = 12;
+pub const RECORD_LINE_COUNT_OFFSET: u32 = 16;
+pub const RECORD_LINES_OFFSET: u32 = 20;
+
+/// Frame words hold the offset in their lower half, so this many methods can have an id.
+pub const MAX_METHODS: usize = 1 << 16;
+
+const THROWABLE: &[u8] = b"java/lang/Throwable";
+const INIT: &[u8] = b"<init>";
+
+/// `Throwable` natives defined here rather than imported, by name and descriptor.
+const NATIVES: &[(&str, &str)] = &[
+    ("backtrace", "()[I"),
+    ("className", "()Ljava/lang/String;"),
+    ("frameClass", "(I)Ljava/lang/String;"),
+    ("frameMethod", "(I)Ljava/lang/String;"),
+    ("frameFile", "(I)Ljava/lang/String;"),
+    ("frameLine", "(I)I"),
+];
+
+/// `static void uncaught(Throwable)` of `Throwable`, which prints the trace
+/// of an exception escaping an exported method.
+pub fn uncaught() -> MethodKey{
+    MethodKey::new(THROWABLE, b"uncaught", b"(Ljava/lang/Throwable;)V")
+}
+
+/// Whether the native is one of those this module implements.
+pub fn implements(key: &MethodKey) -> bool{
+    &*key.class == THROWABLE && NATIVES.iter().any(|(name, descriptor)| &*key.name == name.as_bytes() && &*key.descriptor == descriptor.as_bytes())
+}
+
+/// The frame word of a method at a bytecode offset.
+pub const fn frame_word(id: u32, offset: u32) -> u32{
+    id << 16 | offset
+}
+
+pub fn dotted(name: &[u8]) -> Vec<u8>{
+    name.iter().map(|c| if *c == b'/' {b'.'} else {*c}).collect()
+}
+
+fn mem(offset: u32, align: u32) -> MemArg{
+    MemArg{
+        offset: offset as u64,
+        align,
+        memory_index: 0
+    }
+}
+
+struct MethodTrace{
+    class: Box<[u8]>,
+    name: Box<[u8]>,
+    file: Option<Box<[u8]>>,
+    lines: Vec<(u32, u32)>,
+}
+
+/// Ids and line tables of the lowered methods.
+#[derive(Default)]
+pub struct TraceTable{
+    methods: Vec<MethodTrace>,
+    ids: HashMap<MethodKey, u32>,
+    method_table: u32,
+    class_names: u32,
+    natives: Vec<(&'static str, u32)>,
+}
+
+impl TraceTable{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Interns the names the tables refer to, before the strings are laid out.
+    pub fn intern_names(program: &Program, strings: &StringPool) -> anyhow::Result<()>{
+        strings.intern(INIT)?;
+        for class in program.classes(){
+            strings.intern(&dotted(&class.name))?;
+            if let Some(file) = &class.source_file{
+                strings.intern(file)?;
+            }
+            for method in class.methods.iter(){
+                strings.intern(&method.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the method the next id.
+    pub fn add(&mut self, class: &ParsedClass, method: &ParsedMethod) -> anyhow::Result<u32>{
+        ensure!(self.methods.len() < MAX_METHODS, "More than {} methods can't have stack traces", MAX_METHODS);
+        let debug = class.with_code(&method.name, &method.descriptor, |cp, code| MethodDebug::read(code, cp))?;
+        let id = self.methods.len() as u32;
+        self.ids.insert(MethodKey::new(&class.name, &method.name, &method.descriptor), id);
+        self.methods.push(MethodTrace{
+            class: class.name.clone(),
+            name: method.name.clone(),
+            file: class.source_file.clone(),
+            lines: debug.map(|d| d.lines).unwrap_or_default()
+        });
+        Ok(id)
+    }
+
+    pub fn id(&self, key: &MethodKey) -> Option<u32>{
+        self.ids.get(key).copied()
+    }
+
+    /// Writes the method and class name tables to the data segment.
+    pub fn write(&mut self, program: &Program, layouts: &Layouts, strings: &StringTable, segment: &mut DataSegment) -> anyhow::Result<()>{
+        let mut records = Vec::with_capacity(self.methods.len());
+        for method in self.methods.iter(){
+            let address = segment.alloc(RECORD_LINES_OFFSET + 4 * method.lines.len() as u32, 4);
+            segment.write_u32(address + RECORD_CLASS_OFFSET, strings.address_of(&dotted(&method.class))?);
+            segment.write_u32(address + RECORD_METHOD_OFFSET, strings.address_of(&method.name)?);
+            let file = method.file.as_deref().map(|f| strings.address_of(f)).transpose()?;
+            segment.write_u32(address + RECORD_FILE_OFFSET, file.unwrap_or(layout::NULL));
+            segment.write_u32(address + RECORD_CLASS_ID_OFFSET, layouts.id(&method.class).unwrap_or(0));
+            segment.write_u32(address + RECORD_LINE_COUNT_OFFSET, method.lines.len() as u32);
+            for (i, (start, line)) in method.lines.iter().enumerate(){
+                let entry = address + RECORD_LINES_OFFSET + 4 * i as u32;
+                segment.write_bytes(entry, &(*start as u16).to_le_bytes());
+                segment.write_bytes(entry + 2, &(*line as u16).to_le_bytes());
+            }
+            records.push(address);
+        }
+        self.method_table = segment.alloc(4 * records.len() as u32, 4);
+        for (id, address) in records.into_iter().enumerate(){
+            segment.write_u32(self.method_table + 4 * id as u32, address);
+        }
+
+        let names = program.classes().into_iter()
+            .filter_map(|c| Some((layouts.id(&c.name)?, strings.address_of(&dotted(&c.name)))))
+            .collect::<Vec<_>>();
+        let entries = names.iter().map(|(id, _)| id + 1).max().unwrap_or(0).max(class_id::FIRST_FREE);
+        self.class_names = segment.alloc(4 * entries, 4);
+        for (id, name) in names{
+            segment.write_u32(self.class_names + 4 * id, name?);
+        }
+        Ok(())
+    }
+
+    /// Declares the `Throwable` natives the program kept.
+    pub fn declare_natives(&mut self, module: &mut ModuleBuilder, program: &Program) -> anyhow::Result<()>{
+        let Some(throwable) = program.class(THROWABLE) else {
+            return Ok(());
+        };
+        for (name, descriptor) in NATIVES{
+            let Some(method) = throwable.method(name.as_bytes(), descriptor.as_bytes()) else {
+                continue;
+            };
+            let (params, results) = signature(&method.descriptor, method.flags.contains(AccessFlags::STATIC))?;
+            let function = module.declare_function(MethodKey::new(THROWABLE, &method.name, &method.descriptor), &params, &results);
+            self.natives.push((name, function));
+        }
+        Ok(())
+    }
+
+    /// Defines the natives, after [`Self::write`].
+    pub fn define_natives(&self, module: &mut ModuleBuilder, runtime: &Runtime, strings: &StringTable) -> anyhow::Result<()>{
+        let init = strings.address_of(INIT)? as i32;
+        // Loads the record of the frame word in local 0.
+        let record = [
+            Instruction::LocalGet(0),
+            Instruction::I32Const(16),
+            Instruction::I32ShrU,
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Load(mem(self.method_table, 2)),
+        ];
+
+        for (name, function) in self.natives.iter(){
+            let (locals, instructions) = match *name {
+                // Frames are skipped from the innermost one while they are
+                // constructors of a class the throwable is an instance of.
+                "backtrace" => {
+                    let (this, frame, method, array, i) = (0, 1, 2, 3, 4);
+                    let instructions = vec![
+                        Instruction::GlobalGet(runtime.frame),
+                        Instruction::LocalSet(frame),
+                        Instruction::Block(BlockType::Empty),
+                        Instruction::Loop(BlockType::Empty),
+                        Instruction::LocalGet(frame),
+                        runtime.frames(),
+                        Instruction::I32LtU,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(frame),
+                        Instruction::I32Load(mem(0, 2)),
+                        Instruction::I32Const(16),
+                        Instruction::I32ShrU,
+                        Instruction::I32Const(2),
+                        Instruction::I32Shl,
+                        Instruction::I32Load(mem(self.method_table, 2)),
+                        Instruction::LocalTee(method),
+                        Instruction::I32Load(mem(RECORD_METHOD_OFFSET, 2)),
+                        Instruction::I32Const(init),
+                        Instruction::I32Ne,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(this),
+                        Instruction::LocalGet(method),
+                        Instruction::I32Load(mem(RECORD_CLASS_ID_OFFSET, 2)),
+                        Instruction::Call(runtime.instance_of),
+                        Instruction::I32Eqz,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(frame),
+                        Instruction::I32Const(4),
+                        Instruction::I32Sub,
+                        Instruction::LocalSet(frame),
+                        Instruction::Br(0),
+                        Instruction::End,
+                        Instruction::End,
+                        // Frames from the one left down to the outermost.
+                        Instruction::LocalGet(frame),
+                        runtime.frames(),
+                        Instruction::I32Const(4),
+                        Instruction::I32Sub,
+                        Instruction::I32Sub,
+                        Instruction::I32Const(2),
+                        Instruction::I32ShrU,
+                        Instruction::I32Const(layout::type_tag(class_id::INT_ARRAY, 0) as i32),
+                        Instruction::Call(runtime.new_array),
+                        Instruction::LocalSet(array),
+                        Instruction::LocalGet(array),
+                        Instruction::LocalSet(i),
+                        Instruction::Block(BlockType::Empty),
+                        Instruction::Loop(BlockType::Empty),
+                        Instruction::LocalGet(frame),
+                        runtime.frames(),
+                        Instruction::I32LtU,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(i),
+                        Instruction::LocalGet(frame),
+                        Instruction::I32Load(mem(0, 2)),
+                        Instruction::I32Store(mem(layout::ARRAY_DATA_OFFSET, 2)),
+                        Instruction::LocalGet(i),
+                        Instruction::I32Const(4),
+                        Instruction::I32Add,
+                        Instruction::LocalSet(i),
+                        Instruction::LocalGet(frame),
+                        Instruction::I32Const(4),
+                        Instruction::I32Sub,
+                        Instruction::LocalSet(frame),
+                        Instruction::Br(0),
+                        Instruction::End,
+                        Instruction::End,
+                        Instruction::LocalGet(array),
+                        Instruction::End,
+                    ];
+                    (vec![ValType::I32; 4], instructions)
+                }
+                "className" => (Vec::new(), vec![
+                    Instruction::LocalGet(0),
+                    Instruction::I32Load(mem(layout::HEADER_CLASS_OFFSET, 2)),
+                    Instruction::I32Const(2),
+                    Instruction::I32Shl,
+                    Instruction::I32Load(mem(self.class_names, 2)),
+                    Instruction::End,
+                ]),
+                "frameClass" | "frameMethod" | "frameFile" => {
+                    let field = match *name {
+                        "frameClass" => RECORD_CLASS_OFFSET,
+                        "frameMethod" => RECORD_METHOD_OFFSET,
+                        _ => RECORD_FILE_OFFSET
+                    };
+                    let mut instructions = record.to_vec();
+                    instructions.extend([Instruction::I32Load(mem(field, 2)), Instruction::End]);
+                    (Vec::new(), instructions)
+                }
+                // The line of the last entry starting at or before the
+                // offset, -1 if there is none.
+                "frameLine" => {
+                    let (frame, entry, end, line) = (0, 1, 2, 3);
+                    let mut instructions = record.to_vec();
+                    instructions.extend([
+                        Instruction::LocalTee(entry),
+                        Instruction::LocalGet(entry),
+                        Instruction::I32Load(mem(RECORD_LINE_COUNT_OFFSET, 2)),
+                        Instruction::I32Const(2),
+                        Instruction::I32Shl,
+                        Instruction::I32Add,
+                        Instruction::LocalSet(end),
+                        Instruction::I32Const(-1),
+                        Instruction::LocalSet(line),
+                        Instruction::Block(BlockType::Empty),
+                        Instruction::Loop(BlockType::Empty),
+                        Instruction::LocalGet(entry),
+                        Instruction::LocalGet(end),
+                        Instruction::I32GeU,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(entry),
+                        Instruction::I32Load16U(mem(RECORD_LINES_OFFSET, 1)),
+                        Instruction::LocalGet(frame),
+                        Instruction::I32Const(0xFFFF),
+                        Instruction::I32And,
+                        Instruction::I32GtU,
+                        Instruction::BrIf(1),
+                        Instruction::LocalGet(entry),
+                        Instruction::I32Load16U(mem(RECORD_LINES_OFFSET + 2, 1)),
+                        Instruction::LocalSet(line),
+                        Instruction::LocalGet(entry),
+                        Instruction::I32Const(4),
+                        Instruction::I32Add,
+                        Instruction::LocalSet(entry),
+                        Instruction::Br(0),
+                        Instruction::End,
+                        Instruction::End,
+                        Instruction::LocalGet(line),
+                        Instruction::End,
+                    ]);
+                    (vec![ValType::I32; 3], instructions)
+                }
+                _ => unreachable!("{} is not a trace native", name)
+            };
+            let mut body = Function::new_with_locals_types(locals);
+            for instruction in instructions{
+                body.instruction(&instruction);
+            }
+            module.define(*function, body);
+        }
+        Ok(())
+    }
+}
+
+/// Passes a pending exception to `uncaught` and leaves it pending, using
+/// the local `scratch`.
+pub fn report_uncaught(exception: u32, uncaught: u32, scratch: u32) -> [Instruction<'static>; 11]{
+    [
+        Instruction::GlobalGet(exception),
+        Instruction::If(BlockType::Empty),
+        Instruction::GlobalGet(exception),
+        Instruction::LocalSet(scratch),
+        Instruction::I32Const(layout::NULL as i32),
+        Instruction::GlobalSet(exception),
+        Instruction::LocalGet(scratch),
+        Instruction::Call(uncaught),
+        Instruction::LocalGet(scratch),
+        Instruction::GlobalSet(exception),
+        Instruction::End,
+    ]
+}
+
+/// Declares a function calling `function` with its arguments, which reports
+/// the exception that escapes it. Returns the function to export.
+pub fn catch_uncaught(module: &mut ModuleBuilder, exception: u32, uncaught: u32, function: u32) -> u32{
+    let (params, results) = module.function_type(function).clone();
+    let wrapper = module.declare(&params, &results);
+    let mut locals = results.clone();
+    locals.push(ValType::I32);
+    let mut body = Function::new_with_locals_types(locals);
+    for i in 0..params.len() as u32{
+        body.instruction(&Instruction::LocalGet(i));
+    }
+    body.instruction(&Instruction::Call(function));
+    let result = params.len() as u32;
+    let scratch = result + results.len() as u32;
+    if !results.is_empty(){
+        body.instruction(&Instruction::LocalSet(result));
+    }
+    for instruction in report_uncaught(exception, uncaught, scratch){
+        body.instruction(&instruction);
+    }
+    if !results.is_empty(){
+        body.instruction(&Instruction::LocalGet(result));
+    }
+    body.instruction(&Instruction::End);
+    module.define(wrapper, body);
+    wrapper
+}
@@ -0,0 +1,283 @@
+//! Translation of a whole program into a WebAssembly module.
+
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::bail;
+use noak::AccessFlags;
+use wasm_encoder::{ConstExpr, ExportKind, Function, Instruction, ValType};
+
+use crate::{cache::{self, Cache, CachedMethod}, classes::Layouts, debug::{self, SourceMap}, indy::{self, CallSite, Piece}, init::{InitPlan, Statics, CLINIT}, layout::{self, DataSegment}, link::{Chunks, LinkedModule, Units}, lower::{emit_barrier, lower_method, Env}, module::{body_bytes, signature, ModuleBuilder, PAGE_SIZE}, natives::{self, Natives, HIGH_BITS_EXPORT}, optimize::{CheckReport, Decision, Optimizations, Optimizer}, program::{MethodKey, Program}, resources::{ResourceIndex, Resources}, runtime::{Monitors, Runtime, STRINGS, THREAD_EXPORT}, strings::StringPool, trace::{self, TraceTable}};
+
+/// Export holding the pending exception, `null` after a call that returned normally.
+pub const EXCEPTION_EXPORT: &str = "exception";
+
+#[derive(Clone, Debug, Default)]
+pub struct Options{
+    pub monitors: Monitors,
+    pub optimizations: Optimizations,
+    /// Passes `long`s to and from the host as two ints, see [`crate::natives`].
+    pub split_longs: bool,
+    /// Where the host finds the source map, written into the module for debuggers.
+    pub source_map_url: Option<String>,
+    /// Where lowered methods are kept to be reused by later translations,
+    /// see [`crate::cache`].
+    pub cache: Option<Arc<Cache>>,
+    /// Splits the module into one per unit, see [`crate::link`].
+    pub units: Option<Units>,
+    /// Loads the units on demand, keeping only what startup needs in the
+    /// core, see [`Chunks`]. Needs `units`.
+    pub lazy: bool,
+    /// Files `ClassLoader.getSystemResourceAsStream` finds, see [`crate::resources`].
+    pub resources: Arc<Resources>,
+    /// Deflates the resources, leaving it to the host to inflate them.
+    pub compress_resources: bool,
+}
+
+pub struct Translation{
+    pub wasm: Vec<u8>,
+    /// Lines of the Java sources the code of the module was lowered from.
+    pub source_map: SourceMap,
+    pub natives: Natives,
+    /// Methods that couldn't be lowered, calling them traps.
+    pub failures: Vec<(MethodKey, anyhow::Error)>,
+    /// What the optimizer did with each call it looked at.
+    pub decisions: Vec<Decision>,
+    /// Checks removed from the methods lowered through their SSA form.
+    pub checks: Vec<CheckReport>,
+    /// Number of methods whose lowered body came from the cache.
+    pub cached: usize,
+    /// Every module of a split translation, the core first. `wasm` and
+    /// `source_map` are then those of the core.
+    pub modules: Vec<LinkedModule>,
+}
+
+/// Translates every method of the program.
+///
+/// The entry methods are exported under their [`MethodKey`] display name, and
+/// their classes are initialized when the module is instantiated. Exceptions
+/// escaping them or the initializers are printed by `Throwable.uncaught`.
+pub fn translate(program: &Program, strings: &StringPool, entries: &[MethodKey], options: &Options) -> anyhow::Result<Translation>{
+    if options.lazy && options.units.is_none(){
+        bail!("Lazy loading needs the units to load");
+    }
+    for s in STRINGS{
+        strings.intern_units(&s.encode_utf16().collect::<Vec<_>>());
+    }
+    for class in program.classes(){
+        for (_, site) in indy::call_sites(class)?{
+            if let Ok(CallSite::Concat(concat)) = site{
+                for piece in concat.pieces{
+                    if let Piece::Literal(units) = piece{
+                        strings.intern_units(&units);
+                    }
+                }
+            }
+        }
+    }
+    TraceTable::intern_names(program, strings)?;
+    Layouts::intern_names(program, strings)?;
+    options.resources.intern_names(strings);
+    let mut segment = DataSegment::new(layout::DATA_BASE);
+    let string_table = strings.layout(&mut segment);
+    let init = InitPlan::analyze(program)?;
+    let mut layouts = Layouts::compute(program)?;
+    let mut module = ModuleBuilder::new();
+
+    let mut natives = Natives::collect(program)?;
+    natives.import_all(&mut module, options.split_longs)?;
+    let imports = Runtime::import(&mut module);
+    let mut resources = ResourceIndex::import(&mut module, &options.resources, options.compress_resources)?;
+    let load_chunk = options.lazy.then(|| Chunks::import(&mut module));
+    let high_bits = options.split_longs.then(|| module.add_global(ValType::I32, true, ConstExpr::i32_const(0)));
+    if let Some(high_bits) = high_bits{
+        natives.define_thunks(&mut module, high_bits)?;
+    }
+    // Threads sharing the memory share the statics too.
+    let shared = (options.monitors == Monitors::Atomic).then_some(&mut segment);
+    let statics = Statics::declare(program, &init, &string_table, &mut module, shared)?;
+
+    let mut methods = Vec::new();
+    let mut trace = TraceTable::new();
+    for class in program.classes(){
+        for method in class.methods.iter(){
+            let is_static = method.flags.contains(AccessFlags::STATIC);
+            let (params, results) = signature(&method.descriptor, is_static)?;
+            // Virtual calls need the type even if no method has the signature.
+            module.type_index(&params, &results);
+            if method.flags.intersects(AccessFlags::NATIVE | AccessFlags::ABSTRACT){
+                continue;
+            }
+            let key = MethodKey::new(&class.name, &method.name, &method.descriptor);
+            // Initializers that need to run have been declared with the statics.
+            let index = match &*method.name == CLINIT {
+                true => match module.function(&key) {
+                    Some(index) => index,
+                    None => continue
+                },
+                false => module.declare_function(key, &params, &results)
+            };
+            trace.add(class, method)?;
+            methods.push((class, method, index));
+        }
+    }
+
+    trace.declare_natives(&mut module, program)?;
+    resources.declare_natives(&mut module, program)?;
+    layouts.declare_natives(&mut module, program);
+    layouts.write(program, &module, &string_table, &mut segment)?;
+    trace.write(program, &layouts, &string_table, &mut segment)?;
+    resources.write(&string_table, &mut segment)?;
+    let runtime = Runtime::declare(&mut module, imports, segment.end(), options.monitors);
+    runtime.define(&mut module, program, &layouts, &string_table);
+    trace.define_natives(&mut module, &runtime, &string_table)?;
+    resources.define_natives(&mut module, &runtime);
+    layouts.define_natives(&mut module);
+    let uncaught = module.function(&trace::uncaught());
+    let chunks = match (load_chunk, &options.units) {
+        (Some(load), Some(units)) => {
+            // What the runtime and the start and entry functions call, and
+            // the classes of objects that exist without a `new`.
+            let mut startup = entries.iter().map(|e| e.class.clone()).collect::<Vec<_>>();
+            startup.extend(module.called_methods()?.into_iter().map(|key| key.class));
+            startup.extend([&*trace::uncaught().class, b"java/lang/Object", b"java/lang/String"].map(Box::from));
+            Some(Chunks::new(program, units.clone(), startup, &mut module, load))
+        }
+        _ => None
+    };
+    let start = module.declare(&[], &[]);
+    let optimizer = Optimizer::new(program, &layouts, &string_table, &options.optimizations)?;
+
+    let mut bodies = Vec::with_capacity(methods.len() + 1);
+    let mut debug = Vec::with_capacity(methods.len());
+    let mut failures = Vec::new();
+    let mut cached = 0;
+    {
+        let env = Env{
+            program,
+            strings: &string_table,
+            init: &init,
+            statics: &statics,
+            module: &module,
+            layouts: &layouts,
+            runtime: &runtime,
+            optimizer: &optimizer,
+            trace: &trace,
+            cache: options.cache.as_deref(),
+            chunks: chunks.as_ref()
+        };
+        let cache = options.cache.as_deref().map(|cache| (cache, cache::environment(&env, options)));
+
+        for (class, method, index) in methods{
+            let cache = match cache {
+                Some((cache, environment)) => Some((cache, cache::references(&env, environment, class, method)?)),
+                None => None
+            };
+            if let Some(found) = cache.and_then(|(cache, references)| cache.lowered(class, method, references)){
+                optimizer.replay(found.decisions, found.checks);
+                bodies.push((index, found.body));
+                debug.push((index, debug::source_path(class), found.lines, found.locals));
+                cached += 1;
+                continue;
+            }
+            let logged = optimizer.log_len();
+            match lower_method(&env, class, method) {
+                Ok(lowered) => {
+                    let body = body_bytes(&lowered.body);
+                    if let Some((cache, references)) = cache{
+                        let (decisions, checks) = optimizer.logged_since(logged);
+                        cache.store_lowered(class, method, references, &CachedMethod{
+                            body: body.clone(),
+                            lines: lowered.lines.clone(),
+                            locals: lowered.locals.clone(),
+                            decisions,
+                            checks
+                        })?;
+                    }
+                    bodies.push((index, body));
+                    debug.push((index, debug::source_path(class), lowered.lines, lowered.locals));
+                }
+                Err(e) => {
+                    let mut body = Function::new([]);
+                    body.instruction(&Instruction::Unreachable);
+                    body.instruction(&Instruction::End);
+                    bodies.push((index, body_bytes(&body)));
+                    failures.push((MethodKey::new(&class.name, &method.name, &method.descriptor), e));
+                }
+            }
+        }
+
+        let mut out = runtime.start_thread(&segment);
+        let mut initialized = HashSet::new();
+        for entry in entries{
+            emit_barrier(&env, &mut initialized, &mut out, &entry.class);
+        }
+        if let Some(uncaught) = uncaught{
+            out.extend(trace::report_uncaught(runtime.exception, uncaught, 0));
+        }
+        out.push(Instruction::End);
+        let mut body = Function::new([(1, ValType::I32)]);
+        for instruction in out.iter(){
+            body.instruction(instruction);
+        }
+        bodies.push((start, body_bytes(&body)));
+    }
+
+    for (index, body) in bodies{
+        module.define_raw(index, body);
+    }
+    for (index, source, lines, locals) in debug{
+        module.name_locals(index, locals);
+        if let Some(source) = source{
+            module.map_lines(index, &source, lines);
+        }
+    }
+    module.name(start, "start");
+    module.set_start(start);
+    if let Some(url) = &options.source_map_url{
+        module.set_source_map_url(url);
+    }
+
+    for entry in entries{
+        let Some(mut function) = module.function(entry) else {
+            bail!("Entry point {} is not part of the program", entry);
+        };
+        if let Some(uncaught) = uncaught{
+            function = trace::catch_uncaught(&mut module, runtime.exception, uncaught, function);
+            module.name(function, &format!("entry {}", entry));
+        }
+        match high_bits {
+            Some(high_bits) => natives::export_split(&mut module, &entry.to_string(), function, high_bits),
+            None => module.export(&entry.to_string(), ExportKind::Func, function)
+        }
+    }
+    if let Some(high_bits) = high_bits{
+        module.export(HIGH_BITS_EXPORT, ExportKind::Global, high_bits);
+    }
+    module.export(EXCEPTION_EXPORT, ExportKind::Global, runtime.exception);
+    if options.monitors == Monitors::Atomic{
+        module.export(THREAD_EXPORT, ExportKind::Global, runtime.thread);
+    }
+
+    let pages = runtime.initial_end().div_ceil(PAGE_SIZE);
+    let (decisions, checks) = optimizer.into_logs();
+    let (wasm, source_map, modules) = match chunks.as_ref().map(Chunks::units).or(options.units.as_ref()) {
+        Some(units) => {
+            let modules = module.finish_split(&segment, pages.max(1), units)?;
+            (modules[0].wasm.clone(), modules[0].source_map.clone(), modules)
+        }
+        None => {
+            let (wasm, source_map) = module.finish(&segment, pages.max(1));
+            (wasm, source_map, Vec::new())
+        }
+    };
+    Ok(Translation{
+        wasm,
+        source_map,
+        natives,
+        failures,
+        decisions,
+        checks,
+        cached,
+        modules
+    })
+}
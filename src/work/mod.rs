@@ -1,9 +1,5 @@
-use std::{sync::atomic::AtomicUsize, array};
-
-use tokio::sync::Mutex;
-
 pub type ClassIdentifier = Box<[u8]>;
 
 pub enum Work{
     ParseClass(ClassIdentifier),
-}
\ No newline at end of file
+}
@@ -0,0 +1,126 @@
+//! Translations reusing lowered methods from the cache.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::{fs, sync::Arc};
+
+use harness::{GOLDEN, RUNTIME_JAR};
+
+use wasm_shit::{cache::Cache, classpath::read_jar, data::ParsedClass, program::{MethodKey, Program}, reach::{Reachability, Roots}, strings::StringPool, translate::{translate, Options, Translation}};
+
+/// A program before and after an edit, compiled by `tests/cache/build.sh`.
+const BEFORE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cache/before");
+const AFTER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cache/after");
+
+/// An empty cache in a directory of its own.
+fn cache(test: &str) -> Arc<Cache>{
+    let dir = std::env::temp_dir().join(format!("wasm-shit-cache-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    Arc::new(Cache::open(dir).unwrap())
+}
+
+fn translate_golden(name: &str, nested: &[&str], options: &Options) -> Translation{
+    translate_in(GOLDEN, name, nested, options)
+}
+
+/// Translates the class and the named nested classes found in `dir`.
+fn translate_in(dir: &str, name: &str, nested: &[&str], options: &Options) -> Translation{
+    let mut classes = read_jar(RUNTIME_JAR).unwrap();
+    for class in std::iter::once(name.to_string()).chain(nested.iter().map(|n| format!("{}${}", name, n))){
+        classes.push(ParsedClass::parse(&fs::read(format!("{}/{}.class", dir, class)).unwrap()).unwrap());
+    }
+    let entries = [MethodKey::new(name.as_bytes(), b"main", b"([Ljava/lang/String;)V")];
+    let roots = Roots{
+        methods: entries.to_vec(),
+        ..Roots::default()
+    };
+    let crawled = Program::new(classes);
+    let program = Reachability::analyze(&crawled, &roots).unwrap().prune(&crawled);
+    let strings = StringPool::collect(&program).unwrap();
+    translate(&program, &strings, &entries, options).unwrap()
+}
+
+/// The optimization log and check report, as written by the command line.
+fn logs(translation: &Translation) -> Vec<String>{
+    translation.decisions.iter().map(|d| d.to_string())
+        .chain(translation.checks.iter().map(|c| c.to_string()))
+        .collect()
+}
+
+#[test]
+fn unchanged_programs_reuse_every_method(){
+    let cache = cache("unchanged");
+    let options = Options{
+        cache: Some(cache.clone()),
+        ..Options::default()
+    };
+    let first = translate_golden("Arrays", &["Point"], &options);
+    assert_eq!(first.cached, 0);
+    let (hits, misses) = cache.stats();
+    let second = translate_golden("Arrays", &["Point"], &options);
+    assert!(second.cached > 0);
+    assert_eq!(cache.stats(), (hits + second.cached, misses), "Some lookups missed");
+
+    assert!(first.wasm == second.wasm, "Methods from the cache changed the module");
+    assert_eq!(logs(&first), logs(&second));
+    let run = harness::run_main(&second.wasm, "Arrays").unwrap();
+    assert_eq!(run.stdout, fs::read_to_string(format!("{}/Arrays.out", GOLDEN)).unwrap());
+}
+
+#[test]
+fn other_programs_translate_as_without_cache(){
+    let options = Options{
+        cache: Some(cache("other")),
+        ..Options::default()
+    };
+    translate_golden("Arrays", &["Point"], &options);
+    let nested = ["Base", "Leaf", "Middle", "Named", "Other", "Shape", "Square"];
+    let cached = translate_golden("Casts", &nested, &options);
+    let uncached = translate_golden("Casts", &nested, &Options::default());
+    assert!(cached.wasm == uncached.wasm, "Methods lowered for another program were reused");
+    assert_eq!(logs(&cached), logs(&uncached));
+}
+
+#[test]
+fn options_are_part_of_the_key(){
+    let cache = cache("options");
+    translate_golden("Longs", &[], &Options{
+        cache: Some(cache.clone()),
+        ..Options::default()
+    });
+    let split_longs = Options{
+        split_longs: true,
+        ..Options::default()
+    };
+    let cached = translate_golden("Longs", &[], &Options{
+        cache: Some(cache),
+        ..split_longs.clone()
+    });
+    assert_eq!(cached.cached, 0);
+    assert!(cached.wasm == translate_golden("Longs", &[], &split_longs).wasm);
+}
+
+#[test]
+fn edits_only_invalidate_the_methods_they_reach(){
+    let cache = cache("edits");
+    let options = Options{
+        cache: Some(cache.clone()),
+        ..Options::default()
+    };
+    let nested = ["Counter", "Edited"];
+    translate_in(BEFORE, "Edits", &nested, &options);
+    let (hits, misses) = cache.stats();
+    let edited = translate_in(AFTER, "Edits", &nested, &options);
+
+    // `Edited` got a field and new code, and `main` allocates it and moved
+    // down a line. Those three methods miss for their entry stacks and their
+    // bodies, the methods of `Counter` and the runtime are reused.
+    assert!(edited.cached > 0);
+    assert_eq!(cache.stats(), (hits + edited.cached, misses + 2 * 3));
+    let uncached = translate_in(AFTER, "Edits", &nested, &Options::default());
+    assert!(edited.wasm == uncached.wasm, "Methods from before the edit changed the module");
+    assert_eq!(logs(&edited), logs(&uncached));
+    let run = harness::run_main(&edited.wasm, "Edits").unwrap();
+    assert_eq!(run.stdout, "7\n11\n");
+}
@@ -0,0 +1,33 @@
+// The program after the edit, which adds a field to Edits.Edited and changes its code.
+public class Edits {
+    static class Counter {
+        int count;
+
+        void add(int n) {
+            count += n;
+        }
+
+        int get() {
+            return count;
+        }
+    }
+
+    static class Edited {
+        int value;
+        int extra = 1;
+
+        int twice() {
+            return value * 2 + extra;
+        }
+    }
+
+    public static void main(String[] args) {
+        Counter counter = new Counter();
+        counter.add(3);
+        counter.add(4);
+        System.out.println(counter.get());
+        Edited edited = new Edited();
+        edited.value = 5;
+        System.out.println(edited.twice());
+    }
+}
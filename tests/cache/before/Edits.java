@@ -0,0 +1,32 @@
+// The program before the edit, tests/cache/after has the edited version.
+public class Edits {
+    static class Counter {
+        int count;
+
+        void add(int n) {
+            count += n;
+        }
+
+        int get() {
+            return count;
+        }
+    }
+
+    static class Edited {
+        int value;
+
+        int twice() {
+            return value * 2;
+        }
+    }
+
+    public static void main(String[] args) {
+        Counter counter = new Counter();
+        counter.add(3);
+        counter.add(4);
+        System.out.println(counter.get());
+        Edited edited = new Edited();
+        edited.value = 5;
+        System.out.println(edited.twice());
+    }
+}
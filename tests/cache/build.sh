@@ -0,0 +1,8 @@
+#!/bin/sh
+# Compiles a program before and after an edit, for the cache tests.
+set -e
+cd "$(dirname "$0")"
+for version in before after; do
+    rm -f "$version"/*.class
+    javac --release 17 -encoding UTF-8 -d "$version" "$version"/*.java
+done
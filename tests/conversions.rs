@@ -0,0 +1,199 @@
+//! Property tests of the `x2y` conversions against a model of what the JVM
+//! computes, through the lowering with and without the SSA form and through
+//! constant folding.
+//!
+//! The class is compiled by `tests/conversions/build.sh`.
+
+// Only instantiation and the runtime are used here.
+#[allow(dead_code)]
+mod harness;
+
+use std::{cell::RefCell, fs};
+
+use harness::RUNTIME_JAR;
+
+use noak::reader::attributes::RawInstruction;
+use proptest::{prelude::*, sample::select, test_runner::{Config, TestRunner}};
+use wasmi::{core::{F32, F64}, Instance, Store, Value};
+use wasm_shit::{classpath::read_jar, data::ParsedClass, ir::{self, Constant}, optimize::Optimizations, program::{MethodKey, Program}, reach::{Reachability, Roots}, strings::StringPool, translate::{translate, Options}};
+
+const CONVERSIONS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/conversions/Conversions.class");
+const CLASS: &[u8] = b"Conversions";
+
+/// The conversions by the type they take, with the method doing each.
+fn conversions(from: char) -> Vec<(RawInstruction<'static>, &'static str, char)>{
+    let all = [
+        (RawInstruction::I2L, "i2l", 'I', 'J'),
+        (RawInstruction::I2F, "i2f", 'I', 'F'),
+        (RawInstruction::I2D, "i2d", 'I', 'D'),
+        (RawInstruction::I2B, "i2b", 'I', 'B'),
+        (RawInstruction::I2C, "i2c", 'I', 'C'),
+        (RawInstruction::I2S, "i2s", 'I', 'S'),
+        (RawInstruction::L2I, "l2i", 'J', 'I'),
+        (RawInstruction::L2F, "l2f", 'J', 'F'),
+        (RawInstruction::L2D, "l2d", 'J', 'D'),
+        (RawInstruction::F2I, "f2i", 'F', 'I'),
+        (RawInstruction::F2L, "f2l", 'F', 'J'),
+        (RawInstruction::F2D, "f2d", 'F', 'D'),
+        (RawInstruction::D2I, "d2i", 'D', 'I'),
+        (RawInstruction::D2L, "d2l", 'D', 'J'),
+        (RawInstruction::D2F, "d2f", 'D', 'F'),
+    ];
+    all.into_iter().filter(|c| c.2 == from).map(|(instruction, name, _, to)| (instruction, name, to)).collect()
+}
+
+fn key(name: &str, from: char, to: char) -> MethodKey{
+    MethodKey::new(CLASS, name.as_bytes(), format!("({}){}", from, to).as_bytes())
+}
+
+/// Rounds towards zero and saturates, NaN becomes 0.
+fn truncate(x: f64, min: f64, max: f64) -> Option<f64>{
+    if x.is_nan(){
+        None
+    }
+    else{
+        Some(x.trunc().clamp(min, max))
+    }
+}
+
+/// What the JVM computes, following JLS 5.1.2 and 5.1.3.
+fn model(instruction: &RawInstruction, x: Constant) -> Constant{
+    use Constant::*;
+    // The bounds of int and long as doubles, both are exact.
+    let (int_min, int_max) = (-2f64.powi(31), 2f64.powi(31) - 1.0);
+    let long_min = -2f64.powi(63);
+    let to_long = |x: f64| match x.is_nan() {
+        true => 0,
+        false if x.trunc() >= -long_min => i64::MAX,
+        false if x.trunc() <= long_min => i64::MIN,
+        false => x.trunc() as i64
+    };
+    match (instruction, x) {
+        (RawInstruction::I2L, Int(x)) => Long(x.into()),
+        // Widening to a float rounds to nearest.
+        (RawInstruction::I2F, Int(x)) => Float(x as f32),
+        (RawInstruction::I2D, Int(x)) => Double(x.into()),
+        // Narrowing keeps the low bits, then byte and short sign extend.
+        (RawInstruction::I2B, Int(x)) => Int(((x & 0xFF) ^ 0x80) - 0x80),
+        (RawInstruction::I2C, Int(x)) => Int(x & 0xFFFF),
+        (RawInstruction::I2S, Int(x)) => Int(((x & 0xFFFF) ^ 0x8000) - 0x8000),
+        (RawInstruction::L2I, Long(x)) => Int((x & 0xFFFF_FFFF) as u32 as i32),
+        (RawInstruction::L2F, Long(x)) => Float(x as f32),
+        (RawInstruction::L2D, Long(x)) => Double(x as f64),
+        (RawInstruction::F2I, Float(x)) => Int(truncate(x.into(), int_min, int_max).map_or(0, |t| t as i32)),
+        (RawInstruction::F2L, Float(x)) => Long(to_long(x.into())),
+        (RawInstruction::F2D, Float(x)) => Double(x.into()),
+        (RawInstruction::D2I, Double(x)) => Int(truncate(x, int_min, int_max).map_or(0, |t| t as i32)),
+        (RawInstruction::D2L, Double(x)) => Long(to_long(x)),
+        (RawInstruction::D2F, Double(x)) => Float(x as f32),
+        (instruction, x) => panic!("{:?} doesn't take {}", instruction, x)
+    }
+}
+
+/// Equal, with every NaN being the same.
+fn same(a: Constant, b: Constant) -> bool{
+    match (a, b) {
+        (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+        (Constant::Double(a), Constant::Double(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+        (a, b) => a == b
+    }
+}
+
+fn to_wasm(x: Constant) -> Value{
+    match x {
+        Constant::Int(i) => Value::I32(i),
+        Constant::Long(l) => Value::I64(l),
+        Constant::Float(f) => Value::F32(F32::from(f)),
+        Constant::Double(d) => Value::F64(F64::from(d)),
+        Constant::Null => Value::I32(0),
+    }
+}
+
+fn from_wasm(value: &Value) -> Constant{
+    match value {
+        Value::I32(i) => Constant::Int(*i),
+        Value::I64(l) => Constant::Long(*l),
+        Value::F32(f) => Constant::Float(f.to_float()),
+        Value::F64(d) => Constant::Double(d.to_float()),
+        x => panic!("Unexpected result {:?}", x)
+    }
+}
+
+/// Modules exporting every conversion method, lowered with and without the SSA form.
+fn modules() -> Vec<(&'static str, (Store<harness::State>, Instance))>{
+    let mut classes = read_jar(RUNTIME_JAR).unwrap();
+    classes.push(ParsedClass::parse(&fs::read(CONVERSIONS).unwrap()).unwrap());
+    let entries = "IJFD".chars()
+        .flat_map(|from| conversions(from).into_iter().map(move |(_, name, to)| key(name, from, to)))
+        .collect::<Vec<_>>();
+    let roots = Roots{
+        methods: entries.clone(),
+        ..Roots::default()
+    };
+    let crawled = Program::new(classes);
+    let program = Reachability::analyze(&crawled, &roots).unwrap().prune(&crawled);
+    let strings = StringPool::collect(&program).unwrap();
+
+    let without_ssa = Options{
+        optimizations: Optimizations{
+            ssa: false,
+            ..Optimizations::default()
+        },
+        ..Options::default()
+    };
+    [("ssa", Options::default()), ("stack", without_ssa)].into_iter().map(|(label, options)|{
+        let translation = translate(&program, &strings, &entries, &options).unwrap();
+        assert!(translation.failures.is_empty(), "{:?}", translation.failures);
+        (label, harness::instantiate(&translation.wasm).unwrap())
+    }).collect()
+}
+
+/// Checks every conversion taking `from` on the inputs, which are tried along
+/// with the edge cases.
+fn check(from: char, edges: Vec<Constant>, inputs: impl Strategy<Value = Constant>){
+    // The runner takes a `Fn`, calling into the modules needs them mutably.
+    let modules = RefCell::new(modules());
+    let strategy = prop_oneof![select(edges), inputs];
+    for (instruction, name, to) in conversions(from){
+        let export = key(name, from, to).to_string();
+        let mut runner = TestRunner::new(Config::with_cases(2000));
+        runner.run(&strategy, |x|{
+            let expected = model(&instruction, x);
+            let folded = ir::fold(&instruction, &[x]);
+            prop_assert!(folded.is_some_and(|f| same(f, expected)), "{} of {} folds to {:?}, not {}", name, x, folded, expected);
+            for (label, (store, instance)) in modules.borrow_mut().iter_mut(){
+                let function = instance.get_func(&*store, &export).unwrap();
+                let result_type = function.ty(&*store).results()[0];
+                let mut results = [Value::default(result_type)];
+                function.call(&mut *store, &[to_wasm(x)], &mut results).unwrap();
+                let result = from_wasm(&results[0]);
+                prop_assert!(same(result, expected), "{} ({}) of {} is {}, not {}", name, label, x, result, expected);
+            }
+            Ok(())
+        }).unwrap();
+    }
+}
+
+#[test]
+fn from_int(){
+    let edges = [0, 1, -1, 127, 128, -128, -129, 255, 32767, 32768, -32768, 65535, 65536, 16777217, i32::MIN, i32::MAX];
+    check('I', edges.map(Constant::Int).to_vec(), any::<i32>().prop_map(Constant::Int));
+}
+
+#[test]
+fn from_long(){
+    let edges = [0, 1, -1, (1 << 32) - 1, 1 << 32, 1 << 53 | 1, (1 << 24) + 1, i64::MIN, i64::MAX];
+    check('J', edges.map(Constant::Long).to_vec(), any::<i64>().prop_map(Constant::Long));
+}
+
+#[test]
+fn from_float(){
+    let edges = [0.0, -0.0, 0.5, -0.5, 1.5, -1.5, 2147483648.0, -2147483648.0, -2147483904.0, 9.223372e18, -9.223372e18, 1e30, f32::MIN_POSITIVE, f32::MAX, f32::MIN, f32::INFINITY, f32::NEG_INFINITY, f32::NAN];
+    check('F', edges.map(Constant::Float).to_vec(), any::<f32>().prop_map(Constant::Float));
+}
+
+#[test]
+fn from_double(){
+    let edges = [0.0, -0.0, 0.9999999999, -0.9999999999, 2147483647.5, 2147483648.0, -2147483648.5, -2147483649.0, 9.223372036854776e18, -9.223372036854776e18, 3.4028235677973366e38, 1e-46, 1e300, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+    check('D', edges.map(Constant::Double).to_vec(), any::<f64>().prop_map(Constant::Double));
+}
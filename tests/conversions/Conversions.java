@@ -0,0 +1,21 @@
+// Every x2y instruction, each method compiles to a load, the conversion and a return.
+public class Conversions {
+    static long i2l(int x) { return x; }
+    static float i2f(int x) { return x; }
+    static double i2d(int x) { return x; }
+    static byte i2b(int x) { return (byte) x; }
+    static char i2c(int x) { return (char) x; }
+    static short i2s(int x) { return (short) x; }
+    static int l2i(long x) { return (int) x; }
+    static float l2f(long x) { return x; }
+    static double l2d(long x) { return x; }
+    static int f2i(float x) { return (int) x; }
+    static long f2l(float x) { return (long) x; }
+    static double f2d(float x) { return x; }
+    static int d2i(double x) { return (int) x; }
+    static long d2l(double x) { return (long) x; }
+    static float d2f(double x) { return (float) x; }
+
+    public static void main(String[] args) {
+    }
+}
@@ -0,0 +1,6 @@
+#!/bin/sh
+# Compiles the class the conversion tests call into.
+set -e
+cd "$(dirname "$0")"
+rm -f *.class
+javac --release 17 -encoding UTF-8 -d . *.java
@@ -0,0 +1,147 @@
+//! Names and source maps of translated golden classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::collections::HashMap;
+
+use harness::{leb, RUNTIME_JAR};
+
+use wasm_shit::{classpath::read_jar, debug::SourceMap, program::{MethodKey, Program}, reach::{Reachability, Roots}, strings::StringPool, translate::{translate, Options}};
+
+fn translate_arrays() -> (Vec<u8>, SourceMap){
+    let mut classes = read_jar(RUNTIME_JAR).unwrap();
+    for name in ["Arrays", "Arrays$Point"]{
+        classes.push(harness::golden(name));
+    }
+    let entries = [MethodKey::new(b"Arrays", b"main", b"([Ljava/lang/String;)V")];
+    let roots = Roots{
+        methods: entries.to_vec(),
+        ..Roots::default()
+    };
+    let crawled = Program::new(classes);
+    let program = Reachability::analyze(&crawled, &roots).unwrap().prune(&crawled);
+    let strings = StringPool::collect(&program).unwrap();
+    let options = Options{
+        source_map_url: Some("Arrays.wasm.map".into()),
+        ..Options::default()
+    };
+    let translation = translate(&program, &strings, &entries, &options).unwrap();
+    (translation.wasm, translation.source_map)
+}
+
+fn name(bytes: &[u8], at: &mut usize) -> String{
+    let len = leb(bytes, at) as usize;
+    *at += len;
+    String::from_utf8(bytes[*at - len..*at].to_vec()).unwrap()
+}
+
+/// Custom sections by name.
+fn custom_sections(wasm: &[u8]) -> HashMap<String, &[u8]>{
+    let mut sections = HashMap::new();
+    let mut at = 8;
+    while at < wasm.len(){
+        let id = wasm[at];
+        at += 1;
+        let size = leb(wasm, &mut at) as usize;
+        let end = at + size;
+        if id == 0{
+            let name = name(wasm, &mut at);
+            sections.insert(name, &wasm[at..end]);
+        }
+        at = end;
+    }
+    sections
+}
+
+/// Ranges of the function bodies in the module.
+fn bodies(wasm: &[u8]) -> Vec<std::ops::Range<usize>>{
+    let mut at = 8;
+    while at < wasm.len(){
+        let id = wasm[at];
+        at += 1;
+        let size = leb(wasm, &mut at) as usize;
+        if id == 10{
+            return (0..leb(wasm, &mut at)).map(|_|{
+                let len = leb(wasm, &mut at) as usize;
+                at += len;
+                at - len..at
+            }).collect();
+        }
+        at += size;
+    }
+    Vec::new()
+}
+
+/// Function names and the names of their locals from the `name` section.
+fn names(section: &[u8]) -> (HashMap<u32, String>, HashMap<u32, HashMap<u32, String>>){
+    let (mut functions, mut locals) = (HashMap::new(), HashMap::new());
+    let mut at = 0;
+    while at < section.len(){
+        let id = section[at];
+        at += 1;
+        let size = leb(section, &mut at) as usize;
+        let end = at + size;
+        let map = |at: &mut usize| (0..leb(section, at)).map(|_| (leb(section, at), name(section, at))).collect::<HashMap<_, _>>();
+        match id {
+            1 => functions = map(&mut at),
+            2 => locals = (0..leb(section, &mut at)).map(|_| (leb(section, &mut at), map(&mut at))).collect(),
+            _ => ()
+        }
+        at = end;
+    }
+    (functions, locals)
+}
+
+#[test]
+fn names_functions_and_locals(){
+    let (wasm, _) = translate_arrays();
+    let sections = custom_sections(&wasm);
+    assert_eq!(sections["sourceMappingURL"], b"\x0fArrays.wasm.map");
+
+    let (functions, locals) = names(sections["name"]);
+    let index = |name: &str| *functions.iter().find(|(_, n)| *n == name).unwrap_or_else(|| panic!("No function {} in {:?}", name, functions)).0;
+    for name in ["runtime.alloc", "runtime.throw java/lang/NullPointerException", "start", "Arrays.main([Ljava/lang/String;)V"]{
+        index(name);
+    }
+    // In SSA form only the parameters are left of the JVM variables.
+    let element = locals[&index("Arrays.element([II)I")].values().cloned().collect::<Vec<_>>();
+    for name in ["values", "index", "pc"]{
+        assert!(element.iter().any(|n| n == name), "No local {} in {:?}", name, element);
+    }
+    let sum = locals[&index("Arrays.sum([I)I")].values().cloned().collect::<Vec<_>>();
+    assert!(sum.iter().any(|n| n == "values"), "{:?}", sum);
+}
+
+#[test]
+fn maps_code_to_lines(){
+    let (wasm, map) = translate_arrays();
+    assert!(map.sources.iter().any(|s| s == "Arrays.java"), "{:?}", map.sources);
+    assert!(map.sources.iter().any(|s| s == "java/lang/String.java"), "{:?}", map.sources);
+    assert!(map.mappings.windows(2).all(|w| w[0].0 < w[1].0));
+    let bodies = bodies(&wasm);
+    for (offset, _, _) in map.mappings.iter(){
+        assert!(bodies.iter().any(|b| b.contains(&(*offset as usize))), "{} is outside of every function", offset);
+    }
+
+    // `sum += values[i];` is on line 14 of Arrays.java.
+    let source = map.sources.iter().position(|s| s == "Arrays.java").unwrap() as u32;
+    let lines = map.mappings.iter().filter(|(_, s, _)| *s == source).map(|(_, _, l)| *l).collect::<Vec<_>>();
+    assert!(lines.contains(&14), "{:?}", lines);
+    let (offset, _, _) = map.mappings.iter().find(|(_, s, l)| *s == source && *l == 14).unwrap();
+    assert_eq!(map.lookup(*offset), Some(("Arrays.java", 14)));
+    assert_eq!(map.lookup(*offset + 1).map(|(s, _)| s), Some("Arrays.java"));
+}
+
+#[test]
+fn encodes_mappings(){
+    let map = SourceMap{
+        sources: vec!["A.java".into(), "b/B.java".into()],
+        mappings: vec![(100, 0, 1), (116, 0, 17), (200, 1, 3)],
+    };
+    let json = map.json();
+    assert_eq!(json["version"], 3);
+    assert_eq!(json["sources"][1], "b/B.java");
+    // Offsets 100, +16, +84; sources 0, 0, +1; lines 0, +16, -14.
+    assert_eq!(json["mappings"], "oGAAA,gBAgBA,oFCdA");
+}
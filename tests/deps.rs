@@ -0,0 +1,43 @@
+//! Dependency graphs of the runtime and the golden test classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use harness::RUNTIME_JAR;
+
+use wasm_shit::{classpath::read_jar, deps::{DependencyGraph, Kind}};
+
+#[test]
+fn edges_have_kinds(){
+    let graph = DependencyGraph::build(harness::golden_with_nested("Casts").iter()).unwrap();
+    assert_eq!(graph.kinds(b"Casts$Leaf", b"Casts$Middle"), [Kind::Superclass]);
+    assert_eq!(graph.kinds(b"Casts$Leaf", b"Casts$Square"), [Kind::Superclass]);
+    assert!(graph.kinds(b"Casts", b"Casts$Leaf").contains(&Kind::Instantiation));
+    assert!(graph.kinds(b"Casts", b"java/io/PrintStream").contains(&Kind::FieldType));
+    assert!(graph.kinds(b"Casts", b"Casts$Other").contains(&Kind::Instantiation));
+    assert!(graph.kinds(b"Casts$Leaf", b"Casts$Leaf").is_empty());
+}
+
+#[test]
+fn cycles_are_components(){
+    let graph = DependencyGraph::build(read_jar(RUNTIME_JAR).unwrap().iter()).unwrap();
+    let cycles = graph.cycles();
+    assert!(cycles.iter().any(|c| c.iter().any(|n| &**n == b"java/lang/Object") && c.iter().any(|n| &**n == b"java/lang/String")), "{:?}", cycles);
+    for cycle in cycles.iter(){
+        assert!(cycle.len() > 1);
+        assert!(cycle.windows(2).all(|w| w[0] < w[1]));
+    }
+    // Exceptions only depend on the cycle, they aren't part of it.
+    assert!(!cycles.iter().flatten().any(|n| &**n == b"java/lang/NullPointerException"));
+    assert!(graph.report().contains("java/lang/Object, java/lang/String"), "{}", graph.report());
+}
+
+#[test]
+fn exports_every_edge(){
+    let graph = DependencyGraph::build(harness::golden_with_nested("Casts").iter()).unwrap();
+    let json = graph.json();
+    let edges = json["edges"].as_array().unwrap();
+    assert_eq!(edges.len(), graph.dot().lines().filter(|l| l.contains(" -> ")).count());
+    assert!(edges.iter().any(|e| e["from"] == "Casts$Middle" && e["to"] == "Casts$Base" && e["kinds"][0] == "superclass"));
+    assert!(graph.dot().contains("\"Casts$Leaf\" -> \"Casts$Middle\" [label=\"superclass\", style=bold];"));
+}
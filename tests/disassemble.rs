@@ -0,0 +1,62 @@
+//! Listings of the runtime and the golden test classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::fs;
+
+use harness::{golden, GOLDEN, RUNTIME_JAR};
+
+use wasm_shit::{classpath::read_jar, data::ParsedClass, disassemble::disassemble};
+
+#[test]
+fn every_frame_is_inferred(){
+    let mut classes = read_jar(RUNTIME_JAR).unwrap();
+    for entry in fs::read_dir(GOLDEN).unwrap(){
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|e| e == "class"){
+            classes.push(ParsedClass::parse(&fs::read(path).unwrap()).unwrap());
+        }
+    }
+    for class in classes.iter(){
+        let listing = disassemble(class).unwrap();
+        assert!(!listing.contains("!!"), "{}", listing);
+        assert!(!listing.lines().any(|l| l.trim_start().starts_with("block") && l.ends_with(" unreachable")), "{}", listing);
+    }
+}
+
+#[test]
+fn annotates_instructions(){
+    let listing = disassemble(&golden("Arrays")).unwrap();
+    let lines = listing.lines().map(|l| l.split_whitespace().collect::<Vec<_>>().join(" ")).collect::<Vec<_>>();
+    for expected in [
+        "static sum([I)I",
+        "block 4 -> [22 10]",
+        "13 iaload stack [IAI] locals [AII]",
+        "16 iinc 2, 1 stack [] locals [AII]",
+        "try #0 [0, 3) -> 4 catch java/lang/ArrayIndexOutOfBoundsException",
+        "block 4 -> [] handler #0",
+        "end try #0",
+        "4 astore_2 stack [A] locals [AI-]",
+        "14 invokevirtual java/io/PrintStream.println:(Ljava/lang/String;)V stack [AA] locals [AIA]",
+    ]{
+        assert!(lines.iter().any(|l| l == expected), "No line {:?} in\n{}", expected, listing);
+    }
+}
+
+#[test]
+fn prints_jvm_mnemonics(){
+    let listing = disassemble(&golden("Shuffles")).unwrap();
+    let lines = listing.lines().map(|l| l.split_whitespace().collect::<Vec<_>>().join(" ")).collect::<Vec<_>>();
+    for expected in [
+        "18 dup_x1 stack [AI] locals [AAAA--------]",
+        "28 dup2_x1 stack [AJ] locals [AAAAI-------]",
+        "40 dup_x2 stack [AII] locals [AAAAIJ------]",
+        "52 dup2_x2 stack [AIJ] locals [AAAAIJ-I----]",
+        "61 pop2 stack [J] locals [AAAAIJ-IJ---]",
+        "66 wide iinc 10, 1000 stack [] locals [AAAAIJ-IJ-I-]",
+        "72 aconst_null stack [] locals [AAAAIJ-IJ-I-]",
+    ]{
+        assert!(lines.iter().any(|l| l == expected), "No line {:?} in\n{}", expected, listing);
+    }
+}
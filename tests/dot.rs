@@ -0,0 +1,28 @@
+//! Control flow graphs of the golden test classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use wasm_shit::dot::class_graph;
+
+#[test]
+fn loops_have_back_edges(){
+    let graph = class_graph(&harness::golden("Arrays"), Some("sum"), false).unwrap();
+    for expected in ["label=\"sum([I)I\";", "m0_0 -> m0_4;", "m0_4 -> m0_22;", "m0_4 -> m0_10;", "m0_10 -> m0_4;"]{
+        assert!(graph.lines().any(|l| l.trim() == expected), "No line {:?} in\n{}", expected, graph);
+    }
+    assert!(!graph.contains("in ["), "{}", graph);
+    assert!(!graph.contains("element"), "{}", graph);
+}
+
+#[test]
+fn handlers_have_dashed_edges(){
+    let graph = class_graph(&harness::golden("Arrays"), Some("element([II)I"), true).unwrap();
+    assert!(graph.contains("m0_0 -> m0_4 [style=dashed, label=\"java/lang/ArrayIndexOutOfBoundsException\"];"), "{}", graph);
+    assert!(graph.contains("block 4\\lin [A]\\l    4  astore_2\\l"), "{}", graph);
+}
+
+#[test]
+fn unknown_methods_are_errors(){
+    assert!(class_graph(&harness::golden("Arrays"), Some("missing"), false).is_err());
+}
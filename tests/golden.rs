@@ -0,0 +1,296 @@
+//! Runs the `main` of every class in `tests/golden` and compares its output
+//! with the `.out` file next to it, which is what `java` prints.
+//!
+//! The classes are compiled by `tests/golden/build.sh`.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::{fs, path::Path, sync::Arc};
+
+use harness::{leb, GOLDEN, RUNTIME_JAR};
+
+use wasm_shit::{classpath::read_jar, data::ParsedClass, indy, link::Units, optimize::Optimizations, program::{MethodKey, Program}, reach::{Reachability, Roots}, resources::{Resources, INFLATE}, runtime::{Monitors, MEMORY_IMPORT, RUNTIME_MODULE}, strings::StringPool, translate::{translate, Options, Translation}};
+
+/// Translates the class and its nested classes with the given entry points
+/// besides `main`. If `options` has units without any classes assigned, the
+/// program is split into one per package. The files in `tests/golden/resources`
+/// are embedded as resources.
+fn translate_class(name: &str, entries: &[MethodKey], options: &Options) -> anyhow::Result<Translation>{
+    let mut classes = read_jar(RUNTIME_JAR)?;
+    for entry in fs::read_dir(GOLDEN)?{
+        let path = entry?.path();
+        let Some(file) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        let nested = file.strip_prefix(name).is_some_and(|rest| rest.starts_with('$'));
+        if path.extension().is_some_and(|e| e == "class") && (file == format!("{}.class", name) || nested){
+            classes.push(ParsedClass::parse(&fs::read(&path)?)?);
+        }
+    }
+
+    classes.extend(indy::lambda_classes(&classes)?);
+    let mut entries = entries.to_vec();
+    entries.push(MethodKey::new(name.as_bytes(), b"main", b"([Ljava/lang/String;)V"));
+    let roots = Roots{
+        methods: entries.clone(),
+        ..Roots::default()
+    };
+    let crawled = Program::new(classes);
+    let program = Reachability::analyze(&crawled, &roots)?.prune(&crawled);
+    let strings = StringPool::collect(&program)?;
+    let mut resources = Resources::new();
+    for entry in fs::read_dir(Path::new(GOLDEN).join("resources"))?{
+        let path = entry?.path();
+        let name = format!("resources/{}", path.file_name().and_then(|f| f.to_str()).unwrap_or_default());
+        resources.add(&name, fs::read(&path)?);
+    }
+    let options = Options{
+        resources: Arc::new(resources),
+        units: options.units.as_ref().map(|units| match units.len() {
+            1 => Units::by_package(&program),
+            _ => units.clone()
+        }),
+        ..options.clone()
+    };
+    let translation = translate(&program, &strings, &entries, &options)?;
+    for (method, error) in translation.failures.iter(){
+        if method.class.starts_with(name.as_bytes()){
+            anyhow::bail!("{} failed to translate: {}", method, error);
+        }
+    }
+
+    Ok(translation)
+}
+
+fn run(name: &str, options: &Options) -> anyhow::Result<String>{
+    let translation = translate_class(name, &[], options)?;
+    let run = match options.units {
+        Some(_) if options.lazy => harness::run_lazy_main(&translation.modules, name)?,
+        Some(_) => harness::run_linked_main(&translation.modules, name)?,
+        None => harness::run_main(&translation.wasm, name)?
+    };
+    anyhow::ensure!(run.exception == wasm_shit::layout::NULL, "{} threw an uncaught exception", name);
+    Ok(run.stdout)
+}
+
+#[test]
+fn golden(){
+    let mut names = fs::read_dir(GOLDEN).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "java"))
+        .map(|p| p.file_stem().unwrap().to_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert!(!names.is_empty());
+
+    // Optimized code has to behave the same as unoptimized code.
+    let unoptimized = Options{
+        optimizations: Optimizations{
+            devirtualize: false,
+            inline_limit: 0,
+            ssa: false
+        },
+        ..Options::default()
+    };
+    // Hosts without BigInt see the same behavior.
+    let split_longs = Options{
+        split_longs: true,
+        ..Options::default()
+    };
+    // And so do modules linked from one per package.
+    let split_packages = Options{
+        units: Some(Units::new()),
+        ..Options::default()
+    };
+    // Or loaded one package at a time as the code needs them.
+    let lazy_packages = Options{
+        lazy: true,
+        ..split_packages.clone()
+    };
+    let mut failed = Vec::new();
+    for name in names.iter(){
+        let expected = fs::read_to_string(Path::new(GOLDEN).join(format!("{}.out", name))).unwrap();
+        for (label, options) in [("optimized", &Options::default()), ("unoptimized", &unoptimized), ("split longs", &split_longs), ("split packages", &split_packages), ("lazy packages", &lazy_packages)]{
+            match run(name, options) {
+                Ok(stdout) if stdout == expected => (),
+                Ok(stdout) => failed.push(format!("{} ({}): expected\n{}\ngot\n{}", name, label, expected, stdout)),
+                Err(e) => failed.push(format!("{} ({}): {:?}", name, label, e))
+            }
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n\n"));
+}
+
+#[test]
+fn split_long_exports(){
+    let factorial = MethodKey::new(b"Longs", b"factorial", b"(I)J");
+    let name = factorial.to_string();
+    let options = Options{
+        split_longs: true,
+        ..Options::default()
+    };
+    let wasm = translate_class("Longs", &[factorial], &options).unwrap().wasm;
+    assert_eq!(harness::call_split_long(&wasm, &name, 20).unwrap(), 2432902008176640000);
+    assert_eq!(harness::call_split_long(&wasm, &name, 21).unwrap(), -4249290049419214848);
+}
+
+#[test]
+fn uncaught_exceptions_print_their_trace(){
+    let crash = MethodKey::new(b"Traces", b"crash", b"()V");
+    for options in [Options::default(), Options{split_longs: true, ..Options::default()}]{
+        let wasm = translate_class("Traces", std::slice::from_ref(&crash), &options).unwrap().wasm;
+        let run = harness::run_static(&wasm, &crash.to_string()).unwrap();
+        assert_ne!(run.exception, wasm_shit::layout::NULL);
+        assert_eq!(run.stderr, concat!(
+            "Exception in thread \"main\" Traces$Failure: bottom\n",
+            "\tat Traces.descend(Traces.java:20)\n",
+            "\tat Traces.descend(Traces.java:22)\n",
+            "\tat Traces.crash(Traces.java:60)\n",
+        ));
+    }
+}
+
+#[test]
+fn split_packages_only_import_from_the_core(){
+    let options = Options{
+        units: Some(Units::new()),
+        ..Options::default()
+    };
+    let translation = translate_class("Traces", &[], &options).unwrap();
+    let names = translation.modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names[0], wasm_shit::link::CORE);
+    assert!(names.contains(&"java.lang") && names.contains(&"default"), "{:?}", names);
+
+    let engine = wasmi::Engine::default();
+    for module in translation.modules.iter(){
+        let parsed = wasmi::Module::new(&engine, &module.wasm[..]).unwrap();
+        for import in parsed.imports(){
+            assert!(
+                [wasm_shit::link::CORE, wasm_shit::natives::IMPORT_MODULE, wasm_shit::runtime::RUNTIME_MODULE].contains(&import.module()),
+                "{} imports {}.{}", module.name, import.module(), import.name()
+            );
+        }
+    }
+    let traces = translation.modules.iter().find(|m| m.name == "default").unwrap();
+    assert!(traces.source_map.sources.iter().any(|s| s == "Traces.java"));
+
+    let run = harness::run_linked_main(&translation.modules, "Traces").unwrap();
+    assert_eq!(run.stdout, fs::read_to_string(Path::new(GOLDEN).join("Traces.out")).unwrap());
+}
+
+#[test]
+fn chunks_load_when_their_classes_are_first_used(){
+    let mut units = Units::new();
+    units.assign("late", b"Chunks$Late");
+    units.assign("polite", b"Chunks$Polite");
+    units.assign("polite", b"Chunks$Greeter");
+    units.assign("never", b"Chunks$Never");
+    let options = Options{
+        units: Some(units),
+        lazy: true,
+        ..Options::default()
+    };
+    let translation = translate_class("Chunks", &[], &options).unwrap();
+    let id = |name: &str| translation.modules.iter().find(|m| m.name == name).unwrap().id;
+
+    let run = harness::run_lazy_main(&translation.modules, "Chunks").unwrap();
+    assert_eq!(run.stdout, fs::read_to_string(Path::new(GOLDEN).join("Chunks.out")).unwrap());
+    assert_eq!(run.loaded, [id("late"), id("polite")], "Chunks loaded more than once or out of order");
+    assert!(!run.loaded.contains(&id("never")));
+}
+
+#[test]
+fn compressed_resources_are_inflated_by_the_host(){
+    let options = Options{
+        compress_resources: true,
+        ..Options::default()
+    };
+    let compressed = translate_class("Resources", &[], &options).unwrap();
+    let stored = translate_class("Resources", &[], &Options::default()).unwrap();
+    assert!(compressed.wasm.len() < stored.wasm.len(), "{} bytes compressed, {} stored", compressed.wasm.len(), stored.wasm.len());
+
+    let engine = wasmi::Engine::default();
+    let imports = |wasm: &[u8]| wasmi::Module::new(&engine, wasm).unwrap().imports().any(|i| i.module() == wasm_shit::runtime::RUNTIME_MODULE && i.name() == INFLATE);
+    assert!(imports(&compressed.wasm) && !imports(&stored.wasm));
+
+    let run = harness::run_main(&compressed.wasm, "Resources").unwrap();
+    assert_eq!(run.stdout, fs::read_to_string(Path::new(GOLDEN).join("Resources.out")).unwrap());
+}
+
+/// Sections of an encoded module by id, wasmi can't load modules with
+/// shared memories.
+fn sections(wasm: &[u8]) -> Vec<(u8, &[u8])>{
+    let mut sections = Vec::new();
+    let mut at = 8;
+    while at < wasm.len(){
+        let id = wasm[at];
+        at += 1;
+        let size = leb(wasm, &mut at) as usize;
+        sections.push((id, &wasm[at..at + size]));
+        at += size;
+    }
+    sections
+}
+
+#[test]
+fn atomic_monitors_share_an_imported_memory(){
+    let options = Options{
+        monitors: Monitors::Atomic,
+        ..Options::default()
+    };
+    let translation = translate_class("Monitors", &[], &options).unwrap();
+    let sections = sections(&translation.wasm);
+    let section = |id: u8| sections.iter().find(|(i, _)| *i == id).map(|(_, bytes)| *bytes);
+    assert!(section(5).is_none(), "The memory is imported, not defined");
+
+    // The memory is the first import, with the limits flags of a shared memory.
+    let imports = section(2).unwrap();
+    let mut at = 0;
+    leb(imports, &mut at);
+    let mut name = || {
+        let len = leb(imports, &mut at) as usize;
+        at += len;
+        String::from_utf8(imports[at - len..at].to_vec()).unwrap()
+    };
+    assert_eq!((name(), name()), (RUNTIME_MODULE.to_string(), MEMORY_IMPORT.to_string()));
+    assert_eq!(&imports[at..at + 2], [0x02, 0x03], "Shared memory with a maximum");
+
+    // Every instance copies the data in unless another already did.
+    assert_eq!(section(12), Some(&[1][..]), "One data segment for memory.init");
+    let data = section(11).unwrap();
+    assert_eq!(data[..2], [1, 0x01], "A passive segment");
+
+    // Statics and the heap pointer are in memory, what's left are the
+    // exception, the shadow stack, the innermost frame and the thread id.
+    assert_eq!(section(6).map(|globals| globals[0]), Some(4));
+}
+
+#[test]
+fn monomorphic_calls_are_devirtualized_and_getters_inlined(){
+    let translation = translate_class("Objects", &[], &Options::default()).unwrap();
+    let main = MethodKey::new(b"Objects", b"main", b"([Ljava/lang/String;)V");
+    let decisions = translation.decisions.iter()
+        .filter(|d| d.caller == main)
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>();
+    let has = |decision: &str| decisions.iter().any(|d| d.ends_with(decision));
+
+    // Both `Square` and `Rectangle` inherit `describe` from `Base`, but override `name`.
+    assert!(has("Objects$Base.describe()Ljava/lang/String; devirtualized to Objects$Base.describe()Ljava/lang/String;"), "{:#?}", decisions);
+    assert!(has("Objects$Base.name()Ljava/lang/String; stays virtual, 2 targets"), "{:#?}", decisions);
+    assert!(has("Objects$Square.side()I devirtualized to Objects$Square.side()I"), "{:#?}", decisions);
+    assert!(has("Objects$Square.side()I inlined, getter"), "{:#?}", decisions);
+}
+
+#[test]
+fn methods_with_handlers_are_lowered_through_ssa(){
+    let translation = translate_class("Exceptions", &[], &Options::default()).unwrap();
+    let report = |name: &[u8], descriptor: &[u8]| translation.checks.iter()
+        .find(|c| c.method == MethodKey::new(b"Exceptions", name, descriptor))
+        .unwrap_or_else(|| panic!("No check report for {}", String::from_utf8_lossy(name)))
+        .to_string();
+
+    // The handler reads the field of the exception it caught, which isn't `null`.
+    assert_eq!(report(b"nested", b"(I)I"), "Exceptions.nested(I)I: 1 of 1 null checks, 0 of 0 bounds checks removed");
+}
@@ -0,0 +1,34 @@
+public class Arithmetic {
+    static int fib(int n) {
+        return n < 2 ? n : fib(n - 1) + fib(n - 2);
+    }
+
+    static int gcd(int a, int b) {
+        while (b != 0) {
+            int t = a % b;
+            a = b;
+            b = t;
+        }
+        return a;
+    }
+
+    public static void main(String[] args) {
+        System.out.println(fib(15));
+        System.out.println(gcd(1071, 462));
+        System.out.println(-7 / 2);
+        System.out.println(-7 % 2);
+        System.out.println(Integer.MIN_VALUE / -1);
+        System.out.println(1 << 33);
+        System.out.println(-16 >>> 28);
+        System.out.println(-16 >> 2);
+        int x = 5;
+        x += 100;
+        x ^= 3;
+        System.out.println(x);
+        double d = 7.5;
+        System.out.println(d / 2);
+        System.out.println(d % 2);
+        System.out.println(0.0 / 0.0 > 1.0);
+        System.out.println('A');
+    }
+}
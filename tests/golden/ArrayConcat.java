@@ -0,0 +1,23 @@
+// ArrayConcat$Join.join(ints, longs) is `"" + ints + longs` with the arrays
+// passed to the concatenation as they are, see tools/AssembleJoin.java.
+public class ArrayConcat {
+    static boolean joins(String joined, String a, String b) {
+        if (joined.length() != a.length() + b.length()) {
+            return false;
+        }
+        for (int i = 0; i < joined.length(); i++) {
+            char c = i < a.length() ? a.charAt(i) : b.charAt(i - a.length());
+            if (joined.charAt(i) != c) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    public static void main(String[] args) {
+        int[] ints = {1, 2};
+        long[] longs = {3L};
+        System.out.println(joins(ArrayConcat$Join.join(ints, longs), ints.toString(), longs.toString()));
+        System.out.println(ArrayConcat$Join.join(null, null));
+    }
+}
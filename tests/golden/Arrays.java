@@ -0,0 +1,121 @@
+public class Arrays {
+    static class Point {
+        int x, y;
+
+        Point(int x, int y) {
+            this.x = x;
+            this.y = y;
+        }
+    }
+
+    static int sum(int[] values) {
+        int sum = 0;
+        for (int i = 0; i < values.length; i++) {
+            sum += values[i];
+        }
+        return sum;
+    }
+
+    static int element(int[] values, int index) {
+        try {
+            return values[index];
+        } catch (ArrayIndexOutOfBoundsException e) {
+            System.out.println("index " + index + " out of bounds");
+            return -1;
+        }
+    }
+
+    static int allocate(int length) {
+        try {
+            return new byte[length].length;
+        } catch (NegativeArraySizeException e) {
+            System.out.println("negative size " + length);
+            return -1;
+        }
+    }
+
+    public static void main(String[] args) {
+        byte[] bytes = new byte[3];
+        bytes[0] = (byte) 200;
+        bytes[1] = 127;
+        bytes[2] = (byte) (bytes[1] + 1);
+        System.out.println(bytes[0] + " " + bytes[1] + " " + bytes[2]);
+
+        char[] chars = {'a', '￿', 'z'};
+        System.out.println((int) chars[1] + " " + chars[0] + chars[2]);
+
+        short[] shorts = new short[2];
+        shorts[0] = (short) 40000;
+        shorts[1] = -1;
+        System.out.println(shorts[0] + " " + shorts[1]);
+
+        boolean[] flags = new boolean[4];
+        flags[2] = true;
+        System.out.println((flags[0] ? 1 : 0) + " " + (flags[2] ? 1 : 0));
+
+        int[] ints = new int[10];
+        for (int i = 0; i < ints.length; i++) {
+            ints[i] = i * i;
+        }
+        System.out.println(sum(ints));
+
+        long[] longs = {Long.MIN_VALUE, 1L << 40, -1};
+        longs[2] += longs[1];
+        System.out.println(longs[0] + " " + longs[2]);
+
+        float[] floats = new float[2];
+        floats[1] = 2.5f;
+        double[] doubles = {0.25, -3};
+        System.out.println(floats[0] + floats[1] + " " + (doubles[0] + doubles[1]));
+
+        Point[] points = new Point[3];
+        points[1] = new Point(3, 4);
+        System.out.println((points[0] == null ? "null" : "point") + " " + (points[1].x + points[1].y));
+
+        String[] words = {"zero", "one", "two"};
+        System.out.println(words[2] + " " + words.length);
+
+        int[][] grid = new int[3][4];
+        for (int i = 0; i < grid.length; i++) {
+            for (int j = 0; j < grid[i].length; j++) {
+                grid[i][j] = i * 10 + j;
+            }
+        }
+        System.out.println(grid[2][3] + " " + grid[1].length + " " + sum(grid[2]));
+
+        long[][][] cube = new long[2][3][];
+        System.out.println(cube[1].length + " " + (cube[1][2] == null ? "null" : "array"));
+        cube[1][2] = new long[5];
+        cube[1][2][4] = 7;
+        System.out.println(cube[1][2][4] + cube[1][2].length);
+
+        int[][] jagged = {{1}, {2, 3}, {}};
+        System.out.println(jagged[1][1] + " " + jagged[2].length);
+
+        int[] shifted = {1, 2, 3, 4, 5};
+        System.arraycopy(shifted, 0, shifted, 1, 4);
+        long[] copies = new long[4];
+        System.arraycopy(longs, 1, copies, 2, 2);
+        Object[] objects = new Object[2];
+        System.arraycopy(words, 1, objects, 0, 2);
+        System.out.println(shifted[1] + " " + shifted[4] + " " + copies[3] + " " + objects[1]);
+
+        System.out.println(element(ints, 9));
+        System.out.println(element(ints, 10));
+        System.out.println(element(ints, -1));
+        System.out.println(allocate(5));
+        System.out.println(allocate(-2));
+        try {
+            int[][] bad = new int[2][-1];
+            System.out.println(bad.length);
+        } catch (NegativeArraySizeException e) {
+            System.out.println("negative inner size");
+        }
+        try {
+            ints = null;
+            System.out.println(ints.length);
+        } catch (NullPointerException e) {
+            System.out.println("null array");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+public class Casts {
+    interface Shape {
+        int area();
+    }
+
+    interface Named {
+    }
+
+    interface Square extends Shape {
+    }
+
+    static class Base implements Shape {
+        public int area() {
+            return 1;
+        }
+    }
+
+    static class Middle extends Base implements Named {
+    }
+
+    static class Leaf extends Middle implements Square {
+        public int area() {
+            return 4;
+        }
+    }
+
+    static class Other {
+    }
+
+    static int bit(boolean b) {
+        return b ? 1 : 0;
+    }
+
+    static void classes(Object o) {
+        System.out.println(bit(o instanceof Object) + " " + bit(o instanceof Base) + " " + bit(o instanceof Middle)
+            + " " + bit(o instanceof Leaf) + " " + bit(o instanceof Other) + " " + bit(o instanceof String));
+    }
+
+    static void interfaces(Object o) {
+        System.out.println(bit(o instanceof Shape) + " " + bit(o instanceof Named) + " " + bit(o instanceof Square)
+            + " " + bit(o instanceof Cloneable) + " " + bit(o instanceof java.io.Serializable));
+    }
+
+    static void arrays(Object o) {
+        System.out.println(bit(o instanceof Object[]) + " " + bit(o instanceof Base[]) + " " + bit(o instanceof Leaf[])
+            + " " + bit(o instanceof Shape[]) + " " + bit(o instanceof int[]) + " " + bit(o instanceof long[])
+            + " " + bit(o instanceof Object[][]) + " " + bit(o instanceof int[][]));
+    }
+
+    static String cast(Object o) {
+        try {
+            Base base = (Base) o;
+            return "base " + (base == null ? 0 : base.area());
+        } catch (ClassCastException e) {
+            return "not a base";
+        }
+    }
+
+    static String store(Object[] array, Object value) {
+        try {
+            array[0] = value;
+            return "stored";
+        } catch (ArrayStoreException e) {
+            return "rejected";
+        }
+    }
+
+    public static void main(String[] args) {
+        Object[] objects = {new Object(), new Base(), new Middle(), new Leaf(), new Other(), "text", null};
+        for (int i = 0; i < objects.length; i++) {
+            classes(objects[i]);
+            interfaces(objects[i]);
+        }
+
+        Object[] arrays = {new Object[1], new Base[1], new Leaf[2], new Square[0], new int[3], new long[1][2],
+            new Leaf[1][1], new int[1][1], new Shape[2][]};
+        for (int i = 0; i < arrays.length; i++) {
+            arrays(arrays[i]);
+            interfaces(arrays[i]);
+        }
+
+        System.out.println(cast(new Leaf()));
+        System.out.println(cast(new Other()));
+        System.out.println(cast(null));
+        System.out.println(cast("text"));
+        Object leaves = new Leaf[] {new Leaf()};
+        Shape[] shapes = (Shape[]) leaves;
+        System.out.println(shapes[0].area() + " " + shapes.length);
+        try {
+            Object numbers = new int[1];
+            Object[] wrong = (Object[]) numbers;
+            System.out.println(wrong.length);
+        } catch (ClassCastException e) {
+            System.out.println("int[] is not an Object[]");
+        }
+
+        System.out.println(store(new Base[1], new Leaf()));
+        System.out.println(store(new Leaf[1], new Base()));
+        System.out.println(store(new Leaf[1], null));
+        System.out.println(store(new Shape[1], new Leaf()));
+        System.out.println(store(new Named[1], new Base()));
+        System.out.println(store(new Object[1][], new int[2]));
+        System.out.println(store(new Object[1][], new String[2]));
+        System.out.println(store(new Object[1], "anything"));
+    }
+}
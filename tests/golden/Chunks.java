@@ -0,0 +1,40 @@
+public class Chunks {
+    interface Greeter {
+        String greet(String name);
+    }
+
+    static class Polite implements Greeter {
+        public String greet(String name) {
+            return "Good day, " + name;
+        }
+    }
+
+    static class Late {
+        static int count;
+
+        static {
+            System.out.println("Late initialized");
+        }
+
+        static int next() {
+            return ++count;
+        }
+    }
+
+    static class Never {
+        static void run() {
+            System.out.println("never printed");
+        }
+    }
+
+    public static void main(String[] args) {
+        System.out.println("before Late");
+        System.out.println(Late.next());
+        System.out.println(Late.next());
+        Greeter greeter = new Polite();
+        System.out.println(greeter.greet("chunks"));
+        if (args != null && args.length > 0) {
+            Never.run();
+        }
+    }
+}
@@ -0,0 +1,64 @@
+public class Exceptions {
+    static class Custom extends RuntimeException {
+        int code;
+
+        Custom(int code) {
+            this.code = code;
+        }
+    }
+
+    static int divide(int a, int b) {
+        return a / b;
+    }
+
+    static void fail(int code) {
+        throw new Custom(code);
+    }
+
+    static int nested(int code) {
+        try {
+            try {
+                fail(code);
+                return -1;
+            } catch (ArithmeticException e) {
+                return -2;
+            }
+        } catch (Custom e) {
+            return e.code;
+        }
+    }
+
+    static String finallyOrder() {
+        try {
+            System.out.println("in try");
+            fail(1);
+            return "unreachable";
+        } catch (Custom e) {
+            System.out.println("in catch");
+            return "returned";
+        } finally {
+            System.out.println("in finally");
+        }
+    }
+
+    public static void main(String[] args) {
+        try {
+            System.out.println(divide(10, 0));
+        } catch (ArithmeticException e) {
+            System.out.println("caught division by zero");
+        }
+        System.out.println(nested(7));
+        System.out.println(finallyOrder());
+        Custom nothing = null;
+        try {
+            System.out.println(nothing.code);
+        } catch (NullPointerException e) {
+            System.out.println("caught null pointer");
+        }
+        try {
+            fail(3);
+        } catch (RuntimeException e) {
+            System.out.println("caught by superclass");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+public class Lambdas {
+    interface Action {
+        void run();
+    }
+
+    interface IntOp {
+        int apply(int a, int b);
+    }
+
+    interface Mapper<T> {
+        T map(T value);
+    }
+
+    interface Factory {
+        Point make(int x, int y);
+    }
+
+    static class Point {
+        final int x;
+        final int y;
+
+        Point(int x, int y) {
+            this.x = x;
+            this.y = y;
+        }
+
+        int sum() {
+            return x + y;
+        }
+
+        public String toString() {
+            return "(" + x + ", " + y + ")";
+        }
+    }
+
+    static int counter;
+
+    static int subtract(int a, int b) {
+        return a - b;
+    }
+
+    static String shout(String s) {
+        return s + "!";
+    }
+
+    static void twice(Action action) {
+        action.run();
+        action.run();
+    }
+
+    public static void main(String[] args) {
+        twice(() -> counter++);
+        System.out.println("counter " + counter);
+
+        int base = 10;
+        IntOp add = (a, b) -> a + b + base;
+        IntOp sub = Lambdas::subtract;
+        System.out.println("add " + add.apply(1, 2) + ", sub " + sub.apply(7, 3));
+
+        Mapper<String> exclaim = Lambdas::shout;
+        String prefix = "> ";
+        Mapper<String> quote = s -> prefix + s;
+        System.out.println(quote.map(exclaim.map("hi")));
+
+        Factory factory = Point::new;
+        Point p = factory.make(3, 4);
+        IntOp bound = (a, b) -> p.sum() * a + b;
+        System.out.println("point " + p + " " + bound.apply(2, 1));
+
+        String nothing = null;
+        Object none = null;
+        char c = 'x';
+        char wide = '\u20ac';
+        boolean yes = true;
+        double d = 2.5;
+        float f = 0.1f;
+        short s = -7;
+        System.out.println("str " + nothing + " obj " + none + " char " + c + wide + " bool " + yes);
+        System.out.println("double " + d + " float " + f + " short " + s);
+        System.out.println("" + 0 + -1 + 'a');
+        System.out.println(p + "" + p);
+    }
+}
@@ -0,0 +1,48 @@
+public class Longs {
+    static long factorial(int n) {
+        long result = 1;
+        for (int i = 2; i <= n; i++) {
+            result *= i;
+        }
+        return result;
+    }
+
+    static long divide(long a, long b) {
+        try {
+            return a / b;
+        } catch (ArithmeticException e) {
+            System.out.println("division by zero");
+            return -1;
+        }
+    }
+
+    static int compare(long a, long b) {
+        return a < b ? -1 : a == b ? 0 : 1;
+    }
+
+    public static void main(String[] args) {
+        System.out.println(factorial(20));
+        System.out.println(factorial(21));
+        long big = 0x123456789ABCDEFL;
+        System.out.println(big >>> 4);
+        System.out.println(-big >> 8);
+        System.out.println(big << 65);
+        System.out.println(1L << 63);
+        System.out.println(big & 0xFFFF | 1L << 40);
+        System.out.println(big ^ -1L);
+        System.out.println(Long.MIN_VALUE / -1);
+        System.out.println(Long.MIN_VALUE % -1);
+        System.out.println(-7L / 2);
+        System.out.println(-7L % 2);
+        System.out.println(divide(10, 0));
+        System.out.println(compare(3, 5) + " " + compare(5, 5) + " " + compare(Long.MAX_VALUE, Long.MIN_VALUE));
+        System.out.println((int) big);
+        System.out.println((long) -1.5e19 + " " + (long) 3.9f + " " + (long) Double.NaN);
+        System.out.println((double) (big >> 40) + " " + (float) (big >> 50));
+        long sum = 0;
+        for (long i = 0; i < 100000; i += 3) {
+            sum += i * i;
+        }
+        System.out.println("sum " + sum);
+    }
+}
@@ -0,0 +1,56 @@
+public class Monitors {
+    static int total;
+    int count;
+
+    synchronized void add(int amount) {
+        count += amount;
+    }
+
+    synchronized int addTwice(int amount) {
+        add(amount);
+        add(amount);
+        return count;
+    }
+
+    static synchronized void addTotal(int amount) {
+        total += amount;
+    }
+
+    synchronized void fail() {
+        throw new IllegalStateException();
+    }
+
+    static class IllegalStateException extends RuntimeException {
+    }
+
+    public static void main(String[] args) {
+        Monitors monitors = new Monitors();
+        System.out.println(monitors.addTwice(3));
+        synchronized (monitors) {
+            synchronized (monitors) {
+                monitors.add(1);
+            }
+        }
+        System.out.println(monitors.count);
+        try {
+            synchronized (monitors) {
+                monitors.fail();
+            }
+        } catch (IllegalStateException e) {
+            System.out.println("caught inside synchronized block");
+        }
+        monitors.add(10);
+        System.out.println(monitors.count);
+        addTotal(5);
+        addTotal(6);
+        System.out.println(total);
+        Object lock = null;
+        try {
+            synchronized (lock) {
+                System.out.println("unreachable");
+            }
+        } catch (NullPointerException e) {
+            System.out.println("caught null monitor");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+public class Objects {
+    interface Shape {
+        int area();
+
+        default String describe() {
+            return "a shape";
+        }
+    }
+
+    static abstract class Base implements Shape {
+        int scale = 1;
+
+        abstract String name();
+
+        public String describe() {
+            return name();
+        }
+    }
+
+    static class Square extends Base {
+        int side;
+
+        Square(int side) {
+            this.side = side;
+        }
+
+        public int area() {
+            return side * side * scale;
+        }
+
+        String name() {
+            return "square";
+        }
+
+        int side() {
+            return side;
+        }
+    }
+
+    static class Rectangle extends Base {
+        int width, height;
+
+        Rectangle(int width, int height) {
+            this.width = width;
+            this.height = height;
+            scale = 2;
+        }
+
+        public int area() {
+            return width * height * scale;
+        }
+
+        String name() {
+            return "rectangle";
+        }
+    }
+
+    static class Dot implements Shape {
+        public int area() {
+            return 0;
+        }
+    }
+
+    static void print(Shape shape) {
+        System.out.println(shape.describe());
+        System.out.println(shape.area());
+    }
+
+    public static void main(String[] args) {
+        print(new Square(3));
+        print(new Rectangle(2, 5));
+        print(new Dot());
+        Base base = new Square(4);
+        System.out.println(base.name());
+        System.out.println(base.describe());
+        System.out.println(new Square(5).side());
+        Object o = base;
+        System.out.println(o == base);
+    }
+}
@@ -0,0 +1,50 @@
+import java.io.IOException;
+import java.io.InputStream;
+
+public class Resources {
+    static String text(String name) throws IOException {
+        return text(ClassLoader.getSystemResourceAsStream(name));
+    }
+
+    static String text(InputStream in) throws IOException {
+        String text = "";
+        for (int c = in.read(); c != -1; c = in.read()) {
+            text += (char) c;
+        }
+        return text;
+    }
+
+    public static void main(String[] args) throws IOException {
+        System.out.println(text("resources/greeting.txt"));
+
+        InputStream data = ClassLoader.getSystemResourceAsStream("resources/data.bin");
+        byte[] buffer = new byte[256];
+        int total = 0;
+        int sum = 0;
+        for (int n = data.read(buffer, 0, buffer.length); n > 0; n = data.read(buffer, 0, buffer.length)) {
+            total += n;
+            for (int i = 0; i < n; i++) {
+                sum += buffer[i] & 0xFF;
+            }
+        }
+        System.out.println(total);
+        System.out.println(sum);
+
+        System.out.println(ClassLoader.getSystemResourceAsStream("resources/missing.txt") == null);
+        // Names before, between and after the embedded ones.
+        System.out.println(ClassLoader.getSystemResource("a") == null);
+        System.out.println(ClassLoader.getSystemResource("resources/e") == null);
+        System.out.println(ClassLoader.getSystemResource("z") == null);
+
+        System.out.println(Resources.class.getName());
+        System.out.println(text(Resources.class.getResourceAsStream("resources/greeting.txt")));
+        System.out.println(text(Resources.class.getResource("/resources/greeting.txt").openStream()));
+        System.out.println(String.class.getResource("greeting.txt") == null);
+        System.out.println(new Resources().getClass() == Resources.class);
+        System.out.println("".getClass() == String.class);
+        System.out.println(String.class.getName());
+        System.out.println(new int[0].getClass().getName());
+        System.out.println(new int[0].getClass() == int[].class);
+        System.out.println(new Object[0].getClass().getName());
+    }
+}
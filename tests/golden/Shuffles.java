@@ -0,0 +1,27 @@
+public class Shuffles {
+    int small;
+    long big;
+
+    static long twice(long value) {
+        return value * 2;
+    }
+
+    public static void main(String[] args) {
+        Shuffles s = new Shuffles();
+        int[] ints = new int[2];
+        long[] longs = new long[2];
+        // dup_x1, dup2_x1, dup_x2 and dup2_x2 keep the stored value.
+        int a = s.small = 3;
+        long b = s.big = 1L << 40;
+        int c = ints[1] = a + 4;
+        long d = longs[0] = b + 5;
+        // pop2 drops the long result.
+        twice(d);
+        // A wide iinc.
+        int e = c;
+        e += 1000;
+        Object nothing = null;
+        System.out.println(a + " " + b + " " + c + " " + d + " " + e + " " + (nothing == null));
+        System.out.println(s.small + " " + s.big + " " + ints[1] + " " + longs[0]);
+    }
+}
@@ -0,0 +1,67 @@
+public class Statics {
+    static int counter = 10;
+    static final String GREETING = "hello, statics";
+    static String name;
+
+    static {
+        name = "initialized";
+    }
+
+    static class Lazy {
+        static int value = compute();
+
+        static int compute() {
+            System.out.println("Lazy initialized");
+            return 42;
+        }
+    }
+
+    static class Broken extends Error {
+    }
+
+    static class Failing {
+        static int value = 1;
+
+        static {
+            System.out.println("Failing initializing");
+            if (value > 0) {
+                throw new Broken();
+            }
+        }
+    }
+
+    static class FailingChild extends Failing {
+        static int child = 2;
+
+        static {
+            System.out.println("FailingChild initializing");
+        }
+    }
+
+    static void touchFailing() {
+        try {
+            System.out.println(Failing.value);
+        } catch (Broken e) {
+            System.out.println("caught Broken");
+        } catch (NoClassDefFoundError e) {
+            System.out.println("caught NoClassDefFoundError");
+        }
+    }
+
+    public static void main(String[] args) {
+        System.out.println(GREETING);
+        System.out.println(name);
+        counter++;
+        System.out.println(counter);
+        System.out.println("before Lazy");
+        System.out.println(Lazy.value);
+        System.out.println(Lazy.value);
+        touchFailing();
+        touchFailing();
+        try {
+            System.out.println(FailingChild.child);
+        } catch (LinkageError e) {
+            System.out.println("child caught LinkageError");
+        }
+    }
+}
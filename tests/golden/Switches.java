@@ -0,0 +1,47 @@
+public class Switches {
+    static String dense(int i) {
+        switch (i) {
+            case 0: return "zero";
+            case 1: return "one";
+            case 2: return "two";
+            case 3: return "three";
+            default: return "many";
+        }
+    }
+
+    static int sparse(int i) {
+        switch (i) {
+            case -1000: return 1;
+            case 7: return 2;
+            case 1 << 20: return 3;
+            default: return 0;
+        }
+    }
+
+    static int fallThrough(int i) {
+        int total = 0;
+        switch (i) {
+            case 1:
+                total += 1;
+            case 2:
+                total += 10;
+                break;
+            case 3:
+                total += 100;
+        }
+        return total;
+    }
+
+    public static void main(String[] args) {
+        for (int i = -1; i < 5; i++) {
+            System.out.println(dense(i));
+        }
+        System.out.println(sparse(-1000));
+        System.out.println(sparse(7));
+        System.out.println(sparse(1 << 20));
+        System.out.println(sparse(8));
+        for (int i = 0; i < 4; i++) {
+            System.out.println(fallThrough(i));
+        }
+    }
+}
@@ -0,0 +1,62 @@
+public class Traces {
+    static class Failure extends RuntimeException {
+        Failure(String message) {
+            super(message);
+        }
+    }
+
+    interface Step {
+        int run(int value);
+    }
+
+    static class Halve implements Step {
+        public int run(int value) {
+            return 100 / value;
+        }
+    }
+
+    static int descend(int depth) {
+        if (depth == 0) {
+            throw new Failure("bottom");
+        }
+        return descend(depth - 1) + 1;
+    }
+
+    static synchronized int[] table(int size) {
+        return new int[size];
+    }
+
+    static void print(Throwable e) {
+        System.out.println(e.toString());
+        for (StackTraceElement element : e.getStackTrace()) {
+            System.out.println("  " + element);
+        }
+    }
+
+    public static void main(String[] args) {
+        try {
+            descend(2);
+        } catch (Failure e) {
+            print(e);
+        }
+        try {
+            Step step = new Halve();
+            step.run(0);
+        } catch (ArithmeticException e) {
+            System.out.println(e.getStackTrace()[0] + " " + e.getStackTrace()[1]);
+        }
+        try {
+            table(-1);
+        } catch (NegativeArraySizeException e) {
+            StackTraceElement top = e.getStackTrace()[0];
+            System.out.println(top.getClassName() + " " + top.getMethodName() + " " + top.getFileName() + " " + top.getLineNumber());
+        }
+        Failure here = new Failure("created");
+        System.out.println(here.getStackTrace().length + " " + here.getStackTrace()[0]);
+    }
+
+    /** Exported by the tests, which check what an uncaught exception prints. */
+    static void crash() {
+        descend(1);
+    }
+}
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Compiles the golden tests and records what the JVM prints for each of them.
+set -e
+cd "$(dirname "$0")"
+rm -f *.class
+# ArrayConcat.java calls a concatenation javac can't emit, see tools/AssembleJoin.java.
+java --add-exports java.base/jdk.internal.org.objectweb.asm=ALL-UNNAMED tools/AssembleJoin.java .
+javac --release 17 -g -encoding UTF-8 -cp . -d . *.java
+for source in *.java; do
+    name="${source%.java}"
+    java -Dsun.stdout.encoding=UTF-8 -cp . "$name" > "$name.out"
+done
@@ -0,0 +1,38 @@
+import java.nio.file.Files;
+import java.nio.file.Path;
+
+import jdk.internal.org.objectweb.asm.ClassWriter;
+import jdk.internal.org.objectweb.asm.Handle;
+import jdk.internal.org.objectweb.asm.MethodVisitor;
+import jdk.internal.org.objectweb.asm.Opcodes;
+
+/**
+ * Writes ArrayConcat$Join.class, whose string concatenation takes an int[]
+ * and a long[] themselves. javac passes their String.valueOf instead, other
+ * compilers don't.
+ */
+public class AssembleJoin {
+    public static void main(String[] args) throws Exception {
+        ClassWriter writer = new ClassWriter(ClassWriter.COMPUTE_MAXS);
+        writer.visit(Opcodes.V17, Opcodes.ACC_PUBLIC | Opcodes.ACC_SUPER, "ArrayConcat$Join", null, "java/lang/Object", null);
+
+        MethodVisitor join = writer.visitMethod(Opcodes.ACC_PUBLIC | Opcodes.ACC_STATIC, "join", "([I[J)Ljava/lang/String;", null, null);
+        join.visitCode();
+        join.visitVarInsn(Opcodes.ALOAD, 0);
+        join.visitVarInsn(Opcodes.ALOAD, 1);
+        Handle bootstrap = new Handle(
+            Opcodes.H_INVOKESTATIC,
+            "java/lang/invoke/StringConcatFactory",
+            "makeConcatWithConstants",
+            "(Ljava/lang/invoke/MethodHandles$Lookup;Ljava/lang/String;Ljava/lang/invoke/MethodType;Ljava/lang/String;[Ljava/lang/Object;)Ljava/lang/invoke/CallSite;",
+            false
+        );
+        join.visitInvokeDynamicInsn("makeConcatWithConstants", "([I[J)Ljava/lang/String;", bootstrap, "\u0001\u0001");
+        join.visitInsn(Opcodes.ARETURN);
+        join.visitMaxs(0, 0);
+        join.visitEnd();
+
+        writer.visitEnd();
+        Files.write(Path.of(args[0], "ArrayConcat$Join.class"), writer.toByteArray());
+    }
+}
@@ -0,0 +1,416 @@
+//! Runs translated modules in an embedded WebAssembly engine.
+//!
+//! The natives the runtime in `runtime/` declares are stubbed here, with
+//! `PrintStream` writing to a captured stdout and `Throwable` printing stack
+//! traces to a captured stderr. Deflated resources are inflated. Calling any
+//! other import traps.
+//! Modules that split `long`s at the boundary get stubs taking and returning
+//! the halves. The modules of a split translation are linked like the
+//! manifest in `wasm_shit::link` describes, chunks being instantiated when
+//! they are loaded.
+//!
+//! The runtime and the golden test classes the tests translate are found here
+//! too.
+
+use std::{collections::{HashMap, HashSet}, fs, io::Read, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::bail;
+use flate2::read::DeflateDecoder;
+use wasmi::{core::{Trap, F64}, Caller, Engine, Extern, Instance, Linker, Module, Store, Value};
+use wasm_shit::{data::ParsedClass, layout, link::{LinkedModule, CORE, LOAD_CHUNK, START_EXPORT}, natives::HIGH_BITS_EXPORT, resources::INFLATE, runtime::RUNTIME_MODULE, translate::EXCEPTION_EXPORT};
+
+pub const RUNTIME_JAR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/runtime/runtime.jar");
+pub const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+/// The golden test class with the given binary name.
+pub fn golden(name: &str) -> ParsedClass{
+    ParsedClass::parse(&fs::read(format!("{}/{}.class", GOLDEN, name)).unwrap()).unwrap()
+}
+
+/// The golden test class with the given binary name and its nested classes.
+pub fn golden_with_nested(name: &str) -> Vec<ParsedClass>{
+    let mut classes = Vec::new();
+    for entry in fs::read_dir(GOLDEN).unwrap(){
+        let path = entry.unwrap().path();
+        let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if path.extension().is_some_and(|e| e == "class") && (stem == name || stem.starts_with(&format!("{}$", name))){
+            classes.push(ParsedClass::parse(&fs::read(path).unwrap()).unwrap());
+        }
+    }
+    classes
+}
+
+/// Reads an unsigned LEB128 at `at`, moving past it.
+pub fn leb(bytes: &[u8], at: &mut usize) -> u32{
+    let (mut value, mut shift) = (0, 0);
+    loop {
+        let byte = bytes[*at];
+        *at += 1;
+        value |= ((byte & 0x7F) as u32) << shift;
+        shift += 7;
+        if byte & 0x80 == 0{
+            return value;
+        }
+    }
+}
+
+/// Result of running an entry point.
+pub struct Run{
+    pub stdout: String,
+    pub stderr: String,
+    /// Address of the uncaught exception, 0 if there is none.
+    pub exception: u32,
+    /// Ids of the chunks that were loaded, in order.
+    pub loaded: Vec<usize>,
+}
+
+#[derive(Default)]
+pub struct State{
+    stdout: String,
+    stderr: String,
+    chunks: Option<Arc<Chunks>>,
+    loaded: Vec<usize>,
+}
+
+/// The modules of a lazily loaded translation besides the core, by id. They're
+/// compiled up front as wasmi can't compile modules while it runs one.
+struct Chunks{
+    modules: HashMap<usize, Module>,
+    linker: Linker<State>,
+}
+
+/// Instantiates the chunk with the id, which fills its table slots.
+fn load_chunk(mut caller: Caller<'_, State>, id: i32) -> Result<(), Trap>{
+    let Some(chunks) = caller.data().chunks.clone() else {
+        return Err(Trap::new("No chunks to load"));
+    };
+    let module = chunks.modules.get(&(id as usize)).ok_or_else(|| Trap::new(format!("No chunk {}", id)))?;
+    chunks.linker.instantiate(&mut caller, module).map_err(|e| Trap::new(e.to_string()))?
+        .ensure_no_start(&mut caller).map_err(|e| Trap::new(e.to_string()))?;
+    caller.data_mut().loaded.push(id as usize);
+    Ok(())
+}
+
+fn memory<'a>(caller: &'a Caller<'_, State>) -> &'a [u8]{
+    let memory = caller.get_export("memory").and_then(Extern::into_memory).expect("Modules export their memory");
+    memory.data(caller)
+}
+
+fn read_u32(memory: &[u8], address: u32) -> u32{
+    let address = address as usize;
+    u32::from_le_bytes(memory[address..address + 4].try_into().unwrap())
+}
+
+/// Reads a `java.lang.String` object.
+fn read_string(memory: &[u8], address: u32) -> String{
+    if address == layout::NULL{
+        return "null".into();
+    }
+    let value = read_u32(memory, address + layout::STRING_VALUE_OFFSET);
+    let coder = memory[(address + layout::STRING_CODER_OFFSET) as usize];
+    let length = read_u32(memory, value + layout::ARRAY_LENGTH_OFFSET) as usize;
+    let data = &memory[(value + layout::ARRAY_DATA_OFFSET) as usize..][..length];
+    if coder == layout::CODER_LATIN1{
+        data.iter().map(|b| *b as char).collect()
+    }
+    else{
+        let units = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    }
+}
+
+/// Close to `Double.toString` for the values tests print.
+fn format_double(value: f64) -> String{
+    if value.is_nan(){
+        "NaN".into()
+    }
+    else if value.is_infinite(){
+        if value > 0.0 {"Infinity".into()} else {"-Infinity".into()}
+    }
+    else if value.fract() == 0.0 && value.abs() < 1e7{
+        format!("{:.1}", value)
+    }
+    else{
+        format!("{}", value)
+    }
+}
+
+/// `Float.toString`, which has the shortest digits of the float instead of the double.
+fn format_float(value: f32) -> String{
+    if !value.is_finite() || (value.fract() == 0.0 && value.abs() < 1e7){
+        format_double(value as f64)
+    }
+    else{
+        format!("{}", value)
+    }
+}
+
+/// The current time in milliseconds.
+fn millis() -> i64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Returns the low half of a `long`, leaving the high half in the module's global.
+fn split_result(caller: &mut Caller<'_, State>, value: i64) -> Result<i32, Trap>{
+    let high_bits = caller.get_export(HIGH_BITS_EXPORT).and_then(Extern::into_global).ok_or_else(|| Trap::new("No high bits global exported"))?;
+    high_bits.set(&mut *caller, Value::I32((value >> 32) as i32)).map_err(|e| Trap::new(e.to_string()))?;
+    Ok(value as i32)
+}
+
+/// Defines the stubs, returns their `module.name`s.
+fn stubs(linker: &mut Linker<State>, split_longs: bool) -> anyhow::Result<HashSet<String>>{
+    let mut defined = HashSet::new();
+    let mut java = |name: &'static str|{
+        defined.insert(format!("java.{}", name));
+        name
+    };
+    linker.func_wrap("java", java("java/io/PrintStream.print(Ljava/lang/String;)V"), |mut caller: Caller<'_, State>, _: i32, s: i32|{
+        let s = read_string(memory(&caller), s as u32);
+        caller.data_mut().stdout.push_str(&s);
+    })?;
+    linker.func_wrap("java", java("java/io/PrintStream.print(I)V"), |mut caller: Caller<'_, State>, _: i32, i: i32|{
+        caller.data_mut().stdout.push_str(&i.to_string());
+    })?;
+    linker.func_wrap("java", java("java/io/PrintStream.print(C)V"), |mut caller: Caller<'_, State>, _: i32, c: i32|{
+        let c = String::from_utf16_lossy(&[c as u16]);
+        caller.data_mut().stdout.push_str(&c);
+    })?;
+    linker.func_wrap("java", java("java/io/PrintStream.print(D)V"), |mut caller: Caller<'_, State>, _: i32, d: F64|{
+        caller.data_mut().stdout.push_str(&format_double(d.to_float()));
+    })?;
+    linker.func_wrap("java", java("java/io/PrintStream.println()V"), |mut caller: Caller<'_, State>, _: i32|{
+        caller.data_mut().stdout.push('\n');
+    })?;
+    linker.func_wrap("java", java("java/lang/Object.hashCode()I"), |_: Caller<'_, State>, this: i32| this)?;
+    linker.func_wrap("java", java("java/lang/Throwable.print(Ljava/lang/String;)V"), |mut caller: Caller<'_, State>, s: i32|{
+        let s = read_string(memory(&caller), s as u32);
+        caller.data_mut().stderr.push_str(&s);
+    })?;
+    if split_longs{
+        linker.func_wrap("java", java("java/io/PrintStream.print(J)V"), |mut caller: Caller<'_, State>, _: i32, low: i32, high: i32|{
+            let l = ((high as i64) << 32) | low as u32 as i64;
+            caller.data_mut().stdout.push_str(&l.to_string());
+        })?;
+        linker.func_wrap("java", java("java/lang/System.currentTimeMillis()J"), |mut caller: Caller<'_, State>| split_result(&mut caller, millis()))?;
+    }
+    else{
+        linker.func_wrap("java", java("java/io/PrintStream.print(J)V"), |mut caller: Caller<'_, State>, _: i32, l: i64|{
+            caller.data_mut().stdout.push_str(&l.to_string());
+        })?;
+        linker.func_wrap("java", java("java/lang/System.currentTimeMillis()J"), millis)?;
+    }
+    linker.func_wrap(
+        "java",
+        java("java/lang/System.arraycopy(Ljava/lang/Object;ILjava/lang/Object;II)V"),
+        |mut caller: Caller<'_, State>, src: i32, src_pos: i32, dest: i32, dest_pos: i32, length: i32| -> Result<(), Trap>{
+            let memory = caller.get_export("memory").and_then(Extern::into_memory).expect("Modules export their memory");
+            let data = memory.data_mut(&mut caller);
+            let (src, dest) = (src as u32, dest as u32);
+            if src == layout::NULL || dest == layout::NULL{
+                return Err(Trap::new("arraycopy of null"));
+            }
+            let class = |array: u32| read_u32(data, array + layout::HEADER_CLASS_OFFSET);
+            let is_array = |id: u32| (layout::class_id::BYTE_ARRAY..=layout::class_id::OBJECT_ARRAY).contains(&id);
+            let component = |array: u32| read_u32(data, array + layout::ARRAY_COMPONENT_OFFSET);
+            let id = class(src);
+            // Like the JVM, primitive arrays take only their own type and arrays of
+            // references only references. Those with different components would need
+            // a store check per element, which only copying into an Object[] can skip.
+            if !is_array(id) || id != class(dest){
+                return Err(Trap::new("ArrayStoreException: arraycopy between different array types"));
+            }
+            if id == layout::class_id::OBJECT_ARRAY && component(src) != component(dest) && component(dest) != layout::type_tag(layout::class_id::OBJECT, 0){
+                return Err(Trap::new("arraycopy with store checks is not implemented"));
+            }
+            let fits = |array: u32, pos: i32| pos >= 0 && length >= 0 && pos as u64 + length as u64 <= read_u32(data, array + layout::ARRAY_LENGTH_OFFSET) as u64;
+            if !fits(src, src_pos) || !fits(dest, dest_pos){
+                return Err(Trap::new("arraycopy out of bounds"));
+            }
+            let shift = layout::element_shift(id);
+            let from = (src + layout::ARRAY_DATA_OFFSET) as usize + ((src_pos as usize) << shift);
+            let to = (dest + layout::ARRAY_DATA_OFFSET) as usize + ((dest_pos as usize) << shift);
+            let length = (length as usize) << shift;
+            data.copy_within(from..from + length, to);
+            Ok(())
+        }
+    )?;
+    defined.insert(format!("{}.format_double", RUNTIME_MODULE));
+    linker.func_wrap(RUNTIME_MODULE, "format_double", |mut caller: Caller<'_, State>, value: F64, is_float: i32, buffer: i32|{
+        let text = match is_float {
+            0 => format_double(value.to_float()),
+            _ => format_float(value.to_float() as f32)
+        };
+        let memory = caller.get_export("memory").and_then(Extern::into_memory).expect("Modules export their memory");
+        memory.data_mut(&mut caller)[buffer as usize..][..text.len()].copy_from_slice(text.as_bytes());
+        text.len() as i32
+    })?;
+    defined.insert(format!("{}.{}", RUNTIME_MODULE, INFLATE));
+    linker.func_wrap(RUNTIME_MODULE, INFLATE, |mut caller: Caller<'_, State>, destination: i32, source: i32, length: i32, size: i32| -> Result<(), Trap>{
+        let memory = caller.get_export("memory").and_then(Extern::into_memory).expect("Modules export their memory");
+        let data = memory.data_mut(&mut caller);
+        let mut inflated = Vec::with_capacity(size as usize);
+        DeflateDecoder::new(&data[source as usize..][..length as usize]).read_to_end(&mut inflated).map_err(|e| Trap::new(e.to_string()))?;
+        if inflated.len() != size as usize{
+            return Err(Trap::new("Inflated resource has the wrong size"));
+        }
+        data[destination as usize..][..size as usize].copy_from_slice(&inflated);
+        Ok(())
+    })?;
+    defined.insert(format!("{}.drem", RUNTIME_MODULE));
+    linker.func_wrap(RUNTIME_MODULE, "drem", |_: Caller<'_, State>, a: F64, b: F64| F64::from(a.to_float() % b.to_float()))?;
+    Ok(defined)
+}
+
+/// Defines the imports of the module that have no definition yet as traps,
+/// except for those from `linked`.
+fn stub_missing(linker: &mut Linker<State>, module: &Module, defined: &mut HashSet<String>, linked: Option<&str>) -> anyhow::Result<()>{
+    for import in module.imports(){
+        let name = format!("{}.{}", import.module(), import.name());
+        if Some(import.module()) == linked || !defined.insert(name.clone()){
+            continue;
+        }
+        let Some(ty) = import.ty().func().cloned() else {
+            bail!("Unexpected import {}", name);
+        };
+        linker.func_new(import.module(), import.name(), ty, move |_, _, _| Err(Trap::new(format!("{} is not implemented by the harness", name))))?;
+    }
+    Ok(())
+}
+
+/// Instantiates the module with the stubs, which initializes the entry classes.
+pub fn instantiate(wasm: &[u8]) -> anyhow::Result<(Store<State>, Instance)>{
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm)?;
+    let mut store = Store::new(&engine, State::default());
+    let mut linker = Linker::new(&engine);
+    let split_longs = module.exports().any(|e| e.name() == HIGH_BITS_EXPORT);
+    let mut stubbed = stubs(&mut linker, split_longs)?;
+    stub_missing(&mut linker, &module, &mut stubbed, None)?;
+
+    let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+    Ok((store, instance))
+}
+
+/// Instantiates the modules of a split translation, the core first, then
+/// calls the start function of the core, which initializes the entry classes.
+/// Returns the core.
+pub fn instantiate_linked(modules: &[LinkedModule]) -> anyhow::Result<(Store<State>, Instance)>{
+    let engine = Engine::default();
+    let mut store = Store::new(&engine, State::default());
+    let mut linker = Linker::new(&engine);
+    let modules = modules.iter().map(|m| Module::new(&engine, &m.wasm[..])).collect::<Result<Vec<_>, _>>()?;
+    let Some(core) = modules.first() else {
+        bail!("No modules to link");
+    };
+    let split_longs = core.exports().any(|e| e.name() == HIGH_BITS_EXPORT);
+    let mut stubbed = stubs(&mut linker, split_longs)?;
+
+    let mut instances = Vec::new();
+    for module in modules.iter(){
+        stub_missing(&mut linker, module, &mut stubbed, Some(CORE))?;
+        let instance = linker.instantiate(&mut store, module)?.ensure_no_start(&mut store)?;
+        if instances.is_empty(){
+            for export in instance.exports(&store).map(|e| (e.name().to_string(), e.into_extern())).collect::<Vec<_>>(){
+                linker.define(CORE, &export.0, export.1)?;
+            }
+        }
+        instances.push(instance);
+    }
+    let core = instances[0];
+    core.get_typed_func::<(), ()>(&store, START_EXPORT)?.call(&mut store, ())?;
+    Ok((store, core))
+}
+
+/// Instantiates the core of a lazily loaded translation and calls its start
+/// function, the other modules are instantiated once the core loads them.
+/// Returns the core.
+pub fn instantiate_lazy(modules: &[LinkedModule]) -> anyhow::Result<(Store<State>, Instance)>{
+    let Some((core, chunks)) = modules.split_first() else {
+        bail!("No modules to link");
+    };
+
+    let engine = Engine::default();
+    let mut store = Store::new(&engine, State::default());
+    let mut linker = Linker::new(&engine);
+    let core_module = Module::new(&engine, &core.wasm[..])?;
+    let split_longs = core_module.exports().any(|e| e.name() == HIGH_BITS_EXPORT);
+    let mut stubbed = stubs(&mut linker, split_longs)?;
+    stubbed.insert(format!("{}.{}", RUNTIME_MODULE, LOAD_CHUNK));
+    linker.func_wrap(RUNTIME_MODULE, LOAD_CHUNK, load_chunk)?;
+    stub_missing(&mut linker, &core_module, &mut stubbed, None)?;
+    let modules = chunks.iter().map(|chunk| Ok((chunk.id, Module::new(&engine, &chunk.wasm[..])?))).collect::<anyhow::Result<HashMap<_, _>>>()?;
+    for module in modules.values(){
+        stub_missing(&mut linker, module, &mut stubbed, Some(CORE))?;
+    }
+
+    let instance = linker.instantiate(&mut store, &core_module)?.ensure_no_start(&mut store)?;
+    for export in instance.exports(&store).map(|e| (e.name().to_string(), e.into_extern())).collect::<Vec<_>>(){
+        linker.define(CORE, &export.0, export.1)?;
+    }
+    store.data_mut().chunks = Some(Arc::new(Chunks{modules, linker}));
+    instance.get_typed_func::<(), ()>(&store, START_EXPORT)?.call(&mut store, ())?;
+    Ok((store, instance))
+}
+
+/// Calls an exported static method taking an int and returning a `long`
+/// from a module that splits `long`s at the boundary.
+pub fn call_split_long(wasm: &[u8], export: &str, argument: i32) -> anyhow::Result<i64>{
+    let (mut store, instance) = instantiate(wasm)?;
+    let low = instance.get_typed_func::<i32, i32>(&store, export)?.call(&mut store, argument)?;
+    let Some(Value::I32(high)) = instance.get_global(&store, HIGH_BITS_EXPORT).map(|g| g.get(&store)) else {
+        bail!("No high bits global exported");
+    };
+    Ok(((high as i64) << 32) | low as u32 as i64)
+}
+
+/// Instantiates the module, which initializes the entry classes, and calls
+/// the `static void main(String[])` of `class` with `null` arguments.
+pub fn run_main(wasm: &[u8], class: &str) -> anyhow::Result<Run>{
+    run(instantiate(wasm)?, |store, instance|{
+        let main = instance.get_typed_func::<i32, ()>(&*store, &format!("{}.main([Ljava/lang/String;)V", class))?;
+        Ok(main.call(store, layout::NULL as i32)?)
+    })
+}
+
+/// Instantiates the module and calls an exported `static void` method without parameters.
+pub fn run_static(wasm: &[u8], export: &str) -> anyhow::Result<Run>{
+    run(instantiate(wasm)?, |store, instance|{
+        Ok(instance.get_typed_func::<(), ()>(&*store, export)?.call(store, ())?)
+    })
+}
+
+/// Like [`run_main`] for the modules of a split translation.
+pub fn run_linked_main(modules: &[LinkedModule], class: &str) -> anyhow::Result<Run>{
+    run(instantiate_linked(modules)?, |store, instance|{
+        let main = instance.get_typed_func::<i32, ()>(&*store, &format!("{}.main([Ljava/lang/String;)V", class))?;
+        Ok(main.call(store, layout::NULL as i32)?)
+    })
+}
+
+/// Like [`run_main`] for a lazily loaded translation, see [`instantiate_lazy`].
+pub fn run_lazy_main(modules: &[LinkedModule], class: &str) -> anyhow::Result<Run>{
+    run(instantiate_lazy(modules)?, |store, instance|{
+        let main = instance.get_typed_func::<i32, ()>(&*store, &format!("{}.main([Ljava/lang/String;)V", class))?;
+        Ok(main.call(store, layout::NULL as i32)?)
+    })
+}
+
+/// Calls an export of the instance with `call` unless initializing the module threw.
+fn run((mut store, instance): (Store<State>, Instance), call: impl FnOnce(&mut Store<State>, &Instance) -> anyhow::Result<()>) -> anyhow::Result<Run>{
+    let exception = |store: &Store<State>|{
+        match instance.get_global(store, EXCEPTION_EXPORT).map(|g| g.get(store)) {
+            Some(Value::I32(address)) => Ok(address as u32),
+            _ => bail!("No exception global exported")
+        }
+    };
+    if exception(&store)? == layout::NULL{
+        call(&mut store, &instance)?;
+    }
+
+    Ok(Run{
+        exception: exception(&store)?,
+        stdout: std::mem::take(&mut store.data_mut().stdout),
+        stderr: std::mem::take(&mut store.data_mut().stderr),
+        loaded: std::mem::take(&mut store.data_mut().loaded)
+    })
+}
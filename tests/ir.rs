@@ -0,0 +1,150 @@
+//! The SSA form of the runtime and the golden test classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::fs;
+
+use harness::{GOLDEN, RUNTIME_JAR};
+
+use noak::{AccessFlags, reader::cpool::ConstantPool};
+use wasm_shit::{blocks::{self, Handler}, classpath::read_jar, data::ParsedClass, ir, lower::entry_stacks};
+
+const CHECKS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/ir/Checks.class");
+const HANDLERS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/ir/Handlers.class");
+
+/// Builds, verifies, optimizes and verifies again a method, then calls `f`
+/// with its printed form before optimizing and the optimized function.
+fn with_function<R>(class: &ParsedClass, name: &[u8], descriptor: &[u8], f: impl FnOnce(String, &ir::Function, &ConstantPool) -> anyhow::Result<R>) -> anyhow::Result<R>{
+    let method = class.method(name, descriptor).expect("The method exists");
+    let result = class.with_code(name, descriptor, |cp, code|{
+        let instructions = code.raw_instructions().collect::<Result<Vec<_>, _>>()?;
+        let handlers = Handler::read_all(code, cp)?;
+        let blocks = blocks::split(&instructions, &handlers)?;
+        let entries = entry_stacks(&blocks, &instructions, &handlers, cp)?;
+        let mut function = ir::build(cp, &instructions, &blocks, &handlers, &entries, method)?;
+        ir::verify(&function, cp)?;
+        let built = function.to_string();
+        ir::optimize(&mut function);
+        ir::verify(&function, cp)?;
+        f(built, &function, cp)
+    })?;
+    Ok(result.expect("The method has code"))
+}
+
+/// The printed form of a method before and after optimizing.
+fn print(class: &ParsedClass, name: &[u8], descriptor: &[u8]) -> anyhow::Result<(String, String)>{
+    with_function(class, name, descriptor, |built, function, _| Ok((built, function.to_string())))
+}
+
+#[test]
+fn every_method_verifies() -> anyhow::Result<()>{
+    let mut classes = read_jar(RUNTIME_JAR)?;
+    for entry in fs::read_dir(GOLDEN)?{
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "class"){
+            classes.push(ParsedClass::parse(&fs::read(&path)?)?);
+        }
+    }
+
+    let mut checked = 0;
+    for class in classes.iter(){
+        for method in class.methods.iter().filter(|m| !m.flags.intersects(AccessFlags::NATIVE | AccessFlags::ABSTRACT)){
+            match with_function(class, &method.name, &method.descriptor, |_, function, cp| ir::Checks::analyze(function, cp)) {
+                Ok(_) => checked += 1,
+                // Unsupported instructions fail the same way without the SSA form.
+                Err(e) if e.to_string().contains("not supported yet") => (),
+                Err(e) => panic!("{}.{}{}: {:?}", String::from_utf8_lossy(&class.name), String::from_utf8_lossy(&method.name), String::from_utf8_lossy(&method.descriptor), e)
+            }
+        }
+    }
+    assert!(checked > 50, "Only {} methods were checked", checked);
+    Ok(())
+}
+
+#[test]
+fn phis_at_joins() -> anyhow::Result<()>{
+    let class = harness::golden("Switches");
+    let (built, optimized) = print(&class, b"fallThrough", b"(I)I")?;
+    // `total` is a phi where the cases fall through and after the switch.
+    assert!(built.contains("v5: I32 = phi [b1: v1], [b2: v4]"), "{}", built);
+    // Loads are copies until they're propagated, `0 + 1` and `0 + 100` are folded.
+    assert_eq!(optimized, "\
+b0:
+    v0: I32 = param 0
+    jump b1
+b1 @0: preds b0
+    v1: I32 = const 0
+    switch v0 [1: b2] [2: b3] [3: b4] default b5
+b2 @28: preds b1
+    v4: I32 = const 1
+    jump b3
+b3 @31: preds b1, b2
+    v5: I32 = phi [b1: v1], [b2: v4]
+    v6: I32 = const 10
+    v7: I32 = @31 IAdd v5, v6
+    jump b5
+b4 @37: preds b1
+    v9: I32 = const 100
+    jump b5
+b5 @40: preds b1, b3, b4
+    v10: I32 = phi [b1: v1], [b3: v7], [b4: v9]
+    @41 IReturn v10
+");
+    Ok(())
+}
+
+#[test]
+fn redundant_checks() -> anyhow::Result<()>{
+    let class = ParsedClass::parse(&fs::read(CHECKS)?)?;
+    let counts = |name: &[u8], descriptor: &[u8]| -> anyhow::Result<[usize; 4]>{
+        let checks = with_function(&class, name, descriptor, |_, function, cp| ir::Checks::analyze(function, cp))?;
+        Ok([checks.null_checks, checks.null_checks_removed(), checks.bounds_checks, checks.bounds_checks_removed()])
+    };
+    // `this` is never null.
+    assert_eq!(counts(b"twice", b"()I")?, [2, 2, 0, 0]);
+    // Only the first dereference can fail.
+    assert_eq!(counts(b"follow", b"(LChecks;)I")?, [2, 1, 0, 0]);
+    assert_eq!(counts(b"guarded", b"(LChecks;)I")?, [1, 1, 0, 0]);
+    // The loop condition takes the length, which checks the array, and keeps the index in bounds.
+    assert_eq!(counts(b"sum", b"([I)I")?, [2, 1, 1, 1]);
+    assert_eq!(counts(b"unknown", b"([II)I")?, [1, 0, 1, 0]);
+    // The try block dereferences `values` and the handler the exception it caught,
+    // but the handler can't rely on `values`, as the first access can throw.
+    assert_eq!(counts(b"caught", b"([I)I")?, [5, 2, 1, 0]);
+    Ok(())
+}
+
+#[test]
+fn exception_edges() -> anyhow::Result<()>{
+    let class = ParsedClass::parse(&fs::read(HANDLERS)?)?;
+    let (_, optimized) = print(&class, b"divide", b"([II)I")?;
+    // The store after the division starts a block of its own, so `result` is
+    // the same wherever the try block throws, and the handler's entry block
+    // takes it as a phi next to the caught exception.
+    assert_eq!(optimized, "\
+b0:
+    v0: Ref = param 0
+    v1: I32 = param 1
+    jump b1
+b1 @0: preds b0 catch b6
+    v3: I32 = const -1
+    v6: I32 = @4 IALoad v0, v1
+    jump b4
+b2 @14: preds b6
+    @16 IReturn v10
+b3 @17: preds b5
+    @18 IReturn v9
+b4 @5: preds b1 catch b6
+    v7: I32 = const 10
+    v9: I32 = @9 IDiv v7, v6
+    jump b5
+b5 @10: preds b4
+    jump b3
+b6: preds b1, b4
+    v10: I32 = phi [b1: v3], [b4: v6]
+    v2: Ref = catch
+    jump b2
+");
+    Ok(())
+}
@@ -0,0 +1,40 @@
+public class Checks {
+    int value;
+
+    int twice() {
+        return value + value;
+    }
+
+    static int follow(Checks c) {
+        int a = c.value;
+        int b = c.value;
+        return a + b;
+    }
+
+    static int guarded(Checks c) {
+        if (c != null) {
+            return c.value;
+        }
+        return 0;
+    }
+
+    static int sum(int[] values) {
+        int total = 0;
+        for (int i = 0; i < values.length; i++) {
+            total += values[i];
+        }
+        return total;
+    }
+
+    static int unknown(int[] values, int i) {
+        return values[i];
+    }
+
+    static int caught(int[] values) {
+        try {
+            return values[0] + values.length;
+        } catch (ArrayIndexOutOfBoundsException e) {
+            return values.length + e.getMessage().length();
+        }
+    }
+}
@@ -0,0 +1,12 @@
+public class Handlers {
+    static int divide(int[] values, int i) {
+        int result = -1;
+        try {
+            result = values[i];
+            result = 10 / result;
+        } catch (ArithmeticException e) {
+            return result;
+        }
+        return result;
+    }
+}
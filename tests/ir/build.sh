@@ -0,0 +1,6 @@
+#!/bin/sh
+# Compiles the classes the SSA form tests look at.
+set -e
+cd "$(dirname "$0")"
+rm -f *.class
+javac --release 17 -encoding UTF-8 -d . *.java
@@ -0,0 +1,57 @@
+//! Keep rules applied to the runtime and the golden test classes.
+
+#[allow(dead_code)]
+mod harness;
+
+use harness::RUNTIME_JAR;
+
+use wasm_shit::{classpath::read_jar, keep::KeepRules, program::{MethodKey, Program}, reach::{Item, Reachability, Roots}};
+
+fn program() -> anyhow::Result<Program>{
+    let mut classes = read_jar(RUNTIME_JAR)?;
+    for nested in ["", "$Base", "$Dot", "$Rectangle", "$Shape", "$Square"]{
+        classes.push(harness::golden(&format!("Objects{}", nested)));
+    }
+    Ok(Program::new(classes))
+}
+
+#[test]
+fn rules_are_roots() -> anyhow::Result<()>{
+    let program = program()?;
+    let rules = KeepRules::parse(r#"
+        # Created reflectively
+        -keep class Objects$Sq* extends Objects$Base {
+            <init>(int);
+            int side;
+        }
+        -keep public class java.lang.** extends java.lang.RuntimeException {
+            public <init>(...);
+        }
+        -keep interface Objects$* {
+            !abstract *** describe();
+        }
+    "#)?;
+    assert_eq!(rules.rules().iter().map(|r| r.line).collect::<Vec<_>>(), [3, 7, 10]);
+
+    let mut roots = Roots::default();
+    rules.add_roots(&program, &mut roots);
+    let reachability = Reachability::analyze(&program, &roots)?;
+    let kept = |item: Item| reachability.contains(&item);
+
+    assert!(kept(Item::Method(MethodKey::new(b"Objects$Square", b"<init>", b"(I)V"))));
+    assert!(!kept(Item::Method(MethodKey::new(b"Objects$Square", b"area", b"()I"))));
+    assert!(kept(Item::Field(b"Objects$Square".as_slice().into(), b"side".as_slice().into())));
+    assert!(kept(Item::Method(MethodKey::new(b"java/lang/ArithmeticException", b"<init>", b"()V"))));
+    assert!(kept(Item::Method(MethodKey::new(b"Objects$Shape", b"describe", b"()Ljava/lang/String;"))));
+    assert!(!kept(Item::Class(b"Objects$Dot".as_slice().into())));
+    assert!(!kept(Item::Class(b"Objects".as_slice().into())));
+    Ok(())
+}
+
+#[test]
+fn errors_have_positions(){
+    let error = KeepRules::parse("-keep class Foo {\n    int x\n}\n").unwrap_err().to_string();
+    assert!(error.starts_with("Line 3, column 1:"), "{}", error);
+    let error = KeepRules::parse("-keep clas Foo").unwrap_err().to_string();
+    assert!(error.starts_with("Line 1, column 7:"), "{}", error);
+}
@@ -0,0 +1,80 @@
+//! Parsing manifests and following their class paths.
+
+use std::{fs::{self, File}, io::Write, path::Path};
+
+use wasm_shit::{classpath::{class_path, Seals}, manifest::{Manifest, MANIFEST_PATH}};
+use zip::{write::FileOptions, ZipWriter};
+
+/// A JAR holding only the manifest.
+fn jar(path: &Path, manifest: &str){
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    zip.start_file(MANIFEST_PATH, FileOptions::default()).unwrap();
+    zip.write_all(manifest.as_bytes()).unwrap();
+    zip.finish().unwrap();
+}
+
+#[test]
+fn headers_and_sections(){
+    let manifest = Manifest::parse(concat!(
+        "Manifest-Version: 1.0\r\n",
+        "Main-Class: com.example.Vie\r\n",
+        " wer\r\n",
+        "Class-Path: lib/a.jar  lib/with%20space.jar\r\n",
+        "X-Applet-Width: 765\r\n",
+        "\r\n",
+        "Name: com/example/sealed/\r\n",
+        "Sealed: true\r\n",
+        "\r\n",
+        "Name: com/example/Viewer.class\r\n",
+        "SHA1-Digest: UuwWNA6Hl1nSitGYfYz11gM/R1w=\r\n",
+    )).unwrap();
+    assert_eq!(manifest.main_class().as_deref(), Some("com/example/Viewer"));
+    assert_eq!(manifest.class_path(), ["lib/a.jar", "lib/with space.jar"]);
+    assert_eq!(manifest.main.get("x-applet-width"), Some("765"));
+    assert!(manifest.is_sealed("com/example/sealed"));
+    assert!(!manifest.is_sealed("com/example"));
+    assert_eq!(manifest.entries["com/example/Viewer.class"].get("SHA1-Digest"), Some("UuwWNA6Hl1nSitGYfYz11gM/R1w="));
+    assert_eq!(manifest.entries.len(), 2);
+}
+
+#[test]
+fn malformed_manifests_are_rejected(){
+    let error = Manifest::parse("Manifest-Version: 1.0\nno separator\n").unwrap_err();
+    assert_eq!(error.to_string(), "Line 2: expected a `Name: value` header");
+    assert!(Manifest::parse(" continued\n").is_err());
+    assert!(Manifest::parse("Manifest-Version: 1.0\n\nSealed: true\n").is_err(), "Sections need a name");
+}
+
+#[test]
+fn class_paths_are_followed_relative_to_their_jar(){
+    let dir = std::env::temp_dir().join(format!("wasm-shit-manifest-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    jar(&dir.join("main.jar"), "Manifest-Version: 1.0\nMain-Class: Main\nClass-Path: lib/a.jar missing.jar\n");
+    // Paths in a library's manifest are relative to the library, and cycles end.
+    jar(&dir.join("lib/a.jar"), "Manifest-Version: 1.0\nClass-Path: b.jar a.jar\n");
+    jar(&dir.join("lib/b.jar"), "Manifest-Version: 1.0\nClass-Path: ../main.jar\n");
+
+    let jars = class_path(dir.join("main.jar")).unwrap();
+    assert_eq!(jars, [dir.join("main.jar"), dir.join("lib/a.jar"), dir.join("lib/b.jar")]);
+}
+
+#[test]
+fn sealed_packages_come_from_one_jar(){
+    let sealing = Manifest::parse("Manifest-Version: 1.0\n\nName: app/\nSealed: true\n").unwrap();
+    let open = Manifest::default();
+    let (a, b) = (Path::new("a.jar"), Path::new("b.jar"));
+
+    let mut seals = Seals::new();
+    seals.add(a, &sealing, "app/Main").unwrap();
+    seals.add(a, &sealing, "app/Other").unwrap();
+    seals.add(b, &open, "lib/Util").unwrap();
+    seals.add(a, &sealing, "lib/Helper").unwrap();
+    let error = seals.add(b, &open, "app/Extra").unwrap_err();
+    assert_eq!(error.to_string(), "Package app is sealed by a.jar, but app/Extra comes from b.jar");
+
+    // Sealing a package another JAR already has classes in fails too.
+    let mut seals = Seals::new();
+    seals.add(b, &open, "app/Extra").unwrap();
+    assert!(seals.add(a, &sealing, "app/Main").is_err());
+}
@@ -0,0 +1,37 @@
+//! Reachability of classes the host calls into.
+
+#[allow(dead_code)]
+mod harness;
+
+use std::fs;
+
+use harness::RUNTIME_JAR;
+
+use wasm_shit::{classpath::read_jar, data::ParsedClass, program::{MethodKey, Program}, reach::{Reachability, Roots}};
+
+const HOST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/reach/Host");
+
+#[test]
+fn overrides_of_outside_methods_survive_pruning() -> anyhow::Result<()>{
+    let mut classes = read_jar(RUNTIME_JAR)?;
+    for nested in ["", "$Task", "$Clicks"]{
+        classes.push(ParsedClass::parse(&fs::read(format!("{}{}.class", HOST, nested))?)?);
+    }
+    let program = Program::new(classes);
+    let roots = Roots{
+        methods: vec![MethodKey::new(b"Host", b"main", b"([Ljava/lang/String;)V")],
+        ..Roots::default()
+    };
+    let pruned = Reachability::analyze(&program, &roots)?.prune(&program);
+    let has = |class: &[u8], name: &[u8], descriptor: &[u8]| pruned.class(class).is_some_and(|c| c.method(name, descriptor).is_some());
+
+    // `Runnable` and `MouseAdapter` aren't part of the program.
+    assert!(has(b"Host$Task", b"run", b"()V"));
+    assert!(has(b"Host$Task", b"helper", b"()V"));
+    assert!(has(b"Host$Clicks", b"mouseClicked", b"(Ljava/awt/event/MouseEvent;)V"));
+    assert!(has(b"Host$Clicks", b"mouseMoved", b"(Ljava/awt/event/MouseEvent;)V"));
+    // Package-private and private methods can't override them.
+    assert!(!has(b"Host$Task", b"unused", b"()V"));
+    assert!(!has(b"Host$Clicks", b"unused", b"()V"));
+    Ok(())
+}
@@ -0,0 +1,33 @@
+import java.awt.event.MouseAdapter;
+import java.awt.event.MouseEvent;
+
+// Instances handed to the host, which calls methods the program never calls.
+public class Host {
+    static class Task implements Runnable {
+        public void run() {
+            helper();
+        }
+
+        void helper() {
+        }
+
+        void unused() {
+        }
+    }
+
+    static class Clicks extends MouseAdapter {
+        public void mouseClicked(MouseEvent e) {
+        }
+
+        public void mouseMoved(MouseEvent e) {
+        }
+
+        private void unused() {
+        }
+    }
+
+    public static void main(String[] args) {
+        new Task();
+        new Clicks();
+    }
+}
@@ -0,0 +1,73 @@
+//! Decoding modified UTF-8 constants and laying out the literals.
+
+use wasm_shit::{layout::{self, DataSegment}, strings::{decode_mutf8, StringPool}};
+
+#[test]
+fn valid_sequences(){
+    let cases: &[(&[u8], &[u16])] = &[
+        (b"", &[]),
+        (b"Hello", &[0x48, 0x65, 0x6C, 0x6C, 0x6F]),
+        // NUL takes two bytes.
+        (&[0xC0, 0x80], &[0x0000]),
+        (&[0x61, 0xC0, 0x80, 0x62], &[0x61, 0x0000, 0x62]),
+        (&[0xC3, 0xA9], &[0x00E9]),
+        (&[0xDF, 0xBF], &[0x07FF]),
+        (&[0xE0, 0xA0, 0x80], &[0x0800]),
+        (&[0xE2, 0x82, 0xAC], &[0x20AC]),
+        (&[0xEF, 0xBF, 0xBF], &[0xFFFF]),
+        // U+1F600 is two 3-byte sequences, one per surrogate.
+        (&[0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80], &[0xD83D, 0xDE00]),
+        // Lone surrogates are kept as they are.
+        (&[0xED, 0xB8, 0x80, 0x41], &[0xDE00, 0x41]),
+    ];
+    for (bytes, units) in cases{
+        assert_eq!(decode_mutf8(bytes).unwrap(), *units, "Decoding {:02x?}", bytes);
+    }
+}
+
+#[test]
+fn invalid_sequences(){
+    let cases: &[(&[u8], &str)] = &[
+        (&[0x00], "Raw NUL byte at 0 in modified UTF-8"),
+        (&[0x41, 0x00, 0x42], "Raw NUL byte at 1 in modified UTF-8"),
+        (&[0xC3], "Truncated modified UTF-8 sequence at 1"),
+        (&[0x41, 0xE2, 0x82], "Truncated modified UTF-8 sequence at 3"),
+        (&[0xED, 0xA0, 0xBD, 0xED], "Truncated modified UTF-8 sequence at 4"),
+        (&[0xC3, 0x41], "Invalid continuation byte 0x41 at 1"),
+        (&[0xE2, 0x82, 0xC0], "Invalid continuation byte 0xc0 at 2"),
+        (&[0x80], "Invalid modified UTF-8 lead byte 0x80 at 0"),
+        // Standard UTF-8 encodes supplementary characters with 4 bytes, modified UTF-8 never does.
+        (&[0xF0, 0x9F, 0x98, 0x80], "Invalid modified UTF-8 lead byte 0xf0 at 0"),
+    ];
+    for (bytes, message) in cases{
+        let error = decode_mutf8(bytes).expect_err(&format!("Decoding {:02x?} should fail", bytes));
+        assert_eq!(error.to_string(), *message, "Decoding {:02x?}", bytes);
+    }
+}
+
+/// The value array of the string at `address`, and its coder.
+fn value(segment: &DataSegment, address: u32) -> (&[u8], u8){
+    let bytes = segment.bytes();
+    let at = |address: u32| (address - segment.base()) as usize;
+    let word = |address: u32| u32::from_le_bytes(bytes[at(address)..at(address) + 4].try_into().unwrap());
+    let array = word(address + layout::STRING_VALUE_OFFSET);
+    let length = word(array + layout::ARRAY_LENGTH_OFFSET) as usize;
+    let data = at(array + layout::ARRAY_DATA_OFFSET);
+    (&bytes[data..data + length], bytes[at(address + layout::STRING_CODER_OFFSET)])
+}
+
+#[test]
+fn literals_keep_nul_and_supplementary_characters(){
+    let pool = StringPool::new();
+    let nul = [0x61, 0xC0, 0x80, 0x62];
+    let grin = [0x3A, 0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80];
+    pool.intern(&nul).unwrap();
+    pool.intern(&grin).unwrap();
+    assert!(pool.intern(&[0x00]).is_err());
+
+    let mut segment = DataSegment::new(layout::DATA_BASE);
+    let table = pool.layout(&mut segment);
+    assert_eq!(value(&segment, table.address_of(&nul).unwrap()), (&b"a\0b"[..], layout::CODER_LATIN1));
+    let utf16 = [0x3A, 0x00, 0x3D, 0xD8, 0x00, 0xDE];
+    assert_eq!(value(&segment, table.address_of(&grin).unwrap()), (&utf16[..], layout::CODER_UTF16));
+}